@@ -55,6 +55,15 @@ pub struct Capabilities {
     )]
     pub thirdparty_id_changes: ThirdPartyIdChangesCapability,
 
+    /// Capability to indicate if the user can generate tokens to log further clients into their
+    /// account.
+    #[serde(
+        rename = "m.get_login_token",
+        default,
+        skip_serializing_if = "GetLoginTokenCapability::is_default"
+    )]
+    pub get_login_token: GetLoginTokenCapability,
+
     /// Any other custom capabilities that the server supports outside of the specification,
     /// labeled using the Java package naming convention and stored as arbitrary JSON values.
     #[serde(flatten)]
@@ -84,6 +93,7 @@ impl Capabilities {
             "m.set_display_name" => Some(Cow::Owned(serialize(&self.set_display_name))),
             "m.set_avatar_url" => Some(Cow::Owned(serialize(&self.set_avatar_url))),
             "m.3pid_changes" => Some(Cow::Owned(serialize(&self.thirdparty_id_changes))),
+            "m.get_login_token" => Some(Cow::Owned(serialize(&self.get_login_token))),
             _ => self.custom_capabilities.get(capability).map(Cow::Borrowed),
         }
     }
@@ -100,6 +110,7 @@ impl Capabilities {
             "m.set_display_name" => self.set_display_name = from_json_value(value)?,
             "m.set_avatar_url" => self.set_avatar_url = from_json_value(value)?,
             "m.3pid_changes" => self.thirdparty_id_changes = from_json_value(value)?,
+            "m.get_login_token" => self.get_login_token = from_json_value(value)?,
             _ => {
                 self.custom_capabilities.insert(capability.to_owned(), value);
             }
@@ -278,6 +289,75 @@ impl Default for ThirdPartyIdChangesCapability {
         Self { enabled: true }
     }
 }
+
+/// Information about the `m.get_login_token` capability.
+#[derive(ToSchema, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GetLoginTokenCapability {
+    /// Whether the user can request a login token.
+    pub enabled: bool,
+}
+
+impl GetLoginTokenCapability {
+    /// Creates a new `GetLoginTokenCapability` with the given enabled flag.
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+
+    /// Returns whether all fields have their default value.
+    pub fn is_default(&self) -> bool {
+        !self.enabled
+    }
+}
+
+/// Information about the `m.password_policy` capability, the rules new passwords have to follow
+/// ([MSC2000]).
+///
+/// [MSC2000]: https://github.com/matrix-org/matrix-spec-proposals/pull/2000
+#[derive(ToSchema, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PasswordPolicyCapability {
+    /// The minimum number of characters.
+    #[serde(rename = "m.minimum_length", default, skip_serializing_if = "Option::is_none")]
+    pub minimum_length: Option<u64>,
+
+    /// Whether the password has to contain a digit.
+    #[serde(
+        rename = "m.require_digit",
+        default,
+        skip_serializing_if = "crate::serde::is_default"
+    )]
+    pub require_digit: bool,
+
+    /// Whether the password has to contain a symbol.
+    #[serde(
+        rename = "m.require_symbol",
+        default,
+        skip_serializing_if = "crate::serde::is_default"
+    )]
+    pub require_symbol: bool,
+
+    /// Whether the password has to contain a lowercase letter.
+    #[serde(
+        rename = "m.require_lowercase",
+        default,
+        skip_serializing_if = "crate::serde::is_default"
+    )]
+    pub require_lowercase: bool,
+
+    /// Whether the password has to contain an uppercase letter.
+    #[serde(
+        rename = "m.require_uppercase",
+        default,
+        skip_serializing_if = "crate::serde::is_default"
+    )]
+    pub require_uppercase: bool,
+}
+
+impl PasswordPolicyCapability {
+    /// Returns whether all fields have their default value.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
 /// Iterator implementation for `Capabilities`
 
 /// Reference to a capability.
@@ -372,6 +452,14 @@ impl<'a> Iterator for CapabilitiesIter<'a> {
                     caps: self.caps,
                 })
             }
+            5 => {
+                self.pos += 1;
+                Some(CapabilityRef {
+                    name: "m.get_login_token",
+                    value: None,
+                    caps: self.caps,
+                })
+            }
             _ => self.custom_caps_iterator.next().map(|(name, value)| CapabilityRef {
                 name,
                 value: Some(value),
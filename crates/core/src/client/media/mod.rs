@@ -10,7 +10,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::media::Method;
 use crate::sending::{SendRequest, SendResult};
-use crate::{OwnedMxcUri, OwnedServerName, ServerName, UnixMillis};
+use crate::{OwnedMxcUri, OwnedServerName, RawJsonValue, ServerName, UnixMillis};
 
 /// The default duration that the client should be willing to wait to start receiving data.
 pub(crate) fn default_download_timeout() -> Duration {
@@ -107,48 +107,37 @@ impl ConfigResBody {
 //     }
 // };
 
-// /// Request type for the `get_media_preview` endpoint.
-
-// pub struct MediaPreviewReqBody {
-//     /// URL to get a preview of.
-//     #[salvo(parameter(parameter_in = Query))]
-//     pub url: String,
-
-//     /// Preferred point in time (in milliseconds) to return a preview for.
-//     #[salvo(parameter(parameter_in = Query))]
-//     pub ts: UnixMillis,
-// }
+/// Request type for the `get_media_preview` endpoint.
+#[derive(ToParameters, Deserialize, Debug)]
+pub struct MediaPreviewReqArgs {
+    /// URL to get a preview of.
+    #[salvo(parameter(parameter_in = Query))]
+    pub url: String,
 
-// /// Response type for the `get_media_preview` endpoint.
-// #[derive(ToSchema,Serialize, Debug)]
-// pub struct MediaPreviewResBody {
-//     /// OpenGraph-like data for the URL.
-//     ///
-//     /// Differences from OpenGraph: the image size in bytes is added to the `matrix:image:size`
-//     /// field, and `og:image` returns the MXC URI to the image, if any.
-//     #[salvo(schema(value_type = Object, additional_properties = true))]
-//     pub data: Option<Box<RawJsonValue>>,
-// }
-// impl MediaPreviewResBody {
-//     /// Creates an empty `Response`.
-//     pub fn new() -> Self {
-//         Self { data: None }
-//     }
+    /// Preferred point in time (in milliseconds) to return a preview for.
+    #[salvo(parameter(parameter_in = Query))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts: Option<UnixMillis>,
+}
 
-//     /// Creates a new `Response` with the given OpenGraph data (in a
-//     /// `serde_json::value::RawValue`).
-//     pub fn from_raw_value(data: Box<RawJsonValue>) -> Self {
-//         Self { data: Some(data) }
-//     }
+/// Response type for the `get_media_preview` endpoint.
+#[derive(ToSchema, Serialize, Debug)]
+#[serde(transparent)]
+pub struct MediaPreviewResBody {
+    /// OpenGraph-like data for the URL.
+    ///
+    /// Differences from OpenGraph: the image size in bytes is added to the `matrix:image:size`
+    /// field, and `og:image` returns the MXC URI to the image, if any.
+    #[salvo(schema(value_type = Object, additional_properties = true))]
+    pub data: Box<RawJsonValue>,
+}
+impl MediaPreviewResBody {
+    /// Creates a new `MediaPreviewResBody` with the given OpenGraph data.
+    pub fn from_raw_value(data: Box<RawJsonValue>) -> Self {
+        Self { data }
+    }
+}
 
-//     /// Creates a new `Response` with the given OpenGraph data (in any kind of serializable
-//     /// object).
-//     pub fn from_serialize<T: Serialize>(data: &T) -> serde_json::Result<Self> {
-//         Ok(Self {
-//             data: Some(to_raw_json_value(data)?),
-//         })
-//     }
-// }
 pub fn thumbnail_request(origin: &str, server: &ServerName, args: ThumbnailReqArgs) -> SendResult<SendRequest> {
     let mut url = Url::parse(&format!(
         "{origin}/_matrix/media/v3/thumbnail/{server}/{}",
@@ -158,9 +147,15 @@ pub fn thumbnail_request(origin: &str, server: &ServerName, args: ThumbnailReqAr
         let mut query = url.query_pairs_mut();
         query.append_pair("width", &args.width.to_string());
         query.append_pair("height", &args.height.to_string());
+        if let Some(method) = &args.method {
+            query.append_pair("method", method.as_str());
+        }
         query.append_pair("allow_remote", &args.allow_remote.to_string());
         query.append_pair("timeout_ms", &args.timeout_ms.as_millis().to_string());
         query.append_pair("allow_redirect", &args.allow_redirect.to_string());
+        if let Some(animated) = args.animated {
+            query.append_pair("animated", &animated.to_string());
+        }
     }
     Ok(crate::sending::get(url))
 }
@@ -222,6 +217,15 @@ pub struct ThumbnailReqArgs {
     #[salvo(parameter(parameter_in = Query))]
     #[serde(default, skip_serializing_if = "crate::serde::is_default")]
     pub allow_redirect: bool,
+
+    /// Whether the server should return an animated thumbnail.
+    ///
+    /// When `Some(true)`, the server should return an animated thumbnail if possible and
+    /// supported. When `Some(false)`, the server must not return an animated
+    /// thumbnail. When `None`, the server should not return an animated thumbnail.
+    #[salvo(parameter(parameter_in = Query))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub animated: Option<bool>,
 }
 
 // /// Response type for the `get_content_thumbnail` endpoint.
@@ -185,10 +185,11 @@ impl AvailableResBody {
 // };
 
 /// Request type for the `check_registration_token_validity` endpoint.
-#[derive(ToSchema, Deserialize, Debug)]
-pub struct ValidateTokenReqBody {
+#[derive(ToParameters, Deserialize, Debug)]
+pub struct ValidateTokenReqArgs {
     /// The registration token to check the validity of.
-    pub registration_token: String,
+    #[salvo(parameter(parameter_in = Query))]
+    pub token: String,
 }
 
 /// Response type for the `check_registration_token_validity` endpoint.
@@ -471,6 +471,7 @@ impl UserProfile {
 /// Represents either a room or user ID for returning grouped search results.
 #[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 #[allow(clippy::exhaustive_enums)]
+#[serde(untagged)]
 pub enum OwnedRoomIdOrUserId {
     /// Represents a room ID.
     RoomId(OwnedRoomId),
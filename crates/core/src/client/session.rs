@@ -8,6 +8,7 @@ use serde::{
 };
 use serde_json::Value as JsonValue;
 
+use crate::client::discovery::PasswordPolicyCapability;
 use crate::client::uiaa::{AuthData, UserIdentifier};
 use crate::serde::{JsonObject, StringEnum};
 use crate::{OwnedDeviceId, OwnedMxcUri, OwnedUserId, PrivOwnedStr};
@@ -521,12 +522,16 @@ impl LoginType {
 /// The payload for password login.
 #[derive(ToSchema, Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(tag = "type", rename = "m.login.password")]
-pub struct PasswordLoginType {}
+pub struct PasswordLoginType {
+    /// The rules new passwords have to follow.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_policy: Option<PasswordPolicyCapability>,
+}
 
 impl PasswordLoginType {
     /// Creates a new `PasswordLoginType`.
     pub fn new() -> Self {
-        Self {}
+        Self::default()
     }
 }
 
@@ -720,6 +725,44 @@ impl TokenResBody {
         Duration::from_secs(2 * 60)
     }
 }
+
+/// `GET /_matrix/client/*/login/sso/redirect`
+///
+/// Redirects the user to the SSO interface of the homeserver.
+/// `/v3/` ([spec])
+///
+/// [spec]: https://spec.matrix.org/latest/client-server-api/#get_matrixclientv3loginssoredirect
+
+/// Request type for the `sso_login` endpoint.
+#[derive(ToParameters, Deserialize, Debug)]
+pub struct SsoRedirectReqArgs {
+    /// URL to which the homeserver should return the user after completing
+    /// authentication with the SSO identity provider.
+    #[salvo(parameter(parameter_in = Query))]
+    #[serde(rename = "redirectUrl")]
+    pub redirect_url: String,
+}
+
+/// `GET /_matrix/client/*/login/sso/redirect/{idpId}`
+///
+/// Redirects the user to the SSO interface of the given identity provider.
+/// `/v3/` ([spec])
+///
+/// [spec]: https://spec.matrix.org/latest/client-server-api/#get_matrixclientv3loginssoredirectidpid
+
+/// Request type for the `sso_login_with_provider` endpoint.
+#[derive(ToParameters, Deserialize, Debug)]
+pub struct SsoRedirectWithProviderReqArgs {
+    /// The ID of the provider to use for SSO login.
+    #[salvo(parameter(parameter_in = Path))]
+    pub idp_id: String,
+
+    /// URL to which the homeserver should return the user after completing
+    /// authentication with the SSO identity provider.
+    #[salvo(parameter(parameter_in = Query))]
+    #[serde(rename = "redirectUrl")]
+    pub redirect_url: String,
+}
 // #[cfg(test)]
 // mod tests {
 //     use assert_matches2::assert_matches;
@@ -41,6 +41,9 @@ pub enum AuthData {
     /// Registration token-based authentication (`m.login.registration_token`).
     RegistrationToken(RegistrationToken),
 
+    /// Terms of service consent (`m.login.terms`).
+    Terms(Terms),
+
     /// Fallback acknowledgement.
     FallbackAcknowledgement(FallbackAcknowledgement),
 
@@ -77,6 +80,7 @@ impl AuthData {
             "m.login.msisdn" => Self::Msisdn(deserialize_variant(session, data)?),
             "m.login.dummy" => Self::Dummy(deserialize_variant(session, data)?),
             "m.registration_token" => Self::RegistrationToken(deserialize_variant(session, data)?),
+            "m.login.terms" => Self::Terms(deserialize_variant(session, data)?),
             _ => Self::_Custom(CustomAuthData {
                 auth_type: auth_type.into(),
                 session,
@@ -99,6 +103,7 @@ impl AuthData {
             Self::Msisdn(_) => Some(AuthType::Msisdn),
             Self::Dummy(_) => Some(AuthType::Dummy),
             Self::RegistrationToken(_) => Some(AuthType::RegistrationToken),
+            Self::Terms(_) => Some(AuthType::Terms),
             Self::FallbackAcknowledgement(_) => None,
             Self::_Custom(c) => Some(AuthType::_Custom(PrivOwnedStr(c.auth_type.as_str().into()))),
         }
@@ -113,6 +118,7 @@ impl AuthData {
             Self::Msisdn(x) => x.session.as_deref(),
             Self::Dummy(x) => x.session.as_deref(),
             Self::RegistrationToken(x) => x.session.as_deref(),
+            Self::Terms(x) => x.session.as_deref(),
             Self::FallbackAcknowledgement(x) => Some(&x.session),
             Self::_Custom(x) => x.session.as_deref(),
         }
@@ -155,8 +161,8 @@ impl AuthData {
                 token: x.token.clone(),
                 session: None,
             })),
-            // Dummy and fallback acknowledgement have no associated data
-            Self::Dummy(_) | Self::FallbackAcknowledgement(_) => Cow::Owned(JsonObject::default()),
+            // Dummy, terms and fallback acknowledgement have no associated data
+            Self::Dummy(_) | Self::Terms(_) | Self::FallbackAcknowledgement(_) => Cow::Owned(JsonObject::default()),
             Self::_Custom(c) => Cow::Borrowed(&c.extra),
         }
     }
@@ -172,6 +178,7 @@ impl fmt::Debug for AuthData {
             Self::Msisdn(inner) => inner.fmt(f),
             Self::Dummy(inner) => inner.fmt(f),
             Self::RegistrationToken(inner) => inner.fmt(f),
+            Self::Terms(inner) => inner.fmt(f),
             Self::FallbackAcknowledgement(inner) => inner.fmt(f),
             Self::_Custom(inner) => inner.fmt(f),
         }
@@ -202,6 +209,7 @@ impl<'de> Deserialize<'de> for AuthData {
             Some("m.login.msisdn") => from_raw_json_value(&json).map(Self::Msisdn),
             Some("m.login.dummy") => from_raw_json_value(&json).map(Self::Dummy),
             Some("m.login.registration_token") => from_raw_json_value(&json).map(Self::RegistrationToken),
+            Some("m.login.terms") => from_raw_json_value(&json).map(Self::Terms),
             None => from_raw_json_value(&json).map(Self::FallbackAcknowledgement),
             Some(_) => from_raw_json_value(&json).map(Self::_Custom),
         }
@@ -240,6 +248,10 @@ pub enum AuthType {
     #[palpo_enum(rename = "m.login.registration_token")]
     RegistrationToken,
 
+    /// Terms of service consent (`m.login.terms`).
+    #[palpo_enum(rename = "m.login.terms")]
+    Terms,
+
     #[doc(hidden)]
     _Custom(PrivOwnedStr),
 }
@@ -403,6 +415,25 @@ impl fmt::Debug for RegistrationToken {
     }
 }
 
+/// Data for terms of service UIAA flow.
+///
+/// See [the spec] for how to use this.
+///
+/// [the spec]: https://spec.matrix.org/latest/client-server-api/#terms-of-service-at-registration
+#[derive(ToSchema, Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "m.login.terms")]
+pub struct Terms {
+    /// The value of the session key given by the homeserver, if any.
+    pub session: Option<String>,
+}
+
+impl Terms {
+    /// Creates an empty `Terms`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
 /// Data for UIAA fallback acknowledgement.
 ///
 /// See [the spec] for how to use this.
@@ -547,9 +578,15 @@ pub struct ThirdpartyIdCredentials {
     pub client_secret: OwnedClientSecret,
 
     /// Identity server URL.
+    ///
+    /// Empty when the homeserver validated the session itself.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub id_server: String,
 
     /// Identity server access token.
+    ///
+    /// Empty when the homeserver validated the session itself.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub id_access_token: String,
 }
 
@@ -125,6 +125,12 @@ pub enum ErrorKind {
     /// M_CANNOT_LEAVE_SERVER_NOTICE_ROOM
     CannotLeaveServerNoticeRoom,
 
+    /// M_CONSENT_NOT_GIVEN
+    ConsentNotGiven {
+        /// The page where the user can review and accept the terms of service.
+        consent_uri: String,
+    },
+
     /// M_WEAK_PASSWORD
     WeakPassword,
 
@@ -209,6 +215,7 @@ impl AsRef<str> for ErrorKind {
             Self::Exclusive => "M_EXCLUSIVE",
             Self::ResourceLimitExceeded { .. } => "M_RESOURCE_LIMIT_EXCEEDED",
             Self::CannotLeaveServerNoticeRoom => "M_CANNOT_LEAVE_SERVER_NOTICE_ROOM",
+            Self::ConsentNotGiven { .. } => "M_CONSENT_NOT_GIVEN",
             Self::WeakPassword => "M_WEAK_PASSWORD",
             Self::UnableToAuthorizeJoin => "M_UNABLE_TO_AUTHORISE_JOIN",
             Self::UnableToGrantJoin => "M_UNABLE_TO_GRANT_JOIN",
@@ -21,6 +21,7 @@ enum Field<'de> {
     RetryAfterMs,
     RoomVersion,
     AdminContact,
+    ConsentUri,
     Status,
     Body,
     CurrentVersion,
@@ -35,6 +36,7 @@ impl<'de> Field<'de> {
             "retry_after_ms" => Self::RetryAfterMs,
             "room_version" => Self::RoomVersion,
             "admin_contact" => Self::AdminContact,
+            "consent_uri" => Self::ConsentUri,
             "status" => Self::Status,
             "body" => Self::Body,
             "current_version" => Self::CurrentVersion,
@@ -101,6 +103,7 @@ impl<'de> Visitor<'de> for ErrorKindVisitor {
         let mut retry_after_ms = None;
         let mut room_version = None;
         let mut admin_contact = None;
+        let mut consent_uri = None;
         let mut status = None;
         let mut body = None;
         let mut current_version = None;
@@ -126,6 +129,7 @@ impl<'de> Visitor<'de> for ErrorKindVisitor {
             (@variant_containing retry_after_ms) => { ErrCode::LimitExceeded };
             (@variant_containing room_version) => { ErrCode::IncompatibleRoomVersion };
             (@variant_containing admin_contact) => { ErrCode::ResourceLimitExceeded };
+            (@variant_containing consent_uri) => { ErrCode::ConsentNotGiven };
             (@variant_containing status) => { ErrCode::BadStatus };
             (@variant_containing body) => { ErrCode::BadStatus };
             (@variant_containing current_version) => { ErrCode::WrongRoomKeysVersion };
@@ -146,6 +150,7 @@ impl<'de> Visitor<'de> for ErrorKindVisitor {
                 Field::RetryAfterMs => set_field!(retry_after_ms),
                 Field::RoomVersion => set_field!(room_version),
                 Field::AdminContact => set_field!(admin_contact),
+                Field::ConsentUri => set_field!(consent_uri),
                 Field::Status => set_field!(status),
                 Field::Body => set_field!(body),
                 Field::CurrentVersion => set_field!(current_version),
@@ -214,6 +219,10 @@ impl<'de> Visitor<'de> for ErrorKindVisitor {
                     .map_err(de::Error::custom)?,
             },
             ErrCode::CannotLeaveServerNoticeRoom => ErrorKind::CannotLeaveServerNoticeRoom,
+            ErrCode::ConsentNotGiven => ErrorKind::ConsentNotGiven {
+                consent_uri: from_json_value(consent_uri.ok_or_else(|| de::Error::missing_field("consent_uri"))?)
+                    .map_err(de::Error::custom)?,
+            },
             ErrCode::WeakPassword => ErrorKind::WeakPassword,
             ErrCode::UnableToAuthorizeJoin => ErrorKind::UnableToAuthorizeJoin,
             ErrCode::UnableToGrantJoin => ErrorKind::UnableToGrantJoin,
@@ -273,6 +282,7 @@ enum ErrCode {
     Exclusive,
     ResourceLimitExceeded,
     CannotLeaveServerNoticeRoom,
+    ConsentNotGiven,
     WeakPassword,
     UnableToAuthorizeJoin,
     UnableToGrantJoin,
@@ -326,6 +336,9 @@ impl Serialize for ErrorKind {
             Self::ResourceLimitExceeded { admin_contact } => {
                 st.serialize_entry("admin_contact", admin_contact)?;
             }
+            Self::ConsentNotGiven { consent_uri } => {
+                st.serialize_entry("consent_uri", consent_uri)?;
+            }
             Self::_Custom { extra, .. } => {
                 for (k, v) in extra {
                     st.serialize_entry(k, v)?;
@@ -376,4 +389,19 @@ mod tests {
             }
         );
     }
+    #[test]
+    fn deserialize_consent_not_given() {
+        let deserialized: ErrorKind = from_json_value(json!({
+            "errcode": "M_CONSENT_NOT_GIVEN",
+            "consent_uri": "https://example.org/_matrix/consent",
+        }))
+        .unwrap();
+
+        assert_eq!(
+            deserialized,
+            ErrorKind::ConsentNotGiven {
+                consent_uri: "https://example.org/_matrix/consent".to_owned()
+            }
+        );
+    }
 }
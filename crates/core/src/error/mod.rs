@@ -32,6 +32,12 @@ macro_rules! simple_kind_fns {
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ErrorBody(JsonMap<String, JsonValue>);
 
+impl ErrorBody {
+    /// Returns the human-readable `error` message, if any.
+    pub fn message(&self) -> Option<&str> {
+        self.0.get("error").and_then(|message| message.as_str())
+    }
+}
 impl From<String> for ErrorBody {
     fn from(message: String) -> Self {
         Self(JsonMap::from_iter(vec![("error".to_owned(), json!(message))]))
@@ -125,6 +131,9 @@ impl MatrixError {
     pub fn resource_limit_exceeded(admin_contact: String, body: impl Into<ErrorBody>) -> Self {
         Self::new(ErrorKind::ResourceLimitExceeded { admin_contact }, body)
     }
+    pub fn consent_not_given(consent_uri: String, body: impl Into<ErrorBody>) -> Self {
+        Self::new(ErrorKind::ConsentNotGiven { consent_uri }, body)
+    }
     pub fn wrong_room_keys_version(current_version: Option<String>, body: impl Into<ErrorBody>) -> Self {
         Self::new(ErrorKind::WrongRoomKeysVersion { current_version }, body)
     }
@@ -159,7 +168,7 @@ impl Scribe for MatrixError {
                     Unauthorized | UnknownToken { .. } | MissingToken => StatusCode::UNAUTHORIZED,
                     NotFound | Unrecognized => StatusCode::NOT_FOUND,
                     LimitExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
-                    UserDeactivated => StatusCode::FORBIDDEN,
+                    UserDeactivated | ConsentNotGiven { .. } => StatusCode::FORBIDDEN,
                     TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
                     CannotOverwriteMedia => StatusCode::CONFLICT,
                     NotYetUploaded => StatusCode::GATEWAY_TIMEOUT,
@@ -174,7 +183,13 @@ impl Scribe for MatrixError {
         };
 
         let Self { kind, mut body, .. } = self;
-        body.0.insert("errcode".to_owned(), kind.to_string().into());
+        // Kinds like `M_LIMIT_EXCEEDED` or `M_UNKNOWN_TOKEN` carry extra fields next to `errcode`.
+        match serde_json::to_value(&kind) {
+            Ok(JsonValue::Object(fields)) => body.0.extend(fields),
+            _ => {
+                body.0.insert("errcode".to_owned(), kind.to_string().into());
+            }
+        }
 
         let bytes: Vec<u8> = crate::serde::json_to_buf(&body.0).unwrap();
         res.write_body(bytes).ok();
@@ -13,7 +13,7 @@ use serde::{Deserialize, Serialize};
 use crate::events::AnyStrippedStateEvent;
 use crate::sending::{SendError, SendRequest, SendResult};
 use crate::serde::{RawJson, RawJsonValue};
-use crate::OwnedRoomId;
+use crate::{OwnedEventId, OwnedRoomId};
 use crate::{OwnedUserId, RoomVersionId};
 // const METADATA: Metadata = metadata! {
 //     method: GET,
@@ -50,11 +50,12 @@ pub struct MakeKnockReqArgs {
     ///
     /// Defaults to `vec![RoomVersionId::V1]`.
     #[salvo(parameter(parameter_in = Query))]
+    #[serde(default)]
     pub ver: Vec<RoomVersionId>,
 }
 
 /// Response type for the `create_knock_event_template` endpoint.
-#[derive(ToSchema, Serialize, Debug)]
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
 
 pub struct MakeKnockResBody {
     /// The version of the room where the server is trying to knock.
@@ -91,24 +92,37 @@ impl MakeKnockResBody {
 //     }
 // };
 
-/// Request type for the `send_knock` endpoint.
+pub fn send_knock_request(origin: &str, args: SendKnockReqArgs, body: SendKnockReqBody) -> SendResult<SendRequest> {
+    let url = Url::parse(&format!(
+        "{origin}/_matrix/federation/v1/send_knock/{}/{}",
+        args.room_id, args.event_id
+    ))?;
+    crate::sending::put(url).stuff(body)
+}
 
-#[derive(ToSchema, Deserialize, Debug)]
-pub struct SendKnockReqBody {
-    // /// The room ID that should receive the knock.
-    // #[salvo(parameter(parameter_in = Path))]
-    // pub room_id: OwnedRoomId,
+/// Request args for the `send_knock` endpoint.
+#[derive(ToParameters, Deserialize, Serialize, Debug)]
+pub struct SendKnockReqArgs {
+    /// The room ID that should receive the knock.
+    #[salvo(parameter(parameter_in = Path))]
+    pub room_id: OwnedRoomId,
 
-    // /// The event ID for the knock event.
-    // #[salvo(parameter(parameter_in = Path))]
-    // pub event_id: OwnedEventId,
-    /// The PDU.
-    #[salvo(schema(value_type = Object, additional_properties = true))]
-    pub pdu: Box<RawJsonValue>,
+    /// The event ID for the knock event.
+    #[salvo(parameter(parameter_in = Path))]
+    pub event_id: OwnedEventId,
 }
 
+/// Request type for the `send_knock` endpoint.
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
+#[salvo(schema(value_type = Object))]
+pub struct SendKnockReqBody(
+    /// The PDU.
+    pub Box<RawJsonValue>,
+);
+crate::json_body_modifier!(SendKnockReqBody);
+
 /// Response type for the `send_knock` endpoint.
-#[derive(ToSchema, Serialize, Debug)]
+#[derive(ToSchema, Deserialize, Serialize, Debug)]
 
 pub struct SendKnockResBody {
     /// State events providing public room metadata.
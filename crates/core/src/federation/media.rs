@@ -1,13 +1,14 @@
 /// Endpoints for the media repository.
 use std::time::Duration;
 
+use rand::distributions::{Alphanumeric, DistString};
 use reqwest::Url;
 use salvo::oapi::{ToParameters, ToSchema};
 use serde::{Deserialize, Serialize};
 
 use crate::http_headers::ContentDisposition;
 use crate::media::Method;
-use crate::sending::{SendRequest, SendResult};
+use crate::sending::{SendError, SendRequest, SendResult};
 use crate::ServerName;
 
 /// The `multipart/mixed` mime "essence".
@@ -29,7 +30,13 @@ pub fn thumbnail_request(origin: &str, args: ThumbnailReqArgs) -> SendResult<Sen
         let mut query = url.query_pairs_mut();
         query.append_pair("width", &args.width.to_string());
         query.append_pair("height", &args.height.to_string());
+        if let Some(method) = &args.method {
+            query.append_pair("method", method.as_str());
+        }
         query.append_pair("timeout_ms", &args.timeout_ms.as_millis().to_string());
+        if let Some(animated) = args.animated {
+            query.append_pair("animated", &animated.to_string());
+        }
     }
     Ok(crate::sending::get(url))
 }
@@ -105,7 +112,7 @@ pub struct ThumbnailResBody {
 
 pub fn content_request(origin: &str, args: ContentReqArgs) -> SendResult<SendRequest> {
     let url = Url::parse(&format!(
-        "{origin}federation/v1/media/download/{}?timeout_ms={}",
+        "{origin}/_matrix/federation/v1/media/download/{}?timeout_ms={}",
         args.media_id,
         args.timeout_ms.as_millis()
     ))?;
@@ -175,3 +182,190 @@ impl ContentMetadata {
         Self {}
     }
 }
+
+/// Serializes the metadata and the content of a media response into a `multipart/mixed` body.
+///
+/// Returns the value of the `Content-Type` header and the body.
+pub fn try_into_multipart_mixed(metadata: &ContentMetadata, content: &FileOrLocation) -> SendResult<(String, Vec<u8>)> {
+    let boundary = Alphanumeric.sample_string(&mut rand::thread_rng(), GENERATED_BOUNDARY_LENGTH);
+
+    let mut body = Vec::new();
+    body.extend_from_slice(format!("--{boundary}\r\nContent-Type: application/json\r\n\r\n").as_bytes());
+    serde_json::to_writer(&mut body, metadata)?;
+    body.extend_from_slice(format!("\r\n--{boundary}\r\n").as_bytes());
+    match content {
+        FileOrLocation::File(content) => {
+            let content_type = content.content_type.as_deref().unwrap_or("application/octet-stream");
+            body.extend_from_slice(format!("Content-Type: {content_type}\r\n").as_bytes());
+            if let Some(content_disposition) = &content.content_disposition {
+                body.extend_from_slice(format!("Content-Disposition: {content_disposition}\r\n").as_bytes());
+            }
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(&content.file);
+        }
+        FileOrLocation::Location(location) => {
+            body.extend_from_slice(format!("Location: {location}\r\n\r\n").as_bytes());
+        }
+    }
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    Ok((format!("{MULTIPART_MIXED}; boundary={boundary}"), body))
+}
+
+/// Parses a `multipart/mixed` media response, `content_type` is the value of its `Content-Type` header.
+pub fn try_from_multipart_mixed(
+    content_type: Option<&str>,
+    body: &[u8],
+) -> SendResult<(ContentMetadata, FileOrLocation)> {
+    let content_type = content_type.ok_or_else(|| SendError::other("missing Content-Type header"))?;
+    let mut params = content_type.split(';').map(str::trim);
+    if !params
+        .next()
+        .is_some_and(|essence| essence.eq_ignore_ascii_case(MULTIPART_MIXED))
+    {
+        return Err(SendError::other(format!(
+            "expected {MULTIPART_MIXED}, got {content_type}"
+        )));
+    }
+    let boundary = params
+        .find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("boundary")
+                .then(|| value.trim().trim_matches('"'))
+        })
+        .ok_or_else(|| SendError::other("missing multipart boundary"))?;
+
+    let delimiter = format!("--{boundary}");
+    let start =
+        find_bytes(body, delimiter.as_bytes()).ok_or_else(|| SendError::other("missing first multipart boundary"))?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut parts = Vec::with_capacity(2);
+    let delimiter = format!("\r\n--{boundary}");
+    while parts.len() < 2 {
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or_else(|| SendError::other("missing multipart body part"))?;
+        let end =
+            find_bytes(rest, delimiter.as_bytes()).ok_or_else(|| SendError::other("missing multipart boundary"))?;
+        parts.push(&rest[..end]);
+        rest = &rest[end + delimiter.len()..];
+    }
+
+    let (_, metadata) = parse_body_part(parts[0])?;
+    let metadata = serde_json::from_slice(metadata)?;
+
+    let (headers, file) = parse_body_part(parts[1])?;
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    };
+    let content = if let Some(location) = header("location") {
+        FileOrLocation::Location(location.to_owned())
+    } else {
+        FileOrLocation::File(Content {
+            file: file.to_vec(),
+            content_type: header("content-type").map(ToOwned::to_owned),
+            content_disposition: header("content-disposition").and_then(|value| value.parse().ok()),
+        })
+    };
+    Ok((metadata, content))
+}
+
+/// Splits a body part into its headers and its content.
+fn parse_body_part(part: &[u8]) -> SendResult<(Vec<(&str, &str)>, &[u8])> {
+    let (headers, content) = if let Some(content) = part.strip_prefix(b"\r\n") {
+        (&[][..], content)
+    } else {
+        let end = find_bytes(part, b"\r\n\r\n").ok_or_else(|| SendError::other("missing end of part headers"))?;
+        (&part[..end], &part[end + 4..])
+    };
+    let headers = std::str::from_utf8(headers).map_err(|_| SendError::other("part headers are not valid UTF-8"))?;
+    let headers = headers
+        .split("\r\n")
+        .filter(|line| !line.is_empty())
+        .take(MAX_HEADERS_COUNT + 1)
+        .map(|line| {
+            line.split_once(':')
+                .map(|(name, value)| (name.trim(), value.trim()))
+                .ok_or_else(|| SendError::other(format!("invalid part header: {line}")))
+        })
+        .collect::<SendResult<Vec<_>>>()?;
+    if headers.len() > MAX_HEADERS_COUNT {
+        return Err(SendError::other("too many part headers"));
+    }
+    Ok((headers, content))
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{try_from_multipart_mixed, try_into_multipart_mixed, Content, ContentMetadata, FileOrLocation};
+
+    #[test]
+    fn multipart_file_roundtrip() {
+        let content = FileOrLocation::File(Content {
+            file: b"some\r\n--binary content".to_vec(),
+            content_type: Some("image/png".to_owned()),
+            content_disposition: None,
+        });
+        let (content_type, body) = try_into_multipart_mixed(&ContentMetadata::new(), &content).unwrap();
+
+        let (_, content) = try_from_multipart_mixed(Some(&content_type), &body).unwrap();
+        let FileOrLocation::File(content) = content else {
+            panic!("expected a file");
+        };
+        assert_eq!(content.file, b"some\r\n--binary content");
+        assert_eq!(content.content_type.as_deref(), Some("image/png"));
+    }
+
+    #[test]
+    fn multipart_quoted_boundary() {
+        let body = b"preamble\r\n--abc\r\nContent-Type: application/json\r\n\r\n{}\r\n--abc\r\n\
+            content-type: text/plain\r\n\r\nhello\r\n--abc--\r\n";
+
+        let (_, content) =
+            try_from_multipart_mixed(Some("Multipart/Mixed; charset=utf-8; boundary=\"abc\""), body).unwrap();
+        let FileOrLocation::File(content) = content else {
+            panic!("expected a file");
+        };
+        assert_eq!(content.file, b"hello");
+        assert_eq!(content.content_type.as_deref(), Some("text/plain"));
+    }
+
+    #[test]
+    fn multipart_location() {
+        let body = b"--abc\r\nContent-Type: application/json\r\n\r\n{}\r\n--abc\r\n\
+            Location: https://cdn.example.org/media/abc\r\n\r\n\r\n--abc--\r\n";
+
+        let (_, content) = try_from_multipart_mixed(Some("multipart/mixed; boundary=abc"), body).unwrap();
+        let FileOrLocation::Location(location) = content else {
+            panic!("expected a location");
+        };
+        assert_eq!(location, "https://cdn.example.org/media/abc");
+    }
+
+    #[test]
+    fn multipart_invalid() {
+        let body = b"--abc\r\nContent-Type: application/json\r\n\r\n{}\r\n--abc\r\n\r\nhello\r\n--abc--\r\n";
+
+        // Missing or wrong content type
+        assert!(try_from_multipart_mixed(None, body).is_err());
+        assert!(try_from_multipart_mixed(Some("multipart/related; boundary=abc"), body).is_err());
+        // Missing boundary
+        assert!(try_from_multipart_mixed(Some("multipart/mixed"), body).is_err());
+        assert!(try_from_multipart_mixed(Some("multipart/mixed; charset=utf-8"), body).is_err());
+        // Wrong boundary
+        assert!(try_from_multipart_mixed(Some("multipart/mixed; boundary=xyz"), body).is_err());
+        // Truncated body
+        assert!(try_from_multipart_mixed(Some("multipart/mixed; boundary=abc"), &body[..40]).is_err());
+        // Metadata that is not JSON
+        let body = b"--abc\r\n\r\nnot json\r\n--abc\r\n\r\nhello\r\n--abc--\r\n";
+        assert!(try_from_multipart_mixed(Some("multipart/mixed; boundary=abc"), body).is_err());
+    }
+}
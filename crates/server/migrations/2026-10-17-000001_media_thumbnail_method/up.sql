@@ -0,0 +1,6 @@
+-- Thumbnails stored with the placeholder resize method `_` are kept, so their files are still
+-- deleted with the media. They are not served anymore and get regenerated on demand.
+ALTER TABLE media_thumbnails ADD COLUMN animated boolean NOT NULL DEFAULT false;
+
+DROP INDEX IF EXISTS media_thumbnail_index;
+CREATE UNIQUE INDEX media_thumbnail_index ON media_thumbnails USING btree (media_id, origin_server, width, height, resize_method, animated);
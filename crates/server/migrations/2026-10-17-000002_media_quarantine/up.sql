@@ -0,0 +1,10 @@
+CREATE TABLE media_quarantines (
+    id bigserial not null PRIMARY KEY,
+    media_id text NOT NULL,
+    origin_server text NOT NULL,
+    quarantined_by text NOT NULL,
+    quarantined_at bigint NOT NULL
+);
+CREATE UNIQUE INDEX media_quarantine_index ON media_quarantines USING btree (media_id, origin_server);
+
+CREATE INDEX media_metadatas_created_by_idx ON media_metadatas USING btree (created_by);
@@ -0,0 +1,7 @@
+CREATE TABLE media_url_previews (
+    id bigserial not null PRIMARY KEY,
+    url text NOT NULL,
+    og_data json NOT NULL,
+    created_at bigint NOT NULL
+);
+CREATE INDEX media_url_previews_url_idx ON media_url_previews USING btree (url, created_at);
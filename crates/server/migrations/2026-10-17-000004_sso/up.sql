@@ -0,0 +1,18 @@
+CREATE TABLE user_external_ids (
+    id bigserial NOT NULL PRIMARY KEY,
+    auth_provider text NOT NULL,
+    external_id text NOT NULL,
+    user_id text NOT NULL,
+    created_at bigint NOT NULL
+);
+CREATE UNIQUE INDEX user_external_ids_provider_idx ON user_external_ids USING btree (auth_provider, external_id);
+CREATE INDEX user_external_ids_user_id_idx ON user_external_ids USING btree (user_id);
+
+CREATE TABLE user_login_tokens (
+    id bigserial NOT NULL PRIMARY KEY,
+    user_id text NOT NULL,
+    token text NOT NULL,
+    expired_at bigint NOT NULL,
+    created_at bigint NOT NULL,
+    CONSTRAINT user_login_tokens_token_key UNIQUE (token)
+);
@@ -0,0 +1,7 @@
+ALTER TABLE user_uiaa_datas ADD COLUMN request json;
+ALTER TABLE user_uiaa_datas ADD COLUMN registration_token text;
+ALTER TABLE user_uiaa_datas ADD COLUMN threepid_session_id text;
+ALTER TABLE user_uiaa_datas ADD COLUMN expires_at bigint NOT NULL DEFAULT 0;
+ALTER TABLE user_uiaa_datas ADD COLUMN created_at bigint NOT NULL DEFAULT 0;
+CREATE INDEX user_uiaa_datas_session_idx ON user_uiaa_datas USING btree (session);
+CREATE INDEX user_uiaa_datas_expires_at_idx ON user_uiaa_datas USING btree (expires_at);
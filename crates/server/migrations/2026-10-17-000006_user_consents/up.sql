@@ -0,0 +1,7 @@
+CREATE TABLE user_consents (
+    id bigserial NOT NULL PRIMARY KEY,
+    user_id text NOT NULL,
+    version text NOT NULL,
+    accepted_at bigint NOT NULL,
+    CONSTRAINT user_consents_ukey UNIQUE (user_id, version)
+);
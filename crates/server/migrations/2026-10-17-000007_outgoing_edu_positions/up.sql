@@ -0,0 +1,5 @@
+CREATE TABLE outgoing_edu_positions (
+    server_id text NOT NULL PRIMARY KEY,
+    last_edu_sn bigint NOT NULL,
+    updated_at bigint NOT NULL
+);
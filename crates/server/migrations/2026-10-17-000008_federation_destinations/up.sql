@@ -0,0 +1,7 @@
+CREATE TABLE federation_destinations (
+    server_id text NOT NULL PRIMARY KEY,
+    failure_count integer NOT NULL DEFAULT 0,
+    failure_ts bigint,
+    retry_last_ts bigint,
+    retry_interval bigint NOT NULL DEFAULT 0
+);
@@ -0,0 +1,16 @@
+CREATE TABLE media_references (
+    origin_server text NOT NULL,
+    media_id text NOT NULL,
+    event_id text NOT NULL,
+    PRIMARY KEY (origin_server, media_id, event_id)
+);
+
+-- Events stored before references were tracked, their references are recorded in batches by a
+-- background job, which deletes the row once it reached `end_event_sn`.
+CREATE TABLE media_reference_backfills (
+    id bigserial NOT NULL PRIMARY KEY,
+    last_event_sn bigint NOT NULL,
+    end_event_sn bigint NOT NULL
+);
+INSERT INTO media_reference_backfills (last_event_sn, end_event_sn)
+SELECT min(event_sn) - 1, max(event_sn) FROM event_datas HAVING count(*) > 0;
@@ -0,0 +1,18 @@
+CREATE TABLE oidc_pending_logins (
+    state text NOT NULL PRIMARY KEY,
+    idp_id text NOT NULL,
+    code_verifier text NOT NULL,
+    redirect_url text NOT NULL,
+    expired_at bigint NOT NULL,
+    created_at bigint NOT NULL
+);
+CREATE INDEX oidc_pending_logins_expired_at_idx ON oidc_pending_logins USING btree (expired_at);
+
+CREATE TABLE oidc_discoveries (
+    idp_id text NOT NULL PRIMARY KEY,
+    issuer text NOT NULL,
+    authorization_endpoint text NOT NULL,
+    token_endpoint text NOT NULL,
+    userinfo_endpoint text NOT NULL,
+    fetched_at bigint NOT NULL
+);
@@ -71,9 +71,18 @@ enum AdminCommand {
     /// List users in the database
     ListLocalUsers,
 
+    /// Count the users active during the last 30 days
+    MonthlyActiveUsers,
+
     /// List all rooms we are currently handling an incoming pdu from
     IncomingFederation,
 
+    /// List the servers federation requests failed for, and when they are retried
+    ListFailingDestinations,
+
+    /// Send federation requests to a backed off server again
+    ResetDestination { server_name: Box<ServerName> },
+
     /// Deactivate a user
     ///
     /// User will not be removed from all rooms by default.
@@ -153,6 +162,61 @@ enum AdminCommand {
     /// Enables incoming federation handling for a room again.
     EnableRoom { room_id: Box<RoomId> },
 
+    /// Quarantine media so it is not served to clients or other servers anymore
+    QuarantineMedia {
+        /// The MXC URI of the media, local or remote
+        mxc: String,
+    },
+
+    /// Quarantine all media uploaded by a local user
+    QuarantineUserMedia { user_id: Box<UserId> },
+
+    /// Quarantine all media referenced by the events of a room
+    QuarantineRoomMedia { room_id: Box<RoomId> },
+
+    /// Serve quarantined media again
+    UnquarantineMedia {
+        /// The MXC URI of the media, local or remote
+        mxc: String,
+    },
+
+    /// Delete the file, thumbnails and metadata of media
+    ///
+    /// Quarantine is kept, so remote media is not fetched again.
+    DeleteMedia {
+        /// The MXC URI of the media, local or remote
+        mxc: String,
+    },
+
+    /// Create a registration token
+    CreateRegistrationToken {
+        /// The token, a random one is generated when omitted
+        token: Option<String>,
+        /// How many users may register with the token, unlimited when omitted
+        #[arg(long)]
+        uses_allowed: Option<i64>,
+        /// Days until the token expires, never when omitted
+        #[arg(long)]
+        expires_in_days: Option<u64>,
+    },
+
+    /// List all registration tokens and their usage
+    ListRegistrationTokens,
+
+    /// Change the usage limit and expiry of a registration token
+    ///
+    /// Limits that are omitted are removed.
+    UpdateRegistrationToken {
+        token: String,
+        #[arg(long)]
+        uses_allowed: Option<i64>,
+        #[arg(long)]
+        expires_in_days: Option<u64>,
+    },
+
+    /// Delete a registration token
+    DeleteRegistrationToken { token: String },
+
     /// Verify json signatures
     /// [commandbody]
     // #``
@@ -273,6 +337,23 @@ async fn process_admin_message(room_message: String) -> RoomMessageEventContent
     }
 }
 
+fn palpo_user_id() -> OwnedUserId {
+    UserId::parse(format!("@palpo:{}", crate::server_name())).expect("@palpo:server_name is valid")
+}
+
+/// The timestamp `days` days from now, in milliseconds.
+fn expires_at(days: u64) -> i64 {
+    (UnixMillis::now().get() + days * 24 * 60 * 60 * 1000) as i64
+}
+
+fn parse_mxc(mxc: &str) -> AppResult<(OwnedServerName, String)> {
+    let mxc = <&MxcUri>::from(mxc);
+    let (server_name, media_id) = mxc
+        .parts()
+        .map_err(|e| AppError::public(format!("Invalid MXC URI {mxc}: {e}")))?;
+    Ok((server_name.to_owned(), media_id.to_owned()))
+}
+
 // Parse chat messages from the admin room into an AdminCommand object
 fn parse_admin_command(command_line: &str) -> std::result::Result<AdminCommand, String> {
     // Note: argv[0] is `@palpo:servername:`, which is treated as the main command
@@ -353,6 +434,38 @@ async fn process_admin_command(command: AdminCommand, body: Vec<&str>) -> AppRes
             }
             Err(e) => RoomMessageEventContent::text_plain(e.to_string()),
         },
+        AdminCommand::MonthlyActiveUsers => match crate::user::count_monthly_active_users() {
+            Ok(count) => {
+                RoomMessageEventContent::text_plain(format!("{count} user(s) active during the last 30 days."))
+            }
+            Err(e) => RoomMessageEventContent::text_plain(e.to_string()),
+        },
+        AdminCommand::ListFailingDestinations => {
+            let destinations = crate::federation::destination::failing_destinations()?;
+            let mut msg = format!("{} failing destination(s):\n", destinations.len());
+            for destination in destinations {
+                let retry = match destination.backoff_remaining() {
+                    Some(remaining) => format!("retrying in {}s", remaining.as_secs()),
+                    None => "retrying with the next request".to_owned(),
+                };
+                let failing_for = destination
+                    .failure_ts
+                    .map(|failure_ts| (UnixMillis::now().get() as i64 - failure_ts) / 60_000)
+                    .unwrap_or_default();
+                msg += &format!(
+                    "{}: {} failure(s) in {failing_for}m, {retry}\n",
+                    destination.server_id, destination.failure_count
+                );
+            }
+            RoomMessageEventContent::text_plain(msg)
+        }
+        AdminCommand::ResetDestination { server_name } => {
+            if crate::federation::destination::reset_destination(&server_name)? {
+                RoomMessageEventContent::text_plain(format!("Requests are sent to {server_name} again."))
+            } else {
+                RoomMessageEventContent::text_plain(format!("{server_name} is not failing."))
+            }
+        }
         AdminCommand::IncomingFederation => {
             let map = crate::ROOM_ID_FEDERATION_HANDLE_TIME.read().unwrap();
             let mut msg: String = format!("Handling {} incoming pdus:\n", map.len());
@@ -457,7 +570,7 @@ async fn process_admin_command(command: AdminCommand, body: Vec<&str>) -> AppRes
                 ));
             }
 
-            let new_password = utils::random_string(AUTO_GEN_PASSWORD_LENGTH);
+            let new_password = crate::user::generate_password(&user_id);
 
             match crate::user::set_password(&user_id, &new_password) {
                 Ok(()) => RoomMessageEventContent::text_plain(format!(
@@ -528,6 +641,80 @@ async fn process_admin_command(command: AdminCommand, body: Vec<&str>) -> AppRes
             crate::room::disable_room(&room_id, false)?;
             RoomMessageEventContent::text_plain("Room enabled.")
         }
+        AdminCommand::QuarantineMedia { mxc } => {
+            let media = parse_mxc(&mxc)?;
+            crate::media::quarantine_media(&[media], &palpo_user_id())?;
+            RoomMessageEventContent::text_plain(format!("Media {mxc} quarantined."))
+        }
+        AdminCommand::QuarantineUserMedia { user_id } => {
+            let count = crate::media::quarantine_user_media(&user_id, &palpo_user_id())?;
+            RoomMessageEventContent::text_plain(format!("Quarantined {count} media uploaded by {user_id}."))
+        }
+        AdminCommand::QuarantineRoomMedia { room_id } => {
+            let count = crate::media::quarantine_room_media(&room_id, &palpo_user_id())?;
+            RoomMessageEventContent::text_plain(format!("Quarantined {count} media referenced in {room_id}."))
+        }
+        AdminCommand::UnquarantineMedia { mxc } => {
+            let (server_name, media_id) = parse_mxc(&mxc)?;
+            if crate::media::unquarantine_media(&server_name, &media_id)? {
+                RoomMessageEventContent::text_plain(format!("Media {mxc} is no longer quarantined."))
+            } else {
+                RoomMessageEventContent::text_plain(format!("Media {mxc} is not quarantined."))
+            }
+        }
+        AdminCommand::DeleteMedia { mxc } => {
+            let (server_name, media_id) = parse_mxc(&mxc)?;
+            crate::media::delete_media(&server_name, &media_id)?;
+            RoomMessageEventContent::text_plain(format!("Media {mxc} deleted."))
+        }
+        AdminCommand::CreateRegistrationToken {
+            token,
+            uses_allowed,
+            expires_in_days,
+        } => {
+            let token = crate::user::create_registration_token(token, uses_allowed, expires_in_days.map(expires_at))?;
+            RoomMessageEventContent::text_plain(format!("Created registration token {}.", token.token))
+        }
+        AdminCommand::ListRegistrationTokens => {
+            let tokens = crate::user::list_registration_tokens()?;
+            let mut msg = format!("Found {} registration token(s):\n", tokens.len());
+            for token in tokens {
+                let uses_allowed = token.uses_allowed.map_or("unlimited".to_owned(), |u| u.to_string());
+                let expires = token
+                    .expired_at
+                    .and_then(chrono::DateTime::from_timestamp_millis)
+                    .map_or("never".to_owned(), |expired_at| expired_at.to_rfc3339());
+                msg += &format!(
+                    "{}: {} completed, {} pending, {} allowed, expires {}{}\n",
+                    token.token,
+                    token.completed,
+                    token.pending,
+                    uses_allowed,
+                    expires,
+                    if token.is_valid() { "" } else { " (invalid)" }
+                );
+            }
+            RoomMessageEventContent::text_plain(msg)
+        }
+        AdminCommand::UpdateRegistrationToken {
+            token,
+            uses_allowed,
+            expires_in_days,
+        } => {
+            if crate::user::update_registration_token(&token, uses_allowed, expires_in_days.map(expires_at))?.is_some()
+            {
+                RoomMessageEventContent::text_plain(format!("Registration token {token} updated."))
+            } else {
+                RoomMessageEventContent::text_plain(format!("Registration token {token} not found."))
+            }
+        }
+        AdminCommand::DeleteRegistrationToken { token } => {
+            if crate::user::delete_registration_token(&token)? {
+                RoomMessageEventContent::text_plain(format!("Registration token {token} deleted."))
+            } else {
+                RoomMessageEventContent::text_plain(format!("Registration token {token} not found."))
+            }
+        }
         AdminCommand::DeactivateUser { leave_rooms, user_id } => {
             let user_id = Arc::<UserId>::from(user_id);
             if crate::user::user_exists(&user_id)? {
@@ -6,11 +6,12 @@ use state_at_incoming::{state_at_incoming_degree_one, state_at_incoming_resolved
 use std::collections::{hash_map, BTreeMap, HashMap, HashSet};
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock, Mutex};
 use std::time::{Duration, Instant, SystemTime};
 
 use diesel::prelude::*;
 use futures_util::{stream::FuturesUnordered, StreamExt};
+use lru_cache::LruCache;
 use palpo_core::federation::event::EventResBody;
 use tokio::sync::{RwLock, RwLockWriteGuard, Semaphore};
 
@@ -380,6 +381,7 @@ pub async fn upgrade_outlier_to_timeline_pdu(
         .do_update()
         .set(&event_data)
         .execute(&mut db::connect()?)?;
+    crate::media::add_media_references(&incoming_pdu.event_id, &event_data.json_data)?;
 
     let event = DbEvent {
         id: (&*incoming_pdu.event_id).to_owned(),
@@ -844,32 +846,56 @@ async fn fetch_missing_prev_events(
     Ok((sorted, eventid_info))
 }
 
-/// Returns Ok if the acl allows the server
+/// Outcomes of the ACL checks by room, state frame and server.
+static ACL_CACHE: LazyLock<Mutex<LruCache<(OwnedRoomId, i64, OwnedServerName), bool>>> =
+    LazyLock::new(|| Mutex::new(LruCache::new(10_000)));
+
+/// Refuses servers denied by the `m.room.server_acl` event of the room.
 pub fn acl_check(server_name: &ServerName, room_id: &RoomId) -> AppResult<()> {
-    let acl_event = match crate::room::state::get_state(room_id, &StateEventType::RoomServerAcl, "", None)? {
-        Some(acl) => acl,
-        None => return Ok(()),
+    let Some(frame_id) = crate::room::state::get_room_frame_id(room_id, None)? else {
+        return Ok(());
+    };
+    let key = (room_id.to_owned(), frame_id, server_name.to_owned());
+    let cached = ACL_CACHE.lock().unwrap().get_mut(&key).copied();
+    let allowed = match cached {
+        Some(allowed) => allowed,
+        None => {
+            let allowed = is_acl_allowed(server_name, frame_id)?;
+            ACL_CACHE.lock().unwrap().insert(key, allowed);
+            allowed
+        }
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        info!("Server {} was denied by room ACL in {}", server_name, room_id);
+        Err(MatrixError::forbidden("Server was denied by room ACL").into())
+    }
+}
+
+fn is_acl_allowed(server_name: &ServerName, frame_id: i64) -> AppResult<bool> {
+    let Some(event_id) = crate::room::state::get_state_event_id(frame_id, &StateEventType::RoomServerAcl, "")? else {
+        return Ok(true);
+    };
+    let Some(acl_event) = crate::room::timeline::get_pdu(&event_id)? else {
+        return Ok(true);
     };
 
     let acl_event_content: RoomServerAclEventContent = match serde_json::from_str(acl_event.content.get()) {
         Ok(content) => content,
         Err(_) => {
             warn!("Invalid ACL event");
-            return Ok(());
+            return Ok(true);
         }
     };
 
     if acl_event_content.allow.is_empty() {
         // Ignore broken acl events
-        return Ok(());
+        return Ok(true);
     }
 
-    if acl_event_content.is_allowed(server_name) {
-        Ok(())
-    } else {
-        info!("Server {} was denied by room ACL in {}", server_name, room_id);
-        Err(MatrixError::forbidden("Server was denied by room ACL").into())
-    }
+    Ok(acl_event_content.is_allowed(server_name))
 }
 
 fn check_room_id(room_id: &RoomId, pdu: &PduEvent) -> AppResult<()> {
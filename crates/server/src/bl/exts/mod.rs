@@ -9,6 +9,8 @@ pub use url::*;
 pub trait DepotExt {
     fn authed_info(&self) -> AppResult<&AuthedInfo>;
     fn take_authed_info(&mut self) -> AppResult<AuthedInfo>;
+    /// The server that signed the current federation request.
+    fn origin(&self) -> AppResult<&OwnedServerName>;
 }
 impl DepotExt for Depot {
     fn authed_info(&self) -> AppResult<&AuthedInfo> {
@@ -19,6 +21,10 @@ impl DepotExt for Depot {
         self.scrape::<AuthedInfo>()
             .map_err(|_| StatusError::unauthorized().into())
     }
+    fn origin(&self) -> AppResult<&OwnedServerName> {
+        self.obtain::<OwnedServerName>()
+            .map_err(|_| StatusError::unauthorized().into())
+    }
 }
 
 pub trait IsRemote {
@@ -0,0 +1,120 @@
+//! Health of the servers we send federation requests to
+//!
+//! Servers that can't be reached are backed off: requests to them fail without being sent until
+//! their retry interval elapsed, the interval doubles with each failed retry. The state is kept in
+//! the database so that a restart doesn't contact every dead server again.
+
+use std::time::Duration;
+
+use diesel::prelude::*;
+
+use crate::core::identifiers::*;
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, AppResult};
+
+/// Retry interval after the first failure, in milliseconds.
+const MIN_RETRY_INTERVAL: i64 = 30 * 1000;
+/// Longest retry interval, in milliseconds.
+const MAX_RETRY_INTERVAL: i64 = 24 * 60 * 60 * 1000;
+
+#[derive(Identifiable, Queryable, Debug, Clone)]
+#[diesel(table_name = federation_destinations, primary_key(server_id))]
+pub struct DbDestination {
+    pub server_id: OwnedServerName,
+    /// Number of failed requests since the last successful one.
+    pub failure_count: i32,
+    /// First failure since the last successful request.
+    pub failure_ts: Option<i64>,
+    /// Last failure, the retry interval starts from it.
+    pub retry_last_ts: Option<i64>,
+    /// Time to wait before sending requests again, in milliseconds.
+    pub retry_interval: i64,
+}
+
+impl DbDestination {
+    /// Time left before requests are sent again.
+    pub fn backoff_remaining(&self) -> Option<Duration> {
+        let retry_at = self.retry_last_ts? + self.retry_interval;
+        let now = UnixMillis::now().get() as i64;
+        (retry_at > now).then(|| Duration::from_millis((retry_at - now) as u64))
+    }
+}
+
+pub fn get_destination(server_name: &ServerName) -> AppResult<Option<DbDestination>> {
+    federation_destinations::table
+        .find(server_name)
+        .first::<DbDestination>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+/// Destinations with failed requests, the longest failing first.
+pub fn failing_destinations() -> AppResult<Vec<DbDestination>> {
+    federation_destinations::table
+        .filter(federation_destinations::failure_count.gt(0))
+        .order_by(federation_destinations::failure_ts.asc())
+        .load::<DbDestination>(&mut *db::connect()?)
+        .map_err(Into::into)
+}
+
+/// Time left before requests are sent to the server again, `None` when it is not backed off.
+pub fn backoff_remaining(server_name: &ServerName) -> AppResult<Option<Duration>> {
+    Ok(get_destination(server_name)?.and_then(|destination| destination.backoff_remaining()))
+}
+
+/// Records a request that didn't reach the server, it is backed off.
+///
+/// Failures of requests started before the server was backed off don't extend the interval.
+pub fn record_failure(server_name: &ServerName) -> AppResult<()> {
+    let now = UnixMillis::now().get() as i64;
+    let destination = get_destination(server_name)?;
+    if destination
+        .as_ref()
+        .is_some_and(|destination| destination.backoff_remaining().is_some())
+    {
+        return Ok(());
+    }
+    let (failure_count, failure_ts, retry_interval) = match destination {
+        Some(destination) if destination.failure_count > 0 => (
+            destination.failure_count + 1,
+            destination.failure_ts.unwrap_or(now),
+            (destination.retry_interval * 2).clamp(MIN_RETRY_INTERVAL, MAX_RETRY_INTERVAL),
+        ),
+        _ => (1, now, MIN_RETRY_INTERVAL),
+    };
+    let values = (
+        federation_destinations::failure_count.eq(failure_count),
+        federation_destinations::failure_ts.eq(failure_ts),
+        federation_destinations::retry_last_ts.eq(now),
+        federation_destinations::retry_interval.eq(retry_interval),
+    );
+    diesel::insert_into(federation_destinations::table)
+        .values((federation_destinations::server_id.eq(server_name), values))
+        .on_conflict(federation_destinations::server_id)
+        .do_update()
+        .set(values)
+        .execute(&mut *db::connect()?)?;
+    if failure_count == 1 {
+        warn!("Backing off {server_name} after a failed request");
+    }
+    Ok(())
+}
+
+/// Clears the failures of the server, requests are sent to it again. Returns whether it was
+/// failing.
+pub fn reset_destination(server_name: &ServerName) -> AppResult<bool> {
+    let count = diesel::update(
+        federation_destinations::table
+            .find(server_name)
+            .filter(federation_destinations::failure_count.gt(0)),
+    )
+    .set((
+        federation_destinations::failure_count.eq(0),
+        federation_destinations::failure_ts.eq(None::<i64>),
+        federation_destinations::retry_last_ts.eq(None::<i64>),
+        federation_destinations::retry_interval.eq(0),
+    ))
+    .execute(&mut *db::connect()?)?;
+    Ok(count > 0)
+}
@@ -1,15 +1,59 @@
 use std::fmt::Debug;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::LazyLock;
 use std::time::{Duration, Instant};
 
+use regex::RegexSet;
 use salvo::http::header::AUTHORIZATION;
 use salvo::http::headers::authorization::Credentials;
 use salvo::http::headers::{CacheControl, Header};
 
+pub mod destination;
+
 use crate::core::authorization::XMatrix;
 use crate::core::{signatures, MatrixError, ServerName};
 use crate::{AppError, AppResult};
 
+/// The `federation_domain_whitelist` and `federation_domain_blacklist` patterns.
+static DOMAIN_LISTS: LazyLock<(RegexSet, RegexSet)> = LazyLock::new(|| {
+    let conf = crate::config();
+    (
+        domain_patterns(&conf.federation_domain_whitelist),
+        domain_patterns(&conf.federation_domain_blacklist),
+    )
+});
+
+fn domain_patterns(patterns: &[String]) -> RegexSet {
+    RegexSet::new(patterns.iter().map(|pattern| {
+        let pattern = regex::escape(pattern).replace(r"\*", ".*").replace(r"\?", ".");
+        format!("(?i)^{pattern}$")
+    }))
+    .expect("escaped domain patterns are valid regexes")
+}
+
+/// Whether the federation allow and deny lists let us federate with the server.
+pub fn is_server_allowed(server_name: &ServerName) -> bool {
+    if server_name == crate::server_name() {
+        return true;
+    }
+    let (whitelist, blacklist) = &*DOMAIN_LISTS;
+    domain_lists_allow(whitelist, blacklist, server_name.as_str())
+}
+
+/// An empty whitelist allows every server that is not in the blacklist.
+fn domain_lists_allow(whitelist: &RegexSet, blacklist: &RegexSet, server_name: &str) -> bool {
+    (whitelist.is_empty() || whitelist.is_match(server_name)) && !blacklist.is_match(server_name)
+}
+
+/// Refuses servers the federation allow and deny lists block.
+pub fn check_server_allowed(server_name: &ServerName) -> AppResult<()> {
+    if is_server_allowed(server_name) {
+        Ok(())
+    } else {
+        Err(MatrixError::forbidden(format!("Federation with {server_name} is not allowed by this server.")).into())
+    }
+}
+
 #[tracing::instrument(skip(request))]
 pub(crate) async fn send_request(
     destination: &ServerName,
@@ -85,6 +129,9 @@ pub(crate) async fn send_request(
 
     match response {
         Ok(response) => {
+            if let Err(e) = destination::reset_destination(destination) {
+                error!("Failed to reset the failures of {destination}: {e}");
+            }
             let status = response.status();
 
             if status == 200 {
@@ -99,7 +146,53 @@ pub(crate) async fn send_request(
         }
         Err(e) => {
             warn!("Could not send request to {} at {}: {}", destination, url, e);
+            if let Err(e) = destination::record_failure(destination) {
+                error!("Failed to record the failure of {destination}: {e}");
+            }
             Err(e.into())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> RegexSet {
+        domain_patterns(&patterns.iter().map(|pattern| pattern.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn patterns_are_anchored_globs() {
+        let set = patterns(&["*.example.com", "matrix.org", "node?.example.net"]);
+        assert!(set.is_match("chat.example.com"));
+        assert!(set.is_match("a.b.example.com"));
+        assert!(!set.is_match("example.com"));
+        assert!(!set.is_match("evil-example.com"));
+        assert!(!set.is_match("chat.example.com.evil.org"));
+        assert!(set.is_match("matrix.org"));
+        assert!(!set.is_match("matrixXorg"));
+        assert!(!set.is_match("notmatrix.org"));
+        assert!(set.is_match("node1.example.net"));
+        assert!(!set.is_match("node12.example.net"));
+    }
+
+    #[test]
+    fn patterns_are_case_insensitive() {
+        let set = patterns(&["*.Example.COM"]);
+        assert!(set.is_match("CHAT.example.com"));
+    }
+
+    #[test]
+    fn blacklist_wins_over_whitelist() {
+        let whitelist = patterns(&["*.example.com"]);
+        let blacklist = patterns(&["bad.example.com"]);
+        assert!(domain_lists_allow(&whitelist, &blacklist, "good.example.com"));
+        assert!(!domain_lists_allow(&whitelist, &blacklist, "bad.example.com"));
+        assert!(!domain_lists_allow(&whitelist, &blacklist, "matrix.org"));
+
+        let empty = patterns(&[]);
+        assert!(domain_lists_allow(&empty, &blacklist, "matrix.org"));
+        assert!(!domain_lists_allow(&empty, &blacklist, "bad.example.com"));
+    }
+}
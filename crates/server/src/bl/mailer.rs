@@ -0,0 +1,186 @@
+//! Sending emails
+//!
+//! Emails are rendered from templates, the built-in ones can be replaced by files in
+//! `email.template_dir`. How they are delivered is configured with `email.transport`.
+
+use std::sync::OnceLock;
+
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncFileTransport, AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+use crate::config::{EmailConfig, MailTransport, SmtpTls};
+use crate::{AppError, AppResult, MatrixError};
+
+/// The emails the server sends.
+#[derive(Clone, Copy, Debug)]
+pub enum MailTemplate {
+    /// Validates an address that is added to an account.
+    VerifyEmail,
+    /// Validates the address of an account being registered.
+    Registration,
+    /// Validates the address of an account whose password is reset.
+    PasswordReset,
+}
+
+impl MailTemplate {
+    fn name(self) -> &'static str {
+        match self {
+            Self::VerifyEmail => "verify_email",
+            Self::Registration => "registration",
+            Self::PasswordReset => "password_reset",
+        }
+    }
+
+    fn default_text(self) -> &'static str {
+        match self {
+            Self::VerifyEmail => {
+                "Validate your email address on {app_name}\n\
+                 A request to add {address} to an account on {app_name} was made.\n\n\
+                 Open the following link to confirm it was you:\n\n{link}\n\n\
+                 If it wasn't you, you can ignore this email."
+            }
+            Self::Registration => {
+                "Validate your email address on {app_name}\n\
+                 An account is being registered on {app_name} with {address}.\n\n\
+                 Open the following link to confirm it was you:\n\n{link}\n\n\
+                 If it wasn't you, you can ignore this email."
+            }
+            Self::PasswordReset => {
+                "Reset your password on {app_name}\n\
+                 A password reset was requested for the account of {address} on {app_name}.\n\n\
+                 Open the following link to confirm it was you, then set a new password in your client:\n\n{link}\n\n\
+                 If it wasn't you, you can ignore this email, your password is not changed."
+            }
+        }
+    }
+}
+
+enum Mailer {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    Sendmail(AsyncSendmailTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+    Log,
+}
+
+impl Mailer {
+    fn new(transport: &MailTransport) -> AppResult<Self> {
+        Ok(match transport {
+            MailTransport::Smtp {
+                host,
+                port,
+                username,
+                password,
+                tls,
+            } => {
+                let tls_parameters =
+                    || TlsParameters::new(host.clone()).map_err(|e| AppError::internal(format!("SMTP TLS: {e}")));
+                let (tls, default_port) = match tls {
+                    SmtpTls::Implicit => (Tls::Wrapper(tls_parameters()?), 465),
+                    SmtpTls::Starttls => (Tls::Required(tls_parameters()?), 587),
+                    SmtpTls::None => (Tls::None, 25),
+                };
+                let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+                    .port(port.unwrap_or(default_port))
+                    .tls(tls);
+                if let (Some(username), Some(password)) = (username, password) {
+                    builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+                }
+                Self::Smtp(builder.build())
+            }
+            MailTransport::Sendmail { command } => Self::Sendmail(match command {
+                Some(command) => AsyncSendmailTransport::new_with_command(command),
+                None => AsyncSendmailTransport::new(),
+            }),
+            MailTransport::File { path } => {
+                std::fs::create_dir_all(path)?;
+                Self::File(AsyncFileTransport::new(path))
+            }
+            MailTransport::Log => Self::Log,
+        })
+    }
+
+    async fn send(&self, message: Message) -> AppResult<()> {
+        match self {
+            Self::Smtp(transport) => transport
+                .send(message)
+                .await
+                .map(|_| ())
+                .map_err(|e| AppError::internal(format!("Sending email over SMTP failed: {e}"))),
+            Self::Sendmail(transport) => transport
+                .send(message)
+                .await
+                .map_err(|e| AppError::internal(format!("Sending email with sendmail failed: {e}"))),
+            Self::File(transport) => transport
+                .send(message)
+                .await
+                .map(|_| ())
+                .map_err(|e| AppError::internal(format!("Writing email failed: {e}"))),
+            Self::Log => {
+                info!(
+                    "Email to {:?}:\n{}",
+                    message.envelope().to(),
+                    String::from_utf8_lossy(&message.formatted())
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+fn mailer(conf: &EmailConfig) -> AppResult<&'static Mailer> {
+    static MAILER: OnceLock<Mailer> = OnceLock::new();
+    if let Some(mailer) = MAILER.get() {
+        return Ok(mailer);
+    }
+    let mailer = Mailer::new(&conf.transport)?;
+    Ok(MAILER.get_or_init(|| mailer))
+}
+
+/// Returns whether the server is configured to send emails.
+pub fn is_enabled() -> bool {
+    crate::config().email.is_some()
+}
+
+/// Renders `template` for `address` and sends it there.
+pub async fn send_template(address: &str, template: MailTemplate, link: &str) -> AppResult<()> {
+    let Some(conf) = &crate::config().email else {
+        return Err(MatrixError::threepid_denied("Email is not enabled on this server.").into());
+    };
+    let text = match &conf.template_dir {
+        Some(dir) => {
+            let path = std::path::Path::new(dir).join(format!("{}.txt", template.name()));
+            match tokio::fs::read_to_string(&path).await {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("Failed to read email template {}: {e}", path.display());
+                    template.default_text().to_owned()
+                }
+            }
+        }
+        None => template.default_text().to_owned(),
+    };
+    let text = text
+        .replace("{app_name}", &conf.app_name)
+        .replace("{address}", address)
+        .replace("{link}", link);
+    let (subject, body) = text.split_once('\n').unwrap_or((&text, ""));
+
+    let from = conf
+        .notif_from
+        .parse::<Mailbox>()
+        .map_err(|e| AppError::internal(format!("Invalid email.notif_from: {e}")))?;
+    let to = address
+        .parse::<Mailbox>()
+        .map_err(|_| MatrixError::invalid_param("Invalid email address."))?;
+    let message = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(subject.trim())
+        .header(ContentType::TEXT_PLAIN)
+        .body(body.trim_start().to_owned())
+        .map_err(|e| AppError::internal(format!("Building email failed: {e}")))?;
+    mailer(conf)?.send(message).await
+}
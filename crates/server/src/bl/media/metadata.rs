@@ -1,9 +1,10 @@
 use diesel::prelude::*;
+use uuid::Uuid;
 
 use crate::core::identifiers::*;
 use crate::core::UnixMillis;
 use crate::schema::*;
-use crate::{db, AppResult};
+use crate::{db, utils, AppResult};
 
 #[derive(Insertable, Identifiable, Queryable, Debug, Clone)]
 #[diesel(table_name = media_metadatas)]
@@ -43,3 +44,35 @@ pub fn get_metadata(server_name: &ServerName, media_id: &str) -> AppResult<Optio
         .optional()
         .map_err(Into::into)
 }
+
+/// Stores content the server created itself as local media, returns its MXC URI.
+pub async fn save_local_media(
+    file: &[u8],
+    content_type: Option<String>,
+    upload_name: Option<String>,
+    created_by: Option<OwnedUserId>,
+) -> AppResult<OwnedMxcUri> {
+    let conf = crate::config();
+    let media_id = utils::base32_crockford(Uuid::new_v4().as_bytes());
+    let dest_path = crate::media_path(&conf.server_name, &media_id);
+    tokio::fs::create_dir_all(utils::fs::get_parent_dir(&dest_path)).await?;
+    tokio::fs::write(&dest_path, file).await?;
+
+    let metadata = NewDbMetadata {
+        media_id: media_id.clone(),
+        origin_server: conf.server_name.clone(),
+        content_type: content_type.clone(),
+        content_disposition: None,
+        file_extension: upload_name.as_deref().map(utils::fs::get_file_ext),
+        upload_name,
+        file_size: file.len() as i64,
+        file_hash: None,
+        created_by,
+        created_at: UnixMillis::now(),
+    };
+    diesel::insert_into(media_metadatas::table)
+        .values(&metadata)
+        .execute(&mut *db::connect()?)?;
+    super::spawn_thumbnail_generation(conf.server_name.clone(), media_id.clone(), content_type);
+    Ok(OwnedMxcUri::from(format!("mxc://{}/{}", conf.server_name, media_id)))
+}
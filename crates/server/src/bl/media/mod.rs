@@ -1,57 +1,175 @@
 mod metadata;
+mod preview;
+mod quarantine;
+mod reference;
+mod remote;
+mod retention;
 mod thumbnail;
 
 pub use metadata::*;
+pub use preview::*;
+pub use quarantine::*;
+pub use reference::*;
+pub use remote::*;
+pub use retention::*;
 pub use thumbnail::*;
 
 use std::time::Duration;
 
+use reqwest::Url;
+use salvo::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use salvo::http::HeaderValue;
 use salvo::Response;
 
-use crate::core::client::media::ContentReqArgs;
+use crate::core::federation::media::{self as federation_media, Content, FileOrLocation};
+use crate::core::media::Method;
 use crate::core::ServerName;
-use crate::{exts::*, join_path, AppResult};
+use crate::{exts::*, join_path, AppResult, MatrixError};
 
-pub async fn get_remote_content(
-    mxc: &str,
+/// Downloads media from the server it was uploaded to.
+///
+/// The authenticated federation endpoint is tried first, servers that don't support it yet are
+/// asked on the legacy unauthenticated endpoint.
+pub async fn fetch_remote_content(server_name: &ServerName, media_id: &str, timeout: Duration) -> AppResult<Content> {
+    let origin = server_name.origin().await;
+    let request = federation_media::content_request(
+        &origin,
+        federation_media::ContentReqArgs {
+            media_id: media_id.to_owned(),
+            timeout_ms: timeout,
+        },
+    )?
+    .into_inner();
+    match fetch_multipart(server_name, request, timeout).await {
+        Ok(content) => Ok(content),
+        Err(e) => {
+            debug!("Authenticated download of mxc://{server_name}/{media_id} failed, trying legacy endpoint: {e}");
+            let request = crate::core::client::media::content_request(
+                &origin,
+                crate::core::client::media::ContentReqArgs {
+                    server_name: server_name.to_owned(),
+                    media_id: media_id.to_owned(),
+                    allow_remote: false,
+                    timeout_ms: timeout,
+                    allow_redirect: false,
+                },
+            )?
+            .into_inner();
+            content_from_response(crate::sending::send_federation_request(server_name, request).await?).await
+        }
+    }
+}
+
+/// Downloads a thumbnail from the server the media was uploaded to, like [`fetch_remote_content`].
+pub async fn fetch_remote_thumbnail(
     server_name: &ServerName,
     media_id: &str,
-    res: &mut Response,
-) -> AppResult<()> {
-    // let servername: crate::core::OwnedServerName = "127.0.0.1:8448".parse().unwrap();
-    // let content_response = match crate::sending::get(servername.build_url(&format!("media/{media_id}?allow_remote=false"))?)
-    //     .exec()
-    //     .await {
-    //         Ok(s) => s,
-    //         Err(e) => {
-    //             return Err(e.into());
-    //         }
-    //     };
-    let mut content_req = crate::core::client::media::content_request(
-        &server_name.origin().await,
-        ContentReqArgs {
-            server_name: server_name.to_owned(),
+    width: u32,
+    height: u32,
+    method: Option<Method>,
+    animated: Option<bool>,
+    timeout: Duration,
+) -> AppResult<Content> {
+    let origin = server_name.origin().await;
+    let request = federation_media::thumbnail_request(
+        &origin,
+        federation_media::ThumbnailReqArgs {
             media_id: media_id.to_owned(),
-            allow_remote: false,
-            timeout_ms: Duration::from_secs(20),
-            allow_redirect: false,
+            method: method.clone(),
+            width,
+            height,
+            timeout_ms: timeout,
+            animated,
         },
     )?
     .into_inner();
-    let content_response: reqwest::Response = crate::sending::send_federation_request(server_name, content_req).await?;
+    match fetch_multipart(server_name, request, timeout).await {
+        Ok(content) => Ok(content),
+        Err(e) => {
+            debug!("Authenticated thumbnail of mxc://{server_name}/{media_id} failed, trying legacy endpoint: {e}");
+            let request = crate::core::client::media::thumbnail_request(
+                &origin,
+                server_name,
+                crate::core::client::media::ThumbnailReqArgs {
+                    server_name: server_name.to_owned(),
+                    media_id: media_id.to_owned(),
+                    method,
+                    width,
+                    height,
+                    allow_remote: false,
+                    timeout_ms: timeout,
+                    allow_redirect: false,
+                    animated,
+                },
+            )?
+            .into_inner();
+            content_from_response(crate::sending::send_federation_request(server_name, request).await?).await
+        }
+    }
+}
 
-    *res.headers_mut() = content_response.headers().to_owned();
-    res.status_code(content_response.status());
-    res.stream(content_response.bytes_stream());
+/// Sends an authenticated federation media request. The server may answer with the location of
+/// the content instead, which is fetched with the same address checks as URL previews since it can
+/// point anywhere.
+async fn fetch_multipart(server_name: &ServerName, request: reqwest::Request, timeout: Duration) -> AppResult<Content> {
+    let response = crate::sending::send_federation_request(server_name, request).await?;
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned);
+    let body = read_body(response).await?;
+    let (_, content) = federation_media::try_from_multipart_mixed(content_type.as_deref(), &body)?;
+    match content {
+        FileOrLocation::File(content) => Ok(content),
+        FileOrLocation::Location(location) => {
+            let (_, response) = guarded_get(Url::parse(&location)?, timeout, |_| true).await?;
+            content_from_response(response).await
+        }
+    }
+}
 
-    // crate::media::create_media(
-    //     mxc.to_owned(),
-    //     content_response.content_disposition.as_deref(),
-    //     content_response.content_type.as_deref(),
-    //     &content_response.file,
-    // )
-    // .await?;
+async fn content_from_response(response: reqwest::Response) -> AppResult<Content> {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .map(ToOwned::to_owned)
+    };
+    let content_type = header(CONTENT_TYPE);
+    let content_disposition = header(CONTENT_DISPOSITION).and_then(|value| value.parse().ok());
+    Ok(Content {
+        file: read_body(response).await?,
+        content_type,
+        content_disposition,
+    })
+}
+
+/// Reads the body of a remote media response, failing when it is larger than `media.remote_media_max_size`.
+async fn read_body(mut response: reqwest::Response) -> AppResult<Vec<u8>> {
+    let max_size = crate::config().media.remote_media_max_size;
+    if response.content_length().is_some_and(|len| len > max_size) {
+        return Err(MatrixError::too_large("Remote media is too large.").into());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > max_size {
+            return Err(MatrixError::too_large("Remote media is too large.").into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
 
+/// Writes a federation media response, the metadata and content as `multipart/mixed` body.
+pub fn write_multipart_content(res: &mut Response, content: Content) -> AppResult<()> {
+    let (content_type, body) = federation_media::try_into_multipart_mixed(
+        &federation_media::ContentMetadata::new(),
+        &FileOrLocation::File(content),
+    )?;
+    res.add_header(CONTENT_TYPE, content_type, true)?;
+    res.write_body(body)?;
     Ok(())
 }
 
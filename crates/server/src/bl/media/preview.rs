@@ -0,0 +1,366 @@
+//! URL previews
+//!
+//! Pages are fetched by the server, which makes every preview request a request from inside the
+//! server's network. So redirects are followed by hand, every host is resolved before connecting
+//! and each resolved address is checked against `url_preview.ip_range_denylist`, the connection is
+//! then pinned to the checked addresses. Media that other servers redirect to is fetched the same
+//! way, with [`guarded_get`].
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use diesel::prelude::*;
+use regex::Regex;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use serde_json::{json, Map};
+
+use crate::config::UrlPreviewConfig;
+use crate::core::identifiers::*;
+use crate::core::serde::JsonValue;
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, AppResult, MatrixError};
+
+/// Redirects followed for a single page or image.
+const MAX_REDIRECTS: usize = 5;
+
+static META_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<meta\s[^>]*>").expect("meta regex is valid"));
+static ATTR_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?is)([a-z_:\-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).expect("attribute regex is valid")
+});
+static TITLE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").expect("title regex is valid"));
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = media_url_previews)]
+pub struct NewDbUrlPreview {
+    pub url: String,
+    pub og_data: JsonValue,
+    pub created_at: i64,
+}
+
+/// Returns the OpenGraph data of `url`, as it was at `ts` if a preview from that time is cached.
+pub async fn get_url_preview(url: &str, ts: Option<UnixMillis>, user_id: &UserId) -> AppResult<JsonValue> {
+    let conf = &crate::config().url_preview;
+    if !conf.enabled {
+        return Err(MatrixError::forbidden("URL previews are disabled on this server.").into());
+    }
+    let parsed_url = Url::parse(url).map_err(|_| MatrixError::invalid_param("Invalid URL."))?;
+    if !matches!(parsed_url.scheme(), "http" | "https") {
+        return Err(MatrixError::invalid_param("Only http and https URLs can be previewed.").into());
+    }
+
+    let now = UnixMillis::now().get() as i64;
+    let cache_duration = (conf.cache_duration_s * 1000) as i64;
+    if let Some(ts) = ts {
+        let ts = ts.get() as i64;
+        if let Some(og_data) = cached_preview(url, ts - cache_duration, ts)? {
+            return Ok(og_data);
+        }
+    }
+    if let Some(og_data) = cached_preview(url, now - cache_duration, now)? {
+        return Ok(og_data);
+    }
+
+    let og_data = fetch_preview(parsed_url, user_id).await?;
+    diesel::insert_into(media_url_previews::table)
+        .values(&NewDbUrlPreview {
+            url: url.to_owned(),
+            og_data: og_data.clone(),
+            created_at: now,
+        })
+        .execute(&mut *db::connect()?)?;
+    Ok(og_data)
+}
+
+/// Deletes the previews older than `url_preview.cache_duration_s`, returns how many were deleted.
+pub fn purge_expired_url_previews() -> AppResult<usize> {
+    let created_before = UnixMillis::now().get() as i64 - (crate::config().url_preview.cache_duration_s * 1000) as i64;
+    diesel::delete(media_url_previews::table.filter(media_url_previews::created_at.lt(created_before)))
+        .execute(&mut *db::connect()?)
+        .map_err(Into::into)
+}
+
+fn cached_preview(url: &str, created_after: i64, created_before: i64) -> AppResult<Option<JsonValue>> {
+    media_url_previews::table
+        .filter(media_url_previews::url.eq(url))
+        .filter(media_url_previews::created_at.ge(created_after))
+        .filter(media_url_previews::created_at.le(created_before))
+        .order_by(media_url_previews::created_at.desc())
+        .select(media_url_previews::og_data)
+        .first::<JsonValue>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+async fn fetch_preview(url: Url, user_id: &UserId) -> AppResult<JsonValue> {
+    let (url, content_type, body) = fetch(url).await?;
+    let mut og_data = Map::new();
+    if content_type.as_deref().is_some_and(|t| t.starts_with("image/")) {
+        add_image(&mut og_data, &url, content_type, body, user_id).await?;
+    } else if content_type.as_deref().is_some_and(|t| t.starts_with("text/html")) {
+        let html = String::from_utf8_lossy(&body);
+        let mut metadata = parse_html(&html);
+        if let Some(image) = metadata.remove("og:image") {
+            match url.join(&image) {
+                Ok(image_url) => match fetch(image_url).await {
+                    Ok((image_url, content_type, body)) => {
+                        if content_type.as_deref().is_some_and(|t| t.starts_with("image/")) {
+                            add_image(&mut og_data, &image_url, content_type, body, user_id).await?;
+                        }
+                    }
+                    Err(e) => debug!("Failed to fetch preview image {image}: {e}"),
+                },
+                Err(e) => debug!("Invalid preview image URL {image}: {e}"),
+            }
+        }
+        for (key, value) in metadata {
+            og_data.entry(key).or_insert(JsonValue::String(value));
+        }
+    }
+    Ok(JsonValue::Object(og_data))
+}
+
+/// Stores the image as local media and describes it in `og_data`.
+async fn add_image(
+    og_data: &mut Map<String, JsonValue>,
+    url: &Url,
+    content_type: Option<String>,
+    body: Vec<u8>,
+    user_id: &UserId,
+) -> AppResult<()> {
+    let upload_name = url
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .map(ToOwned::to_owned);
+    let dimensions = image::ImageReader::new(Cursor::new(&body))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+    let mxc = super::save_local_media(&body, content_type.clone(), upload_name, Some(user_id.to_owned())).await?;
+    og_data.insert("og:image".to_owned(), json!(mxc));
+    og_data.insert("matrix:image:size".to_owned(), json!(body.len()));
+    if let Some(content_type) = content_type {
+        og_data.insert("og:image:type".to_owned(), json!(content_type));
+    }
+    if let Some((width, height)) = dimensions {
+        og_data.insert("og:image:width".to_owned(), json!(width));
+        og_data.insert("og:image:height".to_owned(), json!(height));
+    }
+    Ok(())
+}
+
+/// Collects the OpenGraph metadata of a page, Twitter cards, `<title>` and the description are
+/// used for what is missing.
+fn parse_html(html: &str) -> HashMap<String, String> {
+    let mut og = HashMap::new();
+    let mut fallbacks = HashMap::new();
+    for tag in META_REGEX.find_iter(html) {
+        let mut attrs = HashMap::new();
+        for captures in ATTR_REGEX.captures_iter(tag.as_str()) {
+            let value = captures.get(2).or(captures.get(3)).or(captures.get(4));
+            attrs.insert(
+                captures[1].to_ascii_lowercase(),
+                value.map(|v| v.as_str()).unwrap_or_default(),
+            );
+        }
+        let (Some(key), Some(content)) = (attrs.get("property").or(attrs.get("name")), attrs.get("content")) else {
+            continue;
+        };
+        let key = key.to_ascii_lowercase();
+        let content = unescape_html(content);
+        if key.starts_with("og:") {
+            og.entry(key).or_insert(content);
+        } else {
+            let key = match &*key {
+                "twitter:title" => "og:title",
+                "twitter:description" | "description" => "og:description",
+                "twitter:image" | "twitter:image:src" => "og:image",
+                _ => continue,
+            };
+            fallbacks.entry(key.to_owned()).or_insert(content);
+        }
+    }
+    if let Some(title) = TITLE_REGEX.captures(html) {
+        fallbacks
+            .entry("og:title".to_owned())
+            .or_insert_with(|| unescape_html(title[1].trim()));
+    }
+    for (key, value) in fallbacks {
+        og.entry(key).or_insert(value);
+    }
+    og.retain(|_, value| !value.is_empty());
+    og
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Fetches `url` following redirects, returns the final URL, its content type and body.
+async fn fetch(url: Url) -> AppResult<(Url, Option<String>, Vec<u8>)> {
+    let conf = &crate::config().url_preview;
+    let (url, mut response) = guarded_get(url, Duration::from_secs(conf.timeout_s), |url| match url.host() {
+        Some(url::Host::Domain(domain)) => is_domain_allowed(conf, domain),
+        _ => true,
+    })
+    .await?;
+    if response.content_length().is_some_and(|len| len > conf.max_spider_size) {
+        return Err(MatrixError::too_large("Content is too large to preview.").into());
+    }
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned);
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > conf.max_spider_size {
+            return Err(MatrixError::too_large("Content is too large to preview.").into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((url, content_type, body))
+}
+
+/// Sends a GET request to `url` following at most [`MAX_REDIRECTS`] redirects, every URL must pass
+/// `is_url_allowed` and is only connected to at allowed addresses. Returns the final URL and its
+/// successful response, the caller limits the size of the body it reads.
+pub(super) async fn guarded_get(
+    mut url: Url,
+    timeout: Duration,
+    is_url_allowed: impl Fn(&Url) -> bool,
+) -> AppResult<(Url, reqwest::Response)> {
+    for _ in 0..=MAX_REDIRECTS {
+        if !is_url_allowed(&url) {
+            return Err(MatrixError::forbidden("Requests to this domain are not allowed.").into());
+        }
+        let response = client_for(&url, timeout).await?.get(url.clone()).send().await?;
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| MatrixError::unknown("Redirect without location."))?;
+            url = url.join(location)?;
+            continue;
+        }
+        if !response.status().is_success() {
+            return Err(MatrixError::unknown(format!("Fetching {url} failed: {}", response.status())).into());
+        }
+        return Ok((url, response));
+    }
+    Err(MatrixError::unknown("Too many redirects.").into())
+}
+
+/// Builds a client that can only connect to the allowed addresses of the URL's host.
+async fn client_for(url: &Url, timeout: Duration) -> AppResult<reqwest::Client> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(MatrixError::forbidden("Only http and https URLs can be fetched.").into());
+    }
+    let conf = &crate::config().url_preview;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut builder = reqwest::Client::builder()
+        .redirect(Policy::none())
+        .no_proxy()
+        .timeout(timeout);
+    match url.host() {
+        Some(url::Host::Domain(domain)) => {
+            let addrs = tokio::net::lookup_host((domain, port))
+                .await?
+                .collect::<Vec<SocketAddr>>();
+            if addrs.is_empty() || addrs.iter().any(|addr| !is_ip_allowed(conf, addr.ip())) {
+                return Err(MatrixError::forbidden("Requests to this address are not allowed.").into());
+            }
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+        Some(url::Host::Ipv4(ip)) if is_ip_allowed(conf, ip.into()) => {}
+        Some(url::Host::Ipv6(ip)) if is_ip_allowed(conf, ip.into()) => {}
+        _ => return Err(MatrixError::forbidden("Requests to this address are not allowed.").into()),
+    }
+    Ok(builder.build()?)
+}
+
+fn is_domain_allowed(conf: &UrlPreviewConfig, domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    let matches = |entry: &String| {
+        let entry = entry.trim_start_matches("*.").trim_end_matches('.');
+        domain.eq_ignore_ascii_case(entry)
+            || domain
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", entry.to_ascii_lowercase()))
+    };
+    if conf.domain_denylist.iter().any(matches) {
+        return false;
+    }
+    conf.domain_allowlist.is_empty() || conf.domain_allowlist.iter().any(matches)
+}
+
+fn is_ip_allowed(conf: &UrlPreviewConfig, ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+    conf.ip_range_allowlist.iter().any(|net| net.contains(&ip))
+        || !conf.ip_range_denylist.iter().any(|net| net.contains(&ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preview_config() -> UrlPreviewConfig {
+        UrlPreviewConfig {
+            ip_range_allowlist: vec!["192.168.1.0/24".parse().unwrap()],
+            domain_allowlist: vec!["*.example.com".to_owned()],
+            domain_denylist: vec!["internal.example.com".to_owned()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn private_addresses_are_denied() {
+        let conf = preview_config();
+        assert!(is_ip_allowed(&conf, "93.184.216.34".parse().unwrap()));
+        assert!(!is_ip_allowed(&conf, "127.0.0.1".parse().unwrap()));
+        assert!(!is_ip_allowed(&conf, "10.1.2.3".parse().unwrap()));
+        assert!(!is_ip_allowed(&conf, "::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_checked_as_ipv4() {
+        let conf = preview_config();
+        assert!(!is_ip_allowed(&conf, "::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!is_ip_allowed(&conf, "::ffff:10.1.2.3".parse().unwrap()));
+        assert!(is_ip_allowed(&conf, "::ffff:93.184.216.34".parse().unwrap()));
+    }
+
+    #[test]
+    fn allowlist_overrides_denylist() {
+        let conf = preview_config();
+        assert!(is_ip_allowed(&conf, "192.168.1.20".parse().unwrap()));
+        assert!(is_ip_allowed(&conf, "::ffff:192.168.1.20".parse().unwrap()));
+        assert!(!is_ip_allowed(&conf, "192.168.2.20".parse().unwrap()));
+    }
+
+    #[test]
+    fn domains_follow_the_lists() {
+        let conf = preview_config();
+        assert!(is_domain_allowed(&conf, "example.com"));
+        assert!(is_domain_allowed(&conf, "WWW.Example.com."));
+        assert!(!is_domain_allowed(&conf, "internal.example.com"));
+        assert!(!is_domain_allowed(&conf, "api.internal.example.com"));
+        assert!(!is_domain_allowed(&conf, "evil-example.com"));
+    }
+}
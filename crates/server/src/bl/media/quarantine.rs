@@ -0,0 +1,103 @@
+use std::collections::BTreeSet;
+
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+
+use crate::core::identifiers::*;
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, diesel_exists, AppResult};
+
+#[derive(Identifiable, Queryable, Debug, Clone)]
+#[diesel(table_name = media_quarantines)]
+pub struct DbQuarantine {
+    pub id: i64,
+    pub media_id: String,
+    pub origin_server: OwnedServerName,
+    pub quarantined_by: OwnedUserId,
+    pub quarantined_at: UnixMillis,
+}
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = media_quarantines)]
+pub struct NewDbQuarantine {
+    pub media_id: String,
+    pub origin_server: OwnedServerName,
+    pub quarantined_by: OwnedUserId,
+    pub quarantined_at: UnixMillis,
+}
+
+#[derive(QueryableByName, Debug)]
+struct EventJson {
+    #[diesel(sql_type = Text)]
+    json: String,
+}
+
+/// Quarantined media is never served, neither to clients nor to other servers.
+pub fn is_quarantined(server_name: &ServerName, media_id: &str) -> AppResult<bool> {
+    diesel_exists!(
+        media_quarantines::table
+            .filter(media_quarantines::origin_server.eq(server_name))
+            .filter(media_quarantines::media_id.eq(media_id)),
+        &mut *db::connect()?
+    )
+    .map_err(Into::into)
+}
+
+/// Quarantines the given media, returns how many of them were not quarantined yet.
+pub fn quarantine_media(media: &[(OwnedServerName, String)], quarantined_by: &UserId) -> AppResult<usize> {
+    if media.is_empty() {
+        return Ok(0);
+    }
+    let quarantined_at = UnixMillis::now();
+    let rows = media
+        .iter()
+        .map(|(origin_server, media_id)| NewDbQuarantine {
+            media_id: media_id.clone(),
+            origin_server: origin_server.clone(),
+            quarantined_by: quarantined_by.to_owned(),
+            quarantined_at,
+        })
+        .collect::<Vec<_>>();
+    diesel::insert_into(media_quarantines::table)
+        .values(&rows)
+        .on_conflict((media_quarantines::media_id, media_quarantines::origin_server))
+        .do_nothing()
+        .execute(&mut *db::connect()?)
+        .map_err(Into::into)
+}
+
+pub fn unquarantine_media(server_name: &ServerName, media_id: &str) -> AppResult<bool> {
+    let count = diesel::delete(
+        media_quarantines::table
+            .filter(media_quarantines::origin_server.eq(server_name))
+            .filter(media_quarantines::media_id.eq(media_id)),
+    )
+    .execute(&mut *db::connect()?)?;
+    Ok(count > 0)
+}
+
+/// Quarantines everything `user_id` has uploaded to this server.
+pub fn quarantine_user_media(user_id: &UserId, quarantined_by: &UserId) -> AppResult<usize> {
+    let media = media_metadatas::table
+        .filter(media_metadatas::created_by.eq(user_id))
+        .select((media_metadatas::origin_server, media_metadatas::media_id))
+        .load::<(OwnedServerName, String)>(&mut *db::connect()?)?;
+    quarantine_media(&media, quarantined_by)
+}
+
+/// Quarantines all media referenced by the events of `room_id`, local and remote.
+pub fn quarantine_room_media(room_id: &RoomId, quarantined_by: &UserId) -> AppResult<usize> {
+    let events = diesel::sql_query(
+        "SELECT json_data::text AS json FROM event_datas WHERE room_id = $1 AND strpos(json_data::text, 'mxc://') > 0",
+    )
+    .bind::<Text, _>(room_id.as_str())
+    .load::<EventJson>(&mut *db::connect()?)?;
+
+    let media = events
+        .iter()
+        .flat_map(|event| super::referenced_media(&event.json))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    quarantine_media(&media, quarantined_by)
+}
@@ -0,0 +1,199 @@
+//! Media referenced by events
+//!
+//! The media an event contains is recorded when the event is stored, so finding unreferenced media
+//! doesn't need to scan the JSON of every event. The references of events stored before they were
+//! tracked are recorded in batches by a background job.
+
+use std::collections::BTreeSet;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use diesel::prelude::*;
+use regex::Regex;
+
+use crate::core::identifiers::*;
+use crate::core::JsonValue;
+use crate::schema::*;
+use crate::{db, diesel_exists, AppError, AppResult};
+
+/// How many events the backfill job reads per transaction.
+const BACKFILL_BATCH_SIZE: i64 = 1000;
+/// Rows inserted per statement, below the limit of bind parameters.
+const INSERT_CHUNK_SIZE: usize = 1000;
+
+/// Matches the MXC URIs inside an event's JSON.
+static MXC_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"mxc://([^/\s"\\]+)/([A-Za-z0-9_\-]+)"#).expect("mxc regex is valid"));
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = media_references)]
+pub struct NewDbMediaReference {
+    pub origin_server: OwnedServerName,
+    pub media_id: String,
+    pub event_id: OwnedEventId,
+}
+
+#[derive(Identifiable, Queryable, Debug, Clone)]
+#[diesel(table_name = media_reference_backfills)]
+struct DbMediaReferenceBackfill {
+    id: i64,
+    /// The events up to this one are backfilled.
+    last_event_sn: i64,
+    /// Events after this one recorded their references when they were stored.
+    end_event_sn: i64,
+}
+
+/// Returns the distinct media referenced in the JSON of an event, as `(origin_server, media_id)`.
+pub fn referenced_media(json: &str) -> BTreeSet<(OwnedServerName, String)> {
+    MXC_REGEX
+        .captures_iter(json)
+        .filter_map(|captures| {
+            let server_name = OwnedServerName::try_from(&captures[1]).ok()?;
+            Some((server_name, captures[2].to_owned()))
+        })
+        .collect()
+}
+
+fn reference_rows<'a>(event_id: &'a EventId, json_data: &JsonValue) -> impl Iterator<Item = NewDbMediaReference> + 'a {
+    referenced_media(&json_data.to_string())
+        .into_iter()
+        .map(|(origin_server, media_id)| NewDbMediaReference {
+            origin_server,
+            media_id,
+            event_id: event_id.to_owned(),
+        })
+}
+
+/// Records the media referenced by a stored event.
+pub fn add_media_references(event_id: &EventId, json_data: &JsonValue) -> AppResult<()> {
+    let rows = reference_rows(event_id, json_data).collect::<Vec<_>>();
+    if rows.is_empty() {
+        return Ok(());
+    }
+    diesel::insert_into(media_references::table)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(&mut *db::connect()?)?;
+    Ok(())
+}
+
+/// Whether the references of all stored events are recorded, unreferenced media can only be
+/// found once they are.
+pub fn media_references_complete() -> AppResult<bool> {
+    let backfilling = diesel_exists!(
+        media_reference_backfills::table.select(media_reference_backfills::id),
+        &mut *db::connect()?
+    )?;
+    Ok(!backfilling)
+}
+
+/// Records the references of the next batch of events stored before references were tracked,
+/// returns the number of events, 0 once all are done.
+pub fn backfill_media_references() -> AppResult<usize> {
+    db::connect()?.transaction(|conn| {
+        let Some(backfill) = media_reference_backfills::table
+            .first::<DbMediaReferenceBackfill>(conn)
+            .optional()?
+        else {
+            return Ok(0);
+        };
+        let events = event_datas::table
+            .filter(event_datas::event_sn.gt(backfill.last_event_sn))
+            .filter(event_datas::event_sn.le(backfill.end_event_sn))
+            .order_by(event_datas::event_sn.asc())
+            .limit(BACKFILL_BATCH_SIZE)
+            .select((event_datas::event_id, event_datas::event_sn, event_datas::json_data))
+            .load::<(OwnedEventId, i64, JsonValue)>(conn)?;
+        let Some((_, last_event_sn, _)) = events.last() else {
+            diesel::delete(media_reference_backfills::table.find(backfill.id)).execute(conn)?;
+            return Ok(0);
+        };
+
+        let rows = events
+            .iter()
+            .flat_map(|(event_id, _, json_data)| reference_rows(event_id, json_data))
+            .collect::<Vec<_>>();
+        for rows in rows.chunks(INSERT_CHUNK_SIZE) {
+            diesel::insert_into(media_references::table)
+                .values(rows)
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+        }
+        diesel::update(media_reference_backfills::table.find(backfill.id))
+            .set(media_reference_backfills::last_event_sn.eq(last_event_sn))
+            .execute(conn)?;
+        Ok::<_, AppError>(events.len())
+    })
+}
+
+/// Starts the job recording the references of events stored before references were tracked, if
+/// there are any left.
+pub fn start_reference_backfill() {
+    tokio::spawn(async move {
+        loop {
+            match tokio::task::spawn_blocking(backfill_media_references).await {
+                Ok(Ok(0)) => break,
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    error!("Failed to record the media references of stored events: {e}");
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+                Err(e) => {
+                    error!("Media reference backfill job panicked: {e}");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    #[ignore = "needs PALPO_TEST_DATABASE_URL"]
+    fn backfill_records_references_of_stored_events() {
+        init_db();
+        let conn = &mut *db::connect().unwrap();
+        let media_id = crate::utils::random_string(16);
+        let event_id = OwnedEventId::try_from(format!("${}", crate::utils::random_string(16))).unwrap();
+        let event_sn = event_datas::table
+            .select(diesel::dsl::max(event_datas::event_sn))
+            .first::<Option<i64>>(conn)
+            .unwrap()
+            .unwrap_or_default()
+            + 1;
+        diesel::insert_into(event_datas::table)
+            .values((
+                event_datas::event_id.eq(&event_id),
+                event_datas::event_sn.eq(event_sn),
+                event_datas::room_id.eq(format!("!backfill:{TEST_SERVER_NAME}")),
+                event_datas::json_data.eq(json!({
+                    "content": { "msgtype": "m.image", "url": format!("mxc://{TEST_SERVER_NAME}/{media_id}") }
+                })),
+            ))
+            .execute(conn)
+            .unwrap();
+        diesel::insert_into(media_reference_backfills::table)
+            .values((
+                media_reference_backfills::last_event_sn.eq(event_sn - 1),
+                media_reference_backfills::end_event_sn.eq(event_sn),
+            ))
+            .execute(conn)
+            .unwrap();
+        assert!(!media_references_complete().unwrap());
+
+        while backfill_media_references().unwrap() > 0 {}
+        assert!(media_references_complete().unwrap());
+        let referencing_events = media_references::table
+            .filter(media_references::media_id.eq(&media_id))
+            .select(media_references::event_id)
+            .load::<OwnedEventId>(conn)
+            .unwrap();
+        assert_eq!(referencing_events, vec![event_id]);
+    }
+}
@@ -0,0 +1,184 @@
+//! Caching of media from other servers
+//!
+//! Remote media is fetched once and stored like local media, under `media_path` with a metadata
+//! row keyed by its origin server. When only a thumbnail was requested, just that thumbnail is
+//! fetched and stored as a `media_thumbnails` row. Concurrent requests for the same media wait for
+//! the first fetch instead of starting their own.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use diesel::prelude::*;
+
+use crate::core::federation::media::Content;
+use crate::core::identifiers::*;
+use crate::core::media::Method;
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, utils, AppError, AppResult};
+
+use super::{DbMetadata, NewDbMetadata, NewDbThumbnail};
+
+static FETCH_LOCKS: LazyLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> = LazyLock::new(Default::default);
+
+/// Reference to the lock of a fetch, the lock is removed from `FETCH_LOCKS` when the last
+/// reference is dropped, also when the request fetching the media is cancelled.
+struct FetchLock {
+    key: String,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for FetchLock {
+    fn drop(&mut self) {
+        let mut locks = FETCH_LOCKS.lock().unwrap();
+        // Nobody else is waiting, one reference is in the map and one is ours.
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+/// Runs `fetch` while holding the lock of `key`, so the same media is only fetched once at a time.
+async fn coalesce<T>(key: String, fetch: impl Future<Output = AppResult<T>>) -> AppResult<T> {
+    let lock = FETCH_LOCKS.lock().unwrap().entry(key.clone()).or_default().clone();
+    let fetch_lock = FetchLock { key, lock };
+    let _guard = fetch_lock.lock.lock().await;
+    fetch.await
+}
+
+/// Returns the metadata of remote media, fetching and storing the media first if it is not cached yet.
+pub async fn get_remote_content(server_name: &ServerName, media_id: &str, timeout: Duration) -> AppResult<DbMetadata> {
+    coalesce(format!("{server_name}/{media_id}"), async {
+        if let Some(metadata) = super::get_metadata(server_name, media_id)? {
+            if crate::media_path(server_name, media_id).exists() {
+                return Ok(metadata);
+            }
+        }
+        let content = super::fetch_remote_content(server_name, media_id, timeout).await?;
+        save_remote_content(server_name, media_id, content).await?;
+        super::get_metadata(server_name, media_id)?
+            .ok_or_else(|| AppError::internal("Remote media metadata was not saved."))
+    })
+    .await
+}
+
+async fn save_remote_content(server_name: &ServerName, media_id: &str, content: Content) -> AppResult<()> {
+    let dest_path = crate::media_path(server_name, media_id);
+    tokio::fs::create_dir_all(utils::fs::get_parent_dir(&dest_path)).await?;
+    tokio::fs::write(&dest_path, &content.file).await?;
+
+    let upload_name = content
+        .content_disposition
+        .as_ref()
+        .and_then(|content_disposition| content_disposition.filename.clone());
+    let metadata = NewDbMetadata {
+        media_id: media_id.to_owned(),
+        origin_server: server_name.to_owned(),
+        content_type: content.content_type.clone(),
+        content_disposition: content.content_disposition.as_ref().map(ToString::to_string),
+        file_extension: upload_name.as_deref().map(utils::fs::get_file_ext),
+        upload_name,
+        file_size: content.file.len() as i64,
+        file_hash: None,
+        created_by: None,
+        created_at: UnixMillis::now(),
+    };
+    diesel::insert_into(media_metadatas::table)
+        .values(&metadata)
+        .on_conflict((media_metadatas::media_id, media_metadatas::origin_server))
+        .do_nothing()
+        .execute(&mut *db::connect()?)?;
+    super::spawn_thumbnail_generation(server_name.to_owned(), media_id.to_owned(), content.content_type);
+    Ok(())
+}
+
+/// Makes sure a thumbnail of remote media can be served locally.
+///
+/// When the original is cached the thumbnail is generated from it, otherwise only the thumbnail
+/// is fetched. Sizes that are not thumbnailed need the original, which is fetched then.
+pub async fn cache_remote_thumbnail(
+    server_name: &ServerName,
+    media_id: &str,
+    width: u32,
+    height: u32,
+    method: Option<&Method>,
+    animated: bool,
+    timeout: Duration,
+) -> AppResult<()> {
+    if super::get_metadata(server_name, media_id)?.is_some() {
+        return Ok(());
+    }
+    let Some((width, height, crop)) = super::thumbnail_properties(width, height, method) else {
+        return get_remote_content(server_name, media_id, timeout).await.map(|_| ());
+    };
+    let resize_method = super::resize_method(crop);
+    let key = format!("{server_name}/{media_id}/{width}x{height}/{resize_method}/{animated}");
+    coalesce(key, async {
+        if let Some(thumbnail) = super::get_thumbnail(server_name, media_id, width, height, crop, animated)? {
+            if thumbnail.path().exists() {
+                return Ok(());
+            }
+        }
+        let method = if crop { Method::Crop } else { Method::Scale };
+        let content = super::fetch_remote_thumbnail(
+            server_name,
+            media_id,
+            width,
+            height,
+            Some(method),
+            Some(animated),
+            timeout,
+        )
+        .await?;
+
+        let dest_path = super::thumbnail_path(server_name, media_id, width, height, crop, animated);
+        tokio::fs::create_dir_all(utils::fs::get_parent_dir(&dest_path)).await?;
+        tokio::fs::write(&dest_path, &content.file).await?;
+        diesel::insert_into(media_thumbnails::table)
+            .values(&NewDbThumbnail {
+                media_id: media_id.to_owned(),
+                origin_server: server_name.to_owned(),
+                content_type: content
+                    .content_type
+                    .unwrap_or_else(|| mime::APPLICATION_OCTET_STREAM.to_string()),
+                content_disposition: content.content_disposition.as_ref().map(ToString::to_string),
+                file_size: content.file.len() as i64,
+                width: width as i32,
+                height: height as i32,
+                resize_method: resize_method.into(),
+                created_at: UnixMillis::now(),
+                animated,
+            })
+            .on_conflict((
+                media_thumbnails::media_id,
+                media_thumbnails::origin_server,
+                media_thumbnails::width,
+                media_thumbnails::height,
+                media_thumbnails::resize_method,
+                media_thumbnails::animated,
+            ))
+            .do_nothing()
+            .execute(&mut *db::connect()?)?;
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelled_fetch_releases_its_lock() {
+        let key = "cancelled.example/media".to_owned();
+        let fetch = tokio::spawn(coalesce(key.clone(), std::future::pending::<AppResult<()>>()));
+        tokio::task::yield_now().await;
+        assert!(FETCH_LOCKS.lock().unwrap().contains_key(&key));
+
+        fetch.abort();
+        assert!(fetch.await.unwrap_err().is_cancelled());
+        assert!(!FETCH_LOCKS.lock().unwrap().contains_key(&key));
+    }
+}
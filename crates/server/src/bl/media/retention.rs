@@ -0,0 +1,168 @@
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+
+use crate::core::identifiers::*;
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, AppResult};
+
+use super::DbThumbnail;
+
+/// How many expired media are deleted per query, the retention job keeps going until none are left.
+const PURGE_BATCH_SIZE: i64 = 500;
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(QueryableByName, Debug)]
+struct MediaKey {
+    #[diesel(sql_type = Text)]
+    origin_server: OwnedServerName,
+    #[diesel(sql_type = Text)]
+    media_id: String,
+}
+
+fn remove_file(path: &Path) -> AppResult<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn remove_media_files(server_name: &ServerName, media_id: &str) -> AppResult<()> {
+    let thumbnails = media_thumbnails::table
+        .filter(media_thumbnails::origin_server.eq(server_name))
+        .filter(media_thumbnails::media_id.eq(media_id))
+        .load::<DbThumbnail>(&mut *db::connect()?)?;
+    for thumbnail in &thumbnails {
+        remove_file(&thumbnail.path())?;
+    }
+    remove_file(&crate::media_path(server_name, media_id))
+}
+
+fn delete_media_rows(server_name: &ServerName, media_id: &str, conn: &mut PgConnection) -> QueryResult<()> {
+    diesel::delete(
+        media_thumbnails::table
+            .filter(media_thumbnails::origin_server.eq(server_name))
+            .filter(media_thumbnails::media_id.eq(media_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        media_metadatas::table
+            .filter(media_metadatas::origin_server.eq(server_name))
+            .filter(media_metadatas::media_id.eq(media_id)),
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Deletes the file, the thumbnails and the metadata of the media. Quarantine entries are kept, so
+/// remote media that was taken down is not fetched again.
+pub fn delete_media(server_name: &ServerName, media_id: &str) -> AppResult<()> {
+    remove_media_files(server_name, media_id)?;
+    db::connect()?.transaction(|conn| delete_media_rows(server_name, media_id, conn))?;
+    Ok(())
+}
+
+/// Deletes cached remote media and unreferenced local media older than their configured lifetime,
+/// returns how many were deleted.
+pub fn purge_expired_media() -> AppResult<usize> {
+    let conf = crate::config();
+    let mut count = 0;
+    if let Some(days) = conf.media.remote_media_lifetime_days {
+        let created_before = UnixMillis::now().get().saturating_sub(days * MILLIS_PER_DAY);
+        count += purge_media(|| {
+            // Remote media can also be cached as thumbnails only.
+            diesel::sql_query(
+                "SELECT origin_server, media_id FROM media_metadatas \
+                 WHERE origin_server != $1 AND created_at < $2 \
+                 UNION SELECT origin_server, media_id FROM media_thumbnails \
+                 WHERE origin_server != $1 AND created_at < $2 LIMIT $3",
+            )
+            .bind::<Text, _>(conf.server_name.as_str())
+            .bind::<BigInt, _>(created_before as i64)
+            .bind::<BigInt, _>(PURGE_BATCH_SIZE)
+            .load::<MediaKey>(&mut *db::connect()?)
+            .map_err(Into::into)
+        })?;
+    }
+    // Media of events whose references are not recorded yet would look unreferenced.
+    let references_complete = super::media_references_complete()?;
+    if let Some(days) = conf.media.local_media_lifetime_days.filter(|_| references_complete) {
+        let created_before = UnixMillis::now().get().saturating_sub(days * MILLIS_PER_DAY);
+        // Media is referenced by the events it was sent in and by the profiles using it as avatar.
+        count += purge_media(|| {
+            diesel::sql_query(
+                "SELECT m.origin_server, m.media_id FROM media_metadatas m \
+                 WHERE m.origin_server = $1 AND m.created_at < $2 \
+                 AND NOT EXISTS (SELECT 1 FROM media_references r \
+                    WHERE r.origin_server = m.origin_server AND r.media_id = m.media_id) \
+                 AND NOT EXISTS (SELECT 1 FROM user_profiles p \
+                    WHERE p.avatar_url = 'mxc://' || m.origin_server || '/' || m.media_id) \
+                 LIMIT $3",
+            )
+            .bind::<Text, _>(conf.server_name.as_str())
+            .bind::<BigInt, _>(created_before as i64)
+            .bind::<BigInt, _>(PURGE_BATCH_SIZE)
+            .load::<MediaKey>(&mut *db::connect()?)
+            .map_err(Into::into)
+        })?;
+    }
+    Ok(count)
+}
+
+/// Deletes the media returned by `next_batch` until it returns none, the rows of a batch are
+/// deleted in one transaction.
+fn purge_media(mut next_batch: impl FnMut() -> AppResult<Vec<MediaKey>>) -> AppResult<usize> {
+    let mut count = 0;
+    loop {
+        let batch = next_batch()?;
+        if batch.is_empty() {
+            return Ok(count);
+        }
+        for media in &batch {
+            remove_media_files(&media.origin_server, &media.media_id)?;
+        }
+        db::connect()?.transaction(|conn| {
+            for media in &batch {
+                delete_media_rows(&media.origin_server, &media.media_id, conn)?;
+            }
+            QueryResult::Ok(())
+        })?;
+        count += batch.len();
+    }
+}
+
+/// Starts the job deleting expired media and URL previews, if a media lifetime is configured or
+/// previews are enabled.
+pub fn start_retention() {
+    let conf = crate::config();
+    if conf.media.remote_media_lifetime_days.is_none()
+        && conf.media.local_media_lifetime_days.is_none()
+        && !conf.url_preview.enabled
+    {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(conf.media.retention_interval_s));
+        loop {
+            interval.tick().await;
+            match tokio::task::spawn_blocking(purge_expired_media).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(count)) => info!("Deleted {count} expired media"),
+                Ok(Err(e)) => error!("Failed to delete expired media: {e}"),
+                Err(e) => error!("Media retention job panicked: {e}"),
+            }
+            if conf.url_preview.enabled {
+                match tokio::task::spawn_blocking(super::purge_expired_url_previews).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(count)) => info!("Deleted {count} expired URL previews"),
+                    Ok(Err(e)) => error!("Failed to delete expired URL previews: {e}"),
+                    Err(e) => error!("URL preview retention job panicked: {e}"),
+                }
+            }
+        }
+    });
+}
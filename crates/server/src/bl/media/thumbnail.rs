@@ -1,9 +1,37 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::str::FromStr;
+
 use diesel::prelude::*;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::{WebPDecoder, WebPEncoder};
+use image::imageops::FilterType;
+use image::{AnimationDecoder, DynamicImage, Frame, ImageFormat};
+use mime::Mime;
+use salvo::fs::NamedFile;
+use salvo::http::HeaderValue;
+use salvo::{Request, Response};
 
+use crate::core::federation::media::Content;
 use crate::core::identifiers::*;
+use crate::core::media::Method;
 use crate::core::UnixMillis;
 use crate::schema::*;
-use crate::{db, AppResult};
+use crate::{db, AppError, AppResult, MatrixError};
+
+/// The sizes every uploaded image is thumbnailed to in the background, `(width, height, crop)`.
+pub const THUMBNAIL_SIZES: [(u32, u32, bool); 5] = [
+    (32, 32, true),
+    (96, 96, true),
+    (320, 240, false),
+    (640, 480, false),
+    (800, 600, false),
+];
+
+/// Animated thumbnails re-encode every frame, sources with more frames than this get a static one.
+const MAX_ANIMATED_FRAMES: usize = 500;
 
 #[derive(Insertable, Identifiable, Queryable, Debug, Clone)]
 #[diesel(table_name = media_thumbnails)]
@@ -18,6 +46,7 @@ pub struct DbThumbnail {
     pub height: i32,
     pub resize_method: String,
     pub created_at: UnixMillis,
+    pub animated: bool,
 }
 #[derive(Insertable, Debug, Clone)]
 #[diesel(table_name = media_thumbnails)]
@@ -31,6 +60,27 @@ pub struct NewDbThumbnail {
     pub height: i32,
     pub resize_method: String,
     pub created_at: UnixMillis,
+    pub animated: bool,
+}
+
+impl DbThumbnail {
+    pub fn path(&self) -> PathBuf {
+        // Thumbnails stored before the resize method was recorded.
+        if self.resize_method == "_" {
+            return crate::media_path(
+                &self.origin_server,
+                &format!("{}.{}x{}", self.media_id, self.width, self.height),
+            );
+        }
+        thumbnail_path(
+            &self.origin_server,
+            &self.media_id,
+            self.width as u32,
+            self.height as u32,
+            self.resize_method == "crop",
+            self.animated,
+        )
+    }
 }
 
 pub fn get_thumbnail(
@@ -38,12 +88,16 @@ pub fn get_thumbnail(
     media_id: &str,
     width: u32,
     height: u32,
+    crop: bool,
+    animated: bool,
 ) -> AppResult<Option<DbThumbnail>> {
     media_thumbnails::table
         .filter(media_thumbnails::origin_server.eq(origin_server))
         .filter(media_thumbnails::media_id.eq(media_id))
         .filter(media_thumbnails::width.eq(width as i32))
         .filter(media_thumbnails::height.eq(height as i32))
+        .filter(media_thumbnails::resize_method.eq(resize_method(crop)))
+        .filter(media_thumbnails::animated.eq(animated))
         .first::<DbThumbnail>(&mut *db::connect()?)
         .optional()
         .map_err(Into::into)
@@ -51,13 +105,363 @@ pub fn get_thumbnail(
 
 /// Returns width, height of the thumbnail and whether it should be cropped. Returns None when
 /// the server should send the original file.
-pub fn thumbnail_properties(width: u32, height: u32) -> Option<(u32, u32, bool)> {
-    match (width, height) {
-        (0..=32, 0..=32) => Some((32, 32, true)),
-        (0..=96, 0..=96) => Some((96, 96, true)),
-        (0..=320, 0..=240) => Some((320, 240, false)),
-        (0..=640, 0..=480) => Some((640, 480, false)),
-        (0..=800, 0..=600) => Some((800, 600, false)),
-        _ => None,
+///
+/// Without a requested `method` the small sizes are cropped and the larger ones scaled.
+pub fn thumbnail_properties(width: u32, height: u32, method: Option<&Method>) -> Option<(u32, u32, bool)> {
+    let (width, height, crop) = match (width, height) {
+        (0..=32, 0..=32) => (32, 32, true),
+        (0..=96, 0..=96) => (96, 96, true),
+        (0..=320, 0..=240) => (320, 240, false),
+        (0..=640, 0..=480) => (640, 480, false),
+        (0..=800, 0..=600) => (800, 600, false),
+        _ => return None,
+    };
+    let crop = match method {
+        Some(Method::Crop) => true,
+        Some(Method::Scale) => false,
+        _ => crop,
+    };
+    Some((width, height, crop))
+}
+
+pub fn resize_method(crop: bool) -> &'static str {
+    if crop {
+        "crop"
+    } else {
+        "scale"
+    }
+}
+
+pub fn thumbnail_path(
+    server_name: &ServerName,
+    media_id: &str,
+    width: u32,
+    height: u32,
+    crop: bool,
+    animated: bool,
+) -> PathBuf {
+    let animated = if animated { ".animated" } else { "" };
+    crate::media_path(
+        server_name,
+        &format!("{media_id}.{width}x{height}.{}{animated}", resize_method(crop)),
+    )
+}
+
+/// Returns the stored thumbnail, generating it first if needed. Returns None when the original
+/// file should be sent instead, because it is smaller than the thumbnail or not an image.
+///
+/// This decodes and encodes images, so call it from a blocking task.
+pub fn get_or_create_thumbnail(
+    server_name: &ServerName,
+    media_id: &str,
+    width: u32,
+    height: u32,
+    crop: bool,
+    animated: bool,
+) -> AppResult<Option<DbThumbnail>> {
+    if let Some(thumbnail) = get_thumbnail(server_name, media_id, width, height, crop, animated)? {
+        if thumbnail.path().exists() {
+            return Ok(Some(thumbnail));
+        }
+    }
+    let Some(metadata) = crate::media::get_metadata(server_name, media_id)? else {
+        return Err(MatrixError::not_found("Media not found.").into());
+    };
+    let source = std::fs::read(crate::media_path(server_name, media_id))?;
+    let Some((content_type, bytes)) = create_thumbnail(&source, width, height, crop, animated)? else {
+        return Ok(None);
+    };
+
+    let dest_path = thumbnail_path(server_name, media_id, width, height, crop, animated);
+    std::fs::create_dir_all(crate::utils::fs::get_parent_dir(&dest_path))?;
+    std::fs::write(&dest_path, &bytes)?;
+
+    // Save thumbnail in database so we don't have to generate it again next time
+    diesel::insert_into(media_thumbnails::table)
+        .values(&NewDbThumbnail {
+            media_id: media_id.to_owned(),
+            origin_server: server_name.to_owned(),
+            content_type: content_type.into(),
+            content_disposition: metadata.content_disposition,
+            file_size: bytes.len() as i64,
+            width: width as i32,
+            height: height as i32,
+            resize_method: resize_method(crop).into(),
+            created_at: UnixMillis::now(),
+            animated,
+        })
+        .on_conflict((
+            media_thumbnails::media_id,
+            media_thumbnails::origin_server,
+            media_thumbnails::width,
+            media_thumbnails::height,
+            media_thumbnails::resize_method,
+            media_thumbnails::animated,
+        ))
+        .do_nothing()
+        .execute(&mut *db::connect()?)?;
+    get_thumbnail(server_name, media_id, width, height, crop, animated)
+}
+
+/// Generates the standard thumbnail sizes of a freshly uploaded file in the background, so the
+/// first client to view it doesn't have to wait for them.
+pub fn spawn_thumbnail_generation(server_name: OwnedServerName, media_id: String, content_type: Option<String>) {
+    if !content_type.as_deref().unwrap_or_default().starts_with("image/") {
+        return;
+    }
+    let variants: &[bool] = if matches!(content_type.as_deref(), Some("image/gif" | "image/webp")) {
+        &[false, true]
+    } else {
+        &[false]
+    };
+    tokio::task::spawn_blocking(move || {
+        for (width, height, crop) in THUMBNAIL_SIZES {
+            for &animated in variants {
+                match get_or_create_thumbnail(&server_name, &media_id, width, height, crop, animated) {
+                    Ok(Some(_)) => {}
+                    // The image is smaller than this size, so it is smaller than all following sizes too.
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!("Failed to generate thumbnail for mxc://{server_name}/{media_id}: {e}");
+                        return;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Resizes `source` and returns the content type and bytes of the thumbnail, or None when the
+/// source is not an image or is smaller than the thumbnail.
+///
+/// JPEG and WebP sources keep their format and all others are encoded as PNG. When `animated` is
+/// set and the source is an animated GIF or WebP, the thumbnail is an animated GIF (MSC2705).
+fn create_thumbnail(
+    source: &[u8],
+    width: u32,
+    height: u32,
+    crop: bool,
+    animated: bool,
+) -> AppResult<Option<(&'static str, Vec<u8>)>> {
+    let Ok(format) = image::guess_format(source) else {
+        return Ok(None);
+    };
+    if animated {
+        if let Some(bytes) = create_animated_thumbnail(source, format, width, height, crop)? {
+            return Ok(Some(("image/gif", bytes)));
+        }
+    }
+
+    let Ok(image) = image::load_from_memory_with_format(source, format) else {
+        return Ok(None);
+    };
+    if width > image.width() || height > image.height() {
+        return Ok(None);
+    }
+    let thumbnail = resize(&image, width, height, crop);
+
+    let mut bytes = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            thumbnail
+                .to_rgb8()
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, 85))?;
+            Ok(Some(("image/jpeg", bytes)))
+        }
+        ImageFormat::WebP => {
+            thumbnail
+                .to_rgba8()
+                .write_with_encoder(WebPEncoder::new_lossless(&mut bytes))?;
+            Ok(Some(("image/webp", bytes)))
+        }
+        _ => {
+            if thumbnail.color().has_alpha() {
+                thumbnail.to_rgba8().write_with_encoder(PngEncoder::new(&mut bytes))?;
+            } else {
+                thumbnail.to_rgb8().write_with_encoder(PngEncoder::new(&mut bytes))?;
+            }
+            Ok(Some(("image/png", bytes)))
+        }
     }
 }
+
+fn create_animated_thumbnail(
+    source: &[u8],
+    format: ImageFormat,
+    width: u32,
+    height: u32,
+    crop: bool,
+) -> AppResult<Option<Vec<u8>>> {
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(source))?.into_frames(),
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(source))?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+    let frames = frames.take(MAX_ANIMATED_FRAMES + 1).collect::<Result<Vec<_>, _>>()?;
+    if frames.len() < 2 || frames.len() > MAX_ANIMATED_FRAMES {
+        return Ok(None);
+    }
+    let (source_width, source_height) = frames[0].buffer().dimensions();
+    if width > source_width || height > source_height {
+        return Ok(None);
+    }
+
+    let frames = frames.into_iter().map(|frame| {
+        let delay = frame.delay();
+        let image = resize(&DynamicImage::ImageRgba8(frame.into_buffer()), width, height, crop);
+        Frame::from_parts(image.to_rgba8(), 0, 0, delay)
+    });
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut bytes, 10);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames)?;
+    }
+    Ok(Some(bytes))
+}
+
+fn resize(image: &DynamicImage, width: u32, height: u32, crop: bool) -> DynamicImage {
+    if crop {
+        image.resize_to_fill(width, height, FilterType::CatmullRom)
+    } else {
+        let (exact_width, exact_height) = scaled_dimensions(image.width(), image.height(), width, height);
+        image.thumbnail_exact(exact_width, exact_height)
+    }
+}
+
+/// Copied from image::dynimage::resize_dimensions
+fn scaled_dimensions(original_width: u32, original_height: u32, width: u32, height: u32) -> (u32, u32) {
+    let ratio = u64::from(original_width) * u64::from(height);
+    let nratio = u64::from(width) * u64::from(original_height);
+
+    let use_width = nratio <= ratio;
+    let intermediate = if use_width {
+        u64::from(original_height) * u64::from(width) / u64::from(original_width)
+    } else {
+        u64::from(original_width) * u64::from(height) / u64::from(original_height)
+    };
+    if use_width {
+        if intermediate <= u64::from(u32::MAX) {
+            (width, intermediate as u32)
+        } else {
+            ((u64::from(width) * u64::from(u32::MAX) / intermediate) as u32, u32::MAX)
+        }
+    } else if intermediate <= u64::from(u32::MAX) {
+        (intermediate as u32, height)
+    } else {
+        (
+            u32::MAX,
+            (u64::from(height) * u64::from(u32::MAX) / intermediate) as u32,
+        )
+    }
+}
+
+/// Returns the path, content type and content disposition of the thumbnail closest to the
+/// requested size, or of the original file when it is smaller than that or can't be thumbnailed.
+async fn thumbnail_source(
+    server_name: &ServerName,
+    media_id: &str,
+    width: u32,
+    height: u32,
+    method: Option<&Method>,
+    animated: bool,
+) -> AppResult<(PathBuf, Option<String>, Option<String>)> {
+    let Some(metadata) = crate::media::get_metadata(server_name, media_id)? else {
+        // Only a thumbnail of remote media may be cached
+        if let Some((width, height, crop)) = thumbnail_properties(width, height, method) {
+            if let Some(thumbnail) = get_thumbnail(server_name, media_id, width, height, crop, animated)? {
+                return Ok((
+                    thumbnail.path(),
+                    Some(thumbnail.content_type),
+                    thumbnail.content_disposition,
+                ));
+            }
+        }
+        return Err(MatrixError::not_found("Media not found.").into());
+    };
+
+    let thumbnail = if let Some((width, height, crop)) = thumbnail_properties(width, height, method) {
+        let server_name = server_name.to_owned();
+        let media_id = media_id.to_owned();
+        tokio::task::spawn_blocking(move || {
+            get_or_create_thumbnail(&server_name, &media_id, width, height, crop, animated)
+        })
+        .await
+        .map_err(|e| AppError::internal(e.to_string()))?
+        .unwrap_or_else(|e| {
+            tracing::error!(error = ?e, "create thumbnail failed");
+            None
+        })
+    } else {
+        None
+    };
+
+    Ok(match thumbnail {
+        Some(thumbnail) => (
+            thumbnail.path(),
+            Some(thumbnail.content_type),
+            metadata.content_disposition,
+        ),
+        // Send the original file
+        None => (
+            crate::media_path(server_name, media_id),
+            metadata.content_type,
+            metadata.content_disposition,
+        ),
+    })
+}
+
+/// Sends the thumbnail closest to the requested size, or the original file when it is smaller
+/// than that or can't be thumbnailed.
+pub async fn send_thumbnail(
+    server_name: &ServerName,
+    media_id: &str,
+    width: u32,
+    height: u32,
+    method: Option<&Method>,
+    animated: bool,
+    req: &Request,
+    res: &mut Response,
+) -> AppResult<()> {
+    let (path, content_type, content_disposition) =
+        thumbnail_source(server_name, media_id, width, height, method, animated).await?;
+    res.add_header("Cross-Origin-Resource-Policy", "cross-origin", true)?;
+    let mut file = NamedFile::builder(&path)
+        .content_type(
+            content_type
+                .as_deref()
+                .and_then(|c| Mime::from_str(c).ok())
+                .unwrap_or(mime::APPLICATION_OCTET_STREAM),
+        )
+        .build()
+        .await?;
+    if let Some(Ok(content_disposition)) = content_disposition.as_deref().map(HeaderValue::from_str) {
+        file.set_content_disposition(content_disposition);
+    }
+    file.send(req.headers(), res).await;
+    Ok(())
+}
+
+/// Loads the thumbnail closest to the requested size like [`send_thumbnail`], for federation responses.
+pub async fn thumbnail_content(
+    server_name: &ServerName,
+    media_id: &str,
+    width: u32,
+    height: u32,
+    method: Option<&Method>,
+    animated: bool,
+) -> AppResult<Content> {
+    let (path, content_type, content_disposition) =
+        thumbnail_source(server_name, media_id, width, height, method, animated).await?;
+    Ok(Content {
+        file: tokio::fs::read(path).await?,
+        content_type,
+        content_disposition: content_disposition.and_then(|c| c.parse().ok()),
+    })
+}
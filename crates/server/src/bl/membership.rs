@@ -9,6 +9,9 @@ use crate::core::client::membership::{JoinRoomResBody, ThirdPartySigned};
 use crate::core::events::room::join_rules::{AllowRule, JoinRule, RoomJoinRulesEventContent};
 use crate::core::events::room::member::{MembershipState, RoomMemberEventContent};
 use crate::core::events::{StateEventType, TimelineEventType};
+use crate::core::federation::knock::{
+    send_knock_request, MakeKnockReqArgs, MakeKnockResBody, SendKnockReqArgs, SendKnockReqBody, SendKnockResBody,
+};
 use crate::core::federation::membership::{
     make_leave_request, InviteUserResBodyV2, MakeJoinReqArgs, MakeLeaveResBody, SendJoinArgs, SendJoinResBodyV2,
     SendLeaveReqBodyV2,
@@ -18,7 +21,7 @@ use crate::core::serde::{
     to_canonical_value, to_raw_json_value, CanonicalJsonObject, CanonicalJsonValue, RawJsonValue,
 };
 use crate::core::state::event_auth;
-use crate::core::{federation, OwnedServerName, ServerName, UnixMillis};
+use crate::core::{federation, OwnedServerName, RoomVersion, ServerName, UnixMillis};
 use crate::event::{gen_event_id_canonical_json, NewDbEvent, PduBuilder, PduEvent};
 use crate::membership::federation::membership::{
     send_leave_request_v2, InviteUserReqArgs, InviteUserReqBodyV2, MakeJoinResBody, RoomStateV1, RoomStateV2,
@@ -27,8 +30,49 @@ use crate::membership::federation::membership::{
 use crate::membership::state::DeltaInfo;
 use crate::membership::state::FrameInfo;
 use crate::room::state::{self, CompressedState};
+use crate::room::NewDbRoom;
 use crate::{db, diesel_exists, exts::*, schema::*, AppError, AppResult, GetUrlOrigin, MatrixError, SigningKeys};
 
+/// Returns the rooms a user can be in to join `room_id` without an invite, or `None` if the
+/// join rule of the room is not `restricted` or `knock_restricted`.
+pub fn restriction_rooms(room_id: &RoomId) -> AppResult<Option<Vec<OwnedRoomId>>> {
+    let join_rules_event = crate::room::state::get_state(room_id, &StateEventType::RoomJoinRules, "", None)?;
+    let Some(join_rules_event) = join_rules_event else {
+        return Ok(None);
+    };
+    let join_rules_event_content: RoomJoinRulesEventContent = serde_json::from_str(join_rules_event.content.get())
+        .map_err(|e| {
+            warn!("Invalid join rules event: {}", e);
+            AppError::public("Invalid join rules event in database.")
+        })?;
+    let (JoinRule::Restricted(restricted) | JoinRule::KnockRestricted(restricted)) = join_rules_event_content.join_rule
+    else {
+        return Ok(None);
+    };
+    Ok(Some(
+        restricted
+            .allow
+            .into_iter()
+            .filter_map(|a| match a {
+                AllowRule::RoomMembership(r) => Some(r.room_id),
+                _ => None,
+            })
+            .collect(),
+    ))
+}
+
+/// Picks a local user joined to `room_id` who is able to invite `user_id`, to authorise a restricted join.
+pub fn select_authorising_user(room_id: &RoomId, user_id: &UserId) -> AppResult<Option<OwnedUserId>> {
+    for joined_user in crate::room::get_joined_users(room_id, None)? {
+        if joined_user.server_name() == crate::server_name()
+            && state::user_can_invite(room_id, &joined_user, user_id).unwrap_or(false)
+        {
+            return Ok(Some(joined_user));
+        }
+    }
+    Ok(None)
+}
+
 pub async fn send_join_v1(server_name: &ServerName, room_id: &RoomId, pdu: &RawJsonValue) -> AppResult<RoomStateV1> {
     if !crate::room::room_exists(room_id)? {
         return Err(MatrixError::not_found("Room is unknown to this server.").into());
@@ -36,28 +80,6 @@ pub async fn send_join_v1(server_name: &ServerName, room_id: &RoomId, pdu: &RawJ
 
     crate::event::handler::acl_check(server_name, room_id)?;
 
-    // TODO: Palpo does not implement restricted join rules yet, we always reject
-    let join_rules_event = crate::room::state::get_state(room_id, &StateEventType::RoomJoinRules, "", None)?;
-
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-        .as_ref()
-        .map(|join_rules_event| {
-            serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                warn!("Invalid join rules event: {}", e);
-                AppError::public("Invalid join rules event in db.")
-            })
-        })
-        .transpose()?;
-
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. } | JoinRule::KnockRestricted { .. }
-        ) {
-            return Err(MatrixError::unable_to_authorize_join("Palpo does not support restricted rooms yet.").into());
-        }
-    }
-
     // We need to return the state prior to joining, let's keep a reference to that here
     let shortstate_hash =
         crate::room::state::get_room_frame_id(room_id, None)?.ok_or(MatrixError::not_found("Pdu state not found."))?;
@@ -67,7 +89,7 @@ pub async fn send_join_v1(server_name: &ServerName, room_id: &RoomId, pdu: &RawJ
 
     // We do not add the event_id field to the pdu here because of signature and hashes checks
     let room_version_id = crate::room::state::get_room_version(room_id)?;
-    let (event_id, value) = match gen_event_id_canonical_json(pdu, &room_version_id) {
+    let (event_id, mut value) = match gen_event_id_canonical_json(pdu, &room_version_id) {
         Ok(t) => t,
         Err(_) => {
             // Event could not be converted to canonical json
@@ -75,15 +97,62 @@ pub async fn send_join_v1(server_name: &ServerName, room_id: &RoomId, pdu: &RawJ
         }
     };
 
-    let origin: OwnedServerName = serde_json::from_value(
-        serde_json::to_value(
-            value
-                .get("origin")
-                .ok_or(MatrixError::invalid_param("Event needs an origin field."))?,
-        )
-        .expect("CanonicalJson is valid json value"),
+    let content: RoomMemberEventContent = serde_json::from_value(
+        value
+            .get("content")
+            .ok_or(MatrixError::invalid_param("Event missing content property."))?
+            .clone()
+            .into(),
     )
-    .map_err(|_| MatrixError::invalid_param("Origin field is invalid."))?;
+    .map_err(|_| MatrixError::invalid_param("Event content is empty or invalid."))?;
+    if content.membership != MembershipState::Join {
+        return Err(
+            MatrixError::invalid_param("Not allowed to send a non-join membership event to join endpoint.").into(),
+        );
+    }
+    let sender: OwnedUserId = serde_json::from_value(
+        value
+            .get("sender")
+            .ok_or(MatrixError::invalid_param("Event missing sender property."))?
+            .clone()
+            .into(),
+    )
+    .map_err(|_| MatrixError::bad_json("User ID in sender is invalid."))?;
+    if sender.server_name() != server_name {
+        return Err(MatrixError::forbidden("Not allowed to join on behalf of another server.").into());
+    }
+
+    // Restricted joins need the signature of the server the authorising user belongs to
+    let mut signed_event = None;
+    if let Some(authorising_user) = &content.join_authorized_via_users_server {
+        if authorising_user.server_name() == crate::server_name() {
+            if !crate::room::is_joined(authorising_user, room_id)?
+                || !state::user_can_invite(room_id, authorising_user, &sender)?
+            {
+                return Err(MatrixError::invalid_param("Authorising user can not invite to the room.").into());
+            }
+            // The joining user has to be in one of the allowed rooms, make_join is not necessarily called first
+            if let Some(restriction_rooms) = restriction_rooms(room_id)?.filter(|_| {
+                !crate::room::is_joined(&sender, room_id).unwrap_or(false)
+                    && !crate::room::is_invited(&sender, room_id).unwrap_or(false)
+            }) {
+                if !restriction_rooms
+                    .iter()
+                    .any(|room_id| crate::room::is_joined(&sender, room_id).unwrap_or(false))
+                {
+                    return Err(MatrixError::forbidden("Joining user is not known to be in any required room.").into());
+                }
+            }
+            crate::core::signatures::hash_and_sign_event(
+                crate::server_name().as_str(),
+                crate::keypair(),
+                &mut value,
+                &room_version_id,
+            )
+            .map_err(|_| MatrixError::invalid_param("Failed to sign event."))?;
+            signed_event = Some(PduEvent::convert_to_outgoing_federation_event(value.clone()));
+        }
+    }
 
     // let mutex = Arc::clone(
     //     crate::ROOMID_MUTEX_FEDERATION
@@ -93,11 +162,7 @@ pub async fn send_join_v1(server_name: &ServerName, room_id: &RoomId, pdu: &RawJ
     //         .or_default(),
     // );
     // let mutex_lock = mutex.lock().await;
-    // crate::event::handler::handle_incoming_pdu(&origin, &event_id, room_id, value, true, &pub_key_map)
-    //     .await?
-    //     .ok_or(MatrixError::invalid_param(
-    //         "Could not accept incoming PDU as timeline event.",
-    //     ))?;
+    crate::event::handler::handle_incoming_pdu(server_name, &event_id, room_id, value, true).await?;
     // drop(mutex_lock);
 
     let state_ids = crate::room::state::get_full_state_ids(shortstate_hash)?;
@@ -124,7 +189,7 @@ pub async fn send_join_v1(server_name: &ServerName, room_id: &RoomId, pdu: &RawJ
             .filter_map(|(_, id)| crate::room::timeline::get_pdu_json(id).ok().flatten())
             .map(PduEvent::convert_to_outgoing_federation_event)
             .collect(),
-        event: None, // TODO: handle restricted joins
+        event: signed_event,
     })
 }
 pub async fn send_join_v2(server_name: &ServerName, room_id: &RoomId, pdu: &RawJsonValue) -> AppResult<RoomStateV2> {
@@ -159,7 +224,17 @@ pub async fn join_room(
     if !local_join {
         info!("Joining {room_id} over federation.");
 
-        let (make_join_response, remote_server) = make_join_request(user_id, room_id, servers).await?;
+        let servers = servers
+            .iter()
+            .filter(|server| crate::federation::is_server_allowed(server))
+            .cloned()
+            .collect::<Vec<_>>();
+        if servers.is_empty() {
+            return Err(
+                MatrixError::forbidden("This server is not allowed to federate with the servers of this room.").into(),
+            );
+        }
+        let (make_join_response, remote_server) = make_join_request(user_id, room_id, &servers).await?;
 
         info!("make_join finished");
 
@@ -234,7 +309,7 @@ pub async fn join_room(
         };
         info!("Asking {remote_server} for send_join");
         let send_join_request = crate::core::federation::membership::send_join_request(
-            &room_id.server_name().map_err(AppError::public)?.origin().await,
+            &remote_server.origin().await,
             SendJoinArgs {
                 room_id: room_id.to_owned(),
                 event_id: event_id.to_owned(),
@@ -419,50 +494,14 @@ pub async fn join_room(
         crate::room::state::set_room_state(room_id, state_hash_after_join)?;
     } else {
         info!("We can join locally");
-        let join_rules_event = crate::room::state::get_state(room_id, &StateEventType::RoomJoinRules, "", None)?;
-        let power_levels_event = crate::room::state::get_state(room_id, &StateEventType::RoomPowerLevels, "", None)?;
-
-        let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-            .as_ref()
-            .map(|join_rules_event| {
-                serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                    warn!("Invalid join rules event: {}", e);
-                    AppError::public("Invalid join rules event in database.")
-                })
-            })
-            .transpose()?;
-
-        let restriction_rooms = match join_rules_event_content {
-            Some(RoomJoinRulesEventContent {
-                join_rule: JoinRule::Restricted(restricted),
-            })
-            | Some(RoomJoinRulesEventContent {
-                join_rule: JoinRule::KnockRestricted(restricted),
-            }) => restricted
-                .allow
-                .into_iter()
-                .filter_map(|a| match a {
-                    AllowRule::RoomMembership(r) => Some(r.room_id),
-                    _ => None,
-                })
-                .collect(),
-            _ => Vec::new(),
-        };
-
-        let authorized_user = if restriction_rooms
-            .iter()
-            .any(|restriction_room_id| crate::room::is_joined(user_id, restriction_room_id).unwrap_or(false))
+        let restriction_rooms = restriction_rooms(room_id)?.unwrap_or_default();
+        let authorized_user = if !crate::room::is_joined(user_id, room_id)?
+            && !crate::room::is_invited(user_id, room_id)?
+            && restriction_rooms
+                .iter()
+                .any(|restriction_room_id| crate::room::is_joined(user_id, restriction_room_id).unwrap_or(false))
         {
-            let mut auth_user = None;
-            for joined_user in crate::room::get_joined_users(room_id, None)? {
-                if joined_user.server_name() == crate::server_name()
-                    && state::user_can_invite(room_id, &joined_user, user_id).unwrap_or(false)
-                {
-                    auth_user = Some(joined_user);
-                    break;
-                }
-            }
-            auth_user
+            select_authorising_user(room_id, user_id)?
         } else {
             None
         };
@@ -562,7 +601,7 @@ pub async fn join_room(
             let join_event = join_event_stub;
 
             let send_join_request = crate::core::federation::membership::send_join_request(
-                &room_id.server_name().map_err(AppError::public)?.origin().await,
+                &remote_server.origin().await,
                 SendJoinArgs {
                     room_id: room_id.to_owned(),
                     event_id: event_id.to_owned(),
@@ -916,7 +955,7 @@ pub async fn leave_room(user_id: &UserId, room_id: &RoomId, reason: Option<Strin
 async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> AppResult<()> {
     let mut make_leave_response_and_server = Err(AppError::public("No server available to assist in leaving."));
     let invite_state = crate::room::state::get_invite_state(user_id, room_id)?
-        .ok_or(MatrixError::bad_state("User is not invited."))?;
+        .ok_or(MatrixError::bad_state("User is not invited or knocking."))?;
 
     let servers: HashSet<_> = invite_state
         .iter()
@@ -1010,6 +1049,184 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> AppResult<()>
     Ok(())
 }
 
+pub async fn knock_room(
+    user_id: &UserId,
+    room_id: &RoomId,
+    reason: Option<String>,
+    servers: &[OwnedServerName],
+) -> AppResult<()> {
+    let local_knock = crate::room::is_server_in_room(crate::server_name(), room_id)?
+        || servers.is_empty()
+        || (servers.len() == 1 && servers[0] == crate::server_name());
+    if !local_knock {
+        info!("Knocking on {room_id} over federation.");
+        return remote_knock_room(user_id, room_id, reason, servers).await;
+    }
+
+    let room_version_id = crate::room::state::get_room_version(room_id)?;
+    if !RoomVersion::new(&room_version_id).map_or(false, |v| v.allow_knocking) {
+        return Err(MatrixError::forbidden("This room version does not support knocking.").into());
+    }
+
+    let mut event = RoomMemberEventContent::new(MembershipState::Knock);
+    event.display_name = crate::user::display_name(user_id)?;
+    event.avatar_url = crate::user::avatar_url(user_id)?;
+    event.blurhash = crate::user::blurhash(user_id)?;
+    event.reason = reason;
+    crate::room::timeline::build_and_append_pdu(PduBuilder::state(user_id.to_string(), &event), user_id, room_id)?;
+    Ok(())
+}
+
+async fn remote_knock_room(
+    user_id: &UserId,
+    room_id: &RoomId,
+    reason: Option<String>,
+    servers: &[OwnedServerName],
+) -> AppResult<()> {
+    let (make_knock_response, remote_server) = make_knock_request(user_id, room_id, servers).await?;
+
+    let room_version_id = make_knock_response.room_version;
+    if !crate::supported_room_versions().contains(&room_version_id)
+        || !RoomVersion::new(&room_version_id).map_or(false, |v| v.allow_knocking)
+    {
+        return Err(AppError::public("Room version is not supported"));
+    }
+
+    let mut knock_event_stub: CanonicalJsonObject = serde_json::from_str(make_knock_response.event.get())
+        .map_err(|_| AppError::public("Invalid make_knock event json received from server."))?;
+
+    knock_event_stub.insert(
+        "origin".to_owned(),
+        CanonicalJsonValue::String(crate::server_name().as_str().to_owned()),
+    );
+    knock_event_stub.insert(
+        "origin_server_ts".to_owned(),
+        CanonicalJsonValue::Integer(UnixMillis::now().get() as i64),
+    );
+    knock_event_stub.insert(
+        "content".to_owned(),
+        to_canonical_value(RoomMemberEventContent {
+            membership: MembershipState::Knock,
+            display_name: crate::user::display_name(user_id)?,
+            avatar_url: crate::user::avatar_url(user_id)?,
+            is_direct: None,
+            third_party_invite: None,
+            blurhash: crate::user::blurhash(user_id)?,
+            reason,
+            join_authorized_via_users_server: None,
+        })
+        .expect("event is valid, we just created it"),
+    );
+    // Knocking needs room version 7 or above, none of them have the event id in the pdu
+    knock_event_stub.remove("event_id");
+
+    crate::core::signatures::hash_and_sign_event(
+        crate::server_name().as_str(),
+        crate::keypair(),
+        &mut knock_event_stub,
+        &room_version_id,
+    )
+    .expect("event is valid, we just created it");
+
+    // Generate event id
+    let event_id = EventId::parse(format!(
+        "${}",
+        crate::core::signatures::reference_hash(&knock_event_stub, &room_version_id)
+            .expect("palpo can calculate reference hashes")
+    ))
+    .expect("palpo's reference hashes are valid event ids");
+
+    // Add event_id back
+    knock_event_stub.insert(
+        "event_id".to_owned(),
+        CanonicalJsonValue::String(event_id.as_str().to_owned()),
+    );
+    let knock_event = knock_event_stub;
+
+    info!("Asking {remote_server} for send_knock");
+    let request = send_knock_request(
+        &remote_server.origin().await,
+        SendKnockReqArgs {
+            room_id: room_id.to_owned(),
+            event_id: event_id.clone(),
+        },
+        SendKnockReqBody(PduEvent::convert_to_outgoing_federation_event(knock_event.clone())),
+    )?
+    .into_inner();
+    let send_knock_response = crate::sending::send_federation_request(&remote_server, request)
+        .await?
+        .json::<SendKnockResBody>()
+        .await?;
+
+    diesel::insert_into(rooms::table)
+        .values(NewDbRoom {
+            id: room_id.to_owned(),
+            version: room_version_id.to_string(),
+            is_public: false,
+            min_depth: 0,
+            has_auth_chain_index: false,
+            created_by: user_id.to_owned(),
+            created_at: UnixMillis::now(),
+        })
+        .on_conflict_do_nothing()
+        .execute(&mut db::connect()?)?;
+
+    let knock_pdu =
+        PduEvent::from_id_val(&event_id, knock_event).map_err(|_| AppError::public("Invalid knock event PDU."))?;
+    let mut knock_state = send_knock_response.knock_room_state;
+    knock_state.push(knock_pdu.to_stripped_state_event());
+
+    crate::room::update_membership(
+        &event_id,
+        crate::next_sn()?,
+        room_id,
+        user_id,
+        MembershipState::Knock,
+        user_id,
+        Some(knock_state),
+    )?;
+
+    Ok(())
+}
+
+async fn make_knock_request(
+    user_id: &UserId,
+    room_id: &RoomId,
+    servers: &[OwnedServerName],
+) -> AppResult<(MakeKnockResBody, OwnedServerName)> {
+    let mut make_knock_res_body_and_server = Err(AppError::public("No server available to assist in knocking."));
+
+    for remote_server in servers {
+        if remote_server == crate::server_name() {
+            continue;
+        }
+        info!("Asking {remote_server} for make_knock");
+
+        let request = crate::core::federation::knock::make_knock_request(
+            &remote_server.origin().await,
+            MakeKnockReqArgs {
+                room_id: room_id.to_owned(),
+                user_id: user_id.to_owned(),
+                ver: crate::supported_room_versions(),
+            },
+        )?
+        .into_inner();
+        match crate::sending::send_federation_request(remote_server, request).await {
+            Ok(response) => {
+                let res_body = response.json::<MakeKnockResBody>().await;
+                make_knock_res_body_and_server = res_body.map(|r| (r, remote_server.clone())).map_err(Into::into);
+            }
+            Err(e) => make_knock_res_body_and_server = Err(e),
+        }
+
+        if make_knock_res_body_and_server.is_ok() {
+            break;
+        }
+    }
+
+    make_knock_res_body_and_server
+}
+
 /// Makes a user forget a room.
 #[tracing::instrument]
 pub fn forget_room(user_id: &UserId, room_id: &RoomId) -> AppResult<()> {
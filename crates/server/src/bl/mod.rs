@@ -6,16 +6,22 @@ pub mod directory;
 pub mod event;
 pub mod exts;
 pub mod federation;
+pub mod mailer;
 pub mod media;
 pub mod membership;
+pub mod oidc;
 pub mod room;
 pub mod sending;
 pub mod server_key;
 pub mod state;
+pub mod terms;
+pub mod threepid;
 pub mod transaction_id;
 pub mod uiaa;
 pub mod user;
+pub mod watcher;
 pub use exts::*;
+pub use watcher::watch;
 mod cjson;
 pub use cjson::Cjson;
 pub mod sync;
@@ -28,7 +34,6 @@ use std::future::Future;
 use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::path::PathBuf;
-use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, LazyLock, Mutex, OnceLock, RwLock};
@@ -36,7 +41,7 @@ use std::time::{Duration, Instant, SystemTime};
 use std::{future, iter};
 
 use diesel::prelude::*;
-use futures_util::{stream::FuturesUnordered, FutureExt, StreamExt};
+use futures_util::FutureExt;
 use hickory_resolver::TokioAsyncResolver;
 use hyper_util::client::legacy::connect::dns::{GaiResolver, Name as HyperName};
 use reqwest::dns::{Addrs, Name, Resolve, Resolving};
@@ -295,127 +300,6 @@ pub fn federation_client() -> reqwest::Client {
         .clone()
 }
 
-pub async fn watch(user_id: &UserId, device_id: &DeviceId) -> AppResult<()> {
-    let inbox_id = device_inboxes::table
-        .filter(device_inboxes::user_id.eq(user_id))
-        .filter(device_inboxes::device_id.eq(device_id))
-        .order_by(device_inboxes::id.desc())
-        .select(device_inboxes::id)
-        .first::<i64>(&mut *db::connect()?)
-        .unwrap_or_default();
-    let key_change_id = e2e_key_changes::table
-        .filter(e2e_key_changes::user_id.eq(user_id))
-        .order_by(e2e_key_changes::id.desc())
-        .select(e2e_key_changes::id)
-        .first::<i64>(&mut *db::connect()?)
-        .unwrap_or_default();
-    let room_user_id = room_users::table
-        .filter(room_users::user_id.eq(user_id))
-        .order_by(room_users::id.desc())
-        .select(room_users::id)
-        .first::<i64>(&mut *db::connect()?)
-        .unwrap_or_default();
-    let room_ids = crate::user::joined_rooms(user_id, 0)?;
-    // let frame_id = room_state_frames::table
-    //     .filter(room_state_frames::room_id.eq_any(&room_ids))
-    //     .order_by(room_state_frames::id.desc())
-    //     .select(room_state_frames::id)
-    //     .first::<i64>(&mut *db::connect()?)
-    //     .unwrap_or_default();
-    let frame_id = room_state_deltas::table
-        .filter(room_state_deltas::room_id.eq_any(&room_ids))
-        .order_by(room_state_deltas::frame_id.desc())
-        .select(room_state_deltas::frame_id)
-        .first::<i64>(&mut *db::connect()?)
-        .unwrap_or_default();
-
-    let push_rule_sn = user_datas::table
-        .filter(user_datas::user_id.eq(user_id))
-        .filter(user_datas::data_type.eq("m.push_rules"))
-        .order_by(user_datas::occur_sn.desc())
-        .select(user_datas::occur_sn)
-        .first::<i64>(&mut *db::connect()?)
-        .unwrap_or_default();
-
-    let mut futures: FuturesUnordered<Pin<Box<dyn Future<Output = AppResult<()>> + Send>>> = FuturesUnordered::new();
-
-    for room_id in room_ids.clone() {
-        futures.push(Box::into_pin(Box::new(async move {
-            crate::room::typing::wait_for_update(&room_id).await
-        })));
-    }
-    futures.push(Box::into_pin(Box::new(async move {
-        loop {
-            if inbox_id
-                < device_inboxes::table
-                    .filter(device_inboxes::user_id.eq(user_id))
-                    .filter(device_inboxes::device_id.eq(device_id))
-                    .order_by(device_inboxes::id.desc())
-                    .select(device_inboxes::id)
-                    .first::<i64>(&mut *db::connect()?)
-                    .unwrap_or_default()
-            {
-                return Ok(());
-            }
-            if key_change_id
-                < e2e_key_changes::table
-                    .filter(e2e_key_changes::user_id.eq(user_id))
-                    .order_by(e2e_key_changes::id.desc())
-                    .select(e2e_key_changes::id)
-                    .first::<i64>(&mut *db::connect()?)
-                    .unwrap_or_default()
-            {
-                return Ok(());
-            }
-            if room_user_id
-                < room_users::table
-                    .filter(room_users::user_id.eq(user_id))
-                    .order_by(room_users::id.desc())
-                    .select(room_users::id)
-                    .first::<i64>(&mut *db::connect()?)
-                    .unwrap_or_default()
-            {
-                return Ok(());
-            }
-            // if frame_id
-            //     < room_state_frames::table
-            //         .filter(room_state_frames::room_id.eq_any(&room_ids))
-            //         .order_by(room_state_frames::id.desc())
-            //         .select(room_state_frames::id)
-            //         .first::<i64>(&mut *db::connect()?)
-            //         .unwrap_or_default()
-            // {
-            //     return Ok(());
-            // }
-            if frame_id
-                < room_state_deltas::table
-                    .filter(room_state_deltas::room_id.eq_any(&room_ids))
-                    .order_by(room_state_deltas::frame_id.desc())
-                    .select(room_state_deltas::frame_id)
-                    .first::<i64>(&mut *db::connect()?)
-                    .unwrap_or_default()
-            {
-                return Ok(());
-            }
-            if push_rule_sn
-                < user_datas::table
-                    .filter(user_datas::user_id.eq(user_id))
-                    .filter(user_datas::data_type.eq("m.push_rules"))
-                    .order_by(user_datas::occur_sn.desc())
-                    .select(user_datas::occur_sn)
-                    .first::<i64>(&mut *db::connect()?)
-                    .unwrap_or_default()
-            {
-                return Ok(());
-            }
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
-    })));
-    // Wait until one of them finds something
-    futures.next().await;
-    Ok(())
-}
-
 pub fn server_name() -> &'static ServerName {
     config().server_name.as_ref()
 }
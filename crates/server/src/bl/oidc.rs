@@ -0,0 +1,309 @@
+//! Single sign-on through OpenID Connect providers
+//!
+//! A login starts at `/login/sso/redirect`, which sends the user to the provider with a random
+//! `state` and a PKCE challenge. The provider sends the user back to the callback route with an
+//! authorization code, which is exchanged for the user's claims. The claims are mapped to a local
+//! user through `user_external_ids`, then the user is sent back to the client with a short lived
+//! login token for the `m.login.token` flow. Pending logins and the providers' metadata are kept in
+//! the database, so a login can finish on another instance or after a restart.
+
+use std::time::Duration;
+
+use base64::engine::{general_purpose, Engine};
+use diesel::prelude::*;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::config::OidcProviderConfig;
+use crate::core::identifiers::*;
+use crate::core::serde::JsonValue;
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, utils, AppError, AppResult, MatrixError};
+
+/// How long a user may take to log in at the provider.
+const PENDING_LOGIN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+/// How long the client may take to exchange the login token.
+const LOGIN_TOKEN_LIFETIME: Duration = Duration::from_secs(2 * 60);
+/// How long the provider's metadata is used before it is fetched again.
+const DISCOVERY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const STATE_LENGTH: usize = 32;
+const CODE_VERIFIER_LENGTH: usize = 64;
+
+/// The parts of the provider's metadata used for logging in.
+#[derive(Deserialize, Clone, Debug)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+#[derive(Insertable, Queryable, Debug, Clone)]
+#[diesel(table_name = oidc_discoveries)]
+struct DbDiscovery {
+    idp_id: String,
+    /// The issuer the metadata was fetched from, it is fetched again when the config changes.
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    fetched_at: i64,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = oidc_pending_logins)]
+struct NewDbPendingLogin {
+    state: String,
+    idp_id: String,
+    code_verifier: String,
+    redirect_url: String,
+    expired_at: i64,
+    created_at: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+}
+
+pub fn get_provider(idp_id: &str) -> AppResult<&'static OidcProviderConfig> {
+    crate::config()
+        .oidc
+        .providers
+        .iter()
+        .find(|provider| provider.id == idp_id)
+        .ok_or_else(|| MatrixError::not_found("Unknown identity provider.").into())
+}
+
+/// The URL the provider sends users back to, it has to be registered at the provider.
+fn callback_url(idp_id: &str) -> String {
+    format!(
+        "{}/_matrix/client/v3/login/sso/callback/{idp_id}",
+        crate::well_known_client().trim_end_matches('/')
+    )
+}
+
+async fn discover(provider: &OidcProviderConfig) -> AppResult<DbDiscovery> {
+    let now = UnixMillis::now().get() as i64;
+    let discovery = oidc_discoveries::table
+        .find(&provider.id)
+        .filter(oidc_discoveries::issuer.eq(&provider.issuer))
+        .filter(oidc_discoveries::fetched_at.gt(now - DISCOVERY_LIFETIME.as_millis() as i64))
+        .first::<DbDiscovery>(&mut *db::connect()?)
+        .optional()?;
+    if let Some(discovery) = discovery {
+        return Ok(discovery);
+    }
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        provider.issuer.trim_end_matches('/')
+    );
+    let discovery = crate::default_client()
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Discovery>()
+        .await?;
+    let discovery = DbDiscovery {
+        idp_id: provider.id.clone(),
+        issuer: provider.issuer.clone(),
+        authorization_endpoint: discovery.authorization_endpoint,
+        token_endpoint: discovery.token_endpoint,
+        userinfo_endpoint: discovery.userinfo_endpoint,
+        fetched_at: now,
+    };
+    diesel::insert_into(oidc_discoveries::table)
+        .values(&discovery)
+        .on_conflict(oidc_discoveries::idp_id)
+        .do_update()
+        .set((
+            oidc_discoveries::issuer.eq(&discovery.issuer),
+            oidc_discoveries::authorization_endpoint.eq(&discovery.authorization_endpoint),
+            oidc_discoveries::token_endpoint.eq(&discovery.token_endpoint),
+            oidc_discoveries::userinfo_endpoint.eq(&discovery.userinfo_endpoint),
+            oidc_discoveries::fetched_at.eq(now),
+        ))
+        .execute(&mut *db::connect()?)?;
+    Ok(discovery)
+}
+
+/// Whether users may be sent back to the client at `url` without confirming the destination.
+pub fn is_redirect_allowed(url: &Url) -> bool {
+    crate::config()
+        .oidc
+        .client_redirect_allowlist
+        .iter()
+        .filter_map(|origin| Url::parse(origin).ok())
+        .any(|origin| {
+            origin.scheme() == url.scheme()
+                && origin.host_str() == url.host_str()
+                && origin.port_or_known_default() == url.port_or_known_default()
+        })
+}
+
+/// Returns the provider's authorization URL the user has to be redirected to, `redirect_url` is
+/// where the user is sent once logged in.
+pub async fn authorization_url(idp_id: &str, redirect_url: &str) -> AppResult<Url> {
+    let provider = get_provider(idp_id)?;
+    let parsed_redirect_url =
+        Url::parse(redirect_url).map_err(|_| MatrixError::invalid_param("Invalid redirectUrl."))?;
+    if matches!(parsed_redirect_url.scheme(), "javascript" | "data" | "vbscript") {
+        return Err(MatrixError::invalid_param("Invalid redirectUrl.").into());
+    }
+    let discovery = discover(provider).await?;
+
+    let state = utils::random_string(STATE_LENGTH);
+    let code_verifier = utils::random_string(CODE_VERIFIER_LENGTH);
+    let code_challenge =
+        general_purpose::URL_SAFE_NO_PAD.encode(utils::hash::hash_data_sha2_256(code_verifier.as_bytes())?.as_bytes());
+
+    let mut url = Url::parse(&discovery.authorization_endpoint)?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", &callback_url(idp_id))
+        .append_pair("scope", &provider.scopes.join(" "))
+        .append_pair("state", &state)
+        .append_pair("code_challenge", &code_challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    let now = UnixMillis::now().get() as i64;
+    let conn = &mut *db::connect()?;
+    diesel::delete(oidc_pending_logins::table.filter(oidc_pending_logins::expired_at.lt(now))).execute(conn)?;
+    diesel::insert_into(oidc_pending_logins::table)
+        .values(NewDbPendingLogin {
+            state,
+            idp_id: idp_id.to_owned(),
+            code_verifier,
+            redirect_url: redirect_url.to_owned(),
+            expired_at: now + PENDING_LOGIN_LIFETIME.as_millis() as i64,
+            created_at: now,
+        })
+        .execute(conn)?;
+    Ok(url)
+}
+
+/// Finishes a login the provider redirected back to the callback route, returns the client's
+/// `redirectUrl` with a login token for the user.
+///
+/// Check the URL with `is_redirect_allowed` before sending the user to it.
+pub async fn complete_login(idp_id: &str, code: &str, state: &str) -> AppResult<Url> {
+    let provider = get_provider(idp_id)?;
+    let (code_verifier, redirect_url) = diesel::delete(
+        oidc_pending_logins::table
+            .find(state)
+            .filter(oidc_pending_logins::idp_id.eq(idp_id))
+            .filter(oidc_pending_logins::expired_at.ge(UnixMillis::now().get() as i64)),
+    )
+    .returning((oidc_pending_logins::code_verifier, oidc_pending_logins::redirect_url))
+    .get_result::<(String, String)>(&mut *db::connect()?)
+    .optional()?
+    .ok_or_else(|| MatrixError::forbidden("Unknown or expired SSO session."))?;
+    let discovery = discover(provider).await?;
+
+    let mut form = vec![
+        ("grant_type", "authorization_code".to_owned()),
+        ("code", code.to_owned()),
+        ("redirect_uri", callback_url(idp_id)),
+        ("client_id", provider.client_id.clone()),
+        ("code_verifier", code_verifier),
+    ];
+    if let Some(client_secret) = &provider.client_secret {
+        form.push(("client_secret", client_secret.clone()));
+    }
+    let response = crate::default_client()
+        .post(&discovery.token_endpoint)
+        .form(&form)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        warn!(
+            "Exchanging the authorization code at {idp_id} failed: {}",
+            response.status()
+        );
+        return Err(MatrixError::forbidden("Identity provider refused the login.").into());
+    }
+    let token = response.json::<TokenResponse>().await?;
+    let claims = crate::default_client()
+        .get(&discovery.userinfo_endpoint)
+        .bearer_auth(&token.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<JsonValue>()
+        .await?;
+    let subject = claims
+        .get("sub")
+        .and_then(|sub| sub.as_str())
+        .ok_or_else(|| AppError::public("Identity provider returned no subject."))?;
+
+    let user_id = match crate::user::get_user_by_external_id(idp_id, subject)? {
+        Some(user_id) => user_id,
+        None => register_user(provider, subject, &claims)?,
+    };
+    let login_token = crate::user::create_login_token(&user_id, LOGIN_TOKEN_LIFETIME)?;
+    info!("{user_id} logged in through {idp_id}");
+
+    let mut redirect_url = Url::parse(&redirect_url)?;
+    redirect_url.query_pairs_mut().append_pair("loginToken", &login_token);
+    Ok(redirect_url)
+}
+
+/// Creates the account of a user logging in through the provider for the first time.
+fn register_user(provider: &OidcProviderConfig, subject: &str, claims: &JsonValue) -> AppResult<OwnedUserId> {
+    if !provider.allow_registration {
+        return Err(MatrixError::forbidden("No account is linked to this identity.").into());
+    }
+    let claim = |name: &str| {
+        claims
+            .get(name)
+            .and_then(|value| value.as_str())
+            .filter(|value| !value.is_empty())
+    };
+    let localpart = claim(&provider.localpart_claim)
+        .map(sanitize_localpart)
+        .filter(|localpart| !localpart.is_empty())
+        .unwrap_or_else(|| utils::random_string(crate::RANDOM_USER_ID_LENGTH).to_lowercase());
+
+    let server_name = crate::server_name();
+    let mut suffix = 0;
+    let user_id = loop {
+        let candidate = if suffix == 0 {
+            localpart.clone()
+        } else {
+            format!("{localpart}{suffix}")
+        };
+        let user_id = UserId::parse_with_server_name(candidate, server_name)
+            .map_err(|_| AppError::public("Could not derive a user ID from the identity."))?;
+        if !crate::user::user_exists(&user_id)? {
+            break user_id;
+        }
+        suffix += 1;
+    };
+
+    crate::user::create_user(user_id.clone(), None)?;
+    diesel::update(users::table.find(&user_id))
+        .set(users::is_guest.eq(false))
+        .execute(&mut *db::connect()?)?;
+    let display_name = claim(&provider.display_name_claim).unwrap_or(user_id.localpart());
+    crate::user::init_new_user(&user_id, display_name.to_owned())?;
+    crate::user::add_external_id(&provider.id, subject, &user_id)?;
+    info!("New user {user_id} registered through {}", provider.id);
+    Ok(user_id)
+}
+
+/// Lowercases the claim and replaces characters not allowed in localparts.
+fn sanitize_localpart(claim: &str) -> String {
+    claim
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '=' | '-' | '/') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -6,10 +6,9 @@ pub mod directory;
 pub mod lazy_loading;
 pub mod pdu_metadata;
 pub mod receipt;
-mod search;
+pub mod search;
 use palpo_core::events::direct::DirectEventContent;
 use palpo_core::events::ignored_user_list::IgnoredUserListEventContent;
-pub use search::*;
 pub mod space;
 pub mod state;
 pub mod timeline;
@@ -233,37 +232,7 @@ pub fn update_membership(
                     };
                 }
             }
-            db::connect()?.transaction::<_, AppError, _>(|conn| {
-                // let forgotten = room_users::table
-                //     .filter(room_users::room_id.eq(room_id))
-                //     .filter(room_users::user_id.eq(user_id))
-                //     .select(room_users::forgotten)
-                //     .first::<bool>(conn)
-                //     .optional()?
-                //     .unwrap_or_default();
-                diesel::delete(
-                    room_users::table
-                        .filter(room_users::room_id.eq(room_id))
-                        .filter(room_users::user_id.eq(user_id)),
-                )
-                .execute(conn)?;
-                diesel::insert_into(room_users::table)
-                    .values(&NewDbRoomUser {
-                        room_id: room_id.to_owned(),
-                        user_id: user_id.to_owned(),
-                        event_id: event_id.to_owned(),
-                        event_sn,
-                        sender_id: sender.to_owned(),
-                        membership: membership.to_string(),
-                        forgotten: false,
-                        display_name: None,
-                        avatar_url: None,
-                        state_data,
-                        created_at: UnixMillis::now(),
-                    })
-                    .execute(conn)?;
-                Ok(())
-            })?;
+            set_room_user(room_id, user_id, &membership, event_id, event_sn, sender, state_data)?;
         }
         MembershipState::Invite => {
             // We want to know if the sender is ignored by the receiver
@@ -280,78 +249,60 @@ pub fn update_membership(
                 return Ok(());
             }
 
-            db::connect()?.transaction::<_, AppError, _>(|conn| {
-                // let forgotten = room_users::table
-                //     .filter(room_users::room_id.eq(room_id))
-                //     .filter(room_users::user_id.eq(user_id))
-                //     .select(room_users::forgotten)
-                //     .first::<bool>(conn)
-                //     .optional()?
-                //     .unwrap_or_default();
-                diesel::delete(
-                    room_users::table
-                        .filter(room_users::room_id.eq(room_id))
-                        .filter(room_users::user_id.eq(user_id)),
-                )
-                .execute(conn)?;
-                diesel::insert_into(room_users::table)
-                    .values(&NewDbRoomUser {
-                        room_id: room_id.to_owned(),
-                        user_id: user_id.to_owned(),
-                        event_id: event_id.to_owned(),
-                        event_sn,
-                        sender_id: sender.to_owned(),
-                        membership: membership.to_string(),
-                        forgotten: false,
-                        display_name: None,
-                        avatar_url: None,
-                        state_data,
-                        created_at: UnixMillis::now(),
-                    })
-                    .execute(conn)?;
-                Ok(())
-            })?;
+            set_room_user(room_id, user_id, &membership, event_id, event_sn, sender, state_data)?;
+        }
+        MembershipState::Knock => {
+            set_room_user(room_id, user_id, &membership, event_id, event_sn, sender, state_data)?;
         }
         MembershipState::Leave | MembershipState::Ban => {
-            db::connect()?.transaction::<_, AppError, _>(|conn| {
-                // let forgotten = room_users::table
-                //     .filter(room_users::room_id.eq(room_id))
-                //     .filter(room_users::user_id.eq(user_id))
-                //     .select(room_users::forgotten)
-                //     .first::<bool>(conn)
-                //     .optional()?
-                //     .unwrap_or_default();
-                diesel::delete(
-                    room_users::table
-                        .filter(room_users::room_id.eq(room_id))
-                        .filter(room_users::user_id.eq(user_id)),
-                )
-                .execute(conn)?;
-                diesel::insert_into(room_users::table)
-                    .values(&NewDbRoomUser {
-                        room_id: room_id.to_owned(),
-                        user_id: user_id.to_owned(),
-                        event_id: event_id.to_owned(),
-                        event_sn,
-                        sender_id: sender.to_owned(),
-                        membership: membership.to_string(),
-                        forgotten: true,
-                        display_name: None,
-                        avatar_url: None,
-                        state_data,
-                        created_at: UnixMillis::now(),
-                    })
-                    .execute(conn)?;
-                Ok(())
-            })?;
+            set_room_user(room_id, user_id, &membership, event_id, event_sn, sender, state_data)?;
         }
         _ => {}
     }
     update_room_servers(room_id)?;
     update_room_currents(room_id)?;
+    crate::watcher::notify_user(user_id);
     Ok(())
 }
 
+/// Replaces the membership of the user in the room, users who left or were banned are marked as
+/// having forgotten the room.
+fn set_room_user(
+    room_id: &RoomId,
+    user_id: &UserId,
+    membership: &MembershipState,
+    event_id: &EventId,
+    event_sn: i64,
+    sender: &UserId,
+    state_data: Option<JsonValue>,
+) -> AppResult<()> {
+    let forgotten = matches!(membership, MembershipState::Leave | MembershipState::Ban);
+    db::connect()?.transaction::<_, AppError, _>(|conn| {
+        diesel::delete(
+            room_users::table
+                .filter(room_users::room_id.eq(room_id))
+                .filter(room_users::user_id.eq(user_id)),
+        )
+        .execute(conn)?;
+        diesel::insert_into(room_users::table)
+            .values(&NewDbRoomUser {
+                room_id: room_id.to_owned(),
+                user_id: user_id.to_owned(),
+                event_id: event_id.to_owned(),
+                event_sn,
+                sender_id: sender.to_owned(),
+                membership: membership.to_string(),
+                forgotten,
+                display_name: None,
+                avatar_url: None,
+                state_data,
+                created_at: UnixMillis::now(),
+            })
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
 pub fn update_room_currents(room_id: &RoomId) -> AppResult<()> {
     let joined_members = room_users::table
         .filter(room_users::room_id.eq(room_id))
@@ -375,7 +326,7 @@ pub fn update_room_currents(room_id: &RoomId) -> AppResult<()> {
         .get_result::<i64>(&mut *db::connect()?)?;
     let knocked_members = room_users::table
         .filter(room_users::room_id.eq(room_id))
-        .filter(room_users::membership.eq("knocked"))
+        .filter(room_users::membership.eq("knock"))
         .count()
         .get_result::<i64>(&mut *db::connect()?)?;
 
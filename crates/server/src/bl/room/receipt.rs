@@ -63,6 +63,7 @@ pub fn update_read(user_id: &UserId, room_id: &RoomId, event: ReceiptEvent) -> A
             }
         }
     }
+    crate::watcher::notify_room(room_id);
     Ok(())
 }
 
@@ -107,6 +108,7 @@ pub fn set_private_read(room_id: &RoomId, user_id: &UserId, event_id: &EventId,
         })
         .on_conflict_do_nothing()
         .execute(&mut db::connect()?)?;
+    crate::watcher::notify_user(user_id);
     Ok(())
 }
 
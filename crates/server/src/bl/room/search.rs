@@ -1,37 +1,162 @@
+use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Float4, Nullable, Text};
+use serde::Deserialize;
+
+use crate::core::client::search::{Criteria, OrderBy};
+use crate::core::events::TimelineEventType;
 use crate::core::identifiers::*;
-use crate::AppResult;
+use crate::event::PduEvent;
+use crate::schema::*;
+use crate::{db, AppResult};
+
+/// The text search configuration used to build and query `event_searches.vector`.
+const SEARCH_CONFIG: &str = "english";
+
+#[derive(QueryableByName, Debug, Clone)]
+pub struct SearchedEvent {
+    #[diesel(sql_type = Text)]
+    pub event_id: OwnedEventId,
+    #[diesel(sql_type = Text)]
+    pub room_id: OwnedRoomId,
+    #[diesel(sql_type = Text)]
+    pub sender_id: OwnedUserId,
+    #[diesel(sql_type = Float4)]
+    pub rank: f32,
+    #[diesel(sql_type = Nullable<BigInt>)]
+    pub stream_ordering: Option<i64>,
+}
+
+#[derive(QueryableByName, Debug, Clone)]
+struct SearchCount {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
 
-pub fn search_pdus(room_id: &RoomId, search_string: &str) -> AppResult<Option<(Vec<OwnedEventId>, Vec<String>)>> {
-    let words: Vec<_> = search_string
+/// Splits the search term into lowercase words, this is also what clients use to highlight results.
+pub fn search_words(search_term: &str) -> Vec<String> {
+    search_term
         .split_terminator(|c: char| !c.is_alphanumeric())
         .filter(|s| !s.is_empty())
         .map(str::to_lowercase)
-        .collect();
-    Ok(None)
-
-    // TODO: search_pdus
-    // let iterators = words.clone().into_iter().map(move |word| {
-    //     let mut prefix2 = prefix.clone();
-    //     prefix2.extend_from_slice(word.as_bytes());
-    //     prefix2.push(0xff);
-    //     let prefix3 = prefix2.clone();
-
-    //     let mut last_possible_id = prefix2.clone();
-    //     last_possible_id.extend_from_slice(&u64::MAX.to_be_bytes());
-
-    //     self.tokenids
-    //         .iter_from(&last_possible_id, true) // Newest pdus first
-    //         .take_while(move |(k, _)| k.starts_with(&prefix2))
-    //         .map(move |(key, _)| key[prefix3.len()..].to_vec())
-    // });
-
-    // let common_elements = match utils::common_elements(iterators, |a, b| {
-    //     // We compare b with a because we reversed the iterator earlier
-    //     b.cmp(a)
-    // }) {
-    //     Some(it) => it,
-    //     None => return Ok(None),
-    // };
-
-    // Ok(Some((Box::new(common_elements), words)))
+        .collect()
+}
+
+/// Indexes the searchable content of a pdu, only `content.body`, `content.name` and `content.topic` are indexed.
+#[tracing::instrument(skip_all)]
+pub fn save_pdu(pdu: &PduEvent) -> AppResult<()> {
+    #[derive(Deserialize)]
+    struct ExtractSearchable {
+        body: Option<String>,
+        name: Option<String>,
+        topic: Option<String>,
+    }
+
+    let Ok(content) = serde_json::from_str::<ExtractSearchable>(pdu.content.get()) else {
+        return Ok(());
+    };
+    let (key, value) = match pdu.event_ty {
+        TimelineEventType::RoomMessage => ("content.body", content.body),
+        TimelineEventType::RoomName => ("content.name", content.name),
+        TimelineEventType::RoomTopic => ("content.topic", content.topic),
+        _ => return Ok(()),
+    };
+    let Some(value) = value.filter(|v| !v.trim().is_empty()) else {
+        return Ok(());
+    };
+
+    diesel::sql_query(
+        "INSERT INTO event_searches (event_id, room_id, sender_id, key, vector, origin_server_ts, stream_ordering) \
+        VALUES ($1, $2, $3, $4, to_tsvector($5::regconfig, $6), $7, $8) \
+        ON CONFLICT (event_id) DO UPDATE SET key = EXCLUDED.key, vector = EXCLUDED.vector",
+    )
+    .bind::<Text, _>(pdu.event_id.as_str())
+    .bind::<Text, _>(pdu.room_id.as_str())
+    .bind::<Text, _>(pdu.sender.as_str())
+    .bind::<Text, _>(key)
+    .bind::<Text, _>(SEARCH_CONFIG)
+    .bind::<Text, _>(value)
+    .bind::<BigInt, _>(pdu.origin_server_ts.get() as i64)
+    .bind::<BigInt, _>(pdu.event_sn)
+    .execute(&mut *db::connect()?)?;
+    Ok(())
+}
+
+/// Removes a pdu from the search index, used when the pdu is redacted.
+pub fn remove_pdu(event_id: &EventId) -> AppResult<()> {
+    diesel::delete(event_searches::table.filter(event_searches::event_id.eq(event_id.as_str())))
+        .execute(&mut *db::connect()?)?;
+    Ok(())
+}
+
+/// Searches the given rooms for events matching the criteria.
+///
+/// Returns the total count of matched events and the requested page of them. History visibility is
+/// checked by the caller, so the count is an upper bound of the events the user can see.
+pub fn search_pdus(
+    room_ids: &[OwnedRoomId],
+    criteria: &Criteria,
+    skip: usize,
+    limit: usize,
+) -> AppResult<(u64, Vec<SearchedEvent>)> {
+    let words = search_words(&criteria.search_term);
+    if words.is_empty() || room_ids.is_empty() {
+        return Ok((0, Vec::new()));
+    }
+    // Every word must match, the last one may also be a prefix so that partial typing still finds something.
+    let mut query = words.join(" & ");
+    query.push_str(":*");
+
+    let room_ids: Vec<String> = room_ids.iter().map(|r| r.to_string()).collect();
+    let keys: Vec<String> = criteria
+        .keys
+        .as_ref()
+        .map(|keys| keys.iter().map(|k| k.to_string()).collect())
+        .unwrap_or_default();
+    let senders: Vec<String> = criteria
+        .filter
+        .senders
+        .as_ref()
+        .map(|senders| senders.iter().map(|s| s.to_string()).collect())
+        .unwrap_or_default();
+    let not_senders: Vec<String> = criteria.filter.not_senders.iter().map(|s| s.to_string()).collect();
+
+    let conditions = "e.vector @@ q.query \
+        AND e.room_id = ANY($3) \
+        AND (cardinality($4::text[]) = 0 OR e.key = ANY($4)) \
+        AND (cardinality($5::text[]) = 0 OR e.sender_id = ANY($5)) \
+        AND e.sender_id <> ALL($6)";
+    let order = if criteria.order_by == Some(OrderBy::Recent) {
+        "e.stream_ordering DESC NULLS LAST"
+    } else {
+        "rank DESC, e.stream_ordering DESC NULLS LAST"
+    };
+
+    let count = diesel::sql_query(format!(
+        "SELECT COUNT(*) AS count FROM event_searches e, to_tsquery($1::regconfig, $2) AS q(query) WHERE {conditions}"
+    ))
+    .bind::<Text, _>(SEARCH_CONFIG)
+    .bind::<Text, _>(&query)
+    .bind::<Array<Text>, _>(&room_ids)
+    .bind::<Array<Text>, _>(&keys)
+    .bind::<Array<Text>, _>(&senders)
+    .bind::<Array<Text>, _>(&not_senders)
+    .get_result::<SearchCount>(&mut *db::connect()?)?
+    .count;
+
+    let events = diesel::sql_query(format!(
+        "SELECT e.event_id, e.room_id, e.sender_id, ts_rank_cd(e.vector, q.query) AS rank, e.stream_ordering \
+        FROM event_searches e, to_tsquery($1::regconfig, $2) AS q(query) \
+        WHERE {conditions} ORDER BY {order} LIMIT $7 OFFSET $8"
+    ))
+    .bind::<Text, _>(SEARCH_CONFIG)
+    .bind::<Text, _>(&query)
+    .bind::<Array<Text>, _>(&room_ids)
+    .bind::<Array<Text>, _>(&keys)
+    .bind::<Array<Text>, _>(&senders)
+    .bind::<Array<Text>, _>(&not_senders)
+    .bind::<BigInt, _>(crate::utils::usize_to_i64(limit))
+    .bind::<BigInt, _>(crate::utils::usize_to_i64(skip))
+    .load::<SearchedEvent>(&mut *db::connect()?)?;
+
+    Ok((count as u64, events))
 }
@@ -224,6 +224,25 @@ pub fn calculate_invite_state(invite_event: &PduEvent) -> AppResult<Vec<RawJson<
     Ok(state)
 }
 
+/// The stripped state a knocking user gets to see, so that clients can show what they knocked on.
+pub fn calculate_knock_state(room_id: &RoomId) -> AppResult<Vec<RawJson<AnyStrippedStateEvent>>> {
+    let mut state = Vec::new();
+    for event_ty in [
+        StateEventType::RoomCreate,
+        StateEventType::RoomJoinRules,
+        StateEventType::RoomCanonicalAlias,
+        StateEventType::RoomAvatar,
+        StateEventType::RoomName,
+        StateEventType::RoomTopic,
+        StateEventType::RoomEncryption,
+    ] {
+        if let Some(e) = get_state(room_id, &event_ty, "", None)? {
+            state.push(e.to_stripped_state_event());
+        }
+    }
+    Ok(state)
+}
+
 pub fn get_current_frame_id(room_id: &RoomId) -> AppResult<Option<i64>> {
     rooms::table
         .find(room_id)
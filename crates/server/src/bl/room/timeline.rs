@@ -190,6 +190,7 @@ pub fn append_pdu(pdu: &PduEvent, mut pdu_json: CanonicalJsonObject, leaves: Vec
         .do_update()
         .set(&event_data)
         .execute(&mut db::connect()?)?;
+    crate::media::add_media_references(&pdu.event_id, &event_data.json_data)?;
 
     // See if the event matches any known pushers
     let power_levels: RoomPowerLevelsEventContent =
@@ -247,6 +248,11 @@ pub fn append_pdu(pdu: &PduEvent, mut pdu_json: CanonicalJsonObject, leaves: Vec
     }
     increment_notification_counts(&pdu.room_id, notifies, highlights)?;
 
+    if let Err(e) = crate::room::search::save_pdu(pdu) {
+        warn!("Failed to index pdu {} for search: {}", pdu.event_id, e);
+    }
+    crate::watcher::notify_room(&pdu.room_id);
+
     match pdu.event_ty {
         TimelineEventType::RoomRedaction => {
             if let Some(redact_id) = &pdu.redacts {
@@ -279,6 +285,11 @@ pub fn append_pdu(pdu: &PduEvent, mut pdu_json: CanonicalJsonObject, leaves: Vec
                         let state = crate::room::state::calculate_invite_state(pdu)?;
                         Some(state)
                     }
+                    MembershipState::Knock => {
+                        let mut state = crate::room::state::calculate_knock_state(&pdu.room_id)?;
+                        state.push(pdu.to_stripped_state_event());
+                        Some(state)
+                    }
                     _ => None,
                 };
 
@@ -905,6 +916,7 @@ pub fn redact_pdu(event_id: &EventId, reason: &PduEvent) -> AppResult<()> {
     if let Some(mut pdu) = get_pdu(event_id)? {
         pdu.redact(reason)?;
         replace_pdu(&event_id, &utils::to_canonical_object(&pdu)?)?;
+        crate::room::search::remove_pdu(event_id)?;
     }
     // If event does not exist, just noop
     Ok(())
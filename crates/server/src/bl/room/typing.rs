@@ -3,8 +3,9 @@ use std::sync::LazyLock;
 
 use tokio::sync::{broadcast, RwLock};
 
-use crate::core::events::typing::TypingEventContent;
+use crate::core::events::typing::{TypingContent, TypingEventContent};
 use crate::core::events::SyncEphemeralRoomEvent;
+use crate::core::federation::transaction::Edu;
 use crate::core::identifiers::*;
 use crate::core::UnixMillis;
 use crate::{AppError, AppResult};
@@ -37,7 +38,8 @@ pub async fn add_typing(user_id: &UserId, room_id: &RoomId, timeout: u64) -> App
     // crate::room::state::update_point_frame_id(point_id, current_frame_id)?;
 
     let _ = TYPING_UPDATE_SENDER.send(room_id.to_owned());
-    Ok(())
+    crate::watcher::notify_room(room_id);
+    federation_send_typing(user_id, room_id, true)
 }
 
 /// Removes a user from typing before the timeout is reached.
@@ -53,7 +55,17 @@ pub async fn remove_typing(user_id: &UserId, room_id: &RoomId) -> AppResult<()>
         .await
         .insert(room_id.to_owned(), crate::next_sn()?);
     let _ = TYPING_UPDATE_SENDER.send(room_id.to_owned());
-    Ok(())
+    crate::watcher::notify_room(room_id);
+    federation_send_typing(user_id, room_id, false)
+}
+
+/// Tells the other servers of the room that a local user started or stopped typing.
+fn federation_send_typing(user_id: &UserId, room_id: &RoomId, typing: bool) -> AppResult<()> {
+    if user_id.server_name() != crate::server_name() {
+        return Ok(());
+    }
+    let edu = Edu::Typing(TypingContent::new(room_id.to_owned(), user_id.to_owned(), typing));
+    crate::sending::send_edu_room(room_id, &edu)
 }
 
 pub async fn wait_for_update(room_id: &RoomId) -> AppResult<()> {
@@ -86,14 +98,18 @@ async fn maintain_typings(room_id: &RoomId) -> AppResult<()> {
     if !removable.is_empty() {
         let typing = &mut TYPING.write().await;
         let room = typing.entry(room_id.to_owned()).or_default();
-        for user in removable {
-            room.remove(&user);
+        for user in &removable {
+            room.remove(user);
         }
         LAST_TYPING_UPDATE
             .write()
             .await
             .insert(room_id.to_owned(), crate::next_sn()?);
         let _ = TYPING_UPDATE_SENDER.send(room_id.to_owned());
+        crate::watcher::notify_room(room_id);
+        for user in &removable {
+            federation_send_typing(user, room_id, false)?;
+        }
     }
     Ok(())
 }
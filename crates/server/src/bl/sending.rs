@@ -4,6 +4,7 @@ use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
 
 use base64::{engine::general_purpose, Engine as _};
+use diesel::pg::Pg;
 use diesel::prelude::*;
 use futures_util::stream::{FuturesUnordered, StreamExt};
 use tokio::sync::{mpsc, Mutex, Semaphore};
@@ -17,10 +18,11 @@ use crate::core::federation::transaction::{send_messages_request, Edu, SendMessa
 use crate::core::identifiers::*;
 pub use crate::core::sending::*;
 use crate::core::{device_id, push, UnixMillis};
-use crate::{db, exts::*, utils, AppError, AppResult, PduEvent};
+use crate::room::receipt::DbReceipt;
+use crate::schema::*;
+use crate::{db, exts::*, utils, AppError, AppResult, JsonValue, PduEvent};
 
-use super::room::receipt;
-use super::{curr_sn, outgoing_requests};
+use super::curr_sn;
 
 #[derive(Identifiable, Queryable, Insertable, Debug, Clone)]
 #[diesel(table_name = outgoing_requests)]
@@ -87,6 +89,11 @@ pub fn max_request() -> Arc<Semaphore> {
         .clone()
 }
 
+/// Most PDUs sent in one transaction, as allowed by the specification.
+const MAX_PDUS_PER_TRANSACTION: usize = 50;
+/// Most EDUs sent in one transaction, as allowed by the specification.
+const MAX_EDUS_PER_TRANSACTION: usize = 100;
+
 enum TransactionStatus {
     Running,
     Failed(u32, Instant), // number of times failed, time of last failure
@@ -106,27 +113,27 @@ async fn handler() -> AppResult<()> {
     let mut receiver = MPSC_RECEIVER.get().expect("receiver should exist").lock().await;
     let mut futures = FuturesUnordered::new();
     let mut current_transaction_status = HashMap::<OutgoingKind, TransactionStatus>::new();
+    // EDU stream positions reached by the running transactions, saved when they succeed
+    let mut pending_edu_sns = HashMap::<OwnedServerName, i64>::new();
 
     // Retry requests we could not finish yet
-    let mut initial_transactions = HashMap::<OutgoingKind, Vec<SendingEventType>>::new();
-
-    for (id, outgoing_kind, event) in active_requests()? {
-        let entry = initial_transactions
-            .entry(outgoing_kind.clone())
-            .or_insert_with(Vec::new);
-
-        if entry.len() > 30 {
-            warn!("Dropping some current events: {:?} {:?} {:?}", id, outgoing_kind, event);
-            delete_request(id)?;
-            continue;
+    reset_active_requests()?;
+    let outgoing_kinds = all_requests()?
+        .into_iter()
+        .map(|(_, outgoing_kind, _)| outgoing_kind)
+        .collect::<HashSet<_>>();
+    for outgoing_kind in outgoing_kinds {
+        if let OutgoingKind::Normal(server_name) = &outgoing_kind {
+            if crate::federation::destination::backoff_remaining(server_name)?.is_some() {
+                current_transaction_status.insert(outgoing_kind, TransactionStatus::Failed(0, Instant::now()));
+                continue;
+            }
+        }
+        let events = start_transaction(&outgoing_kind, &mut pending_edu_sns)?;
+        if !events.is_empty() {
+            current_transaction_status.insert(outgoing_kind.clone(), TransactionStatus::Running);
+            futures.push(handle_events(outgoing_kind, events));
         }
-
-        entry.push(event);
-    }
-
-    for (outgoing_kind, events) in initial_transactions {
-        current_transaction_status.insert(outgoing_kind.clone(), TransactionStatus::Running);
-        futures.push(handle_events(outgoing_kind.clone(), events));
     }
 
     loop {
@@ -135,25 +142,24 @@ async fn handler() -> AppResult<()> {
                 match response {
                     Ok(outgoing_kind) => {
                         delete_all_active_requests_for(&outgoing_kind)?;
+                        if let OutgoingKind::Normal(server_name) = &outgoing_kind {
+                            if let Some(edu_sn) = pending_edu_sns.remove(server_name) {
+                                set_last_edu_sn(server_name, edu_sn)?;
+                            }
+                        }
 
-                        // Find events that have been added since starting the last request
-                        let new_events = queued_requests(&outgoing_kind).unwrap_or_default().into_iter().take(30).collect::<Vec<_>>();
-
-                        if !new_events.is_empty() {
-                            // Insert pdus we found
-                            mark_as_active(&new_events)?;
-
-                            futures.push(
-                                handle_events(
-                                    outgoing_kind.clone(),
-                                    new_events.into_iter().map(|(_, event)| event).collect(),
-                                )
-                            );
+                        // Send the requests that have been added since starting the last transaction
+                        let events = start_transaction(&outgoing_kind, &mut pending_edu_sns)?;
+                        if !events.is_empty() {
+                            futures.push(handle_events(outgoing_kind.clone(), events));
                         } else {
                             current_transaction_status.remove(&outgoing_kind);
                         }
                     }
                     Err((outgoing_kind, x)) => {
+                        if let OutgoingKind::Normal(server_name) = &outgoing_kind {
+                            pending_edu_sns.remove(server_name);
+                        }
                         current_transaction_status.entry(outgoing_kind).and_modify(|e| *e = match e {
                             TransactionStatus::Running => TransactionStatus::Failed(1, Instant::now()),
                             TransactionStatus::Retrying(n) => TransactionStatus::Failed(*n+1, Instant::now()),
@@ -165,11 +171,11 @@ async fn handler() -> AppResult<()> {
                     }
                 };
             },
-            Some((outgoing_kind, event, id)) = receiver.recv() => {
+            Some((outgoing_kind, _, _)) = receiver.recv() => {
                 if let Ok(Some(events)) = select_events(
                     &outgoing_kind,
-                    vec![(id, event)],
                     &mut current_transaction_status,
+                    &mut pending_edu_sns,
                 ) {
                     futures.push(handle_events(outgoing_kind, events));
                 }
@@ -181,11 +187,16 @@ async fn handler() -> AppResult<()> {
 #[tracing::instrument(skip_all)]
 fn select_events(
     outgoing_kind: &OutgoingKind,
-    new_events: Vec<(i64, SendingEventType)>, // Events we want to send: event and full key
     current_transaction_status: &mut HashMap<OutgoingKind, TransactionStatus>,
+    pending_edu_sns: &mut HashMap<OwnedServerName, i64>,
 ) -> AppResult<Option<Vec<SendingEventType>>> {
     let mut retry = false;
     let mut allow = true;
+    // Servers are backed off by the destination health shared with the other federation requests
+    let destination_backoff = match outgoing_kind {
+        OutgoingKind::Normal(server_name) => Some(crate::federation::destination::backoff_remaining(server_name)?),
+        _ => None,
+    };
 
     let entry = current_transaction_status.entry(outgoing_kind.clone());
 
@@ -200,8 +211,12 @@ fn select_events(
                 if min_elapsed_duration > Duration::from_secs(60 * 60 * 24) {
                     min_elapsed_duration = Duration::from_secs(60 * 60 * 24);
                 }
+                // Transactions can also fail without the server being backed off, e.g. with an
+                // error response, so the local backoff applies too.
+                let backing_off = time.elapsed() < min_elapsed_duration
+                    || destination_backoff.is_some_and(|remaining| remaining.is_some());
 
-                if time.elapsed() < min_elapsed_duration {
+                if backing_off {
                     allow = false;
                 } else {
                     retry = true;
@@ -215,126 +230,156 @@ fn select_events(
         return Ok(None);
     }
 
-    let mut events = Vec::new();
-
-    if retry {
+    let events = if retry {
         // We retry the previous transaction
-        for (_, e) in active_requests_for(outgoing_kind)?.into_iter() {
-            events.push(e);
-        }
+        active_requests_for(outgoing_kind)?
+            .into_iter()
+            .map(|(_, e)| e)
+            .collect()
     } else {
-        mark_as_active(&new_events)?;
-        for (_, e) in new_events {
-            events.push(e);
-        }
+        start_transaction(outgoing_kind, pending_edu_sns)?
+    };
+    if events.is_empty() {
+        current_transaction_status.remove(outgoing_kind);
+        return Ok(None);
+    }
+
+    Ok(Some(events))
+}
 
-        if let OutgoingKind::Normal(server_name) = outgoing_kind {
-            if let Ok((select_edus, last_count)) = select_edus(server_name) {
-                events.extend(select_edus.into_iter().map(SendingEventType::Edu));
+/// Marks the next queued requests of the destination as active and returns their events. Servers
+/// also get the changes of the EDU streams since their last transaction.
+fn start_transaction(
+    outgoing_kind: &OutgoingKind,
+    pending_edu_sns: &mut HashMap<OwnedServerName, i64>,
+) -> AppResult<Vec<SendingEventType>> {
+    if let OutgoingKind::Normal(server_name) = outgoing_kind {
+        // Requests queued before the server got blocked
+        if !crate::federation::is_server_allowed(server_name) {
+            delete_all_requests_for(outgoing_kind)?;
+            return Ok(Vec::new());
+        }
+    }
+    let requests = next_transaction_requests(outgoing_kind)?;
+    mark_as_active(&requests)?;
+    let edu_count = requests
+        .iter()
+        .filter(|(_, event)| matches!(event, SendingEventType::Edu(_)))
+        .count();
+    let mut events = requests.into_iter().map(|(_, event)| event).collect::<Vec<_>>();
+
+    if let OutgoingKind::Normal(server_name) = outgoing_kind {
+        match select_edus(server_name, MAX_EDUS_PER_TRANSACTION.saturating_sub(edu_count)) {
+            Ok((edus, edu_sn)) => {
+                events.extend(edus.into_iter().map(SendingEventType::Edu));
+                pending_edu_sns.insert(server_name.to_owned(), edu_sn);
             }
+            Err(e) => warn!("Failed to select EDUs for {server_name}: {e}"),
         }
     }
 
-    Ok(Some(events))
+    Ok(events)
 }
 
-#[tracing::instrument(skip(server_name))]
-pub fn select_edus(server_name: &ServerName) -> AppResult<(Vec<Vec<u8>>, i64)> {
-    let mut events = Vec::new();
-    let mut max_edu_sn = curr_sn()?;
-    let mut device_list_changes = HashSet::new();
-    let conf = crate::config();
-
-    // u64: count of last
-    let since_sn = curr_sn()?;
-
-    'outer: for room_id in crate::room::server_rooms(server_name)? {
-        // Look for device list updates in this room
-        device_list_changes.extend(
-            crate::room::keys_changed_users(&room_id, since_sn, None)?
-                .into_iter()
-                .filter(|user_id| user_id.server_name() == &conf.server_name),
-        );
-
-        // Look for read receipts in this room
-        for (event_id, event_receipts) in crate::room::receipt::read_receipts(&room_id, since_sn)?.content {
-            let sn = crate::event::get_event_sn(&event_id)?;
-            if sn > max_edu_sn {
-                max_edu_sn = sn;
-            }
+/// Returns the position in the EDU streams up to which the server received the changes.
+///
+/// Servers we never sent EDUs to start at the current position.
+pub fn get_last_edu_sn(server_name: &ServerName) -> AppResult<i64> {
+    let last_edu_sn = outgoing_edu_positions::table
+        .find(server_name)
+        .select(outgoing_edu_positions::last_edu_sn)
+        .first::<i64>(&mut *db::connect()?)
+        .optional()?;
+    match last_edu_sn {
+        Some(last_edu_sn) => Ok(last_edu_sn),
+        None => {
+            let last_edu_sn = curr_sn()?;
+            set_last_edu_sn(server_name, last_edu_sn)?;
+            Ok(last_edu_sn)
+        }
+    }
+}
 
-            for (receipt_type, type_receipts) in event_receipts {
-                if receipt_type != ReceiptType::Read {
-                    continue;
-                }
-                for (user_id, receipt) in type_receipts {
-                    if user_id.server_name() != &conf.server_name {
-                        continue;
-                    }
-                    let mut receipts: BTreeMap<OwnedRoomId, ReceiptMap> = BTreeMap::new();
-                    let mut read = BTreeMap::new();
-                    read.insert(
-                        user_id,
-                        ReceiptData {
-                            data: receipt.clone(),
-                            event_ids: vec![event_id.to_owned()],
-                        },
-                    );
-
-                    let receipt_map = ReceiptMap { read };
-                    receipts.insert(room_id.to_owned(), receipt_map);
-                    let federation_event = Edu::Receipt(ReceiptContent(receipts));
-                    events.push(serde_json::to_vec(&federation_event).expect("json can be serialized"));
-                }
-            }
+fn set_last_edu_sn(server_name: &ServerName, last_edu_sn: i64) -> AppResult<()> {
+    let updated_at = UnixMillis::now().get() as i64;
+    diesel::insert_into(outgoing_edu_positions::table)
+        .values((
+            outgoing_edu_positions::server_id.eq(server_name),
+            outgoing_edu_positions::last_edu_sn.eq(last_edu_sn),
+            outgoing_edu_positions::updated_at.eq(updated_at),
+        ))
+        .on_conflict(outgoing_edu_positions::server_id)
+        .do_update()
+        .set((
+            outgoing_edu_positions::last_edu_sn.eq(last_edu_sn),
+            outgoing_edu_positions::updated_at.eq(updated_at),
+        ))
+        .execute(&mut *db::connect()?)?;
+    Ok(())
+}
 
-            // let event: AnySyncEphemeralRoomEvent = serde_json::from_str(read_receipt.inner().get())
-            //     .map_err(|_| AppError::internal("Invalid edu event in read_receipts."))?;
-            // let federation_event = match event {
-            //     AnySyncEphemeralRoomEvent::Receipt(r) => {
-            //         let mut read = BTreeMap::new();
-
-            //         let (event_id, mut receipt) = r
-            //             .content
-            //             .0
-            //             .into_iter()
-            //             .next()
-            //             .expect("we only use one event per read receipt");
-            //         let receipt = receipt
-            //             .remove(&ReceiptType::Read)
-            //             .expect("our read receipts always set this")
-            //             .remove(&user_id)
-            //             .expect("our read receipts always have the user here");
-
-            //         read.insert(
-            //             user_id,
-            //             ReceiptData {
-            //                 data: receipt.clone(),
-            //                 event_ids: vec![event_id.to_owned()],
-            //             },
-            //         );
-
-            //         let receipt_map = ReceiptMap { read };
-
-            //         receipts.insert(room_id.to_owned(), receipt_map);
-
-            //         Edu::Receipt(ReceiptContent(receipts))
-            //     }
-            //     _ => {
-            //         AppError::internal("Invalid event type in read_receipts");
-            //         continue;
-            //     }
-            // };
-
-            // events.push(serde_json::to_vec(&federation_event).expect("json can be serialized"));
-
-            if events.len() >= 20 {
-                break 'outer;
-            }
+/// Returns the read receipts and device list changes of local users in the rooms shared with the
+/// server since its last transaction, at most `limit` EDUs, and the stream position they reach.
+///
+/// Only the latest state of each stream is sent: the receipts of a room are sent as one EDU with
+/// the last receipt of each user, and device list changes as one update by user, so a server
+/// coming back after a long time does not get every intermediate value.
+#[tracing::instrument(skip(server_name))]
+pub fn select_edus(server_name: &ServerName, limit: usize) -> AppResult<(Vec<Vec<u8>>, i64)> {
+    let since_sn = get_last_edu_sn(server_name)?;
+    let until_sn = curr_sn()?;
+    let room_ids = crate::room::server_rooms(server_name)?;
+    let local_server = crate::server_name();
+    let mut conn = db::connect()?;
+
+    // EDUs with a stream position, changes after it are sent again when the EDU is left out
+    let mut edus = Vec::<(i64, Edu)>::new();
+
+    let receipts = event_receipts::table
+        .filter(event_receipts::room_id.eq_any(&room_ids))
+        .filter(event_receipts::ty.eq(ReceiptType::Read.to_string()))
+        .filter(event_receipts::event_sn.gt(since_sn))
+        .filter(event_receipts::event_sn.le(until_sn))
+        .load::<DbReceipt>(&mut conn)?;
+    let mut room_receipts = BTreeMap::<OwnedRoomId, (i64, ReceiptMap)>::new();
+    for receipt in receipts {
+        if receipt.user_id.server_name() != local_server {
+            continue;
         }
+        let (sn, receipt_map) = room_receipts
+            .entry(receipt.room_id)
+            .or_insert_with(|| (i64::MAX, ReceiptMap { read: BTreeMap::new() }));
+        *sn = (*sn).min(receipt.event_sn);
+        receipt_map.read.insert(
+            receipt.user_id,
+            ReceiptData {
+                data: serde_json::from_value(receipt.json_data).unwrap_or_default(),
+                event_ids: vec![receipt.event_id],
+            },
+        );
+    }
+    for (room_id, (sn, receipt_map)) in room_receipts {
+        edus.push((
+            sn,
+            Edu::Receipt(ReceiptContent(BTreeMap::from([(room_id, receipt_map)]))),
+        ));
     }
 
-    for user_id in device_list_changes {
+    let key_changes = e2e_key_changes::table
+        .filter(e2e_key_changes::room_id.eq_any(&room_ids))
+        .filter(e2e_key_changes::occur_sn.gt(since_sn))
+        .filter(e2e_key_changes::occur_sn.le(until_sn))
+        .select((e2e_key_changes::user_id, e2e_key_changes::occur_sn))
+        .load::<(OwnedUserId, i64)>(&mut conn)?;
+    let mut device_list_changes = BTreeMap::<OwnedUserId, i64>::new();
+    for (user_id, occur_sn) in key_changes {
+        if user_id.server_name() != local_server {
+            continue;
+        }
+        let sn = device_list_changes.entry(user_id).or_default();
+        *sn = (*sn).max(occur_sn);
+    }
+    for (user_id, sn) in device_list_changes {
         // Empty prev id forces synapse to resync: https://github.com/matrix-org/synapse/blob/98aec1cc9da2bd6b8e34ffb282c85abf9b8b42ca/synapse/handlers/device.py#L767
         // Because synapse resyncs, we can just insert dummy data
         let edu = Edu::DeviceListUpdate(DeviceListUpdateContent {
@@ -346,11 +391,22 @@ pub fn select_edus(server_name: &ServerName) -> AppResult<(Vec<Vec<u8>>, i64)> {
             deleted: None,
             keys: None,
         });
+        edus.push((sn, edu));
+    }
 
-        events.push(serde_json::to_vec(&edu).expect("json can be serialized"));
+    // The oldest changes go first, the remaining ones are sent with the next transaction
+    edus.sort_by_key(|(sn, _)| *sn);
+    let mut last_edu_sn = until_sn;
+    if edus.len() > limit {
+        last_edu_sn = edus[limit].0 - 1;
+        edus.truncate(limit);
     }
 
-    Ok((events, max_edu_sn))
+    let edus = edus
+        .into_iter()
+        .map(|(_, edu)| serde_json::to_vec(&edu).expect("json can be serialized"))
+        .collect();
+    Ok((edus, last_edu_sn))
 }
 
 #[tracing::instrument(skip(pdu_id, user, pushkey))]
@@ -370,6 +426,7 @@ pub fn send_push_pdu(pdu_id: &EventId, user: &UserId, pushkey: String) -> AppRes
 pub fn send_pdu<S: Iterator<Item = OwnedServerName>>(servers: S, pdu_id: &EventId) -> AppResult<()> {
     let requests = servers
         .into_iter()
+        .filter(|server| crate::federation::is_server_allowed(server))
         .map(|server| (OutgoingKind::Normal(server), SendingEventType::Pdu(pdu_id.to_owned())))
         .collect::<Vec<_>>();
     let keys = queue_requests(&requests.iter().map(|(o, e)| (o, e.clone())).collect::<Vec<_>>())?;
@@ -382,6 +439,9 @@ pub fn send_pdu<S: Iterator<Item = OwnedServerName>>(servers: S, pdu_id: &EventI
 
 #[tracing::instrument(skip(server, serialized))]
 pub fn send_reliable_edu(server: &ServerName, serialized: Vec<u8>, id: &str) -> AppResult<()> {
+    if !crate::federation::is_server_allowed(server) {
+        return Ok(());
+    }
     let outgoing_kind = OutgoingKind::Normal(server.to_owned());
     let event = SendingEventType::Edu(serialized);
     let keys = queue_requests(&[(&outgoing_kind, event.clone())])?;
@@ -392,6 +452,38 @@ pub fn send_reliable_edu(server: &ServerName, serialized: Vec<u8>, id: &str) ->
     Ok(())
 }
 
+/// Queues an EDU for each of the servers, this server excepted.
+pub fn send_edu_servers<S: Iterator<Item = OwnedServerName>>(servers: S, edu: &Edu) -> AppResult<()> {
+    let serialized = serde_json::to_vec(edu).expect("json can be serialized");
+    let requests = servers
+        .filter(|server| server != crate::server_name() && crate::federation::is_server_allowed(server))
+        .map(|server| (OutgoingKind::Normal(server), SendingEventType::Edu(serialized.clone())))
+        .collect::<Vec<_>>();
+    if requests.is_empty() {
+        return Ok(());
+    }
+    let keys = queue_requests(&requests.iter().map(|(o, e)| (o, e.clone())).collect::<Vec<_>>())?;
+    for ((outgoing_kind, event), key) in requests.into_iter().zip(keys) {
+        sender().send((outgoing_kind, event, key)).unwrap();
+    }
+
+    Ok(())
+}
+
+/// Queues an EDU for the servers of the room.
+pub fn send_edu_room(room_id: &RoomId, edu: &Edu) -> AppResult<()> {
+    send_edu_servers(crate::room::get_room_servers(room_id, true)?.into_iter(), edu)
+}
+
+/// Queues an EDU for the servers sharing a room with the user.
+pub fn send_edu_user_servers(user_id: &UserId, edu: &Edu) -> AppResult<()> {
+    let mut servers = HashSet::new();
+    for room_id in crate::user::joined_rooms(user_id, 0)? {
+        servers.extend(crate::room::get_room_servers(&room_id, true)?);
+    }
+    send_edu_servers(servers.into_iter(), edu)
+}
+
 #[tracing::instrument]
 pub fn send_pdu_appservice(appservice_id: String, pdu_id: &EventId) -> AppResult<()> {
     let outgoing_kind = OutgoingKind::Appservice(appservice_id);
@@ -616,6 +708,13 @@ pub async fn send_federation_request(
     destination: &ServerName,
     request: reqwest::Request,
 ) -> AppResult<reqwest::Response> {
+    crate::federation::check_server_allowed(destination)?;
+    if let Some(remaining) = crate::federation::destination::backoff_remaining(destination)? {
+        return Err(AppError::public(format!(
+            "{destination} is not reachable, retrying in {}s",
+            remaining.as_secs()
+        )));
+    }
     debug!("Waiting for permit");
     let max_request = max_request();
     let permit = max_request.acquire().await;
@@ -628,6 +727,9 @@ pub async fn send_federation_request(
     .await
     .map_err(|_| {
         warn!("Timeout waiting for server response of {}", url);
+        if let Err(e) = crate::federation::destination::record_failure(destination) {
+            error!("Failed to record the failure of {destination}: {e}");
+        }
         AppError::public("Timeout waiting for server response")
     })?;
     drop(permit);
@@ -647,69 +749,74 @@ pub async fn send_federation_request(
 //     response
 // }
 
-fn active_requests() -> AppResult<Vec<(i64, OutgoingKind, SendingEventType)>> {
+fn parse_request(item: DbOutgoingRequest) -> Option<(i64, OutgoingKind, SendingEventType)> {
+    let kind = match item.kind.as_str() {
+        "appservice" => OutgoingKind::Appservice(item.appservice_id?),
+        "push" => OutgoingKind::Push(item.user_id?, item.pushkey?),
+        "normal" => OutgoingKind::Normal(item.server_id?),
+        _ => return None,
+    };
+    let event = if let Some(value) = item.edu_json {
+        SendingEventType::Edu(value)
+    } else if let Some(pdu_id) = item.pdu_id {
+        SendingEventType::Pdu(pdu_id)
+    } else {
+        return None;
+    };
+    Some((item.id, kind, event))
+}
+
+fn all_requests() -> AppResult<Vec<(i64, OutgoingKind, SendingEventType)>> {
     Ok(outgoing_requests::table
-        .filter(outgoing_requests::state.eq("pending"))
+        .order_by(outgoing_requests::id)
         .load::<DbOutgoingRequest>(&mut *db::connect()?)?
         .into_iter()
-        .filter_map(|item| {
-            let kind = match item.kind.as_str() {
-                "appservice" => {
-                    if let Some(appservice_id) = &item.appservice_id {
-                        OutgoingKind::Appservice(appservice_id.clone())
-                    } else {
-                        return None;
-                    }
-                }
-                "push" => {
-                    if let (Some(user_id), Some(pushkey)) = (item.user_id.clone(), item.pushkey.clone()) {
-                        OutgoingKind::Push(user_id, pushkey)
-                    } else {
-                        return None;
-                    }
-                }
-                "normal" => {
-                    if let Some(server_id) = &item.server_id {
-                        OutgoingKind::Normal(server_id.to_owned())
-                    } else {
-                        return None;
-                    }
-                }
-                _ => return None,
-            };
-            let event = if let Some(value) = item.edu_json {
-                SendingEventType::Edu(value)
-            } else if let Some(pdu_id) = item.pdu_id {
-                SendingEventType::Pdu(pdu_id)
-            } else {
-                return None;
-            };
-            Some((item.id, kind, event))
-        })
+        .filter_map(parse_request)
         .collect())
 }
 
-fn delete_request(id: i64) -> AppResult<()> {
-    diesel::delete(outgoing_requests::table.find(id)).execute(&mut *db::connect()?)?;
+/// Requests of transactions interrupted by a restart are sent again with the queued ones.
+fn reset_active_requests() -> AppResult<()> {
+    diesel::update(outgoing_requests::table.filter(outgoing_requests::state.eq("pending")))
+        .set(outgoing_requests::state.eq("created"))
+        .execute(&mut *db::connect()?)?;
     Ok(())
 }
 
-fn delete_all_active_requests_for(outgoing_kind: &OutgoingKind) -> AppResult<()> {
-    diesel::delete(
-        outgoing_requests::table
-            .filter(outgoing_requests::kind.eq(outgoing_kind.name()))
-            .filter(outgoing_requests::state.eq("pending")),
-    )
-    .execute(&mut *db::connect()?)?;
+fn requests_for(outgoing_kind: &OutgoingKind) -> outgoing_requests::BoxedQuery<'static, Pg> {
+    let query = outgoing_requests::table
+        .filter(outgoing_requests::kind.eq(outgoing_kind.name()))
+        .into_boxed();
+    match outgoing_kind {
+        OutgoingKind::Appservice(appservice_id) => {
+            query.filter(outgoing_requests::appservice_id.eq(appservice_id.clone()))
+        }
+        OutgoingKind::Push(user_id, pushkey) => query
+            .filter(outgoing_requests::user_id.eq(user_id.clone()))
+            .filter(outgoing_requests::pushkey.eq(pushkey.clone())),
+        OutgoingKind::Normal(server_id) => query.filter(outgoing_requests::server_id.eq(server_id.clone())),
+    }
+}
+
+fn delete_requests(ids: &[i64]) -> AppResult<()> {
+    diesel::delete(outgoing_requests::table.filter(outgoing_requests::id.eq_any(ids))).execute(&mut *db::connect()?)?;
 
     Ok(())
 }
 
-fn delete_all_requests_for(outgoing_kind: &OutgoingKind) -> AppResult<()> {
-    diesel::delete(outgoing_requests::table.filter(outgoing_requests::kind.eq(outgoing_kind.name())))
-        .execute(&mut *db::connect()?)?;
+fn delete_all_active_requests_for(outgoing_kind: &OutgoingKind) -> AppResult<()> {
+    let ids = active_requests_for(outgoing_kind)?
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    delete_requests(&ids)
+}
 
-    Ok(())
+fn delete_all_requests_for(outgoing_kind: &OutgoingKind) -> AppResult<()> {
+    let ids = requests_for(outgoing_kind)
+        .select(outgoing_requests::id)
+        .load::<i64>(&mut *db::connect()?)?;
+    delete_requests(&ids)
 }
 
 fn queue_requests(requests: &[(&OutgoingKind, SendingEventType)]) -> AppResult<Vec<i64>> {
@@ -752,40 +859,93 @@ fn queue_requests(requests: &[(&OutgoingKind, SendingEventType)]) -> AppResult<V
 }
 
 fn active_requests_for(outgoing_kind: &OutgoingKind) -> AppResult<Vec<(i64, SendingEventType)>> {
-    let list = outgoing_requests::table
-        .filter(outgoing_requests::kind.eq(outgoing_kind.name()))
+    Ok(requests_for(outgoing_kind)
+        .filter(outgoing_requests::state.eq("pending"))
+        .order_by(outgoing_requests::id)
         .load::<DbOutgoingRequest>(&mut *db::connect()?)?
         .into_iter()
-        .filter_map(|r| {
-            if let Some(value) = r.edu_json {
-                Some((r.id, SendingEventType::Edu(value)))
-            } else if let Some(pdu_id) = r.pdu_id {
-                Some((r.id, SendingEventType::Pdu(pdu_id)))
-            } else {
-                None
-            }
-        })
-        .collect();
-
-    Ok(list)
+        .filter_map(parse_request)
+        .map(|(id, _, event)| (id, event))
+        .collect())
 }
 
 fn queued_requests(outgoing_kind: &OutgoingKind) -> AppResult<Vec<(i64, SendingEventType)>> {
-    Ok(outgoing_requests::table
-        .filter(outgoing_requests::kind.eq(outgoing_kind.name()))
+    Ok(requests_for(outgoing_kind)
+        .filter(outgoing_requests::state.ne("pending"))
+        .order_by(outgoing_requests::id)
         .load::<DbOutgoingRequest>(&mut *db::connect()?)?
         .into_iter()
-        .filter_map(|r| {
-            if let Some(value) = r.edu_json {
-                Some((r.id, SendingEventType::Edu(value)))
-            } else if let Some(pdu_id) = r.pdu_id {
-                Some((r.id, SendingEventType::Pdu(pdu_id)))
-            } else {
-                None
-            }
-        })
+        .filter_map(parse_request)
+        .map(|(id, _, event)| (id, event))
         .collect())
 }
+
+/// Picks the queued requests of the next transaction, at most `MAX_PDUS_PER_TRANSACTION` PDUs and
+/// `MAX_EDUS_PER_TRANSACTION` EDUs, the others wait for the following transactions.
+///
+/// Presence and typing EDUs replaced by a newer one of the same user are dropped, so that a server
+/// coming back after a long time gets the latest state only.
+fn next_transaction_requests(outgoing_kind: &OutgoingKind) -> AppResult<Vec<(i64, SendingEventType)>> {
+    let requests = queued_requests(outgoing_kind)?
+        .into_iter()
+        .map(|(id, event)| {
+            let key = match &event {
+                SendingEventType::Edu(edu) => edu_latest_key(edu),
+                SendingEventType::Pdu(_) => None,
+            };
+            (id, event, key)
+        })
+        .collect::<Vec<_>>();
+    let mut latest_ids = HashMap::new();
+    for (id, _, key) in &requests {
+        if let Some(key) = key {
+            latest_ids.insert(key.clone(), *id);
+        }
+    }
+
+    let mut selected = Vec::new();
+    let mut superseded = Vec::new();
+    let (mut pdu_count, mut edu_count) = (0, 0);
+    for (id, event, key) in requests {
+        if key.is_some_and(|key| latest_ids.get(&key) != Some(&id)) {
+            superseded.push(id);
+            continue;
+        }
+        let count = match &event {
+            SendingEventType::Pdu(_) => (&mut pdu_count, MAX_PDUS_PER_TRANSACTION),
+            SendingEventType::Edu(_) => (&mut edu_count, MAX_EDUS_PER_TRANSACTION),
+        };
+        if *count.0 < count.1 {
+            *count.0 += 1;
+            selected.push((id, event));
+        }
+    }
+    delete_requests(&superseded)?;
+
+    Ok(selected)
+}
+
+/// Identifies the EDUs of which only the latest one matters: the presence of a user and the typing
+/// state of a user in a room.
+fn edu_latest_key(edu: &[u8]) -> Option<(String, String)> {
+    let edu = serde_json::from_slice::<JsonValue>(edu).ok()?;
+    let content = edu.get("content")?;
+    match edu.get("edu_type")?.as_str()? {
+        "m.presence" => match content.get("push")?.as_array()?.as_slice() {
+            [update] => Some(("m.presence".to_owned(), update.get("user_id")?.as_str()?.to_owned())),
+            _ => None,
+        },
+        "m.typing" => Some((
+            "m.typing".to_owned(),
+            format!(
+                "{} {}",
+                content.get("room_id")?.as_str()?,
+                content.get("user_id")?.as_str()?
+            ),
+        )),
+        _ => None,
+    }
+}
 fn mark_as_active(events: &[(i64, SendingEventType)]) -> AppResult<()> {
     for (id, e) in events {
         let value = if let SendingEventType::Edu(value) = &e {
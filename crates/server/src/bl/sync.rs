@@ -7,9 +7,9 @@ use tokio::sync::watch::Sender;
 
 use crate::core::client::filter::{FilterDefinition, LazyLoadOptions};
 use crate::core::client::sync_events::{
-    EphemeralV3, FilterV3, GlobalAccountDataV3, InviteStateV3, InvitedRoomV3, JoinedRoomV3, LeftRoomV3, PresenceV3,
-    RoomAccountDataV3, RoomSummaryV3, RoomsV3, StateV3, SyncEventsReqArgsV3, SyncEventsResBodyV3, TimelineV3,
-    ToDeviceV3, UnreadNotificationsCount,
+    EphemeralV3, FilterV3, GlobalAccountDataV3, InviteStateV3, InvitedRoomV3, JoinedRoomV3, KnockStateV3,
+    KnockedRoomV3, LeftRoomV3, PresenceV3, RoomAccountDataV3, RoomSummaryV3, RoomsV3, StateV3, SyncEventsReqArgsV3,
+    SyncEventsResBodyV3, TimelineV3, ToDeviceV3, UnreadNotificationsCount,
 };
 use crate::core::device::DeviceLists;
 use crate::core::events::room::member::{MembershipState, RoomMemberEventContent};
@@ -32,7 +32,7 @@ pub fn sync_events(
 ) -> impl Future<Output = AppResult<()>> {
     Box::pin(async move {
         // Setup watchers, so if there's no response, we can wait for them
-        let watcher = crate::watch(&sender_id, &sender_device_id);
+        let watcher = crate::watch(&sender_id);
 
         let curr_sn = crate::curr_sn()?;
         let since_sn = args.since.as_ref().and_then(|s| s.parse().ok()).unwrap_or_default();
@@ -222,6 +222,20 @@ pub fn sync_events(
             })
             .collect();
 
+        let knocked_rooms: BTreeMap<_, _> = crate::user::knocked_rooms(&sender_id, since_sn)?
+            .into_iter()
+            .map(|(room_id, knock_state_events)| {
+                (
+                    room_id,
+                    KnockedRoomV3 {
+                        knock_state: KnockStateV3 {
+                            events: knock_state_events,
+                        },
+                    },
+                )
+            })
+            .collect();
+
         for left_room in left_rooms.keys() {
             for user_id in crate::room::get_joined_users(left_room, None)? {
                 let dont_share_encrypted_room =
@@ -275,7 +289,7 @@ pub fn sync_events(
                 leave: left_rooms,
                 join: joined_rooms,
                 invite: invited_rooms,
-                knock: BTreeMap::new(), // TODO
+                knock: knocked_rooms,
             },
             presence: PresenceV3 {
                 events: presence_updates
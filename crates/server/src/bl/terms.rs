@@ -0,0 +1,128 @@
+//! Consent to the terms of service
+//!
+//! Users accept the policies of `[terms]` with the `m.login.terms` stage when registering, or on
+//! the consent page. Acceptance is recorded per version, users accept the policies again when the
+//! version changes.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use diesel::prelude::*;
+use ring::hmac;
+use url::Url;
+
+use crate::config::TermsConfig;
+use crate::core::identifiers::*;
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, diesel_exists, AppResult, JsonValue, MatrixError};
+
+#[derive(Identifiable, Queryable, Debug, Clone)]
+#[diesel(table_name = user_consents)]
+pub struct DbUserConsent {
+    pub id: i64,
+    pub user_id: OwnedUserId,
+    pub version: String,
+    pub accepted_at: i64,
+}
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = user_consents)]
+pub struct NewDbUserConsent {
+    pub user_id: OwnedUserId,
+    pub version: String,
+    pub accepted_at: i64,
+}
+
+pub fn is_enabled() -> bool {
+    crate::config().terms.is_some()
+}
+
+/// The policies as given in the `m.login.terms` parameters and by the identity service API: the
+/// version and the document of each language, by policy.
+pub fn policies(terms: &TermsConfig) -> JsonValue {
+    let mut policies = serde_json::Map::new();
+    for policy in &terms.policies {
+        let entry = policies
+            .entry(policy.id.clone())
+            .or_insert_with(|| serde_json::json!({ "version": terms.version }));
+        entry[&policy.lang] = serde_json::json!({
+            "name": policy.name,
+            "url": policy.url,
+        });
+    }
+    JsonValue::Object(policies)
+}
+
+/// Records that the user accepted `version` of the policies.
+pub fn record_consent(user_id: &UserId, version: &str) -> AppResult<()> {
+    diesel::insert_into(user_consents::table)
+        .values(NewDbUserConsent {
+            user_id: user_id.to_owned(),
+            version: version.to_owned(),
+            accepted_at: UnixMillis::now().get() as i64,
+        })
+        .on_conflict_do_nothing()
+        .execute(&mut *db::connect()?)?;
+    Ok(())
+}
+
+/// Returns the last acceptance of the user, whatever the version.
+pub fn get_last_consent(user_id: &UserId) -> AppResult<Option<DbUserConsent>> {
+    user_consents::table
+        .filter(user_consents::user_id.eq(user_id))
+        .order_by(user_consents::accepted_at.desc())
+        .first::<DbUserConsent>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+/// Whether the user accepted the current version of the policies, always true when there are none.
+pub fn has_consented(user_id: &UserId) -> AppResult<bool> {
+    let Some(terms) = &crate::config().terms else {
+        return Ok(true);
+    };
+    let query = user_consents::table
+        .filter(user_consents::user_id.eq(user_id))
+        .filter(user_consents::version.eq(&terms.version));
+    diesel_exists!(query, &mut *db::connect()?).map_err(Into::into)
+}
+
+fn consent_key(terms: &TermsConfig) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, terms.form_secret.as_bytes())
+}
+
+/// Link to the consent page of the user, signed so that only the user gets it.
+pub fn consent_url(terms: &TermsConfig, user_id: &UserId) -> String {
+    let signature = hmac::sign(&consent_key(terms), user_id.as_bytes());
+    let base = format!("{}/_matrix/consent", crate::well_known_client().trim_end_matches('/'));
+    Url::parse_with_params(
+        &base,
+        &[
+            ("u", user_id.as_str()),
+            ("h", &URL_SAFE_NO_PAD.encode(signature.as_ref())),
+        ],
+    )
+    .map(String::from)
+    .unwrap_or(base)
+}
+
+/// Checks the signature of a link to the consent page.
+pub fn verify_consent_signature(terms: &TermsConfig, user_id: &UserId, signature: &str) -> bool {
+    let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+        return false;
+    };
+    hmac::verify(&consent_key(terms), user_id.as_bytes(), &signature).is_ok()
+}
+
+/// Refuses events from users who didn't accept the current version of the policies, the error
+/// links to their consent page.
+pub fn check_consent(user_id: &UserId) -> AppResult<()> {
+    let Some(terms) = &crate::config().terms else {
+        return Ok(());
+    };
+    if !terms.block_events_without_consent || has_consented(user_id)? {
+        return Ok(());
+    }
+    let consent_url = consent_url(terms, user_id);
+    let message = format!("You have to accept the terms of service at {consent_url} to continue.");
+    Err(MatrixError::consent_not_given(consent_url, message).into())
+}
@@ -0,0 +1,238 @@
+//! Validation of third party identifiers by the homeserver
+//!
+//! A validation session is created for an address and a client secret, the address is sent a link
+//! with a token. Opening the link validates the session, which can then be used to add the address
+//! to an account, to register with it or to reset the password of the account it belongs to.
+
+use std::time::Duration;
+
+use diesel::prelude::*;
+use reqwest::Url;
+
+use crate::core::identifiers::*;
+use crate::core::third_party::Medium;
+use crate::core::UnixMillis;
+use crate::hoops::RateLimitClass;
+use crate::mailer::MailTemplate;
+use crate::schema::*;
+use crate::{db, utils, AppResult, MatrixError, SESSION_ID_LENGTH, TOKEN_LENGTH};
+
+#[derive(Identifiable, Queryable, Debug, Clone)]
+#[diesel(table_name = threepid_validation_sessions)]
+pub struct DbValidationSession {
+    pub id: i64,
+    pub session_id: OwnedSessionId,
+    pub medium: String,
+    pub address: String,
+    pub client_secret: String,
+    pub last_send_attempt: i64,
+    pub validated_at: Option<i64>,
+    pub created_at: i64,
+}
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = threepid_validation_sessions)]
+pub struct NewDbValidationSession {
+    pub session_id: OwnedSessionId,
+    pub medium: String,
+    pub address: String,
+    pub client_secret: String,
+    pub last_send_attempt: i64,
+    pub validated_at: Option<i64>,
+    pub created_at: i64,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = threepid_validation_tokens)]
+pub struct NewDbValidationToken {
+    pub token: String,
+    pub session_id: OwnedSessionId,
+    pub next_link: Option<String>,
+    pub expired_at: i64,
+    pub created_at: i64,
+}
+
+/// Email addresses are compared case-insensitively.
+pub fn canonicalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Users are sent to the `next_link` of clients after validating their address, only web pages of
+/// the allowed domains are accepted.
+fn check_next_link(next_link: &str, domain_allowlist: Option<&[String]>) -> Result<(), MatrixError> {
+    let next_link = Url::parse(next_link).map_err(|_| MatrixError::invalid_param("Invalid next_link."))?;
+    if !matches!(next_link.scheme(), "http" | "https") {
+        return Err(MatrixError::invalid_param("next_link must be an http or https URL."));
+    }
+    if let Some(domain_allowlist) = domain_allowlist {
+        let host = next_link.host_str().unwrap_or_default();
+        if !domain_allowlist.iter().any(|domain| domain.eq_ignore_ascii_case(host)) {
+            return Err(MatrixError::invalid_param("next_link domain is not allowed."));
+        }
+    }
+    Ok(())
+}
+
+/// Sends a validation link to `email`, returns the ID of the validation session.
+///
+/// The link is only sent again for an existing session when `send_attempt` is larger than the one
+/// of the last request, so clients can safely retry. Links sent to an address are limited by
+/// `rate_limits.threepid_validation`, whatever the session.
+pub async fn request_email_validation(
+    template: MailTemplate,
+    client_secret: &ClientSecret,
+    email: &str,
+    send_attempt: u64,
+    next_link: Option<&str>,
+) -> AppResult<OwnedSessionId> {
+    let Some(conf) = &crate::config().email else {
+        return Err(MatrixError::threepid_denied("Email is not enabled on this server.").into());
+    };
+    if let Some(next_link) = next_link {
+        check_next_link(next_link, conf.next_link_domain_allowlist.as_deref())?;
+    }
+    let email = canonicalize_email(email);
+    let session = threepid_validation_sessions::table
+        .filter(threepid_validation_sessions::medium.eq(Medium::Email.as_str()))
+        .filter(threepid_validation_sessions::address.eq(&email))
+        .filter(threepid_validation_sessions::client_secret.eq(client_secret.as_str()))
+        .first::<DbValidationSession>(&mut *db::connect()?)
+        .optional()?;
+    if let Some(session) = &session {
+        if send_attempt as i64 <= session.last_send_attempt {
+            return Ok(session.session_id.clone());
+        }
+    }
+    if crate::config().rate_limits.enabled {
+        crate::hoops::check_rate(RateLimitClass::ThreepidValidation, &email)?;
+    }
+    let session_id = match session {
+        Some(session) => {
+            diesel::update(threepid_validation_sessions::table.find(session.id))
+                .set(threepid_validation_sessions::last_send_attempt.eq(send_attempt as i64))
+                .execute(&mut *db::connect()?)?;
+            session.session_id
+        }
+        None => {
+            let session_id = OwnedSessionId::try_from(utils::random_string(SESSION_ID_LENGTH))?;
+            diesel::insert_into(threepid_validation_sessions::table)
+                .values(NewDbValidationSession {
+                    session_id: session_id.clone(),
+                    medium: Medium::Email.to_string(),
+                    address: email.clone(),
+                    client_secret: client_secret.to_string(),
+                    last_send_attempt: send_attempt as i64,
+                    validated_at: None,
+                    created_at: UnixMillis::now().get() as i64,
+                })
+                .execute(&mut *db::connect()?)?;
+            session_id
+        }
+    };
+
+    let token = utils::random_string(TOKEN_LENGTH);
+    let lifetime = Duration::from_secs(conf.validation_token_lifetime_s);
+    diesel::insert_into(threepid_validation_tokens::table)
+        .values(NewDbValidationToken {
+            token: token.clone(),
+            session_id: session_id.clone(),
+            next_link: next_link.map(ToOwned::to_owned),
+            expired_at: UnixMillis::now().get() as i64 + lifetime.as_millis() as i64,
+            created_at: UnixMillis::now().get() as i64,
+        })
+        .execute(&mut *db::connect()?)?;
+
+    let mut link = Url::parse(&format!(
+        "{}/_matrix/identity/v2/validate/email/submitToken",
+        crate::well_known_client().trim_end_matches('/')
+    ))?;
+    link.query_pairs_mut()
+        .append_pair("sid", session_id.as_str())
+        .append_pair("client_secret", client_secret.as_str())
+        .append_pair("token", &token);
+    crate::mailer::send_template(&email, template, link.as_str()).await?;
+    Ok(session_id)
+}
+
+/// Validates a session with the token that was sent to its address, returns the `next_link` the
+/// token was requested with.
+pub fn submit_token(session_id: &SessionId, client_secret: &ClientSecret, token: &str) -> AppResult<Option<String>> {
+    let session = threepid_validation_sessions::table
+        .filter(threepid_validation_sessions::session_id.eq(session_id))
+        .filter(threepid_validation_sessions::client_secret.eq(client_secret.as_str()))
+        .first::<DbValidationSession>(&mut *db::connect()?)
+        .optional()?
+        .ok_or_else(|| MatrixError::threepid_auth_failed("Unknown validation session."))?;
+    let next_link = threepid_validation_tokens::table
+        .filter(threepid_validation_tokens::session_id.eq(session_id))
+        .filter(threepid_validation_tokens::token.eq(token))
+        .filter(threepid_validation_tokens::expired_at.gt(UnixMillis::now().get() as i64))
+        .select(threepid_validation_tokens::next_link)
+        .first::<Option<String>>(&mut *db::connect()?)
+        .optional()?
+        .ok_or_else(|| MatrixError::threepid_auth_failed("Invalid or expired validation token."))?;
+
+    if session.validated_at.is_none() {
+        diesel::update(threepid_validation_sessions::table.find(session.id))
+            .set(threepid_validation_sessions::validated_at.eq(UnixMillis::now().get() as i64))
+            .execute(&mut *db::connect()?)?;
+    }
+    diesel::delete(threepid_validation_tokens::table.filter(threepid_validation_tokens::session_id.eq(session_id)))
+        .execute(&mut *db::connect()?)?;
+    Ok(next_link)
+}
+
+/// Returns the session if it was validated.
+pub fn get_validated_session(
+    session_id: &SessionId,
+    client_secret: &ClientSecret,
+) -> AppResult<Option<DbValidationSession>> {
+    threepid_validation_sessions::table
+        .filter(threepid_validation_sessions::session_id.eq(session_id))
+        .filter(threepid_validation_sessions::client_secret.eq(client_secret.as_str()))
+        .filter(threepid_validation_sessions::validated_at.is_not_null())
+        .first::<DbValidationSession>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+/// Returns the validated session with the ID.
+pub fn get_session(session_id: &str) -> AppResult<Option<DbValidationSession>> {
+    threepid_validation_sessions::table
+        .filter(threepid_validation_sessions::session_id.eq(session_id))
+        .filter(threepid_validation_sessions::validated_at.is_not_null())
+        .first::<DbValidationSession>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+/// Removes a session once it was used, so it can't be used again.
+pub fn delete_session(session_id: &SessionId) -> AppResult<()> {
+    diesel::delete(threepid_validation_tokens::table.filter(threepid_validation_tokens::session_id.eq(session_id)))
+        .execute(&mut *db::connect()?)?;
+    diesel::delete(threepid_validation_sessions::table.filter(threepid_validation_sessions::session_id.eq(session_id)))
+        .execute(&mut *db::connect()?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_link_must_be_a_web_page() {
+        assert!(check_next_link("https://app.example.com/validated", None).is_ok());
+        assert!(check_next_link("http://app.example.com", None).is_ok());
+        assert!(check_next_link("javascript:alert(1)", None).is_err());
+        assert!(check_next_link("data:text/html,<script>alert(1)</script>", None).is_err());
+        assert!(check_next_link("not a url", None).is_err());
+    }
+
+    #[test]
+    fn next_link_domain_must_be_allowed() {
+        let allowlist = ["app.example.com".to_owned()];
+        assert!(check_next_link("https://APP.example.com/validated", Some(&allowlist)).is_ok());
+        assert!(check_next_link("https://evil.example.com/", Some(&allowlist)).is_err());
+        assert!(check_next_link("https://app.example.com.evil.com/", Some(&allowlist)).is_err());
+        assert!(check_next_link("https://app.example.com/", Some(&[])).is_err());
+    }
+}
@@ -1,42 +1,135 @@
-use std::collections::BTreeMap;
-use std::sync::LazyLock;
+//! User-interactive authentication
+//!
+//! Sessions are stored in the database with the request they authorize, so they can be continued
+//! on any process and survive restarts. Unfinished sessions expire after `uiaa.session_lifetime_s`.
+
+use std::time::Duration;
 
 use diesel::prelude::*;
+use serde::Deserialize;
 
 use crate::core::identifiers::*;
 use crate::core::serde::CanonicalJsonValue;
 use crate::core::{
-    client::uiaa::{AuthData, AuthError, AuthType, Password, UiaaInfo, UserIdentifier},
-    JsonValue,
+    client::uiaa::{AuthData, AuthError, AuthType, EmailIdentity, Password, ReCaptcha, UiaaInfo, UserIdentifier},
+    error::ErrorKind,
+    JsonValue, UnixMillis,
 };
 use crate::schema::*;
 use crate::SESSION_ID_LENGTH;
-use crate::{db, utils, AppResult, MatrixError};
+use crate::{db, utils, AppError, AppResult, MatrixError};
 
-use super::LazyRwLock;
+/// Fields of the stored request that are not kept, they are sent again with every attempt.
+const SECRET_REQUEST_FIELDS: [&str; 4] = ["auth", "password", "new_password", "client_secret"];
 
-static UIAA_REQUESTS: LazyRwLock<BTreeMap<(OwnedUserId, OwnedDeviceId, String), CanonicalJsonValue>> =
-    LazyLock::new(Default::default);
+#[derive(Identifiable, Queryable, Debug, Clone)]
+#[diesel(table_name = user_uiaa_datas)]
+pub struct DbUiaaSession {
+    pub id: i64,
+    pub user_id: OwnedUserId,
+    pub device_id: OwnedDeviceId,
+    pub session: String,
+    pub uiaa_info: JsonValue,
+    pub request: Option<JsonValue>,
+    pub registration_token: Option<String>,
+    pub threepid_session_id: Option<String>,
+    pub expires_at: i64,
+    pub created_at: i64,
+}
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = user_uiaa_datas)]
+pub struct NewDbUiaaSession {
+    pub user_id: OwnedUserId,
+    pub device_id: OwnedDeviceId,
+    pub session: String,
+    pub uiaa_info: JsonValue,
+    pub request: Option<JsonValue>,
+    pub registration_token: Option<String>,
+    pub threepid_session_id: Option<String>,
+    pub expires_at: i64,
+    pub created_at: i64,
+}
 
-/// Creates a new Uiaa session. Make sure the session token is unique.
+/// What the stages of a session leave for the request it authorizes.
+#[derive(Default, Debug, Clone)]
+pub struct SessionExtras {
+    /// The registration token `m.login.registration_token` reserved.
+    pub registration_token: Option<String>,
+    /// The validation session `m.login.email.identity` was completed with.
+    pub threepid_session_id: Option<String>,
+}
+
+impl From<&DbUiaaSession> for SessionExtras {
+    fn from(db_session: &DbUiaaSession) -> Self {
+        Self {
+            registration_token: db_session.registration_token.clone(),
+            threepid_session_id: db_session.threepid_session_id.clone(),
+        }
+    }
+}
+
+/// Starts a new session for `uiaa_info` and stores the request it authorizes.
 pub fn create_session(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    uiaa_info: &mut UiaaInfo,
+    request: Option<&CanonicalJsonValue>,
+) -> AppResult<()> {
+    uiaa_info
+        .session
+        .get_or_insert_with(|| utils::random_string(SESSION_ID_LENGTH));
+    let request = match request {
+        Some(request) => {
+            let mut request = serde_json::to_value(request)?;
+            if let Some(request) = request.as_object_mut() {
+                for field in SECRET_REQUEST_FIELDS {
+                    request.remove(field);
+                }
+            }
+            Some(request)
+        }
+        None => None,
+    };
+    save_session(user_id, device_id, uiaa_info, &SessionExtras::default(), request)
+}
+
+/// Stores the state of a session, `request` is only kept when the session is new.
+fn save_session(
     user_id: &UserId,
     device_id: &DeviceId,
     uiaa_info: &UiaaInfo,
-    json_body: &CanonicalJsonValue,
+    extras: &SessionExtras,
+    request: Option<JsonValue>,
 ) -> AppResult<()> {
-    set_uiaa_request(
-        user_id,
-        device_id,
-        uiaa_info.session.as_ref().expect("session should be set"), // TODO: better session error handling (why is it optional in palpo?)
-        json_body,
-    );
-    update_session(
-        user_id,
-        device_id,
-        uiaa_info.session.as_ref().expect("session should be set"),
-        Some(uiaa_info),
-    )
+    let session = uiaa_info.session.clone().expect("session is always set");
+    let uiaa_info = serde_json::to_value(uiaa_info)?;
+    let now = UnixMillis::now().get() as i64;
+    let lifetime = Duration::from_secs(crate::config().uiaa.session_lifetime_s);
+    diesel::insert_into(user_uiaa_datas::table)
+        .values(NewDbUiaaSession {
+            user_id: user_id.to_owned(),
+            device_id: device_id.to_owned(),
+            session,
+            uiaa_info: uiaa_info.clone(),
+            request,
+            registration_token: extras.registration_token.clone(),
+            threepid_session_id: extras.threepid_session_id.clone(),
+            expires_at: now + lifetime.as_millis() as i64,
+            created_at: now,
+        })
+        .on_conflict((
+            user_uiaa_datas::user_id,
+            user_uiaa_datas::device_id,
+            user_uiaa_datas::session,
+        ))
+        .do_update()
+        .set((
+            user_uiaa_datas::uiaa_info.eq(&uiaa_info),
+            user_uiaa_datas::registration_token.eq(&extras.registration_token),
+            user_uiaa_datas::threepid_session_id.eq(&extras.threepid_session_id),
+        ))
+        .execute(&mut *db::connect()?)?;
+    Ok(())
 }
 
 pub fn update_session(
@@ -46,27 +139,15 @@ pub fn update_session(
     uiaa_info: Option<&UiaaInfo>,
 ) -> AppResult<()> {
     if let Some(uiaa_info) = uiaa_info {
-        let uiaa_info = serde_json::to_value(uiaa_info)?;
-        diesel::insert_into(user_uiaa_datas::table)
-            .values((
-                user_uiaa_datas::user_id.eq(user_id),
-                user_uiaa_datas::device_id.eq(device_id),
-                user_uiaa_datas::session.eq(session),
-                user_uiaa_datas::uiaa_info.eq(&uiaa_info),
-            ))
-            .on_conflict((
-                user_uiaa_datas::user_id,
-                user_uiaa_datas::device_id,
-                user_uiaa_datas::session,
-            ))
-            .do_update()
-            .set(user_uiaa_datas::uiaa_info.eq(&uiaa_info))
-            .execute(&mut *db::connect()?)?;
+        let extras = get_db_session(user_id, device_id, session)?
+            .map(|db_session| SessionExtras::from(&db_session))
+            .unwrap_or_default();
+        save_session(user_id, device_id, uiaa_info, &extras, None)?;
     } else {
         diesel::delete(
             user_uiaa_datas::table
                 .filter(user_uiaa_datas::user_id.eq(user_id))
-                .filter(user_uiaa_datas::device_id.eq(user_id))
+                .filter(user_uiaa_datas::device_id.eq(device_id))
                 .filter(user_uiaa_datas::session.eq(session)),
         )
         .execute(&mut *db::connect()?)?;
@@ -74,31 +155,99 @@ pub fn update_session(
 
     Ok(())
 }
-pub fn get_session(user_id: &UserId, device_id: &DeviceId, session: &str) -> AppResult<UiaaInfo> {
-    let uiaa_info = user_uiaa_datas::table
+
+fn get_db_session(user_id: &UserId, device_id: &DeviceId, session: &str) -> AppResult<Option<DbUiaaSession>> {
+    user_uiaa_datas::table
         .filter(user_uiaa_datas::user_id.eq(user_id))
         .filter(user_uiaa_datas::device_id.eq(device_id))
         .filter(user_uiaa_datas::session.eq(session))
-        .select(user_uiaa_datas::uiaa_info)
-        .first::<JsonValue>(&mut *db::connect()?)?;
-    Ok(serde_json::from_value(uiaa_info)?)
+        .filter(user_uiaa_datas::expires_at.gt(UnixMillis::now().get() as i64))
+        .first::<DbUiaaSession>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+pub fn get_session(user_id: &UserId, device_id: &DeviceId, session: &str) -> AppResult<UiaaInfo> {
+    let Some(db_session) = get_db_session(user_id, device_id, session)? else {
+        return Err(MatrixError::not_found("Unknown or expired session.").into());
+    };
+    Ok(serde_json::from_value(db_session.uiaa_info)?)
 }
-pub fn try_auth(
+
+/// Continues the session with the stage `auth` completes, returns whether the session is completed.
+pub async fn try_auth(
     user_id: &UserId,
     device_id: &DeviceId,
     auth: &AuthData,
     uiaa_info: &UiaaInfo,
 ) -> AppResult<(bool, UiaaInfo)> {
-    let mut uiaa_info = auth
-        .session()
-        .map(|session| get_session(user_id, device_id, session))
-        .unwrap_or_else(|| Ok(uiaa_info.clone()))?;
+    let (worked, uiaa_info, extras) = try_auth_with_extras(user_id, device_id, auth, uiaa_info).await?;
+    // Only registration uses the extras, a token reserved by another request is given back.
+    if let Some(registration_token) = &extras.registration_token {
+        crate::user::release_registration_token(registration_token)?;
+    }
+    Ok((worked, uiaa_info))
+}
+
+/// Like [`try_auth`], also returns what the stages left for the request once the session is
+/// completed. The caller has to complete or release the registration token it reserved.
+pub async fn try_auth_with_extras(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    auth: &AuthData,
+    uiaa_info: &UiaaInfo,
+) -> AppResult<(bool, UiaaInfo, SessionExtras)> {
+    let (mut uiaa_info, mut extras) = match auth.session() {
+        Some(session) => {
+            let Some(db_session) = get_db_session(user_id, device_id, session)? else {
+                return Err(MatrixError::not_found("Unknown or expired session.").into());
+            };
+            let extras = SessionExtras::from(&db_session);
+            (serde_json::from_value::<UiaaInfo>(db_session.uiaa_info)?, extras)
+        }
+        None => (uiaa_info.clone(), SessionExtras::default()),
+    };
 
     if uiaa_info.session.is_none() {
         uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
     }
-    let conf = crate::config();
+    let session = uiaa_info.session.clone().expect("session is always set");
+    if !check_stage(user_id, auth, &mut uiaa_info, &mut extras).await? {
+        save_session(user_id, device_id, &uiaa_info, &extras, None)?;
+        return Ok((false, uiaa_info, SessionExtras::default()));
+    }
+
+    // Check if a flow now succeeds
+    let mut completed = false;
+    'flows: for flow in &mut uiaa_info.flows {
+        for stage in &flow.stages {
+            if !uiaa_info.completed.contains(stage) {
+                continue 'flows;
+            }
+        }
+        // We didn't break, so this flow succeeded!
+        completed = true;
+    }
 
+    if !completed {
+        save_session(user_id, device_id, &uiaa_info, &extras, None)?;
+        return Ok((false, uiaa_info, SessionExtras::default()));
+    }
+
+    // UIAA was successful! Remove this session and return true
+    update_session(user_id, device_id, &session, None)?;
+    Ok((true, uiaa_info, extras))
+}
+
+/// Checks the stage `auth` completes and records it in `uiaa_info`, returns false with
+/// `auth_error` set when it failed.
+async fn check_stage(
+    user_id: &UserId,
+    auth: &AuthData,
+    uiaa_info: &mut UiaaInfo,
+    extras: &mut SessionExtras,
+) -> AppResult<bool> {
+    let conf = crate::config();
     match auth {
         // Find out what the user completed
         AuthData::Password(Password {
@@ -119,64 +268,167 @@ pub fn try_auth(
                 return Err(MatrixError::unauthorized("User not found.").into());
             };
             crate::user::vertify_password(&user, &password)?;
+            if !uiaa_info.completed.contains(&AuthType::Password) {
+                uiaa_info.completed.push(AuthType::Password);
+            }
         }
-        AuthData::RegistrationToken(t) => {
-            if Some(t.token.trim()) == conf.registration_token.as_deref() {
+        AuthData::RegistrationToken(t) if !uiaa_info.completed.contains(&AuthType::RegistrationToken) => {
+            let token = t.token.trim();
+            if Some(token) == conf.registration_token.as_deref() {
+                uiaa_info.completed.push(AuthType::RegistrationToken);
+            } else if crate::user::reserve_registration_token(token)? {
+                // Completed once the user is registered, see `try_auth_with_extras`
+                extras.registration_token = Some(token.to_owned());
                 uiaa_info.completed.push(AuthType::RegistrationToken);
             } else {
                 uiaa_info.auth_error = Some(AuthError::forbidden("Invalid registration token."));
-                return Ok((false, uiaa_info));
+                return Ok(false);
+            }
+        }
+        AuthData::RegistrationToken(_) => {}
+        AuthData::EmailIdentity(EmailIdentity { threepid_creds, .. }) => {
+            match crate::threepid::get_validated_session(&threepid_creds.sid, &threepid_creds.client_secret)? {
+                Some(session) => {
+                    extras.threepid_session_id = Some(session.session_id.to_string());
+                    if !uiaa_info.completed.contains(&AuthType::EmailIdentity) {
+                        uiaa_info.completed.push(AuthType::EmailIdentity);
+                    }
+                }
+                None => {
+                    uiaa_info.auth_error = Some(AuthError::new(
+                        ErrorKind::ThreepidAuthFailed,
+                        "Email address has not been validated.",
+                    ));
+                    return Ok(false);
+                }
+            }
+        }
+        AuthData::ReCaptcha(ReCaptcha { response, .. }) if !uiaa_info.completed.contains(&AuthType::ReCaptcha) => {
+            if verify_recaptcha(response).await? {
+                uiaa_info.completed.push(AuthType::ReCaptcha);
+            } else {
+                uiaa_info.auth_error = Some(AuthError::new(ErrorKind::CaptchaInvalid, "Captcha was not solved."));
+                return Ok(false);
+            }
+        }
+        AuthData::ReCaptcha(_) => {}
+        AuthData::Terms(_) => {
+            if !uiaa_info.completed.contains(&AuthType::Terms) {
+                uiaa_info.completed.push(AuthType::Terms);
             }
         }
         AuthData::Dummy(_) => {
             uiaa_info.completed.push(AuthType::Dummy);
         }
+        // Stages completed through the fallback pages are already recorded in the session
+        AuthData::FallbackAcknowledgement(_) => {}
         k => error!("type not supported: {:?}", k),
     }
+    Ok(true)
+}
 
-    // Check if a flow now succeeds
-    let mut completed = false;
-    'flows: for flow in &mut uiaa_info.flows {
-        for stage in &flow.stages {
-            if !uiaa_info.completed.contains(stage) {
-                continue 'flows;
-            }
+async fn verify_recaptcha(response: &str) -> AppResult<bool> {
+    #[derive(Deserialize)]
+    struct VerifyResponse {
+        success: bool,
+    }
+
+    let Some(conf) = &crate::config().uiaa.recaptcha else {
+        return Err(MatrixError::unrecognized("Captchas are not enabled on this server.").into());
+    };
+    let response = crate::default_client()
+        .post(&conf.verify_url)
+        .form(&[("secret", conf.private_key.as_str()), ("response", response)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<VerifyResponse>()
+        .await?;
+    Ok(response.success)
+}
+
+fn get_db_session_by_id(session: &str) -> AppResult<Option<DbUiaaSession>> {
+    user_uiaa_datas::table
+        .filter(user_uiaa_datas::session.eq(session))
+        .filter(user_uiaa_datas::expires_at.gt(UnixMillis::now().get() as i64))
+        .first::<DbUiaaSession>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+/// Returns the user, device and info of a session, for the fallback pages which only know the
+/// session.
+pub fn get_session_by_id(session: &str) -> AppResult<Option<(OwnedUserId, OwnedDeviceId, UiaaInfo)>> {
+    let Some(db_session) = get_db_session_by_id(session)? else {
+        return Ok(None);
+    };
+    let uiaa_info = serde_json::from_value(db_session.uiaa_info)?;
+    Ok(Some((db_session.user_id, db_session.device_id, uiaa_info)))
+}
+
+/// Completes a stage of a session through its fallback page. The client finishes the session
+/// afterwards with a fallback acknowledgement. Returns the updated info of the session, with
+/// `auth_error` set when the stage failed.
+pub async fn complete_fallback_stage(session: &str, auth: &AuthData) -> AppResult<UiaaInfo> {
+    let Some(db_session) = get_db_session_by_id(session)? else {
+        return Err(MatrixError::not_found("Unknown or expired session.").into());
+    };
+    let mut extras = SessionExtras::from(&db_session);
+    let mut uiaa_info = serde_json::from_value::<UiaaInfo>(db_session.uiaa_info)?;
+    uiaa_info.auth_error = None;
+    match check_stage(&db_session.user_id, auth, &mut uiaa_info, &mut extras).await {
+        Ok(_) => {}
+        Err(AppError::Matrix(e)) => {
+            let message = e.body.message().unwrap_or("Authentication failed.").to_owned();
+            uiaa_info.auth_error = Some(AuthError::new(e.kind, message));
         }
-        // We didn't break, so this flow succeeded!
-        completed = true;
+        Err(e) => return Err(e),
+    }
+    if uiaa_info.auth_error.is_none() {
+        save_session(&db_session.user_id, &db_session.device_id, &uiaa_info, &extras, None)?;
     }
+    Ok(uiaa_info)
+}
 
-    if !completed {
-        crate::uiaa::update_session(
-            user_id,
-            device_id,
-            uiaa_info.session.as_ref().expect("session is always set"),
-            Some(&uiaa_info),
-        )?;
-        return Ok((false, uiaa_info));
+/// Returns the request the session was started with, without its secrets.
+pub fn get_uiaa_request(
+    user_id: &UserId,
+    device_id: &DeviceId,
+    session: &str,
+) -> AppResult<Option<CanonicalJsonValue>> {
+    let request = get_db_session(user_id, device_id, session)?.and_then(|db_session| db_session.request);
+    match request {
+        Some(request) => Ok(Some(serde_json::from_value(request)?)),
+        None => Ok(None),
+    }
+}
+
+/// Deletes expired sessions and releases the registration tokens they reserved.
+pub fn purge_expired_sessions() -> AppResult<usize> {
+    let registration_tokens =
+        diesel::delete(user_uiaa_datas::table.filter(user_uiaa_datas::expires_at.le(UnixMillis::now().get() as i64)))
+            .returning(user_uiaa_datas::registration_token)
+            .get_results::<Option<String>>(&mut *db::connect()?)?;
+    let count = registration_tokens.len();
+    for registration_token in registration_tokens.into_iter().flatten() {
+        crate::user::release_registration_token(&registration_token)?;
     }
+    Ok(count)
+}
 
-    // UIAA was successful! Remove this session and return true
-    crate::uiaa::update_session(
-        user_id,
-        device_id,
-        uiaa_info.session.as_ref().expect("session is always set"),
-        None,
-    )?;
-    Ok((true, uiaa_info))
-}
-
-pub fn set_uiaa_request(user_id: &UserId, device_id: &DeviceId, session: &str, request: &CanonicalJsonValue) {
-    UIAA_REQUESTS.write().expect("write UIAA_REQUESTS failed").insert(
-        (user_id.to_owned(), device_id.to_owned(), session.to_owned()),
-        request.to_owned(),
-    );
-}
-
-pub fn get_uiaa_request(user_id: &UserId, device_id: &DeviceId, session: &str) -> Option<CanonicalJsonValue> {
-    UIAA_REQUESTS
-        .read()
-        .expect("read UIAA_REQUESTS failed")
-        .get(&(user_id.to_owned(), device_id.to_owned(), session.to_owned()))
-        .map(|j| j.to_owned())
+/// Starts the job deleting expired sessions.
+pub fn start_cleanup() {
+    let period = Duration::from_secs(crate::config().cleanup_second_interval as u64);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match tokio::task::spawn_blocking(purge_expired_sessions).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(count)) => debug!("Deleted {count} expired UIAA sessions"),
+                Ok(Err(e)) => error!("Failed to delete expired UIAA sessions: {e}"),
+                Err(e) => error!("UIAA session cleanup job panicked: {e}"),
+            }
+        }
+    });
 }
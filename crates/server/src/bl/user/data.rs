@@ -50,13 +50,14 @@ pub fn set_data(
         occur_sn: crate::next_sn()? as i64,
         created_at: UnixMillis::now(),
     };
-    diesel::insert_into(user_datas::table)
+    let data = diesel::insert_into(user_datas::table)
         .values(&new_data)
         .on_conflict((user_datas::user_id, user_datas::room_id, user_datas::data_type))
         .do_update()
         .set(&new_data)
-        .get_result::<DbUserData>(&mut *db::connect()?)
-        .map_err(Into::into)
+        .get_result::<DbUserData>(&mut *db::connect()?)?;
+    crate::watcher::notify_user(user_id);
+    Ok(data)
 }
 
 /// Searches the account data for a specific kind.
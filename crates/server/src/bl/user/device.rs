@@ -52,14 +52,20 @@ pub struct NewDbUserDevice {
 }
 
 impl DbUserDevice {
+    /// Requests that are not written yet are taken into account.
     pub fn into_matrix_device(self) -> Device {
         let Self {
+            user_id,
             device_id,
             display_name,
-            last_seen_at,
-            last_seen_ip,
+            mut last_seen_at,
+            mut last_seen_ip,
             ..
         } = self;
+        if let Some(last_seen) = super::get_pending_last_seen(&user_id, &device_id) {
+            last_seen_at = Some(last_seen.seen_at);
+            last_seen_ip = last_seen.ip.or(last_seen_ip);
+        }
         Device {
             device_id,
             display_name,
@@ -126,6 +132,13 @@ pub fn get_device(user_id: &UserId, device_id: &DeviceId) -> AppResult<DbUserDev
         .map_err(Into::into)
 }
 
+pub fn get_devices(user_id: &UserId) -> AppResult<Vec<DbUserDevice>> {
+    user_devices::table
+        .filter(user_devices::user_id.eq(user_id))
+        .load::<DbUserDevice>(&mut *db::connect()?)
+        .map_err(Into::into)
+}
+
 pub fn all_device_ids(user_id: &UserId) -> AppResult<Vec<OwnedDeviceId>> {
     user_devices::table
         .filter(user_devices::user_id.eq(user_id))
@@ -202,8 +215,20 @@ pub fn set_token(user_id: &UserId, device_id: &DeviceId, token: &str) -> AppResu
         ))
         .on_conflict((user_access_tokens::user_id, user_access_tokens::device_id))
         .do_update()
-        .set(user_access_tokens::token.eq(token))
+        .set((
+            user_access_tokens::token.eq(token),
+            user_access_tokens::refresh_token_id.eq(None::<i64>),
+            user_access_tokens::is_used.eq(false),
+            user_access_tokens::expired_at.eq(None::<i64>),
+        ))
         .execute(&mut db::connect()?)?;
+    // Logging in again ends the previous refresh token session of the device
+    diesel::delete(
+        user_refresh_tokens::table
+            .filter(user_refresh_tokens::user_id.eq(user_id))
+            .filter(user_refresh_tokens::device_id.eq(device_id)),
+    )
+    .execute(&mut db::connect()?)?;
     Ok(())
 }
 
@@ -242,6 +267,7 @@ pub fn add_to_device_event(
             created_at: UnixMillis::now().get() as i64,
         })
         .execute(&mut db::connect()?)?;
+    crate::watcher::notify_user(target_user_id);
 
     Ok(())
 }
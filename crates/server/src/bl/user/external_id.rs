@@ -0,0 +1,38 @@
+use diesel::prelude::*;
+
+use crate::core::identifiers::*;
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, AppResult};
+
+/// Links a local user to their ID at an identity provider.
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = user_external_ids)]
+pub struct NewDbExternalId {
+    pub auth_provider: String,
+    pub external_id: String,
+    pub user_id: OwnedUserId,
+    pub created_at: UnixMillis,
+}
+
+pub fn get_user_by_external_id(auth_provider: &str, external_id: &str) -> AppResult<Option<OwnedUserId>> {
+    user_external_ids::table
+        .filter(user_external_ids::auth_provider.eq(auth_provider))
+        .filter(user_external_ids::external_id.eq(external_id))
+        .select(user_external_ids::user_id)
+        .first::<OwnedUserId>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+pub fn add_external_id(auth_provider: &str, external_id: &str, user_id: &UserId) -> AppResult<()> {
+    diesel::insert_into(user_external_ids::table)
+        .values(NewDbExternalId {
+            auth_provider: auth_provider.to_owned(),
+            external_id: external_id.to_owned(),
+            user_id: user_id.to_owned(),
+            created_at: UnixMillis::now(),
+        })
+        .execute(&mut *db::connect()?)?;
+    Ok(())
+}
@@ -9,6 +9,7 @@ use serde_json::json;
 use crate::core::client::key::ClaimKeysResBody;
 use crate::core::encryption::{CrossSigningKey, DeviceKeys, OneTimeKey};
 use crate::core::events::StateEventType;
+use crate::core::federation::transaction::{Edu, SigningKeyUpdateContent};
 use crate::core::identifiers::*;
 use crate::core::{client, federation};
 use crate::core::{DeviceKeyAlgorithm, OwnedDeviceId, OwnedUserId, UserId};
@@ -546,6 +547,13 @@ pub fn add_cross_signing_keys(
         mark_device_key_update(user_id)?;
     }
 
+    if user_id.server_name() == crate::server_name() {
+        let mut content = SigningKeyUpdateContent::new(user_id.to_owned());
+        content.master_key = Some(master_key.clone());
+        content.self_signing_key = self_signing_key.clone();
+        crate::sending::send_edu_user_servers(user_id, &Edu::SigningKeyUpdate(content))?;
+    }
+
     Ok(())
 }
 
@@ -602,6 +610,7 @@ pub fn mark_device_key_update(user_id: &UserId) -> AppResult<()> {
             .do_update()
             .set(&change)
             .execute(&mut db::connect()?)?;
+        crate::watcher::notify_room(&room_id);
     }
 
     let change = NewDbKeyChange {
@@ -616,6 +625,7 @@ pub fn mark_device_key_update(user_id: &UserId) -> AppResult<()> {
         .do_update()
         .set(&change)
         .execute(&mut db::connect()?)?;
+    crate::watcher::notify_user(user_id);
     Ok(())
 }
 
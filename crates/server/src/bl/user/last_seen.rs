@@ -0,0 +1,159 @@
+//! Where and when devices were last used
+//!
+//! Authenticated requests record the address and user agent of their device in memory, they are
+//! written to the devices and to the user visit stats every `last_seen_flush_interval_s` instead of
+//! on every request.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use diesel::prelude::*;
+
+use crate::core::identifiers::*;
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, AppResult};
+
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(Clone, Debug)]
+pub struct LastSeen {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub seen_at: UnixMillis,
+}
+
+/// Fields left unset keep their stored value.
+#[derive(AsChangeset, Debug)]
+#[diesel(table_name = user_devices)]
+struct LastSeenChangeset {
+    last_seen_ip: Option<String>,
+    user_agent: Option<String>,
+    last_seen_at: Option<UnixMillis>,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = stats_user_daily_visits)]
+pub struct NewDbUserDailyVisit {
+    pub user_id: OwnedUserId,
+    pub device_id: OwnedDeviceId,
+    pub user_agent: Option<String>,
+    /// Start of the day of the visit.
+    pub created_at: i64,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = stats_monthly_active_users)]
+pub struct NewDbMonthlyActiveUser {
+    pub user_id: OwnedUserId,
+    /// Last time the user was active.
+    pub created_at: i64,
+}
+
+static PENDING_LAST_SEEN: LazyLock<Mutex<HashMap<(OwnedUserId, OwnedDeviceId), LastSeen>>> =
+    LazyLock::new(Default::default);
+
+/// Records a request of a device, it is written with the next flush.
+pub fn record_last_seen(user_id: &UserId, device_id: &DeviceId, ip: Option<IpAddr>, user_agent: Option<&str>) {
+    PENDING_LAST_SEEN.lock().expect("lock PENDING_LAST_SEEN failed").insert(
+        (user_id.to_owned(), device_id.to_owned()),
+        LastSeen {
+            ip: ip.map(|ip| ip.to_string()),
+            user_agent: user_agent.map(ToOwned::to_owned),
+            seen_at: UnixMillis::now(),
+        },
+    );
+}
+
+/// Returns the last request of a device that is not written yet, it is newer than the stored one.
+pub fn get_pending_last_seen(user_id: &UserId, device_id: &DeviceId) -> Option<LastSeen> {
+    PENDING_LAST_SEEN
+        .lock()
+        .expect("lock PENDING_LAST_SEEN failed")
+        .get(&(user_id.to_owned(), device_id.to_owned()))
+        .cloned()
+}
+
+/// Writes the recorded requests to the devices and the visit stats, returns the number of devices.
+///
+/// The requests are written in one transaction, when it fails they are kept for the next flush.
+pub fn flush_last_seen() -> AppResult<usize> {
+    let mut conn = db::connect()?;
+    let pending = std::mem::take(&mut *PENDING_LAST_SEEN.lock().expect("lock PENDING_LAST_SEEN failed"));
+    if let Err(e) = conn.transaction(|conn| write_last_seen(&pending, conn)) {
+        // Requests recorded since the map was taken are newer than the ones put back.
+        let mut pending_last_seen = PENDING_LAST_SEEN.lock().expect("lock PENDING_LAST_SEEN failed");
+        for (device, last_seen) in pending {
+            pending_last_seen.entry(device).or_insert(last_seen);
+        }
+        return Err(e.into());
+    }
+    Ok(pending.len())
+}
+
+fn write_last_seen(
+    pending: &HashMap<(OwnedUserId, OwnedDeviceId), LastSeen>,
+    conn: &mut PgConnection,
+) -> QueryResult<()> {
+    for ((user_id, device_id), last_seen) in pending {
+        let seen_at = last_seen.seen_at.get();
+        diesel::update(
+            user_devices::table
+                .filter(user_devices::user_id.eq(user_id))
+                .filter(user_devices::device_id.eq(device_id)),
+        )
+        .set(LastSeenChangeset {
+            last_seen_ip: last_seen.ip.clone(),
+            user_agent: last_seen.user_agent.clone(),
+            last_seen_at: Some(last_seen.seen_at),
+        })
+        .execute(conn)?;
+        diesel::insert_into(stats_user_daily_visits::table)
+            .values(NewDbUserDailyVisit {
+                user_id: user_id.clone(),
+                device_id: device_id.clone(),
+                user_agent: last_seen.user_agent.clone(),
+                created_at: (seen_at - seen_at % DAY_MILLIS) as i64,
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        diesel::insert_into(stats_monthly_active_users::table)
+            .values(NewDbMonthlyActiveUser {
+                user_id: user_id.clone(),
+                created_at: seen_at as i64,
+            })
+            .on_conflict(stats_monthly_active_users::user_id)
+            .do_update()
+            .set(stats_monthly_active_users::created_at.eq(seen_at as i64))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Number of users active during the last 30 days.
+pub fn count_monthly_active_users() -> AppResult<i64> {
+    let since = UnixMillis::now().get().saturating_sub(30 * DAY_MILLIS) as i64;
+    stats_monthly_active_users::table
+        .filter(stats_monthly_active_users::created_at.ge(since))
+        .count()
+        .get_result(&mut *db::connect()?)
+        .map_err(Into::into)
+}
+
+/// Starts the job writing the recorded requests.
+pub fn start_last_seen_flush() {
+    let period = Duration::from_secs(crate::config().last_seen_flush_interval_s);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match tokio::task::spawn_blocking(flush_last_seen).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => error!("Failed to write last seen devices: {e}"),
+                Err(e) => error!("Last seen devices flush job panicked: {e}"),
+            }
+        }
+    });
+}
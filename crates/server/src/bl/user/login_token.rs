@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use diesel::prelude::*;
+
+use crate::core::identifiers::*;
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, utils, AppResult, TOKEN_LENGTH};
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = user_login_tokens)]
+pub struct NewDbLoginToken {
+    pub user_id: OwnedUserId,
+    pub token: String,
+    pub expired_at: i64,
+    pub created_at: UnixMillis,
+}
+
+/// Creates a single-use token for the `m.login.token` login flow.
+pub fn create_login_token(user_id: &UserId, lifetime: Duration) -> AppResult<String> {
+    let token = utils::random_string(TOKEN_LENGTH);
+    diesel::insert_into(user_login_tokens::table)
+        .values(NewDbLoginToken {
+            user_id: user_id.to_owned(),
+            token: token.clone(),
+            expired_at: UnixMillis::now().get() as i64 + lifetime.as_millis() as i64,
+            created_at: UnixMillis::now(),
+        })
+        .execute(&mut *db::connect()?)?;
+    Ok(token)
+}
+
+/// Uses up a login token, returns the user it was created for if it is still valid.
+pub fn take_login_token(token: &str) -> AppResult<Option<OwnedUserId>> {
+    let now = UnixMillis::now().get() as i64;
+    diesel::delete(user_login_tokens::table.filter(user_login_tokens::expired_at.lt(now)))
+        .execute(&mut *db::connect()?)?;
+    diesel::delete(user_login_tokens::table.filter(user_login_tokens::token.eq(token)))
+        .returning(user_login_tokens::user_id)
+        .get_result::<OwnedUserId>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
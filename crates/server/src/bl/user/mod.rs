@@ -6,10 +6,20 @@ mod profile;
 pub use profile::*;
 mod access_token;
 pub use access_token::*;
+mod external_id;
+pub use external_id::*;
 mod filter;
 pub use filter::*;
+mod login_token;
+pub use login_token::*;
 mod refresh_token;
 pub use refresh_token::*;
+mod registration_token;
+pub use registration_token::*;
+mod threepid;
+pub use threepid::*;
+mod last_seen;
+pub use last_seen::*;
 mod data;
 pub use data::*;
 pub mod key;
@@ -36,8 +46,11 @@ use palpo_core::JsonValue;
 use crate::core::client::sync_events::{
     ExtensionsConfigV4, RoomSubscriptionV4, SyncEventsReqBodyV4, SyncRequestListV4,
 };
-use crate::core::events::AnyStrippedStateEvent;
+use crate::core::events::push_rules::PushRulesEventContent;
+use crate::core::events::{AnyStrippedStateEvent, GlobalAccountDataEventType};
 use crate::core::identifiers::*;
+use crate::core::presence::PresenceState;
+use crate::core::push::Ruleset;
 use crate::core::serde::RawJson;
 use crate::core::{OwnedMxcUri, OwnedRoomId, UnixMillis};
 use crate::schema::*;
@@ -135,6 +148,26 @@ pub fn invited_rooms(
     Ok(list)
 }
 
+pub fn knocked_rooms(
+    user_id: &UserId,
+    since_sn: i64,
+) -> AppResult<Vec<(OwnedRoomId, Vec<RawJson<AnyStrippedStateEvent>>)>> {
+    let list = room_users::table
+        .filter(room_users::user_id.eq(user_id))
+        .filter(room_users::membership.eq("knock"))
+        .filter(room_users::event_sn.ge(since_sn))
+        .select((room_users::room_id, room_users::state_data))
+        .load::<(OwnedRoomId, Option<JsonValue>)>(&mut *db::connect()?)?
+        .into_iter()
+        .filter_map(|(room_id, state_data)| {
+            state_data
+                .and_then(|state_data| serde_json::from_value(state_data).ok())
+                .map(|state_data| (room_id, state_data))
+        })
+        .collect();
+    Ok(list)
+}
+
 pub const CONNECTIONS: LazyLock<Mutex<BTreeMap<(OwnedUserId, OwnedDeviceId, String), Arc<Mutex<SlidingSyncCache>>>>> =
     LazyLock::new(|| Default::default());
 
@@ -181,6 +214,47 @@ pub fn create_user(user_id: impl Into<OwnedUserId>, password: Option<&str>) -> A
     Ok(user)
 }
 
+/// Sets up the profile, presence and push rules of a newly created account.
+pub fn init_new_user(user_id: &UserId, display_name: String) -> AppResult<()> {
+    diesel::insert_into(user_profiles::table)
+        .values(NewDbProfile {
+            user_id: user_id.to_owned(),
+            room_id: None,
+            display_name: Some(display_name),
+            avatar_url: None,
+            blurhash: None,
+        })
+        .execute(&mut db::connect()?)?;
+
+    // Presence update
+    set_presence(
+        NewDbPresence {
+            user_id: user_id.to_owned(),
+            stream_id: None,
+            state: Some(PresenceState::Online.to_string()),
+            status_msg: None,
+            last_active_at: Some(UnixMillis::now()),
+            last_federation_update_at: None,
+            last_user_sync_at: None,
+            currently_active: None,
+            occur_sn: None,
+        },
+        true,
+    )?;
+
+    // Initial account data
+    set_data(
+        user_id,
+        None,
+        &GlobalAccountDataEventType::PushRules.to_string(),
+        serde_json::to_value(PushRulesEventContent {
+            global: Ruleset::server_default(user_id),
+        })
+        .expect("to json always works"),
+    )?;
+    Ok(())
+}
+
 pub fn forget_sync_request_connection(user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String) {
     CONNECTIONS.lock().unwrap().remove(&(user_id, device_id, conn_id));
 }
@@ -1,9 +1,16 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
 use diesel::prelude::*;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
 
+use crate::config::PasswordPolicyConfig;
+use crate::core::client::discovery::PasswordPolicyCapability;
 use crate::core::identifiers::*;
 use crate::core::UnixMillis;
 use crate::schema::*;
-use crate::{db, utils, AppResult, MatrixError};
+use crate::{db, utils, AppResult, MatrixError, AUTO_GEN_PASSWORD_LENGTH};
 
 use super::DbUser;
 
@@ -45,12 +52,21 @@ pub fn vertify_password(user: &DbUser, password: &str) -> AppResult<()> {
 
     if !hash_matches {
         return Err(MatrixError::unauthorized("Wrong username or password.").into());
-    } else {
-        Ok(())
     }
+    if utils::is_password_hash_outdated(&hash) {
+        if let Err(e) = store_password(&user.id, password) {
+            warn!("Failed to rehash the password of {}: {e}", user.id);
+        }
+    }
+    Ok(())
 }
 
 pub fn set_password(user_id: &UserId, password: &str) -> AppResult<()> {
+    validate_password(user_id, password)?;
+    store_password(user_id, password)
+}
+
+fn store_password(user_id: &UserId, password: &str) -> AppResult<()> {
     if let Ok(hash) = utils::hash_password(password) {
         diesel::insert_into(user_passwords::table)
             .values(NewDbPassword {
@@ -64,3 +80,150 @@ pub fn set_password(user_id: &UserId, password: &str) -> AppResult<()> {
         Err(MatrixError::invalid_param("Password does not meet the requirements.").into())
     }
 }
+
+/// The password policy as advertised to clients.
+pub fn password_policy() -> PasswordPolicyCapability {
+    let policy = &crate::config().password_policy;
+    PasswordPolicyCapability {
+        minimum_length: (policy.minimum_length > 0).then_some(policy.minimum_length as u64),
+        require_digit: policy.require_digit,
+        require_symbol: policy.require_symbol,
+        require_lowercase: policy.require_lowercase,
+        require_uppercase: policy.require_uppercase,
+    }
+}
+
+/// The refused passwords of the policy, lowercased.
+fn password_denylist() -> &'static HashSet<String> {
+    static DENYLIST: OnceLock<HashSet<String>> = OnceLock::new();
+    DENYLIST.get_or_init(|| {
+        let policy = &crate::config().password_policy;
+        let mut denylist = policy
+            .denylist
+            .iter()
+            .map(|password| password.to_lowercase())
+            .collect::<HashSet<_>>();
+        if let Some(path) = &policy.denylist_file {
+            match std::fs::read_to_string(path) {
+                Ok(content) => denylist.extend(
+                    content
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty())
+                        .map(str::to_lowercase),
+                ),
+                Err(e) => error!("Failed to read password denylist {path}: {e}"),
+            }
+        }
+        denylist
+    })
+}
+
+/// Checks a new password against the password policy.
+pub fn validate_password(user_id: &UserId, password: &str) -> AppResult<()> {
+    check_password_policy(&crate::config().password_policy, password_denylist(), user_id, password)?;
+    Ok(())
+}
+
+fn check_password_policy(
+    policy: &PasswordPolicyConfig,
+    denylist: &HashSet<String>,
+    user_id: &UserId,
+    password: &str,
+) -> Result<(), MatrixError> {
+    if password.chars().count() < policy.minimum_length {
+        return Err(MatrixError::weak_password(format!(
+            "Password must be at least {} characters long.",
+            policy.minimum_length
+        )));
+    }
+    if policy.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+        return Err(MatrixError::weak_password("Password must contain a digit."));
+    }
+    if policy.require_symbol && password.chars().all(char::is_alphanumeric) {
+        return Err(MatrixError::weak_password("Password must contain a symbol."));
+    }
+    if policy.require_lowercase && !password.chars().any(char::is_lowercase) {
+        return Err(MatrixError::weak_password("Password must contain a lowercase letter."));
+    }
+    if policy.require_uppercase && !password.chars().any(char::is_uppercase) {
+        return Err(MatrixError::weak_password("Password must contain an uppercase letter."));
+    }
+    let lowercase = password.to_lowercase();
+    if denylist.contains(&lowercase) {
+        return Err(MatrixError::weak_password("Password is too common."));
+    }
+    if policy.forbid_username && lowercase.contains(&user_id.localpart().to_lowercase()) {
+        return Err(MatrixError::weak_password("Password must not contain the username."));
+    }
+    Ok(())
+}
+
+/// Generates a random password following the password policy, for passwords reset by admins.
+pub fn generate_password(user_id: &UserId) -> String {
+    const SYMBOLS: &[u8] = b"!#$%&*+-=?@^_~";
+    let policy = &crate::config().password_policy;
+    let length = AUTO_GEN_PASSWORD_LENGTH.max(policy.minimum_length);
+    loop {
+        let mut password = utils::random_string(length);
+        if policy.require_symbol {
+            password.push(*SYMBOLS.choose(&mut thread_rng()).expect("symbols are not empty") as char);
+        }
+        if validate_password(user_id, &password).is_ok() {
+            return password;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(policy: &PasswordPolicyConfig, password: &str) -> bool {
+        let denylist = HashSet::from(["password1".to_owned()]);
+        let user_id = UserId::parse("@alice:test.palpo.im").unwrap();
+        check_password_policy(policy, &denylist, &user_id, password).is_ok()
+    }
+
+    #[test]
+    fn default_policy_only_refuses_denylisted_passwords() {
+        let policy = PasswordPolicyConfig::default();
+        assert!(check(&policy, "a"));
+        assert!(check(&policy, "alice"));
+        assert!(!check(&policy, "PassWord1"));
+    }
+
+    #[test]
+    fn each_rule_is_enforced() {
+        let policy = |set: fn(&mut PasswordPolicyConfig)| {
+            let mut policy = PasswordPolicyConfig::default();
+            set(&mut policy);
+            policy
+        };
+        let minimum_length = policy(|p| p.minimum_length = 8);
+        assert!(!check(&minimum_length, "short"));
+        assert!(check(&minimum_length, "long enough"));
+        // Length is counted in characters, not bytes.
+        assert!(!check(&minimum_length, "ééééééé"));
+
+        let require_digit = policy(|p| p.require_digit = true);
+        assert!(!check(&require_digit, "no digits"));
+        assert!(check(&require_digit, "digit 4"));
+
+        let require_symbol = policy(|p| p.require_symbol = true);
+        assert!(!check(&require_symbol, "NoSymbols123"));
+        assert!(check(&require_symbol, "symbol!"));
+
+        let require_lowercase = policy(|p| p.require_lowercase = true);
+        assert!(!check(&require_lowercase, "UPPER"));
+        assert!(check(&require_lowercase, "Lower"));
+
+        let require_uppercase = policy(|p| p.require_uppercase = true);
+        assert!(!check(&require_uppercase, "lower"));
+        assert!(check(&require_uppercase, "Upper"));
+
+        let forbid_username = policy(|p| p.forbid_username = true);
+        assert!(!check(&forbid_username, "xxALICExx"));
+        assert!(check(&forbid_username, "bob's password"));
+    }
+}
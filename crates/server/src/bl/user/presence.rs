@@ -2,7 +2,8 @@ use std::collections::HashMap;
 
 use crate::core::{
     events::presence::{PresenceEvent, PresenceEventContent},
-    presence::PresenceState,
+    federation::transaction::Edu,
+    presence::{PresenceContent, PresenceState, PresenceUpdate},
     OwnedUserId, RoomId, UserId,
 };
 
@@ -98,7 +99,11 @@ pub fn get_last_presence(user_id: &UserId) -> AppResult<Option<DbPresence>> {
 }
 
 /// Adds a presence event which will be saved until a new event replaces it.
+///
+/// Changes of local users are sent to the servers sharing a room with them when
+/// `allow_outgoing_presence` is enabled.
 pub fn set_presence(mut presence: NewDbPresence, force: bool) -> AppResult<()> {
+    let mut changed = force;
     if force {
         diesel::delete(user_presences::table.filter(user_presences::user_id.eq(&presence.user_id)))
             .execute(&mut db::connect()?)?;
@@ -116,6 +121,7 @@ pub fn set_presence(mut presence: NewDbPresence, force: bool) -> AppResult<()> {
             .optional()?
             .flatten();
         if old_state != presence.state && presence.state.is_some() {
+            changed = true;
             diesel::delete(user_presences::table.filter(user_presences::user_id.eq(&presence.user_id)))
                 .execute(&mut db::connect()?)?;
             diesel::insert_into(user_presences::table)
@@ -133,9 +139,25 @@ pub fn set_presence(mut presence: NewDbPresence, force: bool) -> AppResult<()> {
                 .execute(&mut db::connect()?)?;
         }
     }
+    if changed && presence.user_id.server_name() == crate::server_name() && crate::allow_outcoming_presence() {
+        federation_send_presence(&presence)?;
+    }
     Ok(())
 }
 
+fn federation_send_presence(presence: &NewDbPresence) -> AppResult<()> {
+    let state = presence.state.as_deref().map(PresenceState::from).unwrap_or_default();
+    let last_active_ago = presence
+        .last_active_at
+        .map(|last_active_at| UnixMillis::now().0.saturating_sub(last_active_at.0))
+        .unwrap_or_default();
+    let mut update = PresenceUpdate::new(presence.user_id.clone(), state.clone(), last_active_ago);
+    update.status_msg = presence.status_msg.clone();
+    update.currently_active = presence.currently_active.unwrap_or(state == PresenceState::Online);
+    let edu = Edu::Presence(PresenceContent::new(vec![update]));
+    crate::sending::send_edu_user_servers(&presence.user_id, &edu)
+}
+
 /// Removes the presence record for the given user from the database.
 pub fn remove_presence(user_id: &UserId) -> AppResult<()> {
     diesel::delete(user_presences::table.filter(user_presences::user_id.eq(user_id))).execute(&mut db::connect()?)?;
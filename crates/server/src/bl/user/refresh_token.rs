@@ -1,8 +1,11 @@
+use std::time::Duration;
+
 use diesel::prelude::*;
 
 use crate::core::identifiers::*;
 use crate::core::UnixMillis;
 use crate::schema::*;
+use crate::{db, diesel_exists, utils, AppError, AppResult, MatrixError, TOKEN_LENGTH};
 
 #[derive(Identifiable, Queryable, Debug, Clone)]
 #[diesel(table_name = user_refresh_tokens)]
@@ -27,3 +30,252 @@ pub struct NewDbRefreshToken {
     pub ultimate_session_expired_at: Option<i64>,
     pub created_at: UnixMillis,
 }
+
+impl NewDbRefreshToken {
+    pub fn new(user_id: OwnedUserId, device_id: OwnedDeviceId, token: String) -> Self {
+        Self {
+            user_id,
+            device_id,
+            token,
+            next_token_id: None,
+            expired_at: None,
+            ultimate_session_expired_at: None,
+            created_at: UnixMillis::now(),
+        }
+    }
+}
+
+/// Tokens returned to a client that supports refresh tokens: access token, refresh token and the
+/// lifetime of the access token.
+pub type SessionTokens = (String, String, Duration);
+
+/// Gives the device a refresh token, its current access token expires after
+/// `access_token_lifetime_s` from now on.
+pub fn issue_refresh_token(user_id: &UserId, device_id: &DeviceId) -> AppResult<(String, Duration)> {
+    let lifetime = Duration::from_secs(crate::config().access_token_lifetime_s);
+    let refresh_token = utils::random_string(TOKEN_LENGTH);
+    db::connect()?.transaction::<_, AppError, _>(|conn| {
+        let refresh_token_id = diesel::insert_into(user_refresh_tokens::table)
+            .values(NewDbRefreshToken::new(
+                user_id.to_owned(),
+                device_id.to_owned(),
+                refresh_token.clone(),
+            ))
+            .returning(user_refresh_tokens::id)
+            .get_result::<i64>(conn)?;
+        diesel::update(
+            user_access_tokens::table
+                .filter(user_access_tokens::user_id.eq(user_id))
+                .filter(user_access_tokens::device_id.eq(device_id)),
+        )
+        .set((
+            user_access_tokens::refresh_token_id.eq(refresh_token_id),
+            user_access_tokens::is_used.eq(false),
+            user_access_tokens::expired_at.eq(expires_at(lifetime)),
+        ))
+        .execute(conn)?;
+        Ok(())
+    })?;
+    Ok((refresh_token, lifetime))
+}
+
+/// Exchanges a refresh token for a new access token and a new refresh token.
+///
+/// The old refresh token keeps working until the tokens it was exchanged for are used, in case
+/// the client never received them. Using it after that means it was leaked, so every token of
+/// the device is revoked.
+///
+/// The refresh token row is locked for the whole exchange, concurrent requests with the same token
+/// are handled one after the other.
+pub fn refresh_access_token(refresh_token: &str) -> AppResult<SessionTokens> {
+    let lifetime = Duration::from_secs(crate::config().access_token_lifetime_s);
+    let access_token = utils::random_string(TOKEN_LENGTH);
+    let new_refresh_token = utils::random_string(TOKEN_LENGTH);
+    let reused = db::connect()?.transaction::<_, AppError, _>(|conn| {
+        let current = user_refresh_tokens::table
+            .filter(user_refresh_tokens::token.eq(refresh_token))
+            .for_update()
+            .first::<DbRefreshToken>(conn)
+            .optional()?
+            .ok_or_else(|| MatrixError::unknown_token(false, "Unknown refresh token."))?;
+        let now = UnixMillis::now().get() as i64;
+        if current.expired_at.is_some_and(|expired_at| expired_at < now) {
+            return Err(MatrixError::unknown_token(false, "Refresh token has expired.").into());
+        }
+
+        if let Some(next_token_id) = current.next_token_id {
+            let next = user_refresh_tokens::table
+                .find(next_token_id)
+                .first::<DbRefreshToken>(conn)
+                .optional()?;
+            let next_used = match &next {
+                Some(next) => {
+                    next.next_token_id.is_some()
+                        || diesel_exists!(
+                            user_access_tokens::table
+                                .filter(user_access_tokens::refresh_token_id.eq(next.id))
+                                .filter(user_access_tokens::is_used.eq(true)),
+                            conn
+                        )?
+                }
+                None => true,
+            };
+            if next_used {
+                warn!(
+                    "Refresh token of {} device {} was used again, revoking its tokens",
+                    current.user_id, current.device_id
+                );
+                delete_device_tokens(&current.user_id, &current.device_id, conn)?;
+                return Ok(true);
+            }
+            diesel::delete(user_refresh_tokens::table.find(next_token_id)).execute(conn)?;
+        }
+
+        let refresh_token_id = diesel::insert_into(user_refresh_tokens::table)
+            .values(NewDbRefreshToken::new(
+                current.user_id.clone(),
+                current.device_id.clone(),
+                new_refresh_token.clone(),
+            ))
+            .returning(user_refresh_tokens::id)
+            .get_result::<i64>(conn)?;
+        diesel::update(user_refresh_tokens::table.find(current.id))
+            .set(user_refresh_tokens::next_token_id.eq(refresh_token_id))
+            .execute(conn)?;
+        // Only the token being exchanged is needed to detect reuse, older ones are dropped.
+        diesel::delete(
+            user_refresh_tokens::table
+                .filter(user_refresh_tokens::user_id.eq(&current.user_id))
+                .filter(user_refresh_tokens::device_id.eq(&current.device_id))
+                .filter(user_refresh_tokens::next_token_id.is_not_null())
+                .filter(user_refresh_tokens::id.ne(current.id)),
+        )
+        .execute(conn)?;
+        diesel::update(
+            user_access_tokens::table
+                .filter(user_access_tokens::user_id.eq(&current.user_id))
+                .filter(user_access_tokens::device_id.eq(&current.device_id)),
+        )
+        .set((
+            user_access_tokens::token.eq(&access_token),
+            user_access_tokens::refresh_token_id.eq(refresh_token_id),
+            user_access_tokens::is_used.eq(false),
+            user_access_tokens::expired_at.eq(expires_at(lifetime)),
+        ))
+        .execute(conn)?;
+        Ok(false)
+    })?;
+    if reused {
+        return Err(MatrixError::unknown_token(false, "Refresh token has already been used.").into());
+    }
+    Ok((access_token, new_refresh_token, lifetime))
+}
+
+/// Logs the device out without removing it.
+pub fn revoke_device_tokens(user_id: &UserId, device_id: &DeviceId) -> AppResult<()> {
+    delete_device_tokens(user_id, device_id, &mut *db::connect()?)?;
+    Ok(())
+}
+
+fn delete_device_tokens(user_id: &UserId, device_id: &DeviceId, conn: &mut PgConnection) -> QueryResult<()> {
+    diesel::delete(
+        user_access_tokens::table
+            .filter(user_access_tokens::user_id.eq(user_id))
+            .filter(user_access_tokens::device_id.eq(device_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        user_refresh_tokens::table
+            .filter(user_refresh_tokens::user_id.eq(user_id))
+            .filter(user_refresh_tokens::device_id.eq(device_id)),
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+fn expires_at(lifetime: Duration) -> i64 {
+    UnixMillis::now().get() as i64 + lifetime.as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn login(user_id: &UserId) -> (OwnedDeviceId, String) {
+        let device_id = OwnedDeviceId::from(utils::random_string(10));
+        crate::user::set_token(user_id, &device_id, &utils::random_string(TOKEN_LENGTH)).unwrap();
+        let (refresh_token, _) = issue_refresh_token(user_id, &device_id).unwrap();
+        (device_id, refresh_token)
+    }
+
+    /// What authenticating a request with the access token does.
+    fn use_access_token(access_token: &str) {
+        diesel::update(user_access_tokens::table.filter(user_access_tokens::token.eq(access_token)))
+            .set(user_access_tokens::is_used.eq(true))
+            .execute(&mut *db::connect().unwrap())
+            .unwrap();
+    }
+
+    fn unexchanged_refresh_token_ids(user_id: &UserId, device_id: &DeviceId) -> Vec<i64> {
+        user_refresh_tokens::table
+            .filter(user_refresh_tokens::user_id.eq(user_id))
+            .filter(user_refresh_tokens::device_id.eq(device_id))
+            .filter(user_refresh_tokens::next_token_id.is_null())
+            .select(user_refresh_tokens::id)
+            .load::<i64>(&mut *db::connect().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs PALPO_TEST_DATABASE_URL"]
+    fn reused_refresh_token_revokes_device() {
+        init_db();
+        let user_id = unique_user_id();
+        let (device_id, first) = login(&user_id);
+
+        let (_, second, _) = refresh_access_token(&first).unwrap();
+        // The client may not have received the new tokens, so the old refresh token still works.
+        let (access_token, third, _) = refresh_access_token(&first).unwrap();
+        assert!(refresh_access_token(&second).is_err());
+
+        use_access_token(&access_token);
+        assert!(refresh_access_token(&first).is_err());
+        assert!(refresh_access_token(&third).is_err());
+        assert!(!diesel_exists!(
+            user_access_tokens::table
+                .filter(user_access_tokens::user_id.eq(&user_id))
+                .filter(user_access_tokens::device_id.eq(&device_id)),
+            &mut *db::connect().unwrap()
+        )
+        .unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs PALPO_TEST_DATABASE_URL"]
+    fn concurrent_refreshes_keep_one_session() {
+        init_db();
+        let user_id = unique_user_id();
+        let (device_id, refresh_token) = login(&user_id);
+
+        let results = std::thread::scope(|scope| {
+            let handles = (0..4)
+                .map(|_| scope.spawn(|| refresh_access_token(&refresh_token)))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert!(results.iter().all(Result::is_ok));
+
+        let unexchanged = unexchanged_refresh_token_ids(&user_id, &device_id);
+        assert_eq!(unexchanged.len(), 1);
+        let access_token = user_access_tokens::table
+            .filter(user_access_tokens::user_id.eq(&user_id))
+            .filter(user_access_tokens::device_id.eq(&device_id))
+            .first::<crate::user::DbAccessToken>(&mut *db::connect().unwrap())
+            .unwrap();
+        assert_eq!(access_token.refresh_token_id, Some(unexchanged[0]));
+    }
+}
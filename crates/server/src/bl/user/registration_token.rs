@@ -0,0 +1,146 @@
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, utils, AppResult};
+
+/// Length of the generated registration tokens.
+const REGISTRATION_TOKEN_LENGTH: usize = 16;
+
+#[derive(Identifiable, Queryable, Debug, Clone)]
+#[diesel(table_name = user_registration_tokens)]
+pub struct DbRegistrationToken {
+    pub id: i64,
+    pub token: String,
+    /// How many users may register with the token, unlimited when None.
+    pub uses_allowed: Option<i64>,
+    /// Registrations that completed the token stage but are not finished yet.
+    pub pending: i64,
+    pub completed: i64,
+    pub expired_at: Option<i64>,
+    pub created_at: UnixMillis,
+}
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = user_registration_tokens)]
+pub struct NewDbRegistrationToken {
+    pub token: String,
+    pub uses_allowed: Option<i64>,
+    pub pending: i64,
+    pub completed: i64,
+    pub expired_at: Option<i64>,
+    pub created_at: UnixMillis,
+}
+
+impl DbRegistrationToken {
+    pub fn is_valid(&self) -> bool {
+        self.expired_at
+            .map_or(true, |expired_at| expired_at > UnixMillis::now().get() as i64)
+            && self
+                .uses_allowed
+                .map_or(true, |uses_allowed| self.pending + self.completed < uses_allowed)
+    }
+}
+
+/// Creates a registration token, a random one when `token` is None.
+pub fn create_registration_token(
+    token: Option<String>,
+    uses_allowed: Option<i64>,
+    expired_at: Option<i64>,
+) -> AppResult<DbRegistrationToken> {
+    diesel::insert_into(user_registration_tokens::table)
+        .values(NewDbRegistrationToken {
+            token: token.unwrap_or_else(|| utils::random_string(REGISTRATION_TOKEN_LENGTH)),
+            uses_allowed,
+            pending: 0,
+            completed: 0,
+            expired_at,
+            created_at: UnixMillis::now(),
+        })
+        .get_result::<DbRegistrationToken>(&mut *db::connect()?)
+        .map_err(Into::into)
+}
+
+pub fn get_registration_token(token: &str) -> AppResult<Option<DbRegistrationToken>> {
+    user_registration_tokens::table
+        .filter(user_registration_tokens::token.eq(token))
+        .first::<DbRegistrationToken>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+pub fn list_registration_tokens() -> AppResult<Vec<DbRegistrationToken>> {
+    user_registration_tokens::table
+        .order_by(user_registration_tokens::id)
+        .load::<DbRegistrationToken>(&mut *db::connect()?)
+        .map_err(Into::into)
+}
+
+/// Changes the usage limit and expiry of a token, returns None when the token doesn't exist.
+pub fn update_registration_token(
+    token: &str,
+    uses_allowed: Option<i64>,
+    expired_at: Option<i64>,
+) -> AppResult<Option<DbRegistrationToken>> {
+    diesel::update(user_registration_tokens::table.filter(user_registration_tokens::token.eq(token)))
+        .set((
+            user_registration_tokens::uses_allowed.eq(uses_allowed),
+            user_registration_tokens::expired_at.eq(expired_at),
+        ))
+        .get_result::<DbRegistrationToken>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+pub fn delete_registration_token(token: &str) -> AppResult<bool> {
+    let count = diesel::delete(user_registration_tokens::table.filter(user_registration_tokens::token.eq(token)))
+        .execute(&mut *db::connect()?)?;
+    Ok(count > 0)
+}
+
+pub fn is_registration_token_valid(token: &str) -> AppResult<bool> {
+    Ok(get_registration_token(token)?.is_some_and(|token| token.is_valid()))
+}
+
+/// Counts a registration as pending on the token if it is still valid, returns whether it was.
+///
+/// The check and the update are one statement, so concurrent registrations can't use the token
+/// more often than allowed.
+pub fn reserve_registration_token(token: &str) -> AppResult<bool> {
+    let count = diesel::sql_query(
+        "UPDATE user_registration_tokens SET pending = pending + 1 \
+         WHERE token = $1 AND (expired_at IS NULL OR expired_at > $2) \
+         AND (uses_allowed IS NULL OR pending + completed < uses_allowed)",
+    )
+    .bind::<Text, _>(token)
+    .bind::<BigInt, _>(UnixMillis::now().get() as i64)
+    .execute(&mut *db::connect()?)?;
+    Ok(count > 0)
+}
+
+/// Gives back a pending registration on the token, for registrations that were abandoned.
+pub fn release_registration_token(token: &str) -> AppResult<()> {
+    diesel::update(
+        user_registration_tokens::table
+            .filter(user_registration_tokens::token.eq(token))
+            .filter(user_registration_tokens::pending.gt(0)),
+    )
+    .set(user_registration_tokens::pending.eq(user_registration_tokens::pending - 1))
+    .execute(&mut *db::connect()?)?;
+    Ok(())
+}
+
+/// Turns a pending registration on the token into a completed one.
+pub fn complete_registration_token(token: &str) -> AppResult<()> {
+    diesel::update(
+        user_registration_tokens::table
+            .filter(user_registration_tokens::token.eq(token))
+            .filter(user_registration_tokens::pending.gt(0)),
+    )
+    .set((
+        user_registration_tokens::pending.eq(user_registration_tokens::pending - 1),
+        user_registration_tokens::completed.eq(user_registration_tokens::completed + 1),
+    ))
+    .execute(&mut *db::connect()?)?;
+    Ok(())
+}
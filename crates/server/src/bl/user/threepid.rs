@@ -0,0 +1,117 @@
+use diesel::prelude::*;
+
+use crate::core::identifiers::*;
+use crate::core::third_party::{Medium, ThirdPartyIdentifier, ThirdPartyIdentifierInit};
+use crate::core::UnixMillis;
+use crate::schema::*;
+use crate::{db, AppResult};
+
+#[derive(Identifiable, Queryable, Debug, Clone)]
+#[diesel(table_name = user_threepids)]
+pub struct DbUserThreepid {
+    pub id: i64,
+    pub user_id: OwnedUserId,
+    pub medium: String,
+    pub address: String,
+    pub validated_at: UnixMillis,
+    pub added_at: UnixMillis,
+}
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = user_threepids)]
+pub struct NewDbUserThreepid {
+    pub user_id: OwnedUserId,
+    pub medium: String,
+    pub address: String,
+    pub validated_at: UnixMillis,
+    pub added_at: UnixMillis,
+}
+
+impl From<DbUserThreepid> for ThirdPartyIdentifier {
+    fn from(threepid: DbUserThreepid) -> Self {
+        ThirdPartyIdentifierInit {
+            address: threepid.address,
+            medium: Medium::from(threepid.medium),
+            validated_at: threepid.validated_at,
+            added_at: threepid.added_at,
+        }
+        .into()
+    }
+}
+
+pub fn get_threepids(user_id: &UserId) -> AppResult<Vec<DbUserThreepid>> {
+    user_threepids::table
+        .filter(user_threepids::user_id.eq(user_id))
+        .order_by(user_threepids::id)
+        .load::<DbUserThreepid>(&mut *db::connect()?)
+        .map_err(Into::into)
+}
+
+/// Returns the user the third party identifier belongs to.
+pub fn get_user_by_threepid(medium: &Medium, address: &str) -> AppResult<Option<OwnedUserId>> {
+    user_threepids::table
+        .filter(user_threepids::medium.eq(medium.as_str()))
+        .filter(user_threepids::address.eq(address))
+        .select(user_threepids::user_id)
+        .first::<OwnedUserId>(&mut *db::connect()?)
+        .optional()
+        .map_err(Into::into)
+}
+
+pub fn add_threepid(user_id: &UserId, medium: &Medium, address: &str, validated_at: UnixMillis) -> AppResult<()> {
+    diesel::insert_into(user_threepids::table)
+        .values(NewDbUserThreepid {
+            user_id: user_id.to_owned(),
+            medium: medium.to_string(),
+            address: address.to_owned(),
+            validated_at,
+            added_at: UnixMillis::now(),
+        })
+        .execute(&mut *db::connect()?)?;
+    Ok(())
+}
+
+/// Removes a third party identifier from the account, returns whether it was there.
+pub fn delete_threepid(user_id: &UserId, medium: &Medium, address: &str) -> AppResult<bool> {
+    let count = diesel::delete(
+        user_threepids::table
+            .filter(user_threepids::user_id.eq(user_id))
+            .filter(user_threepids::medium.eq(medium.as_str()))
+            .filter(user_threepids::address.eq(address)),
+    )
+    .execute(&mut *db::connect()?)?;
+    Ok(count > 0)
+}
+
+/// Remembers that the third party identifier was bound to the user on an identity server.
+pub fn add_threepid_id_server(user_id: &UserId, medium: &Medium, address: &str, id_server: &str) -> AppResult<()> {
+    diesel::insert_into(threepid_id_servers::table)
+        .values((
+            threepid_id_servers::user_id.eq(user_id),
+            threepid_id_servers::medium.eq(medium.as_str()),
+            threepid_id_servers::address.eq(address),
+            threepid_id_servers::id_server.eq(id_server),
+        ))
+        .execute(&mut *db::connect()?)?;
+    Ok(())
+}
+
+/// Forgets the identity servers the third party identifier was bound on, returns them.
+pub fn remove_threepid_id_servers(
+    user_id: &UserId,
+    medium: &Medium,
+    address: &str,
+    id_server: Option<&str>,
+) -> AppResult<Vec<String>> {
+    let mut query = diesel::delete(threepid_id_servers::table)
+        .filter(threepid_id_servers::user_id.eq(user_id))
+        .filter(threepid_id_servers::medium.eq(medium.as_str()))
+        .filter(threepid_id_servers::address.eq(address))
+        .into_boxed();
+    if let Some(id_server) = id_server {
+        query = query.filter(threepid_id_servers::id_server.eq(id_server));
+    }
+    query
+        .returning(threepid_id_servers::id_server)
+        .get_results::<String>(&mut *db::connect()?)
+        .map_err(Into::into)
+}
@@ -0,0 +1,154 @@
+//! Wakes up waiting `/sync` requests when something they care about changes.
+//!
+//! Every user and every room has a watch channel, `notify_user` and `notify_room` are called
+//! wherever data that shows up in a sync response is written. When `db.listen_notify` is enabled
+//! the notifications are also published with Postgres `NOTIFY`, so that syncs waiting in other
+//! processes sharing the database are woken up as well.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::sync::watch::{self, Receiver, Sender};
+
+use crate::core::identifiers::*;
+use crate::{db, AppError, AppResult};
+
+/// The Postgres channel used to share notifications between processes.
+const NOTIFY_CHANNEL: &str = "palpo_sync";
+/// Channels nobody is waiting on are dropped, but only when there are this many of them.
+const MAX_IDLE_WATCHERS: usize = 10_000;
+/// How often the listener connection is checked for new notifications.
+const LISTEN_INTERVAL: Duration = Duration::from_millis(50);
+
+type Watchers<K> = LazyLock<Mutex<HashMap<K, Sender<()>>>>;
+
+static USER_WATCHERS: Watchers<OwnedUserId> = LazyLock::new(Default::default);
+static ROOM_WATCHERS: Watchers<OwnedRoomId> = LazyLock::new(Default::default);
+
+/// Identifies this process, so it can skip the notifications it published itself.
+static INSTANCE_ID: LazyLock<String> = LazyLock::new(|| crate::utils::random_string(16));
+
+fn subscribe<K: Hash + Eq>(watchers: &Mutex<HashMap<K, Sender<()>>>, key: K) -> Receiver<()> {
+    let mut watchers = watchers.lock().unwrap();
+    if watchers.len() > MAX_IDLE_WATCHERS {
+        watchers.retain(|_, sender| sender.receiver_count() > 0);
+    }
+    watchers.entry(key).or_insert_with(|| watch::channel(()).0).subscribe()
+}
+
+fn wake<K, Q>(watchers: &Mutex<HashMap<K, Sender<()>>>, key: &Q)
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+{
+    let mut watchers = watchers.lock().unwrap();
+    if let Some(sender) = watchers.get(key) {
+        if sender.send(()).is_err() {
+            watchers.remove(key);
+        }
+    }
+}
+
+/// Wakes up the syncs of `user_id`, used for to-device messages, account data and membership changes.
+pub fn notify_user(user_id: &UserId) {
+    wake(&USER_WATCHERS, user_id);
+    if let Err(e) = publish('u', user_id.as_str()) {
+        warn!("Failed to publish sync notification for {}: {}", user_id, e);
+    }
+}
+
+/// Wakes up the syncs of every user joined to `room_id`.
+pub fn notify_room(room_id: &RoomId) {
+    wake(&ROOM_WATCHERS, room_id);
+    if let Err(e) = publish('r', room_id.as_str()) {
+        warn!("Failed to publish sync notification for {}: {}", room_id, e);
+    }
+}
+
+fn publish(kind: char, id: &str) -> AppResult<()> {
+    if !crate::config().db.listen_notify {
+        return Ok(());
+    }
+    diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(NOTIFY_CHANNEL)
+        .bind::<Text, _>(format!("{} {kind} {id}", *INSTANCE_ID))
+        .execute(&mut *db::connect()?)?;
+    Ok(())
+}
+
+/// Returns a future that resolves when anything in the sync response of `user_id` may have changed.
+///
+/// The watchers are registered when this is called rather than when the future is first polled,
+/// so nothing that happens while the sync response is computed gets lost.
+pub fn watch(user_id: &UserId) -> impl Future<Output = AppResult<()>> + Send + 'static {
+    let receivers = crate::user::joined_rooms(user_id, 0).map(|room_ids| {
+        let mut receivers: Vec<_> = room_ids
+            .into_iter()
+            .map(|room_id| subscribe(&ROOM_WATCHERS, room_id))
+            .collect();
+        receivers.push(subscribe(&USER_WATCHERS, user_id.to_owned()));
+        receivers
+    });
+    async move {
+        let mut futures: FuturesUnordered<_> = receivers?
+            .into_iter()
+            .map(|mut receiver| async move { receiver.changed().await })
+            .collect();
+        // Wait until one of them finds something
+        futures.next().await;
+        Ok(())
+    }
+}
+
+/// Starts listening to the notifications published by other processes, if `db.listen_notify` is enabled.
+pub fn start_listener() {
+    let conf = crate::config();
+    if !conf.db.listen_notify {
+        return;
+    }
+    let url = db::connection_url(&conf.db, &conf.db.url);
+    std::thread::spawn(move || loop {
+        if let Err(e) = listen(&url) {
+            error!("Sync notification listener failed: {}", e);
+        }
+        std::thread::sleep(Duration::from_secs(5));
+    });
+}
+
+fn listen(url: &str) -> AppResult<()> {
+    let mut conn = PgConnection::establish(url).map_err(|e| AppError::internal(e.to_string()))?;
+    diesel::sql_query(format!("LISTEN {NOTIFY_CHANNEL}")).execute(&mut conn)?;
+    loop {
+        for notification in conn.notifications_iter() {
+            let notification = notification?;
+            let mut parts = notification.payload.splitn(3, ' ');
+            let (Some(instance_id), Some(kind), Some(id)) = (parts.next(), parts.next(), parts.next()) else {
+                continue;
+            };
+            if instance_id == *INSTANCE_ID {
+                continue;
+            }
+            match kind {
+                "u" => {
+                    if let Ok(user_id) = <&UserId>::try_from(id) {
+                        wake(&USER_WATCHERS, user_id);
+                    }
+                }
+                "r" => {
+                    if let Ok(room_id) = <&RoomId>::try_from(id) {
+                        wake(&ROOM_WATCHERS, room_id);
+                    }
+                }
+                _ => {}
+            }
+        }
+        std::thread::sleep(LISTEN_INTERVAL);
+    }
+}
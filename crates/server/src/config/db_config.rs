@@ -67,6 +67,10 @@ pub struct DbConfig {
     /// Whether to enforce that all the database connections are encrypted with TLS.
     #[serde(default = "false_value")]
     pub enforce_tls: bool,
+    /// Whether to share sync wakeups with other processes through Postgres `LISTEN`/`NOTIFY`.
+    /// Enable this when several processes serve clients from the same database.
+    #[serde(default = "false_value")]
+    pub listen_notify: bool,
 }
 
 impl fmt::Display for DbConfig {
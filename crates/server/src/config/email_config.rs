@@ -0,0 +1,70 @@
+//! Configuration for sending emails
+
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EmailConfig {
+    /// Sender of the emails, e.g. `Palpo <noreply@example.com>`.
+    pub notif_from: String,
+    /// Name of the service in the emails.
+    #[serde(default = "default_app_name")]
+    pub app_name: String,
+    #[serde(default)]
+    pub transport: MailTransport,
+    /// How long the links in validation emails can be used.
+    #[serde(default = "default_validation_token_lifetime_s")]
+    pub validation_token_lifetime_s: u64,
+    /// Directory with templates replacing the built-in ones, `<template>.txt` with the subject on
+    /// the first line and the body after it. `{app_name}`, `{address}` and `{link}` are replaced.
+    #[serde(default)]
+    pub template_dir: Option<String>,
+    /// Domains users may be sent to after validating their address, any domain when unset. The
+    /// `next_link` of clients always has to be an `http` or `https` URL.
+    #[serde(default)]
+    pub next_link_domain_allowlist: Option<Vec<String>>,
+}
+
+/// How emails are delivered.
+#[derive(Clone, Debug, Deserialize, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MailTransport {
+    Smtp {
+        host: String,
+        /// The default port of `tls`.
+        port: Option<u16>,
+        username: Option<String>,
+        password: Option<String>,
+        #[serde(default)]
+        tls: SmtpTls,
+    },
+    /// Pipe the emails to a `sendmail` compatible command.
+    Sendmail {
+        /// `sendmail` from `PATH` when unset.
+        command: Option<String>,
+    },
+    /// Write the emails as `.eml` files into a directory.
+    File { path: String },
+    /// Only log the emails, for testing.
+    #[default]
+    Log,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// TLS from the start, on port 465 by default.
+    Implicit,
+    /// Upgrade with `STARTTLS`, on port 587 by default.
+    #[default]
+    Starttls,
+    /// Unencrypted, on port 25 by default.
+    None,
+}
+
+fn default_app_name() -> String {
+    "Palpo".to_owned()
+}
+
+fn default_validation_token_lifetime_s() -> u64 {
+    60 * 60
+}
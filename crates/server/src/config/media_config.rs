@@ -0,0 +1,44 @@
+//! Configuration for the media repository
+
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MediaConfig {
+    /// Cached remote media is deleted this many days after it was fetched, never when unset.
+    #[serde(default)]
+    pub remote_media_lifetime_days: Option<u64>,
+    /// Local media that no event or profile references is deleted this many days after it was
+    /// uploaded, never when unset.
+    #[serde(default)]
+    pub local_media_lifetime_days: Option<u64>,
+    /// Media from other servers larger than this is not fetched or cached, in bytes.
+    #[serde(default = "default_remote_media_max_size")]
+    pub remote_media_max_size: u64,
+    /// Stop serving local media on the legacy unauthenticated `/_matrix/media` download and
+    /// thumbnail endpoints, so it is only available through the authenticated ones.
+    #[serde(default)]
+    pub freeze_legacy_media: bool,
+    /// How often expired media is looked for.
+    #[serde(default = "default_retention_interval_s")]
+    pub retention_interval_s: u64,
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            remote_media_lifetime_days: None,
+            local_media_lifetime_days: None,
+            remote_media_max_size: default_remote_media_max_size(),
+            freeze_legacy_media: false,
+            retention_interval_s: default_retention_interval_s(),
+        }
+    }
+}
+
+fn default_remote_media_max_size() -> u64 {
+    50 * 1024 * 1024
+}
+
+fn default_retention_interval_s() -> u64 {
+    3600
+}
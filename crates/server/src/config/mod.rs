@@ -1,10 +1,26 @@
 mod db_config;
+mod email_config;
+mod media_config;
+mod oidc_config;
+mod password_config;
+mod rate_limit_config;
 mod server_config;
+mod terms_config;
+mod uiaa_config;
+mod url_preview_config;
 
 use std::sync::OnceLock;
 
 pub use db_config::*;
+pub use email_config::*;
+pub use media_config::*;
+pub use oidc_config::*;
+pub use password_config::*;
+pub use rate_limit_config::*;
 pub use server_config::*;
+pub use terms_config::*;
+pub use uiaa_config::*;
+pub use url_preview_config::*;
 
 pub static CONFIG: OnceLock<ServerConfig> = OnceLock::new();
 
@@ -0,0 +1,57 @@
+//! Configuration for single sign-on through OpenID Connect providers
+
+use serde::Deserialize;
+
+use crate::core::OwnedMxcUri;
+use crate::true_value;
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub struct OidcConfig {
+    /// Refuse `m.login.password`, so users can only log in through a provider.
+    #[serde(default)]
+    pub disable_password_login: bool,
+    /// Origins of clients users are sent back to without confirmation, such as
+    /// `https://app.element.io`. Users logging in to other clients are asked to confirm the
+    /// destination first, so that a crafted link can't hand their login token to someone else.
+    #[serde(default)]
+    pub client_redirect_allowlist: Vec<String>,
+    #[serde(default)]
+    pub providers: Vec<OidcProviderConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OidcProviderConfig {
+    /// Identifies the provider in the SSO URLs, the callback URL registered at the provider is
+    /// `<well_known.client>/_matrix/client/v3/login/sso/callback/<id>`.
+    pub id: String,
+    /// Shown to users choosing a provider.
+    pub name: String,
+    pub icon: Option<OwnedMxcUri>,
+    /// The endpoints are discovered from `<issuer>/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    /// The userinfo claim the localpart of new users is derived from.
+    #[serde(default = "default_localpart_claim")]
+    pub localpart_claim: String,
+    /// The userinfo claim used as display name of new users.
+    #[serde(default = "default_display_name_claim")]
+    pub display_name_claim: String,
+    /// Create accounts for users logging in for the first time.
+    #[serde(default = "true_value")]
+    pub allow_registration: bool,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_owned(), "profile".to_owned()]
+}
+
+fn default_localpart_claim() -> String {
+    "preferred_username".to_owned()
+}
+
+fn default_display_name_claim() -> String {
+    "name".to_owned()
+}
@@ -0,0 +1,65 @@
+//! Configuration for passwords and their hashing
+
+use serde::Deserialize;
+
+/// Rules new passwords have to follow, they are checked when registering, changing and resetting a
+/// password. Existing passwords keep working.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PasswordPolicyConfig {
+    #[serde(default)]
+    pub minimum_length: usize,
+    #[serde(default)]
+    pub require_digit: bool,
+    #[serde(default)]
+    pub require_symbol: bool,
+    #[serde(default)]
+    pub require_lowercase: bool,
+    #[serde(default)]
+    pub require_uppercase: bool,
+    /// Refused passwords, e.g. common ones. Compared case-insensitively.
+    #[serde(default)]
+    pub denylist: Vec<String>,
+    /// File with more refused passwords, one per line.
+    #[serde(default)]
+    pub denylist_file: Option<String>,
+    /// Refuse passwords containing the localpart of the user.
+    #[serde(default)]
+    pub forbid_username: bool,
+}
+
+/// Cost parameters of the Argon2id password hashes. Stored hashes made with other parameters are
+/// replaced when their user logs in.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Argon2Config {
+    /// Memory used to hash a password, in KiB.
+    #[serde(default = "default_memory_cost_kib")]
+    pub memory_cost_kib: u32,
+    /// Number of passes over the memory.
+    #[serde(default = "default_time_cost")]
+    pub time_cost: u32,
+    /// Degree of parallelism.
+    #[serde(default = "default_lanes")]
+    pub lanes: u32,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            memory_cost_kib: default_memory_cost_kib(),
+            time_cost: default_time_cost(),
+            lanes: default_lanes(),
+        }
+    }
+}
+
+fn default_memory_cost_kib() -> u32 {
+    19 * 1024
+}
+
+fn default_time_cost() -> u32 {
+    2
+}
+
+fn default_lanes() -> u32 {
+    1
+}
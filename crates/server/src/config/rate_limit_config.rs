@@ -0,0 +1,92 @@
+//! Configuration for request rate limiting
+//!
+//! Every endpoint class has its own token bucket per requester. A bucket holds at most `burst_count`
+//! tokens and refills at `per_second` tokens per second, each request takes one token.
+
+use serde::Deserialize;
+
+use crate::core::OwnedUserId;
+use crate::true_value;
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct RateLimitConfig {
+    /// How many requests per second are allowed on average.
+    pub per_second: f64,
+    /// How many requests can be made in a burst before being limited.
+    pub burst_count: u32,
+}
+
+impl RateLimitConfig {
+    pub const fn new(per_second: f64, burst_count: u32) -> Self {
+        Self {
+            per_second,
+            burst_count,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitsConfig {
+    #[serde(default = "true_value")]
+    pub enabled: bool,
+    /// Users that are never rate limited, in addition to appservices.
+    #[serde(default)]
+    pub exempt_users: Vec<OwnedUserId>,
+
+    /// Endpoints that don't fall in any of the other classes.
+    #[serde(default = "default_client")]
+    pub client: RateLimitConfig,
+    #[serde(default = "default_login")]
+    pub login: RateLimitConfig,
+    #[serde(default = "default_registration")]
+    pub registration: RateLimitConfig,
+    /// Sending messages, state events and redactions.
+    #[serde(default = "default_message")]
+    pub message: RateLimitConfig,
+    #[serde(default = "default_media_upload")]
+    pub media_upload: RateLimitConfig,
+    /// Requests from other servers, keyed by their server name.
+    #[serde(default = "default_federation")]
+    pub federation: RateLimitConfig,
+    /// Validation emails sent to an address, keyed by the address.
+    #[serde(default = "default_threepid_validation")]
+    pub threepid_validation: RateLimitConfig,
+}
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            exempt_users: Vec::new(),
+            client: default_client(),
+            login: default_login(),
+            registration: default_registration(),
+            message: default_message(),
+            media_upload: default_media_upload(),
+            federation: default_federation(),
+            threepid_validation: default_threepid_validation(),
+        }
+    }
+}
+
+fn default_client() -> RateLimitConfig {
+    RateLimitConfig::new(10.0, 50)
+}
+fn default_login() -> RateLimitConfig {
+    RateLimitConfig::new(0.17, 3)
+}
+fn default_registration() -> RateLimitConfig {
+    RateLimitConfig::new(0.17, 3)
+}
+fn default_message() -> RateLimitConfig {
+    RateLimitConfig::new(0.2, 10)
+}
+fn default_media_upload() -> RateLimitConfig {
+    RateLimitConfig::new(0.5, 10)
+}
+fn default_federation() -> RateLimitConfig {
+    RateLimitConfig::new(50.0, 200)
+}
+fn default_threepid_validation() -> RateLimitConfig {
+    RateLimitConfig::new(0.003, 5)
+}
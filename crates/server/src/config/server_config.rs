@@ -1,6 +1,11 @@
 use std::fmt;
 
-use super::DbConfig;
+use ipnet::IpNet;
+
+use super::{
+    Argon2Config, DbConfig, EmailConfig, MediaConfig, OidcConfig, PasswordPolicyConfig, RateLimitsConfig, TermsConfig,
+    UiaaConfig, UrlPreviewConfig,
+};
 use crate::core::{OwnedServerName, RoomVersionId};
 use crate::env_vars::required_var;
 use crate::{false_value, true_value};
@@ -40,13 +45,79 @@ pub struct ServerConfig {
     pub max_concurrent_requests: u16,
     #[serde(default = "default_max_fetch_prev_events")]
     pub max_fetch_prev_events: u16,
+    /// Per endpoint class request rate limits, see `[rate_limits]` in the example config.
+    #[serde(default)]
+    pub rate_limits: RateLimitsConfig,
+    /// Retention of uploaded and cached remote media, see `[media]` in the example config.
+    #[serde(default)]
+    pub media: MediaConfig,
+    /// Fetching of URL previews, see `[url_preview]` in the example config.
+    #[serde(default)]
+    pub url_preview: UrlPreviewConfig,
     #[serde(default = "false_value")]
     pub allow_registration: bool,
     pub registration_token: Option<String>,
+    /// Registration needs a token, either `registration_token` or one created with the
+    /// `create-registration-token` admin command. Registration is open to token holders even
+    /// when `allow_registration` is false.
+    #[serde(default = "false_value")]
+    pub registration_requires_token: bool,
+    /// Single sign-on providers, see `[oidc]` in the example config.
+    #[serde(default)]
+    pub oidc: OidcConfig,
+    /// Lifetime of the access tokens of clients that support refresh tokens, they get a new one
+    /// with their refresh token. Access tokens of other clients don't expire.
+    ///
+    /// default: 300
+    #[serde(default = "default_access_token_lifetime_s")]
+    pub access_token_lifetime_s: u64,
+    /// Let logged in users create login tokens with `/login/get_token`, to sign in new devices from
+    /// an existing session, e.g. by scanning a QR code.
+    #[serde(default = "false_value")]
+    pub login_via_existing_session: bool,
+    /// Lifetime of the tokens created with `/login/get_token`.
+    ///
+    /// default: 120
+    #[serde(default = "default_login_token_lifetime_s")]
+    pub login_token_lifetime_s: u64,
+    /// Sending of emails, to validate email addresses and to reset passwords. Email addresses can't
+    /// be added to accounts when unset.
+    #[serde(default)]
+    pub email: Option<EmailConfig>,
+    /// Captcha and fallback pages of user-interactive authentication, see `[uiaa]` in the example
+    /// config.
+    #[serde(default)]
+    pub uiaa: UiaaConfig,
+    /// Terms of service users accept when registering, see `[terms]` in the example config.
+    #[serde(default)]
+    pub terms: Option<TermsConfig>,
+    /// Rules new passwords have to follow, see `[password_policy]` in the example config.
+    #[serde(default)]
+    pub password_policy: PasswordPolicyConfig,
+    /// Cost of the password hashes, see `[argon2]` in the example config.
+    #[serde(default)]
+    pub argon2: Argon2Config,
+    /// Proxies trusted to give the address of clients with `X-Forwarded-For`, e.g. `127.0.0.1/32`.
+    /// Without them, clients are identified by the address connecting to palpo.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Interval at which the addresses, user agents and times devices were last seen at are written
+    /// to the database.
+    ///
+    /// default: 60
+    #[serde(default = "default_last_seen_flush_interval_s")]
+    pub last_seen_flush_interval_s: u64,
     #[serde(default = "true_value")]
     pub allow_encryption: bool,
     #[serde(default = "false_value")]
     pub allow_federation: bool,
+    /// When not empty, only servers matching one of these patterns are federated with. Patterns
+    /// can use the `*` and `?` wildcards, e.g. `*.example.com`.
+    #[serde(default)]
+    pub federation_domain_whitelist: Vec<String>,
+    /// Servers matching one of these patterns are never federated with, even when whitelisted.
+    #[serde(default)]
+    pub federation_domain_blacklist: Vec<String>,
     #[serde(default = "true_value")]
     pub allow_room_creation: bool,
     #[serde(default = "true_value")]
@@ -299,6 +370,18 @@ fn default_turn_ttl() -> u64 {
     60 * 60 * 24
 }
 
+fn default_access_token_lifetime_s() -> u64 {
+    5 * 60
+}
+
+fn default_login_token_lifetime_s() -> u64 {
+    2 * 60
+}
+
+fn default_last_seen_flush_interval_s() -> u64 {
+    60
+}
+
 fn default_presence_idle_timeout_s() -> u64 {
     5 * 60
 }
@@ -0,0 +1,36 @@
+//! Configuration for the terms of service users have to accept
+
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TermsConfig {
+    /// Version of the policies. Users accept the policies again when it changes.
+    pub version: String,
+    /// The documents users accept, they are shown when registering and on the consent page.
+    pub policies: Vec<PolicyConfig>,
+    /// Secret signing the links to the consent page, links sent to a user stay valid when it is
+    /// kept.
+    pub form_secret: String,
+    /// Refuse events from users who didn't accept the current version with `M_CONSENT_NOT_GIVEN`.
+    #[serde(default = "default_block_events_without_consent")]
+    pub block_events_without_consent: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PolicyConfig {
+    /// Identifier of the policy, e.g. `privacy_policy`.
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    /// Language of the document.
+    #[serde(default = "default_lang")]
+    pub lang: String,
+}
+
+fn default_block_events_without_consent() -> bool {
+    true
+}
+
+fn default_lang() -> String {
+    "en".to_owned()
+}
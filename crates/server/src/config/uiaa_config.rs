@@ -0,0 +1,45 @@
+//! Configuration for user-interactive authentication
+
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct UiaaConfig {
+    /// Unfinished sessions are forgotten this long after they were started.
+    #[serde(default = "default_session_lifetime_s")]
+    pub session_lifetime_s: u64,
+    /// Directory with pages replacing the built-in fallback pages of the authentication stages,
+    /// `<stage>.html` for `password`, `registration_token`, `terms`, `recaptcha` and `success`.
+    #[serde(default)]
+    pub fallback_template_dir: Option<String>,
+    /// Registering needs solving a captcha when set.
+    #[serde(default)]
+    pub recaptcha: Option<RecaptchaConfig>,
+}
+
+impl Default for UiaaConfig {
+    fn default() -> Self {
+        Self {
+            session_lifetime_s: default_session_lifetime_s(),
+            fallback_template_dir: None,
+            recaptcha: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RecaptchaConfig {
+    /// The site key shown to users.
+    pub public_key: String,
+    /// The secret key responses are verified with.
+    pub private_key: String,
+    #[serde(default = "default_verify_url")]
+    pub verify_url: String,
+}
+
+fn default_session_lifetime_s() -> u64 {
+    60 * 60
+}
+
+fn default_verify_url() -> String {
+    "https://www.google.com/recaptcha/api/siteverify".to_owned()
+}
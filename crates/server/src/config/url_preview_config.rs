@@ -0,0 +1,96 @@
+//! Configuration for URL previews
+//!
+//! Previews are fetched by the server, so every request first checks the domain against the
+//! allow / deny lists and every resolved address against the IP range denylist. This keeps users
+//! from probing services on the server's network.
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct UrlPreviewConfig {
+    /// Previews make the server fetch any URL its users send, so they have to be enabled explicitly.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Addresses previews are never fetched from, private, loopback and link-local ranges by default.
+    ///
+    /// Also applies to media that other servers redirect to.
+    #[serde(default = "default_ip_range_denylist")]
+    pub ip_range_denylist: Vec<IpNet>,
+    /// Addresses in the denylist that may be fetched from anyway.
+    #[serde(default)]
+    pub ip_range_allowlist: Vec<IpNet>,
+    /// When not empty, only these domains and their subdomains are previewed.
+    #[serde(default)]
+    pub domain_allowlist: Vec<String>,
+    /// These domains and their subdomains are never previewed.
+    #[serde(default)]
+    pub domain_denylist: Vec<String>,
+    /// The maximum size of a fetched page or image in bytes.
+    #[serde(default = "default_max_spider_size")]
+    pub max_spider_size: u64,
+    /// How long fetching a page or image may take.
+    #[serde(default = "default_timeout_s")]
+    pub timeout_s: u64,
+    /// How long a preview is reused for requests without a timestamp, older previews are deleted by
+    /// the media retention job.
+    #[serde(default = "default_cache_duration_s")]
+    pub cache_duration_s: u64,
+}
+
+impl Default for UrlPreviewConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ip_range_denylist: default_ip_range_denylist(),
+            ip_range_allowlist: Vec::new(),
+            domain_allowlist: Vec::new(),
+            domain_denylist: Vec::new(),
+            max_spider_size: default_max_spider_size(),
+            timeout_s: default_timeout_s(),
+            cache_duration_s: default_cache_duration_s(),
+        }
+    }
+}
+
+fn default_ip_range_denylist() -> Vec<IpNet> {
+    [
+        "127.0.0.0/8",
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "100.64.0.0/10",
+        "192.0.0.0/24",
+        "169.254.0.0/16",
+        "192.88.99.0/24",
+        "198.18.0.0/15",
+        "192.0.2.0/24",
+        "198.51.100.0/24",
+        "203.0.113.0/24",
+        "224.0.0.0/4",
+        "0.0.0.0/8",
+        "255.255.255.255/32",
+        "::1/128",
+        "::/128",
+        "fe80::/10",
+        "fc00::/7",
+        "2001:db8::/32",
+        "ff00::/8",
+        "fec0::/10",
+    ]
+    .into_iter()
+    .map(|net| net.parse().expect("default ip range is valid"))
+    .collect()
+}
+
+fn default_max_spider_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_timeout_s() -> u64 {
+    10
+}
+
+fn default_cache_duration_s() -> u64 {
+    24 * 60 * 60
+}
@@ -3,6 +3,7 @@ use std::{collections::BTreeMap, iter::FromIterator, str};
 use diesel::prelude::*;
 use palpo_core::UnixMillis;
 use salvo::http::{
+    header,
     headers::{
         authorization::{Authorization, Credentials},
         HeaderMapExt,
@@ -13,17 +14,17 @@ use salvo::prelude::*;
 
 use crate::core::authorization::XMatrix;
 use crate::core::serde::CanonicalJsonValue;
-use crate::core::{signatures, OwnedServerName};
+use crate::core::{signatures, OwnedRoomId, OwnedServerName};
 use crate::schema::*;
 use crate::server_key::{PubKeyMap, PubKeys};
 use crate::user::{DbAccessToken, DbUser, DbUserDevice};
-use crate::{db, AppResult, AuthArgs, AuthedInfo, MatrixError};
+use crate::{db, AppResult, AuthArgs, AuthedInfo, DepotExt, MatrixError};
 
 #[handler]
 pub async fn auth_by_access_token_or_signatures(aa: AuthArgs, req: &mut Request, depot: &mut Depot) -> AppResult<()> {
     if let Some(authorization) = &aa.authorization {
         if authorization.starts_with("Bearer ") {
-            auth_by_access_token_inner(aa, depot).await
+            auth_by_access_token_inner(aa, req, depot).await
         } else {
             auth_by_signatures_inner(req, depot).await
         }
@@ -33,15 +34,38 @@ pub async fn auth_by_access_token_or_signatures(aa: AuthArgs, req: &mut Request,
 }
 
 #[handler]
-pub async fn auth_by_access_token(aa: AuthArgs, depot: &mut Depot) -> AppResult<()> {
-    auth_by_access_token_inner(aa, depot).await
+pub async fn auth_by_access_token(aa: AuthArgs, req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    auth_by_access_token_inner(aa, req, depot).await
+}
+/// Authenticates requests that have an access token, for endpoints that can also be used without one.
+#[handler]
+pub async fn auth_by_access_token_if_present(aa: AuthArgs, req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    if aa.require_access_token().is_ok() {
+        auth_by_access_token_inner(aa, req, depot).await
+    } else {
+        Ok(())
+    }
 }
 #[handler]
 pub async fn auth_by_signatures(_aa: AuthArgs, req: &mut Request, depot: &mut Depot) -> AppResult<()> {
     auth_by_signatures_inner(req, depot).await
 }
 
-async fn auth_by_access_token_inner(aa: AuthArgs, depot: &mut Depot) -> AppResult<()> {
+/// Refuses federation requests about the room in the path from servers its `m.room.server_acl`
+/// denies.
+#[handler]
+pub async fn check_server_acl(req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    let Some(room_id) = req.param::<OwnedRoomId>("room_id") else {
+        return Ok(());
+    };
+    // Requests authenticated with an access token have no origin
+    let Ok(origin) = depot.origin() else {
+        return Ok(());
+    };
+    crate::event::handler::acl_check(origin, &room_id)
+}
+
+async fn auth_by_access_token_inner(aa: AuthArgs, req: &Request, depot: &mut Depot) -> AppResult<()> {
     let token = aa.require_access_token()?;
 
     let access_token = user_access_tokens::table
@@ -49,6 +73,18 @@ async fn auth_by_access_token_inner(aa: AuthArgs, depot: &mut Depot) -> AppResul
         .first::<DbAccessToken>(&mut *db::connect()?)
         .ok();
     if let Some(access_token) = access_token {
+        if access_token
+            .expired_at
+            .is_some_and(|expired_at| expired_at < UnixMillis::now())
+        {
+            return Err(MatrixError::unknown_token(true, "Access token has expired.").into());
+        }
+        if access_token.refresh_token_id.is_some() && !access_token.is_used {
+            // Using the access token revokes the refresh token it was exchanged for
+            diesel::update(user_access_tokens::table.find(access_token.id))
+                .set(user_access_tokens::is_used.eq(true))
+                .execute(&mut *db::connect()?)?;
+        }
         let user = users::table
             .find(&access_token.user_id)
             .first::<DbUser>(&mut *db::connect()?)
@@ -58,6 +94,11 @@ async fn auth_by_access_token_inner(aa: AuthArgs, depot: &mut Depot) -> AppResul
             .filter(user_devices::user_id.eq(&user.id))
             .first::<DbUserDevice>(&mut *db::connect()?)
             .map_err(|_| MatrixError::unknown_token(true, "User device not found"))?;
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok());
+        crate::user::record_last_seen(&user.id, &user_device.device_id, super::client_ip(req), user_agent);
 
         depot.inject(AuthedInfo {
             user,
@@ -83,6 +124,7 @@ async fn auth_by_signatures_inner(req: &mut Request, depot: &mut Depot) -> AppRe
     )]);
 
     let origin = &x_matrix.origin;
+    crate::federation::check_server_allowed(origin)?;
     let signatures = BTreeMap::from_iter([(
         origin.as_str().to_owned(),
         CanonicalJsonValue::Object(origin_signatures),
@@ -143,6 +185,7 @@ async fn auth_by_signatures_inner(req: &mut Request, depot: &mut Depot) -> AppRe
 
         Err(MatrixError::forbidden("Failed to verify X-Matrix signatures.").into())
     } else {
+        depot.inject(origin.to_owned());
         Ok(())
     }
 }
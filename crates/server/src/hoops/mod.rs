@@ -4,10 +4,11 @@ use salvo::size_limiter;
 use url::Url;
 
 use crate::core::MatrixError;
-use crate::AppResult;
 
 mod auth;
 pub use auth::*;
+mod rate_limit;
+pub use rate_limit::*;
 
 #[handler]
 pub async fn ensure_accept(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
@@ -67,11 +68,6 @@ pub fn get_origin_host(req: &mut Request) -> Option<String> {
         .and_then(|url| url.host_str().map(|v| v.to_owned()))
 }
 
-#[handler]
-pub async fn limit_rate() -> AppResult<()> {
-    Ok(())
-}
-
 // utf8 will cause complement testing fail.
 #[handler]
 pub async fn remove_json_utf8(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
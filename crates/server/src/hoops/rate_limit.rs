@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use salvo::http::Method;
+use salvo::prelude::*;
+
+use crate::config::RateLimitConfig;
+use crate::core::MatrixError;
+use crate::{AppResult, AuthArgs, DepotExt};
+
+/// Buckets are dropped once they are full again, but only when there are this many of them.
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+static BUCKETS: LazyLock<Mutex<HashMap<(RateLimitClass, String), TokenBucket>>> = LazyLock::new(Default::default);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitClass {
+    Client,
+    Login,
+    Registration,
+    Message,
+    MediaUpload,
+    Federation,
+    /// Not a class of requests, validation emails are limited per address.
+    ThreepidValidation,
+}
+
+impl RateLimitClass {
+    pub fn from_request(req: &Request) -> Self {
+        let path = req.uri().path();
+        let method = req.method();
+        if path.starts_with("/_matrix/federation/") {
+            Self::Federation
+        } else if *method == Method::POST && path.ends_with("/login") {
+            Self::Login
+        } else if *method == Method::POST && path.ends_with("/register") {
+            Self::Registration
+        } else if *method == Method::PUT
+            && (path.contains("/send/") || path.contains("/state/") || path.contains("/redact/"))
+            || *method == Method::POST && path.contains("/send/")
+        {
+            Self::Message
+        } else if path.contains("/media/") && (path.contains("/upload") || path.ends_with("/create")) {
+            Self::MediaUpload
+        } else {
+            Self::Client
+        }
+    }
+
+    pub fn config(&self) -> RateLimitConfig {
+        let conf = &crate::config().rate_limits;
+        match self {
+            Self::Client => conf.client,
+            Self::Login => conf.login,
+            Self::Registration => conf.registration,
+            Self::Message => conf.message,
+            Self::MediaUpload => conf.media_upload,
+            Self::Federation => conf.federation,
+            Self::ThreepidValidation => conf.threepid_validation,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn full(conf: &RateLimitConfig) -> Self {
+        Self {
+            tokens: conf.burst_count as f64,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, conf: &RateLimitConfig) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * conf.per_second).min(conf.burst_count as f64);
+        self.updated_at = now;
+    }
+
+    /// Takes one token, or returns how long to wait until one is available.
+    fn take(&mut self, conf: &RateLimitConfig) -> Result<(), Duration> {
+        self.refill(conf);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if conf.per_second > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / conf.per_second))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+}
+
+/// Takes a token from the `key` bucket of `class`.
+pub fn check_rate(class: RateLimitClass, key: &str) -> Result<(), MatrixError> {
+    let conf = class.config();
+    let mut buckets = BUCKETS.lock().unwrap();
+    if buckets.len() > MAX_IDLE_BUCKETS {
+        buckets.retain(|(class, _), bucket| {
+            let conf = class.config();
+            bucket.refill(&conf);
+            bucket.tokens < conf.burst_count as f64
+        });
+    }
+    buckets
+        .entry((class, key.to_owned()))
+        .or_insert_with(|| TokenBucket::full(&conf))
+        .take(&conf)
+        .map_err(|retry_after| {
+            MatrixError::limit_exceeded(
+                Some(retry_after.min(Duration::from_secs(60 * 60 * 24))),
+                "Too many requests.",
+            )
+        })
+}
+
+/// Returns the address of the client. Behind the proxies of `trusted_proxies`, it is the last
+/// address of `X-Forwarded-For` that is not a trusted proxy.
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    let remote_ip = req.remote_addr().clone().into_std().map(|addr| addr.ip())?;
+    let trusted_proxies = &crate::config().trusted_proxies;
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+    if !is_trusted(&remote_ip) {
+        return Some(remote_ip);
+    }
+
+    // Each proxy appends the address it got the request from
+    let forwarded_ips = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    let mut client_ip = remote_ip;
+    for ip in forwarded_ips.into_iter().rev() {
+        client_ip = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    Some(client_ip)
+}
+
+/// Limits requests with a token bucket per endpoint class, keyed by the authenticated user,
+/// the requesting server, or the client IP address.
+///
+/// Appservices and the users in `rate_limits.exempt_users` are never limited.
+#[handler]
+pub async fn limit_rate(aa: AuthArgs, req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    let conf = &crate::config().rate_limits;
+    if !conf.enabled {
+        return Ok(());
+    }
+
+    let key = if let Ok(authed) = depot.authed_info() {
+        if authed.appservice.is_some() || conf.exempt_users.contains(authed.user_id()) {
+            return Ok(());
+        }
+        authed.user_id().to_string()
+    } else if let Ok(token) = aa.require_access_token() {
+        // Routes without authentication, e.g. appservices registering their users
+        if crate::appservice::find_from_token(token).await?.is_some() {
+            return Ok(());
+        }
+        match client_ip(req) {
+            Some(ip) => ip.to_string(),
+            None => return Ok(()),
+        }
+    } else if let Ok(origin) = depot.origin() {
+        origin.to_string()
+    } else if let Some(ip) = client_ip(req) {
+        ip.to_string()
+    } else {
+        return Ok(());
+    };
+
+    check_rate(RateLimitClass::from_request(req), &key).map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_is_exhausted_after_burst() {
+        let conf = RateLimitConfig::new(1.0, 3);
+        let mut bucket = TokenBucket::full(&conf);
+        for _ in 0..3 {
+            assert!(bucket.take(&conf).is_ok());
+        }
+        let retry_after = bucket.take(&conf).unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_burst() {
+        let conf = RateLimitConfig::new(2.0, 3);
+        let mut bucket = TokenBucket {
+            tokens: 0.0,
+            updated_at: Instant::now() - Duration::from_secs(1),
+        };
+        assert!(bucket.take(&conf).is_ok());
+        assert!(bucket.take(&conf).is_ok());
+        assert!(bucket.take(&conf).is_err());
+
+        bucket.updated_at = Instant::now() - Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.take(&conf).is_ok());
+        }
+        assert!(bucket.take(&conf).is_err());
+    }
+
+    #[test]
+    fn bucket_without_refill_stays_empty() {
+        let conf = RateLimitConfig::new(0.0, 1);
+        let mut bucket = TokenBucket::full(&conf);
+        assert!(bucket.take(&conf).is_ok());
+        assert_eq!(bucket.take(&conf), Err(Duration::MAX));
+    }
+}
@@ -24,6 +24,8 @@ pub mod db;
 pub mod env_vars;
 pub mod hoops;
 pub mod schema;
+#[cfg(test)]
+mod test_utils;
 pub mod utils;
 
 pub mod error;
@@ -135,6 +137,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     crate::db::migrate();
 
     crate::sending::start_handler();
+    crate::watcher::start_listener();
+    crate::media::start_retention();
+    crate::media::start_reference_backfill();
+    crate::uiaa::start_cleanup();
+    crate::user::start_last_seen_flush();
 
     let router = routing::router();
     let doc = OpenApi::new("palpo api", "0.0.1").merge_router(&router);
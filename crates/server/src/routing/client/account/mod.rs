@@ -4,24 +4,23 @@ mod threepid;
 use salvo::oapi::extract::*;
 use salvo::prelude::*;
 
+use crate::core::client::account::threepid::{TokenViaEmailReqBody, TokenViaEmailResBody};
 use crate::core::client::account::{DeactivateReqBody, DeactivateResBody, ThirdPartyIdRemovalStatus, WhoamiResBody};
 use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo};
-use crate::{exts::*, hoops, json_ok, utils, AuthArgs, EmptyResult, JsonResult, MatrixError, SESSION_ID_LENGTH};
+use crate::core::serde::CanonicalJsonValue;
+use crate::core::third_party::Medium;
+use crate::mailer::MailTemplate;
+use crate::{exts::*, hoops, json_ok, AuthArgs, EmptyResult, JsonResult, MatrixError};
 
 pub fn public_router() -> Router {
     Router::with_path("account")
-        // .push(
-        //     Router::with_path("3pid")
-        //         .get(get_3pid)
-        //         .push(Router::with_path("add").post(add_3pid))
-        //         .push(Router::with_path("bind").post(bind_3pid))
-        //         .push(Router::with_path("unbind").post(unbind_3pid))
-        //         .push(Router::with_path("delete").post(delete_3pid))
-        //         .push(Router::with_path("msisdn/requestToken").post(msisdn_request_token))
-        //         .push(Router::with_path("email/requestTZoken").post(email_request_token)),
-        // )
-        .push(Router::with_path("email/requestToken").post(token_via_email))
-        .push(Router::with_path("msisdn/requestToken").post(token_via_msisdn))
+        .push(
+            Router::with_path("3pid")
+                .hoop(hoops::limit_rate)
+                .push(Router::with_path("email/requestToken").post(token_via_email))
+                .push(Router::with_path("msisdn/requestToken").post(token_via_msisdn)),
+        )
+        .push(password::public_router())
 }
 pub fn authed_router() -> Router {
     Router::with_path("account")
@@ -37,17 +36,32 @@ pub fn authed_router() -> Router {
         // )
         .push(Router::with_path("whoami").hoop(hoops::limit_rate).get(whoami))
         .push(Router::with_path("deactivate").hoop(hoops::limit_rate).post(deactivate))
-        .push(password::authed_router())
         .push(threepid::authed_router())
 }
 
 /// #POST /_matrix/client/v3/account/3pid/email/requestToken
 /// "This API should be used to request validation tokens when adding an email address to an account"
 ///
-/// - 403 signals that The homeserver does not allow the third party identifier as a contact option.
+/// - 400 signals that the email address is already used by an account
+/// - 403 signals that the homeserver does not allow the third party identifier as a contact option
 #[endpoint]
-async fn token_via_email(_aa: AuthArgs) -> EmptyResult {
-    Err(MatrixError::threepid_denied("Third party identifier is not allowed").into())
+async fn token_via_email(_aa: AuthArgs, body: JsonBody<TokenViaEmailReqBody>) -> JsonResult<TokenViaEmailResBody> {
+    if !crate::mailer::is_enabled() {
+        return Err(MatrixError::threepid_denied("Third party identifier is not allowed").into());
+    }
+    let email = crate::threepid::canonicalize_email(&body.email);
+    if crate::user::get_user_by_threepid(&Medium::Email, &email)?.is_some() {
+        return Err(MatrixError::threepid_in_use("Email address is already in use.").into());
+    }
+    let sid = crate::threepid::request_email_validation(
+        MailTemplate::VerifyEmail,
+        &body.client_secret,
+        &email,
+        body.send_attempt,
+        body.next_link.as_deref(),
+    )
+    .await?;
+    json_ok(TokenViaEmailResBody { sid, submit_url: None })
 }
 
 /// #POST /_matrix/client/v3/account/3pid/msisdn/requestToken
@@ -88,10 +102,12 @@ async fn whoami(_aa: AuthArgs, depot: &mut Depot) -> JsonResult<WhoamiResBody> {
 async fn deactivate(
     _aa: AuthArgs,
     body: JsonBody<DeactivateReqBody>,
+    req: &mut Request,
     depot: &mut Depot,
     res: &mut Response,
 ) -> JsonResult<DeactivateResBody> {
     let authed = depot.authed_info()?;
+    let request = req.parse_json::<CanonicalJsonValue>().await.ok();
 
     let mut uiaa_info = UiaaInfo {
         flows: vec![AuthFlow {
@@ -104,10 +120,13 @@ async fn deactivate(
     };
 
     let Some(auth) = &body.auth else {
-        uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
         return Err(uiaa_info.into());
     };
-    if crate::uiaa::try_auth(authed.user_id(), authed.device_id(), &auth, &uiaa_info).is_err() {
+    if crate::uiaa::try_auth(authed.user_id(), authed.device_id(), &auth, &uiaa_info)
+        .await
+        .is_err()
+    {
         res.status_code(StatusCode::UNAUTHORIZED);
         return Err(MatrixError::forbidden("Authentication failed.").into());
     }
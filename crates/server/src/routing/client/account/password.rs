@@ -3,15 +3,57 @@ use palpo_core::client::account::ChangePasswordReqBody;
 use salvo::oapi::extract::*;
 use salvo::prelude::*;
 
-use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo};
+use crate::core::client::account::{TokenViaEmailReqBody, TokenViaEmailResBody};
+use crate::core::client::uiaa::{AuthData, AuthError, AuthFlow, AuthType, EmailIdentity, UiaaInfo};
+use crate::core::error::ErrorKind;
+use crate::core::serde::CanonicalJsonValue;
+use crate::core::third_party::Medium;
 use crate::exts::*;
+use crate::mailer::MailTemplate;
 use crate::schema::*;
-use crate::{db, empty_ok, hoops, utils, AuthArgs, EmptyResult, SESSION_ID_LENGTH};
+use crate::{db, empty_ok, hoops, json_ok, utils, AuthArgs, EmptyResult, JsonResult, MatrixError, SESSION_ID_LENGTH};
 
-pub fn authed_router() -> Router {
+pub fn public_router() -> Router {
     Router::with_path("password")
         .hoop(hoops::limit_rate)
-        .post(change_password)
+        .push(
+            Router::new()
+                .hoop(hoops::auth_by_access_token_if_present)
+                .post(change_password),
+        )
+        .push(Router::with_path("email/requestToken").post(token_via_email))
+        .push(Router::with_path("msisdn/requestToken").post(token_via_msisdn))
+}
+
+/// #POST /_matrix/client/v3/account/password/email/requestToken
+/// Sends a link to the email address of an account, to reset its password.
+///
+/// - 400 signals that no account uses the email address
+#[endpoint]
+async fn token_via_email(_aa: AuthArgs, body: JsonBody<TokenViaEmailReqBody>) -> JsonResult<TokenViaEmailResBody> {
+    if !crate::mailer::is_enabled() {
+        return Err(MatrixError::threepid_denied("Third party identifier is not allowed").into());
+    }
+    let email = crate::threepid::canonicalize_email(&body.email);
+    if crate::user::get_user_by_threepid(&Medium::Email, &email)?.is_none() {
+        return Err(MatrixError::threepid_not_found("Email address is not used by any account.").into());
+    }
+    let sid = crate::threepid::request_email_validation(
+        MailTemplate::PasswordReset,
+        &body.client_secret,
+        &email,
+        body.send_attempt,
+        body.next_link.as_deref(),
+    )
+    .await?;
+    json_ok(TokenViaEmailResBody { sid, submit_url: None })
+}
+
+/// #POST /_matrix/client/v3/account/password/msisdn/requestToken
+/// Password resets by phone number are not supported.
+#[endpoint]
+async fn token_via_msisdn(_aa: AuthArgs) -> EmptyResult {
+    Err(MatrixError::threepid_denied("Third party identifier is not allowed").into())
 }
 
 /// #POST /_matrix/client/r0/account/password
@@ -28,8 +70,16 @@ pub fn authed_router() -> Router {
 /// - Forgets to-device events
 /// - Triggers device list updates
 #[endpoint]
-async fn change_password(_aa: AuthArgs, body: JsonBody<ChangePasswordReqBody>, depot: &mut Depot) -> EmptyResult {
-    let authed = depot.authed_info()?;
+async fn change_password(
+    _aa: AuthArgs,
+    body: JsonBody<ChangePasswordReqBody>,
+    req: &mut Request,
+    depot: &mut Depot,
+) -> EmptyResult {
+    let Ok(authed) = depot.authed_info() else {
+        return reset_password(body.into_inner());
+    };
+    let request = req.parse_json::<CanonicalJsonValue>().await.ok();
 
     let mut uiaa_info = UiaaInfo {
         flows: vec![AuthFlow {
@@ -41,11 +91,14 @@ async fn change_password(_aa: AuthArgs, body: JsonBody<ChangePasswordReqBody>, d
         auth_error: None,
     };
     let Some(auth) = &body.auth else {
-        uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
         return Err(uiaa_info.into());
     };
-    if crate::uiaa::try_auth(authed.user_id(), authed.device_id(), &auth, &uiaa_info).is_err() {
-        uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
+    if crate::uiaa::try_auth(authed.user_id(), authed.device_id(), &auth, &uiaa_info)
+        .await
+        .is_err()
+    {
+        crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
         return Err(uiaa_info.into());
     }
 
@@ -85,3 +138,40 @@ async fn change_password(_aa: AuthArgs, body: JsonBody<ChangePasswordReqBody>, d
 
     empty_ok()
 }
+
+/// Sets the password of the account the validated email address belongs to, for users who can't
+/// log in anymore.
+fn reset_password(body: ChangePasswordReqBody) -> EmptyResult {
+    let mut uiaa_info = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::EmailIdentity],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: Some(utils::random_string(SESSION_ID_LENGTH)),
+        auth_error: None,
+    };
+    let Some(AuthData::EmailIdentity(EmailIdentity { threepid_creds, .. })) = &body.auth else {
+        return Err(uiaa_info.into());
+    };
+    let Some(session) = crate::threepid::get_validated_session(&threepid_creds.sid, &threepid_creds.client_secret)?
+    else {
+        uiaa_info.auth_error = Some(AuthError::new(
+            ErrorKind::ThreepidAuthFailed,
+            "Email address has not been validated.",
+        ));
+        return Err(uiaa_info.into());
+    };
+    let Some(user_id) = crate::user::get_user_by_threepid(&Medium::from(session.medium.as_str()), &session.address)?
+    else {
+        return Err(MatrixError::threepid_not_found("Email address is not used by any account.").into());
+    };
+    crate::threepid::delete_session(&session.session_id)?;
+
+    crate::user::set_password(&user_id, &body.new_password)?;
+    if body.logout_devices {
+        crate::user::remove_all_devices(&user_id)?;
+    }
+    info!("User {} reset their password.", user_id);
+    empty_ok()
+}
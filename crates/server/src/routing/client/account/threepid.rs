@@ -6,10 +6,21 @@
 //!
 //! [spec]: https://spec.matrix.org/latest/client-server-api/#post_matrixclientv3account3pidadd
 
+use salvo::oapi::extract::*;
 use salvo::prelude::*;
+use serde::Deserialize;
 
-use crate::core::client::account::threepid::ThreepidsResBody;
-use crate::{empty_ok, json_ok, AuthArgs, EmptyResult, JsonResult};
+use crate::core::client::account::threepid::{
+    AddThreepidReqBody, BindThreepidReqBody, DeleteThreepidReqBody, DeleteThreepidResBody, ThreepidsResBody,
+    UnbindThreepidReqBody, UnbindThreepidResBody,
+};
+use crate::core::client::account::ThirdPartyIdRemovalStatus;
+use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo};
+use crate::core::error::ErrorKind;
+use crate::core::serde::CanonicalJsonValue;
+use crate::core::third_party::Medium;
+use crate::core::UnixMillis;
+use crate::{empty_ok, json_ok, AppError, AuthArgs, DepotExt, EmptyResult, JsonResult, MatrixError};
 
 pub fn authed_router() -> Router {
     Router::with_path("3pid")
@@ -20,39 +31,156 @@ pub fn authed_router() -> Router {
         // 1.1 => "/_matrix/client/v3/account/3pid/bind",
         .push(Router::with_path("add").post(add))
         .push(Router::with_path("bind").post(bind))
+        .push(Router::with_path("unbind").post(unbind))
         .push(Router::with_path("delete").post(delete))
 }
 
+/// The identity server's answer to a bind request.
+#[derive(Deserialize, Debug)]
+struct IdentityBindResponse {
+    medium: Medium,
+    address: String,
+}
+
 /// #GET _matrix/client/v3/account/3pid
 /// Get a list of third party identifiers associated with this account.
-///
-/// - Currently always returns empty list
 #[endpoint]
-async fn get(_aa: AuthArgs) -> JsonResult<ThreepidsResBody> {
-    // TODO: later
-    json_ok(ThreepidsResBody::new(Vec::new()))
+async fn get(_aa: AuthArgs, depot: &mut Depot) -> JsonResult<ThreepidsResBody> {
+    let authed = depot.authed_info()?;
+    let threepids = crate::user::get_threepids(authed.user_id())?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    json_ok(ThreepidsResBody::new(threepids))
 }
 
+/// #POST _matrix/client/v3/account/3pid/add
+/// Adds a third party identifier the homeserver validated to the account.
+///
+/// - Requires UIAA to verify user password
 #[endpoint]
-async fn add(_aa: AuthArgs) -> EmptyResult {
-    // TODO: later
+async fn add(_aa: AuthArgs, body: JsonBody<AddThreepidReqBody>, req: &mut Request, depot: &mut Depot) -> EmptyResult {
+    let authed = depot.authed_info()?;
+    let request = req.parse_json::<CanonicalJsonValue>().await.ok();
+    let body = body.into_inner();
+
+    // UIAA
+    let mut uiaa_info = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::Password],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+    let Some(auth) = &body.auth else {
+        crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
+        return Err(uiaa_info.into());
+    };
+    match crate::uiaa::try_auth(authed.user_id(), authed.device_id(), auth, &uiaa_info).await {
+        Ok((true, _)) => {}
+        Ok((false, uiaa_info)) => return Err(uiaa_info.into()),
+        Err(AppError::Matrix(e)) if e.kind == ErrorKind::Forbidden => return Err(e.into()),
+        Err(_) => {
+            crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
+            return Err(uiaa_info.into());
+        }
+    }
+
+    let Some(session) = crate::threepid::get_validated_session(&body.sid, &body.client_secret)? else {
+        return Err(MatrixError::threepid_auth_failed("Third party identifier has not been validated.").into());
+    };
+    let medium = Medium::from(session.medium.as_str());
+    if crate::user::get_user_by_threepid(&medium, &session.address)?.is_some() {
+        return Err(MatrixError::threepid_in_use("Third party identifier is already in use.").into());
+    }
+    let validated_at = UnixMillis(session.validated_at.unwrap_or_default() as u64);
+    crate::user::add_threepid(authed.user_id(), &medium, &session.address, validated_at)?;
+    crate::threepid::delete_session(&session.session_id)?;
     empty_ok()
 }
 
+/// #POST _matrix/client/v3/account/3pid/bind
+/// Binds a third party identifier the identity server validated to the user on that identity
+/// server.
 #[endpoint]
-async fn bind(_aa: AuthArgs) -> EmptyResult {
-    // TODO: later
+async fn bind(_aa: AuthArgs, body: JsonBody<BindThreepidReqBody>, depot: &mut Depot) -> EmptyResult {
+    let authed = depot.authed_info()?;
+    let body = body.into_inner();
+    let id_server = &body.identity_server_info.id_server;
+
+    let response = crate::default_client()
+        .post(format!("https://{id_server}/_matrix/identity/v2/3pid/bind"))
+        .bearer_auth(&body.identity_server_info.id_access_token)
+        .json(&serde_json::json!({
+            "sid": body.sid,
+            "client_secret": body.client_secret,
+            "mxid": authed.user_id(),
+        }))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        warn!(
+            "Binding third party identifier on {id_server} failed: {}",
+            response.status()
+        );
+        return Err(
+            MatrixError::threepid_auth_failed("Identity server refused to bind the third party identifier.").into(),
+        );
+    }
+    let bound = response.json::<IdentityBindResponse>().await?;
+    crate::user::add_threepid_id_server(authed.user_id(), &bound.medium, &bound.address, id_server)?;
     empty_ok()
 }
 
+/// #POST _matrix/client/v3/account/3pid/unbind
+/// Forgets the bindings of a third party identifier on identity servers.
+///
+/// - Unbinding on the identity servers themselves is not supported
 #[endpoint]
-async fn unbind(_aa: AuthArgs) -> EmptyResult {
-    // TODO: later
-    empty_ok()
+async fn unbind(
+    _aa: AuthArgs,
+    body: JsonBody<UnbindThreepidReqBody>,
+    depot: &mut Depot,
+) -> JsonResult<UnbindThreepidResBody> {
+    let authed = depot.authed_info()?;
+    let id_server_unbind_result =
+        remove_id_server_bindings(authed.user_id(), &body.medium, &body.address, body.id_server.as_deref())?;
+    json_ok(UnbindThreepidResBody {
+        id_server_unbind_result,
+    })
 }
 
+/// #POST _matrix/client/v3/account/3pid/delete
+/// Removes a third party identifier from the account.
+///
+/// - Unbinding on the identity servers themselves is not supported
 #[endpoint]
-async fn delete(_aa: AuthArgs) -> EmptyResult {
-    // TODO: later
-    empty_ok()
+async fn delete(
+    _aa: AuthArgs,
+    body: JsonBody<DeleteThreepidReqBody>,
+    depot: &mut Depot,
+) -> JsonResult<DeleteThreepidResBody> {
+    let authed = depot.authed_info()?;
+    let id_server_unbind_result =
+        remove_id_server_bindings(authed.user_id(), &body.medium, &body.address, body.id_server.as_deref())?;
+    crate::user::delete_threepid(authed.user_id(), &body.medium, &body.address)?;
+    json_ok(DeleteThreepidResBody {
+        id_server_unbind_result,
+    })
+}
+
+fn remove_id_server_bindings(
+    user_id: &crate::core::UserId,
+    medium: &Medium,
+    address: &str,
+    id_server: Option<&str>,
+) -> crate::AppResult<ThirdPartyIdRemovalStatus> {
+    let id_servers = crate::user::remove_threepid_id_servers(user_id, medium, address, id_server)?;
+    if id_servers.is_empty() {
+        Ok(ThirdPartyIdRemovalStatus::Success)
+    } else {
+        Ok(ThirdPartyIdRemovalStatus::NoSupport)
+    }
 }
@@ -1,13 +1,50 @@
+use std::collections::BTreeMap;
+
+use salvo::oapi::extract::PathParam;
 use salvo::prelude::*;
 
-use crate::{empty_ok, AuthArgs, EmptyResult};
+use crate::core::client::server::{ConnectionInfo, DeviceInfo, SessionInfo, UserInfoResBody};
+use crate::core::OwnedUserId;
+use crate::{json_ok, AuthArgs, DepotExt, JsonResult, MatrixError};
 
 pub fn authed_router() -> Router {
     Router::with_path("admin/whois/<user_id>").get(whois)
 }
 
+/// #GET /_matrix/client/r0/admin/whois/{user_id}
+/// Returns where and when the devices of a user were last seen.
+///
+/// - Only server admins can look up other users
 #[endpoint]
-async fn whois(_aa: AuthArgs) -> EmptyResult {
-    // TODDO: todo
-    empty_ok()
+async fn whois(_aa: AuthArgs, user_id: PathParam<OwnedUserId>, depot: &mut Depot) -> JsonResult<UserInfoResBody> {
+    let authed = depot.authed_info()?;
+    let user_id = user_id.into_inner();
+    if authed.user_id() != &user_id && !authed.is_admin() {
+        return Err(MatrixError::forbidden("Only server admins can look up other users.").into());
+    }
+
+    let mut devices = BTreeMap::new();
+    for device in crate::user::get_devices(&user_id)? {
+        let user_agent = crate::user::get_pending_last_seen(&user_id, &device.device_id)
+            .and_then(|last_seen| last_seen.user_agent)
+            .or_else(|| device.user_agent.clone());
+        let device = device.into_matrix_device();
+        let connection = ConnectionInfo {
+            ip: device.last_seen_ip,
+            last_seen: device.last_seen_ts,
+            user_agent,
+        };
+        devices.insert(
+            device.device_id.to_string(),
+            DeviceInfo {
+                sessions: vec![SessionInfo {
+                    connections: vec![connection],
+                }],
+            },
+        );
+    }
+    json_ok(UserInfoResBody {
+        user_id: Some(user_id),
+        devices,
+    })
 }
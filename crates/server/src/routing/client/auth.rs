@@ -1,13 +1,258 @@
+//! `GET /_matrix/client/*/auth/{auth_type}/fallback/web`
+//!
+//! Pages completing an authentication stage in a browser, for clients that don't support the stage.
+//! `/v3/` ([spec])
+//!
+//! [spec]: https://spec.matrix.org/latest/client-server-api/#fallback
+use salvo::oapi::extract::*;
 use salvo::prelude::*;
 
-use crate::{empty_ok, AuthArgs, EmptyResult};
+use crate::core::client::uiaa::{AuthData, AuthType, Password, ReCaptcha, RegistrationToken, Terms, UiaaInfo};
+use crate::core::serde::JsonValue;
+use crate::core::{OwnedUserId, UserId};
+use crate::utils::HtmlEscape;
+use crate::{hoops, AppResult, AuthArgs, MatrixError};
 
-pub fn authed_router() -> Router {
-    Router::with_path("auth/<auth_type>/fallback/web").get(uiaa_fallback)
+pub fn public_router() -> Router {
+    Router::with_path("auth/<auth_type>/fallback/web")
+        .hoop(hoops::limit_rate)
+        .get(uiaa_fallback)
+        .post(submit_uiaa_fallback)
 }
 
+const PAGE_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+</head>
+<body>
+<h1>{title}</h1>
+<p style="color: red">{error}</p>
+<form method="post" action="">
+<input type="hidden" name="session" value="{session}">
+{form}
+<p><input type="submit" value="Continue"></p>
+</form>
+</body>
+</html>
+"#;
+
+const SUCCESS_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<script>
+if (window.onAuthDone) {
+    window.onAuthDone();
+} else if (window.opener && window.opener.postMessage) {
+    window.opener.postMessage("authDone", "*");
+}
+</script>
+</head>
+<body>
+<p>Thank you. You may now close this window and return to your client.</p>
+</body>
+</html>
+"#;
+
+/// The stages that can be completed through a fallback page.
+#[derive(Clone, Copy, Debug)]
+enum FallbackStage {
+    Password,
+    RegistrationToken,
+    Terms,
+    ReCaptcha,
+}
+
+impl FallbackStage {
+    fn new(auth_type: &AuthType) -> Option<Self> {
+        match auth_type {
+            AuthType::Password => Some(Self::Password),
+            AuthType::RegistrationToken => Some(Self::RegistrationToken),
+            AuthType::Terms => Some(Self::Terms),
+            AuthType::ReCaptcha => Some(Self::ReCaptcha),
+            _ => None,
+        }
+    }
+
+    /// The name of the stage's template.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Password => "password",
+            Self::RegistrationToken => "registration_token",
+            Self::Terms => "terms",
+            Self::ReCaptcha => "recaptcha",
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Self::Password => "Confirm your password",
+            Self::RegistrationToken => "Enter your registration token",
+            Self::Terms => "Accept the terms of service",
+            Self::ReCaptcha => "Confirm you are not a robot",
+        }
+    }
+
+    /// The inputs of the stage's form.
+    fn form(self, user_id: &UserId, uiaa_info: &UiaaInfo) -> String {
+        match self {
+            Self::Password => format!(
+                r#"<p><label>Password for {}: <input type="password" name="password" autofocus required></label></p>"#,
+                HtmlEscape(user_id.as_str())
+            ),
+            Self::RegistrationToken => {
+                r#"<p><label>Token: <input type="text" name="token" autofocus required></label></p>"#.to_owned()
+            }
+            Self::Terms => {
+                let params = serde_json::from_str::<JsonValue>(uiaa_info.params.get()).unwrap_or_default();
+                let mut form = String::new();
+                if let Some(policies) = params["m.login.terms"]["policies"].as_object() {
+                    for policy in policies.values() {
+                        // Policies are given per language, prefer English
+                        let Some(translation) = policy.get("en").or_else(|| {
+                            policy
+                                .as_object()
+                                .and_then(|policy| policy.values().find(|value| value.is_object()))
+                        }) else {
+                            continue;
+                        };
+                        let name = translation["name"].as_str().unwrap_or_default();
+                        let url = translation["url"].as_str().unwrap_or_default();
+                        let link = format!(
+                            r#"<a href="{}" target="_blank">{}</a>"#,
+                            HtmlEscape(url),
+                            HtmlEscape(name)
+                        );
+                        form.push_str(&format!(
+                            r#"<p><label><input type="checkbox" required> I agree to the {link}</label></p>"#
+                        ));
+                    }
+                }
+                form
+            }
+            Self::ReCaptcha => {
+                let public_key = crate::config()
+                    .uiaa
+                    .recaptcha
+                    .as_ref()
+                    .map(|recaptcha| recaptcha.public_key.as_str())
+                    .unwrap_or_default();
+                format!(
+                    r#"<script src="https://www.google.com/recaptcha/api.js" async defer></script>
+<div class="g-recaptcha" data-sitekey="{}"></div>"#,
+                    HtmlEscape(public_key)
+                )
+            }
+        }
+    }
+}
+
+/// Reads the template `name` from `uiaa.fallback_template_dir`, falls back to `default`.
+async fn load_template(name: &str, default: &str) -> String {
+    let Some(dir) = &crate::config().uiaa.fallback_template_dir else {
+        return default.to_owned();
+    };
+    let path = std::path::Path::new(dir).join(format!("{name}.html"));
+    match tokio::fs::read_to_string(&path).await {
+        Ok(template) => template,
+        Err(e) => {
+            warn!("Failed to read UIAA fallback template {}: {e}", path.display());
+            default.to_owned()
+        }
+    }
+}
+
+/// Returns the stage of the fallback page and the session it completes.
+fn get_stage_session(auth_type: &str, session: &str) -> AppResult<(FallbackStage, OwnedUserId, UiaaInfo)> {
+    let auth_type = AuthType::from(auth_type);
+    let stage = FallbackStage::new(&auth_type)
+        .ok_or_else(|| MatrixError::not_found("Authentication stage has no fallback page."))?;
+    let Some((user_id, _device_id, uiaa_info)) = crate::uiaa::get_session_by_id(session)? else {
+        return Err(MatrixError::not_found("Unknown session.").into());
+    };
+    if !uiaa_info.flows.iter().any(|flow| flow.stages.contains(&auth_type)) {
+        return Err(MatrixError::invalid_param("Authentication stage is not part of the session.").into());
+    }
+    Ok((stage, user_id, uiaa_info))
+}
+
+async fn render_stage(
+    res: &mut Response,
+    stage: FallbackStage,
+    auth_type: &str,
+    session: &str,
+    user_id: &UserId,
+    uiaa_info: &UiaaInfo,
+) {
+    let error = uiaa_info
+        .auth_error
+        .as_ref()
+        .map(|error| error.message.as_str())
+        .unwrap_or_default();
+    let page = load_template(stage.name(), PAGE_TEMPLATE)
+        .await
+        .replace("{title}", stage.title())
+        .replace("{stage}", &HtmlEscape(auth_type).to_string())
+        .replace("{session}", &HtmlEscape(session).to_string())
+        .replace("{error}", &HtmlEscape(error).to_string())
+        .replace("{form}", &stage.form(user_id, uiaa_info));
+    res.render(Text::Html(page));
+}
+
+/// #GET /_matrix/client/r0/auth/{auth_type}/fallback/web?session={session_id}
+/// Shows the form of an authentication stage.
 #[endpoint]
-async fn uiaa_fallback(_aa: AuthArgs) -> EmptyResult {
-    // TODDO: todo
-    empty_ok()
+async fn uiaa_fallback(
+    _aa: AuthArgs,
+    auth_type: PathParam<String>,
+    session: QueryParam<String, true>,
+    res: &mut Response,
+) -> AppResult<()> {
+    let (stage, user_id, uiaa_info) = get_stage_session(&auth_type, &session)?;
+    render_stage(res, stage, &auth_type, &session, &user_id, &uiaa_info).await;
+    Ok(())
+}
+
+/// #POST /_matrix/client/r0/auth/{auth_type}/fallback/web?session={session_id}
+/// Completes an authentication stage with the submitted form, the client continues the session
+/// once the page reports `authDone`.
+#[endpoint]
+async fn submit_uiaa_fallback(
+    _aa: AuthArgs,
+    auth_type: PathParam<String>,
+    session: QueryParam<String, true>,
+    req: &mut Request,
+    res: &mut Response,
+) -> AppResult<()> {
+    let (stage, user_id, _) = get_stage_session(&auth_type, &session)?;
+    let auth = match stage {
+        FallbackStage::Password => {
+            let password = req.form::<String>("password").await.unwrap_or_default();
+            AuthData::Password(Password::new((&user_id).into(), password))
+        }
+        FallbackStage::RegistrationToken => {
+            let token = req.form::<String>("token").await.unwrap_or_default();
+            AuthData::RegistrationToken(RegistrationToken::new(token))
+        }
+        FallbackStage::Terms => AuthData::Terms(Terms::new()),
+        FallbackStage::ReCaptcha => {
+            let response = req.form::<String>("g-recaptcha-response").await.unwrap_or_default();
+            AuthData::ReCaptcha(ReCaptcha::new(response))
+        }
+    };
+
+    let uiaa_info = crate::uiaa::complete_fallback_stage(&session, &auth).await?;
+    if uiaa_info.auth_error.is_some() {
+        render_stage(res, stage, &auth_type, &session, &user_id, &uiaa_info).await;
+    } else {
+        let page = load_template("success", SUCCESS_TEMPLATE)
+            .await
+            .replace("{title}", "Authentication complete");
+        res.render(Text::Html(page));
+    }
+    Ok(())
 }
@@ -14,10 +14,11 @@ use crate::core::client::device::{
 };
 use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo};
 use crate::core::error::ErrorKind;
+use crate::core::serde::CanonicalJsonValue;
 use crate::core::OwnedDeviceId;
 use crate::schema::*;
 use crate::user::DbUserDevice;
-use crate::{db, empty_ok, json_ok, utils, AppError, AuthArgs, DepotExt, EmptyResult, JsonResult, SESSION_ID_LENGTH};
+use crate::{db, empty_ok, json_ok, AppError, AuthArgs, DepotExt, EmptyResult, JsonResult};
 
 pub fn authed_router() -> Router {
     Router::with_path("devices")
@@ -58,9 +59,7 @@ async fn get_device(
 async fn list_devices(_aa: AuthArgs, depot: &mut Depot) -> JsonResult<DevicesResBody> {
     let authed = depot.authed_info()?;
 
-    let devices = user_devices::table
-        .filter(user_devices::user_id.eq(authed.user_id()))
-        .load::<DbUserDevice>(&mut *db::connect()?)?;
+    let devices = crate::user::get_devices(authed.user_id())?;
     json_ok(DevicesResBody {
         devices: devices.into_iter().map(DbUserDevice::into_matrix_device).collect(),
     })
@@ -99,9 +98,11 @@ async fn delete_device(
     _aa: AuthArgs,
     device_id: PathParam<OwnedDeviceId>,
     body: JsonBody<Option<DeleteDeviceReqBody>>,
+    req: &mut Request,
     depot: &mut Depot,
 ) -> EmptyResult {
     let authed = depot.authed_info()?;
+    let request = req.parse_json::<CanonicalJsonValue>().await.ok();
     let auth = body.into_inner().map(|body| body.auth).flatten();
     let device_id = device_id.into_inner();
 
@@ -116,17 +117,17 @@ async fn delete_device(
         auth_error: None,
     };
     let Some(auth) = auth else {
-        uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
         return Err(uiaa_info.into());
     };
 
-    if let Err(e) = crate::uiaa::try_auth(authed.user_id(), authed.device_id(), &auth, &uiaa_info) {
+    if let Err(e) = crate::uiaa::try_auth(authed.user_id(), authed.device_id(), &auth, &uiaa_info).await {
         if let AppError::Matrix(e) = e {
             if e.kind == ErrorKind::Forbidden {
                 return Err(e.into());
             }
         }
-        uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
         return Err(uiaa_info.into());
     }
     crate::user::remove_device(authed.user_id(), &device_id)?;
@@ -144,12 +145,18 @@ async fn delete_device(
 /// - Forgets to-device events
 /// - Triggers device list updates
 #[endpoint]
-async fn delete_devices(_aa: AuthArgs, body: JsonBody<DeleteDevicesReqBody>, depot: &mut Depot) -> EmptyResult {
+async fn delete_devices(
+    _aa: AuthArgs,
+    body: JsonBody<DeleteDevicesReqBody>,
+    req: &mut Request,
+    depot: &mut Depot,
+) -> EmptyResult {
     let authed = depot.authed_info()?;
+    let request = req.parse_json::<CanonicalJsonValue>().await.ok();
     let DeleteDevicesReqBody { devices, auth } = body.into_inner();
 
     // UIAA
-    let uiaa_info = UiaaInfo {
+    let mut uiaa_info = UiaaInfo {
         flows: vec![AuthFlow {
             stages: vec![AuthType::Password],
         }],
@@ -159,10 +166,11 @@ async fn delete_devices(_aa: AuthArgs, body: JsonBody<DeleteDevicesReqBody>, dep
         auth_error: None,
     };
     let Some(auth) = auth else {
+        crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
         return Err(uiaa_info.into());
     };
 
-    crate::uiaa::try_auth(authed.user_id(), authed.device_id(), &auth, &uiaa_info)?;
+    crate::uiaa::try_auth(authed.user_id(), authed.device_id(), &auth, &uiaa_info).await?;
     diesel::delete(
         user_devices::table
             .filter(user_devices::user_id.eq(authed.device_id()))
@@ -3,15 +3,22 @@ use salvo::prelude::*;
 
 use crate::core::client::key::UploadSigningKeysReqBody;
 use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo};
-use crate::{empty_ok, utils, AuthArgs, DepotExt, EmptyResult, SESSION_ID_LENGTH};
+use crate::core::serde::CanonicalJsonValue;
+use crate::{empty_ok, AuthArgs, DepotExt, EmptyResult};
 
 /// #POST /_matrix/client/r0/keys/device_signing/upload
 /// Uploads end-to-end key information for the sender user.
 ///
 /// - Requires UIAA to verify password
 #[endpoint]
-pub(super) async fn upload(_aa: AuthArgs, body: JsonBody<UploadSigningKeysReqBody>, depot: &mut Depot) -> EmptyResult {
+pub(super) async fn upload(
+    _aa: AuthArgs,
+    body: JsonBody<UploadSigningKeysReqBody>,
+    req: &mut Request,
+    depot: &mut Depot,
+) -> EmptyResult {
     let authed = depot.authed_info()?;
+    let request = req.parse_json::<CanonicalJsonValue>().await.ok();
 
     // UIAA
     let mut uiaa_info = UiaaInfo {
@@ -24,11 +31,11 @@ pub(super) async fn upload(_aa: AuthArgs, body: JsonBody<UploadSigningKeysReqBod
         auth_error: None,
     };
     let Some(auth) = &body.auth else {
-        uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
+        crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
         return Err(uiaa_info.into());
     };
 
-    crate::uiaa::try_auth(authed.user_id(), authed.device_id(), &auth, &uiaa_info)?;
+    crate::uiaa::try_auth(authed.user_id(), authed.device_id(), &auth, &uiaa_info).await?;
 
     if let Some(master_key) = &body.master_key {
         crate::user::add_cross_signing_keys(
@@ -1,17 +1,13 @@
 use std::fs;
-use std::io::Cursor;
 use std::path::Path;
 use std::str::FromStr;
-use std::time::Duration;
 
 use diesel::prelude::*;
 use hickory_resolver::proto::op::Header;
-use image::imageops::FilterType;
 use mime::Mime;
-use room_tags::content;
 use salvo::fs::NamedFile;
 use salvo::http::header::CONTENT_TYPE;
-use salvo::http::{HeaderValue, ResBody};
+use salvo::http::HeaderValue;
 use salvo::prelude::*;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
@@ -23,18 +19,18 @@ use crate::media::*;
 use crate::schema::*;
 use crate::{db, empty_ok, exts::*, hoops, json_ok, utils, AppResult, AuthArgs, EmptyResult, JsonResult, MatrixError};
 
-pub fn self_auth_router() -> Router {
+/// Authenticated media endpoints (MSC3916), only served under `/_matrix/client/v1`.
+pub fn authed_router() -> Router {
     Router::with_path("media")
         .oapi_tag("client")
+        .hoop(hoops::auth_by_access_token)
         .push(
             Router::with_path("download/<server_name>/<media_id>")
-                .hoop(hoops::auth_by_access_token_or_signatures)
                 .get(get_content)
                 .push(Router::with_path("<filename>").get(get_content_with_filename)),
         )
         .push(
             Router::with_hoop(hoops::limit_rate)
-                .hoop(hoops::auth_by_access_token)
                 .push(Router::with_path("config").get(get_config))
                 .push(Router::with_path("preview_url").get(preview_url))
                 .push(Router::with_path("thumbnail/<server_name>/<media_id>").get(get_thumbnail)),
@@ -47,7 +43,15 @@ pub fn self_auth_router() -> Router {
 /// - Only allows federation if `allow_remote` is true
 #[endpoint]
 pub async fn get_content(args: ContentReqArgs, req: &mut Request, res: &mut Response) -> AppResult<()> {
-    if let Some(metadata) = crate::media::get_metadata(&args.server_name, &args.media_id)? {
+    if crate::media::is_quarantined(&args.server_name, &args.media_id)? {
+        return Err(MatrixError::not_found("Media not found.").into());
+    }
+    let metadata = if &*args.server_name != crate::server_name() && args.allow_remote {
+        Some(crate::media::get_remote_content(&args.server_name, &args.media_id, args.timeout_ms).await?)
+    } else {
+        crate::media::get_metadata(&args.server_name, &args.media_id)?
+    };
+    if let Some(metadata) = metadata {
         let content_type = metadata
             .content_type
             .as_deref()
@@ -75,9 +79,6 @@ pub async fn get_content(args: ContentReqArgs, req: &mut Request, res: &mut Resp
         } else {
             Err(MatrixError::not_yet_uploaded("Media has not been uploaded yet").into())
         }
-    } else if &*args.server_name != crate::server_name() && args.allow_remote {
-        let mxc = format!("mxc://{}/{}", args.server_name, args.media_id);
-        get_remote_content(&mxc, &args.server_name, &args.media_id, res).await
     } else {
         Err(MatrixError::not_yet_uploaded("Media has not been uploaded yet").into())
     }
@@ -93,7 +94,14 @@ pub async fn get_content_with_filename(
     req: &mut Request,
     res: &mut Response,
 ) -> AppResult<()> {
-    let Some(metadata) = crate::media::get_metadata(&args.server_name, &args.media_id)? else {
+    if crate::media::is_quarantined(&args.server_name, &args.media_id)? {
+        return Err(MatrixError::not_found("Media not found.").into());
+    }
+    let metadata = if &*args.server_name != crate::server_name() && args.allow_remote {
+        crate::media::get_remote_content(&args.server_name, &args.media_id, args.timeout_ms).await?
+    } else if let Some(metadata) = crate::media::get_metadata(&args.server_name, &args.media_id)? {
+        metadata
+    } else {
         return Err(MatrixError::not_yet_uploaded("Media has not been uploaded yet").into());
     };
     let content_type = if let Some(content_type) = metadata.content_type.as_deref() {
@@ -130,9 +138,6 @@ pub async fn get_content_with_filename(
         file.send(req.headers(), res).await;
 
         Ok(())
-    } else if &*args.server_name != crate::server_name() && args.allow_remote {
-        let mxc = format!("mxc://{}/{}", args.server_name, args.media_id);
-        get_remote_content(&mxc, &args.server_name, &args.media_id, res).await
     } else {
         Err(MatrixError::not_yet_uploaded("Media has not been uploaded yet").into())
     }
@@ -164,9 +169,9 @@ pub async fn create_content(
     _aa: AuthArgs,
     args: CreateContentReqArgs,
     req: &mut Request,
-    _depot: &mut Depot,
+    depot: &mut Depot,
 ) -> JsonResult<CreateContentResBody> {
-    // let authed = depot.take_authed_info()?;
+    let authed = depot.authed_info()?;
     let upload_name = args.filename.clone();
     let file_extension = upload_name.as_deref().map(utils::fs::get_file_ext);
 
@@ -211,14 +216,14 @@ pub async fn create_content(
             file_extension,
             file_size: payload.len() as i64,
             file_hash: None,
-            created_by: None,
+            created_by: Some(authed.user_id().to_owned()),
             created_at: UnixMillis::now(),
         };
 
         diesel::insert_into(media_metadatas::table)
             .values(&metadata)
             .execute(&mut *db::connect()?)?;
-        //TODO: thumbnail support
+        crate::media::spawn_thumbnail_generation(conf.server_name.clone(), metadata.media_id, metadata.content_type);
     } else {
         return Err(MatrixError::cannot_overwrite_media("Media ID already has content").into());
     }
@@ -236,9 +241,9 @@ pub async fn upload_content(
     _aa: AuthArgs,
     args: UploadContentReqArgs,
     req: &mut Request,
-    _depot: &mut Depot,
+    depot: &mut Depot,
 ) -> EmptyResult {
-    // let authed = depot.take_authed_info()?;
+    let authed = depot.authed_info()?;
     let upload_name = args.filename.clone();
     let file_extension = upload_name.as_deref().map(utils::fs::get_file_ext);
 
@@ -280,15 +285,14 @@ pub async fn upload_content(
             file_extension,
             file_size: payload.len() as i64,
             file_hash: None,
-            created_by: None,
+            created_by: Some(authed.user_id().to_owned()),
             created_at: UnixMillis::now(),
         };
 
         diesel::insert_into(media_metadatas::table)
             .values(&metadata)
             .execute(&mut *db::connect()?)?;
-
-        //TODO: thumbnail support
+        crate::media::spawn_thumbnail_generation(conf.server_name.clone(), metadata.media_id, metadata.content_type);
         empty_ok()
     } else {
         Err(MatrixError::cannot_overwrite_media("Media ID already has content").into())
@@ -304,10 +308,19 @@ pub async fn get_config(_aa: AuthArgs) -> JsonResult<ConfigResBody> {
     })
 }
 
+/// #GET /_matrix/client/v1/media/preview_url
+/// Returns the OpenGraph data of a URL, fetched by the server.
 #[endpoint]
-pub async fn preview_url(_aa: AuthArgs) -> EmptyResult {
-    // TODDO: todo
-    empty_ok()
+pub async fn preview_url(
+    _aa: AuthArgs,
+    args: MediaPreviewReqArgs,
+    depot: &mut Depot,
+) -> JsonResult<MediaPreviewResBody> {
+    let authed = depot.authed_info()?;
+    let og_data = crate::media::get_url_preview(&args.url, args.ts, authed.user_id()).await?;
+    json_ok(MediaPreviewResBody::from_raw_value(serde_json::value::to_raw_value(
+        &og_data,
+    )?))
 }
 
 //// #GET /_matrix/media/r0/thumbnail/{server_name}/{media_id}
@@ -326,7 +339,7 @@ pub async fn preview_url(_aa: AuthArgs) -> EmptyResult {
 /// - Server rounds that up again to (958, 600) to fix the aspect ratio (only for width,height>96)
 /// - Server creates the thumbnail and sends it to the user
 ///
-/// For width,height <= 96 the server uses another thumbnailing algorithm which crops the image afterwards.
+/// For width,height <= 96 the image is cropped to the size instead, unless the client requests another `method`.
 #[endpoint]
 pub async fn get_thumbnail(
     _aa: AuthArgs,
@@ -335,215 +348,31 @@ pub async fn get_thumbnail(
     depot: &mut Depot,
     res: &mut Response,
 ) -> AppResult<()> {
-    if &*args.server_name != &crate::config().server_name && args.allow_remote {
-        let request = crate::core::federation::media::thumbnail_request(
-            &args.server_name.origin().await,
-            crate::core::federation::media::ThumbnailReqArgs {
-                height: args.height,
-                width: args.width,
-                method: args.method.clone(),
-                media_id: args.media_id.clone(),
-                timeout_ms: Duration::from_secs(20),
-                animated: None,
-            },
-        )?
-        .into_inner();
-        let mut response = crate::sending::send_federation_request(&args.server_name, request).await?;
-        *res.headers_mut() = response.headers().clone();
-        let bytes = response.bytes().await?;
-
-        let thumb_path = crate::media_path(
-            &args.server_name,
-            &format!("{}.{}x{}", args.media_id, args.width, args.height),
-        );
-        std::fs::create_dir_all(utils::fs::get_parent_dir(&thumb_path))?;
-        let mut f = File::create(&thumb_path).await?;
-        f.write_all(&bytes).await?;
-
-        res.body = ResBody::Once(bytes);
-        return Ok(());
+    if crate::media::is_quarantined(&args.server_name, &args.media_id)? {
+        return Err(MatrixError::not_found("Media not found.").into());
     }
-
-    match crate::media::get_thumbnail(&args.server_name, &args.media_id, args.width, args.height) {
-        Ok(Some(DbThumbnail {
-            content_disposition,
-            content_type,
-            ..
-        })) => {
-            let thumb_path = crate::media_path(
-                &args.server_name,
-                &format!("{}.{}x{}", args.media_id, args.width, args.height),
-            );
-
-            res.add_header("Cross-Origin-Resource-Policy", "cross-origin", true)?;
-            let mut file = NamedFile::builder(&thumb_path)
-                .content_type(
-                    Mime::from_str(&content_type)
-                        .ok()
-                        .unwrap_or(mime::APPLICATION_OCTET_STREAM),
-                )
-                .build()
-                .await?;
-            if let Some(Ok(content_disposition)) = content_disposition.as_deref().map(HeaderValue::from_str) {
-                file.set_content_disposition(content_disposition);
-            }
-
-            return Ok(());
-        }
-        Err(e) => {
-            tracing::error!(error = ?e, "get_thumbnail error");
-            return Err(MatrixError::not_found("Media not found.").into());
-        }
-        _ => {}
+    if &*args.server_name != crate::server_name() && args.allow_remote {
+        crate::media::cache_remote_thumbnail(
+            &args.server_name,
+            &args.media_id,
+            args.width,
+            args.height,
+            args.method.as_ref(),
+            args.animated.unwrap_or(false),
+            args.timeout_ms,
+        )
+        .await?;
     }
 
-    let (width, height, crop) = crate::media::thumbnail_properties(args.width, args.height).unwrap_or((0, 0, false)); // 0, 0 because that's the original file
-
-    let thumb_path = crate::media_path(&args.server_name, &format!("{}.{width}x{height}", &args.media_id));
-    if let Some(DbThumbnail {
-        media_id,
-        width,
-        height,
-        content_disposition,
-        content_type,
-        ..
-    }) = crate::media::get_thumbnail(&args.server_name, &args.media_id, width, height)?
-    {
-        // Using saved thumbnail
-        let mut file = NamedFile::builder(&thumb_path)
-            .content_type(
-                Mime::from_str(&content_type)
-                    .ok()
-                    .unwrap_or(mime::APPLICATION_OCTET_STREAM),
-            )
-            .build()
-            .await?;
-        if let Some(Ok(content_disposition)) = content_disposition.as_deref().map(HeaderValue::from_str) {
-            file.set_content_disposition(content_disposition);
-        }
-        file.send(req.headers(), res).await;
-
-        Ok(())
-    } else if let Ok(Some(DbMetadata {
-        content_disposition,
-        content_type,
-        media_id,
-        ..
-    })) = crate::media::get_metadata(&args.server_name, &args.media_id)
-    {
-        // Generate a thumbnail
-        let image_path = crate::media_path(&args.server_name, &args.media_id);
-        if let Ok(image) = image::open(&image_path) {
-            let original_width = image.width();
-            let original_height = image.height();
-            if width > original_width || height > original_height {
-                let mut file = NamedFile::builder(&thumb_path)
-                    .content_type(
-                        content_type
-                            .as_deref()
-                            .map(|c| Mime::from_str(c).ok())
-                            .flatten()
-                            .unwrap_or(mime::APPLICATION_OCTET_STREAM),
-                    )
-                    .build()
-                    .await?;
-                if let Some(Ok(content_disposition)) = content_disposition.as_deref().map(HeaderValue::from_str) {
-                    file.set_content_disposition(content_disposition);
-                }
-                file.send(req.headers(), res).await;
-                return Ok(());
-            }
-
-            let thumbnail = if crop {
-                image.resize_to_fill(width, height, FilterType::CatmullRom)
-            } else {
-                let (exact_width, exact_height) = {
-                    // Copied from image::dynimage::resize_dimensions
-                    let ratio = u64::from(original_width) * u64::from(height);
-                    let nratio = u64::from(width) * u64::from(original_height);
-
-                    let use_width = nratio <= ratio;
-                    let intermediate = if use_width {
-                        u64::from(original_height) * u64::from(width) / u64::from(original_width)
-                    } else {
-                        u64::from(original_width) * u64::from(height) / u64::from(original_height)
-                    };
-                    if use_width {
-                        if intermediate <= u64::from(::std::u32::MAX) {
-                            (width, intermediate as u32)
-                        } else {
-                            (
-                                (u64::from(width) * u64::from(::std::u32::MAX) / intermediate) as u32,
-                                ::std::u32::MAX,
-                            )
-                        }
-                    } else if intermediate <= u64::from(::std::u32::MAX) {
-                        (intermediate as u32, height)
-                    } else {
-                        (
-                            ::std::u32::MAX,
-                            (u64::from(height) * u64::from(::std::u32::MAX) / intermediate) as u32,
-                        )
-                    }
-                };
-
-                image.thumbnail_exact(exact_width, exact_height)
-            };
-
-            let mut thumbnail_bytes = Vec::new();
-            thumbnail.write_to(&mut Cursor::new(&mut thumbnail_bytes), image::ImageFormat::Png)?;
-
-            // Save thumbnail in database so we don't have to generate it again next time
-            diesel::insert_into(media_thumbnails::table)
-                .values(&NewDbThumbnail {
-                    media_id: args.media_id.clone(),
-                    origin_server: args.server_name.clone(),
-                    content_type: "image/png".into(),
-                    content_disposition: None,
-                    file_size: thumbnail_bytes.len() as i64,
-                    width: width as i32,
-                    height: height as i32,
-                    resize_method: "_".into(),
-                    created_at: UnixMillis::now(),
-                })
-                .execute(&mut *db::connect()?)?;
-            let mut f = File::create(&thumb_path).await?;
-            f.write_all(&thumbnail_bytes).await?;
-
-            let mut file = NamedFile::builder(&thumb_path)
-                .content_type(
-                    content_type
-                        .as_deref()
-                        .map(|c| Mime::from_str(c).ok())
-                        .flatten()
-                        .unwrap_or(mime::APPLICATION_OCTET_STREAM),
-                )
-                .build()
-                .await?;
-            if let Some(Ok(content_disposition)) = content_disposition.as_deref().map(HeaderValue::from_str) {
-                file.set_content_disposition(content_disposition);
-            }
-            file.send(req.headers(), res).await;
-            Ok(())
-        } else {
-            // Couldn't parse file to generate thumbnail, send original
-            let mut file = NamedFile::builder(&image_path)
-                .content_type(
-                    content_type
-                        .as_deref()
-                        .map(|c| Mime::from_str(c).ok())
-                        .flatten()
-                        .unwrap_or(mime::APPLICATION_OCTET_STREAM),
-                )
-                .build()
-                .await?;
-            if let Some(Ok(content_disposition)) = content_disposition.as_deref().map(HeaderValue::from_str) {
-                file.set_content_disposition(content_disposition);
-            }
-            file.send(req.headers(), res).await;
-            Ok(())
-        }
-    } else {
-        Err(MatrixError::not_found("file not found").into())
-    }
+    crate::media::send_thumbnail(
+        &args.server_name,
+        &args.media_id,
+        args.width,
+        args.height,
+        args.method.as_ref(),
+        args.animated.unwrap_or(false),
+        req,
+        res,
+    )
+    .await
 }
@@ -28,10 +28,12 @@ use salvo::oapi::extract::*;
 use salvo::prelude::*;
 
 use crate::core::client::discovery::{
-    Capabilities, CapabilitiesResBody, RoomVersionStability, RoomVersionsCapability, VersionsResBody,
+    Capabilities, CapabilitiesResBody, GetLoginTokenCapability, RoomVersionStability, RoomVersionsCapability,
+    VersionsResBody,
 };
 use crate::core::client::search::{
-    EventContextResult, ResultCategories, ResultRoomEvents, SearchReqArgs, SearchReqBody, SearchResBody, SearchResult,
+    EventContext, EventContextResult, GroupingKey, OwnedRoomIdOrUserId, ResultCategories, ResultGroup,
+    ResultRoomEvents, SearchReqArgs, SearchReqBody, SearchResBody, SearchResult, UserProfile,
 };
 use crate::core::client::sync_events::{
     AccountDataV4, E2eeV4, ExtensionsV4, ReceiptsV4, SlidingOpV4, SyncEventsReqArgsV3, SyncEventsReqArgsV4,
@@ -41,9 +43,10 @@ use crate::core::client::sync_events::{
 use crate::core::device::DeviceLists;
 use crate::core::events::room::member::{MembershipState, RoomMemberEventContent};
 use crate::core::events::{StateEventType, TimelineEventType};
-use crate::core::UserId;
+use crate::core::{OwnedRoomId, UserId};
+use crate::event::PduEvent;
 use crate::user::NewDbPresence;
-use crate::{empty_ok, hoops, json_ok, AppError, AuthArgs, DepotExt, EmptyResult, JsonResult, MatrixError};
+use crate::{empty_ok, hoops, json_ok, AppError, AppResult, AuthArgs, DepotExt, EmptyResult, JsonResult, MatrixError};
 
 pub fn router() -> Router {
     let mut client = Router::with_path("client").oapi_tag("client");
@@ -55,9 +58,9 @@ pub fn router() -> Router {
                     .push(profile::public_router())
                     .push(register::public_router())
                     .push(session::public_router())
+                    .push(auth::public_router())
                     .push(room::public_router())
                     .push(directory::public_router())
-                    .push(media::self_auth_router())
                     .push(
                         Router::with_path("publicRooms")
                             .get(room::get_public_rooms)
@@ -68,7 +71,6 @@ pub fn router() -> Router {
                 Router::with_path(v)
                     .hoop(hoops::auth_by_access_token)
                     .push(account::authed_router())
-                    .push(register::authed_router())
                     .push(session::authed_router())
                     .push(device::authed_router())
                     .push(room_key::authed_router())
@@ -83,7 +85,6 @@ pub fn router() -> Router {
                     .push(admin::authed_router())
                     .push(third_party::authed_router())
                     .push(to_device::authed_router())
-                    .push(auth::authed_router())
                     .push(pusher::authed_router())
                     .push(push_rule::authed_router())
                     .push(presence::authed_router())
@@ -102,14 +103,16 @@ pub fn router() -> Router {
             )
             .push(
                 Router::with_path(v)
-                    .hoop(hoops::limit_rate)
                     .hoop(hoops::auth_by_access_token)
+                    .hoop(hoops::limit_rate)
                     .push(Router::with_path("search").post(search))
                     .push(Router::with_path("capabilities").get(get_capabilities))
                     .push(Router::with_path("knock/<room_id_or_alias>").post(room::membership::knock_room)),
             )
     }
-    client.push(Router::with_path("versions").get(supported_versions))
+    client
+        .push(Router::with_path("v1").push(media::authed_router()))
+        .push(Router::with_path("versions").get(supported_versions))
 }
 
 /// #POST /_matrix/client/r0/search
@@ -125,95 +128,157 @@ fn search(
 ) -> JsonResult<SearchResBody> {
     let authed = depot.authed_info()?;
 
-    let search_criteria = body.search_categories.room_events.as_ref().unwrap();
+    let Some(search_criteria) = body.search_categories.room_events.as_ref() else {
+        return json_ok(SearchResBody::new(ResultCategories::new()));
+    };
     let filter = &search_criteria.filter;
 
-    let room_ids = filter
+    let room_ids: Vec<OwnedRoomId> = filter
         .rooms
         .clone()
-        .unwrap_or_else(|| crate::user::joined_rooms(authed.user_id(), 0).unwrap_or_default());
-
-    // Use limit or else 10, with maximum 100
-    let limit = filter.limit.unwrap_or(10).min(100) as usize;
-
-    let mut searches = Vec::new();
+        .unwrap_or_else(|| crate::user::joined_rooms(authed.user_id(), 0).unwrap_or_default())
+        .into_iter()
+        .filter(|room_id| !filter.not_rooms.contains(room_id))
+        .collect();
 
-    for room_id in room_ids {
-        if !crate::room::is_joined(authed.user_id(), &room_id)? {
+    for room_id in &room_ids {
+        if !crate::room::is_joined(authed.user_id(), room_id)? {
             return Err(MatrixError::forbidden("You don't have permission to view this room.").into());
         }
-
-        if let Some(s) = crate::room::search_pdus(&room_id, &search_criteria.search_term)? {
-            searches.push(s.0.into_iter().peekable());
-        }
     }
 
+    // Use limit or else 10, with maximum 100
+    let limit = filter.limit.unwrap_or(10).min(100) as usize;
+
     let skip = match args.next_batch.as_ref().map(|s| s.parse()) {
         Some(Ok(s)) => s,
         Some(Err(_)) => return Err(MatrixError::invalid_param("Invalid next_batch token.").into()),
         None => 0, // Default to the start
     };
 
-    let mut results = Vec::new();
-    for _ in 0..skip + limit {
-        if let Some(s) = searches
-            .iter_mut()
-            .map(|s| (s.peek().cloned(), s))
-            .max_by_key(|(peek, _)| peek.clone())
-            .and_then(|(_, i)| i.next())
-        {
-            results.push(s);
-        }
-    }
+    let (count, searched) = crate::room::search::search_pdus(&room_ids, search_criteria, skip, limit)?;
 
-    let results: Vec<_> = results
-        .iter()
-        .filter_map(|result| {
-            crate::room::timeline::get_pdu(result)
-                .ok()?
-                .filter(|pdu| {
-                    crate::room::state::user_can_see_event(authed.user_id(), &pdu.room_id, &pdu.event_id)
-                        .unwrap_or(false)
-                })
-                .map(|pdu| pdu.to_room_event())
-        })
-        .map(|result| SearchResult {
-            context: EventContextResult {
-                end: None,
-                events_after: Vec::new(),
-                events_before: Vec::new(),
-                profile_info: BTreeMap::new(),
-                start: None,
-            },
-            rank: None,
-            result: Some(result),
-        })
-        .skip(skip)
-        .take(limit)
-        .collect();
-
-    let next_batch = if results.len() < limit {
-        None
-    } else {
+    let next_batch = if skip + searched.len() < count as usize {
         Some((skip + limit).to_string())
+    } else {
+        None
     };
 
+    let mut results = Vec::with_capacity(searched.len());
+    let mut groups: BTreeMap<GroupingKey, BTreeMap<OwnedRoomIdOrUserId, ResultGroup>> = BTreeMap::new();
+    let mut result_room_ids = BTreeSet::new();
+    for searched in searched {
+        let Some(pdu) = crate::room::timeline::get_pdu(&searched.event_id)? else {
+            continue;
+        };
+        if !crate::room::state::user_can_see_event(authed.user_id(), &pdu.room_id, &pdu.event_id)? {
+            continue;
+        }
+
+        for grouping in &search_criteria.groupings.group_by {
+            let group_id = match &grouping.key {
+                Some(GroupingKey::RoomId) => OwnedRoomIdOrUserId::RoomId(pdu.room_id.clone()),
+                Some(GroupingKey::Sender) => OwnedRoomIdOrUserId::UserId(pdu.sender.clone()),
+                _ => continue,
+            };
+            let Some(key) = grouping.key.clone() else {
+                continue;
+            };
+            let group_count = groups.get(&key).map(|g| g.len()).unwrap_or_default();
+            let group = groups
+                .entry(key)
+                .or_default()
+                .entry(group_id)
+                .or_insert_with(|| ResultGroup {
+                    next_batch: next_batch.clone(),
+                    order: Some(group_count as u64 + 1),
+                    results: Vec::new(),
+                });
+            group.results.push((*pdu.event_id).to_owned());
+        }
+
+        result_room_ids.insert(pdu.room_id.clone());
+        results.push(SearchResult {
+            context: search_event_context(authed.user_id(), &pdu, &search_criteria.event_context)?,
+            rank: Some(searched.rank as f64),
+            result: Some(pdu.to_room_event()),
+        });
+    }
+
+    let mut state = BTreeMap::new();
+    if search_criteria.include_state == Some(true) {
+        for room_id in result_room_ids {
+            if let Some(frame_id) = crate::room::state::get_current_frame_id(&room_id)? {
+                let room_state = crate::room::state::get_full_state(frame_id)?
+                    .into_values()
+                    .map(|pdu| pdu.to_state_event())
+                    .collect();
+                state.insert(room_id, room_state);
+            }
+        }
+    }
+
     json_ok(SearchResBody::new(ResultCategories {
         room_events: ResultRoomEvents {
-            count: Some((results.len() as u32).into()), // TODO: set this to none. Element shouldn't depend on it
-            groups: BTreeMap::new(),                    // TODO
+            // Approximate as allowed by the spec, it includes the events hidden by history visibility
+            count: Some(count),
+            groups,
             next_batch,
             results,
-            state: BTreeMap::new(), // TODO
-            highlights: search_criteria
-                .search_term
-                .split_terminator(|c: char| !c.is_alphanumeric())
-                .map(str::to_lowercase)
-                .collect(),
+            state,
+            highlights: crate::room::search::search_words(&search_criteria.search_term),
         },
     }))
 }
 
+/// Loads the events around a search result, and the profiles of their senders if requested.
+fn search_event_context(user_id: &UserId, pdu: &PduEvent, context: &EventContext) -> AppResult<EventContextResult> {
+    let events_before = crate::room::timeline::get_pdus_backward(
+        user_id,
+        &pdu.room_id,
+        pdu.event_sn - 1,
+        context.before_limit.min(100) as usize,
+        None,
+    )?;
+    let events_after = crate::room::timeline::get_pdus_forward(
+        user_id,
+        &pdu.room_id,
+        pdu.event_sn + 1,
+        context.after_limit.min(100) as usize,
+        None,
+        None,
+    )?;
+
+    let mut profile_info = BTreeMap::new();
+    if context.include_profile {
+        let senders = events_before
+            .iter()
+            .chain(events_after.iter())
+            .map(|(_, pdu)| &pdu.sender)
+            .chain(std::iter::once(&pdu.sender))
+            .collect::<HashSet<_>>();
+        for sender in senders {
+            if let Some(member) = crate::room::state::get_member(&pdu.room_id, sender)? {
+                profile_info.insert(
+                    sender.to_owned(),
+                    UserProfile {
+                        avatar_url: member.avatar_url,
+                        display_name: member.display_name,
+                    },
+                );
+            }
+        }
+    }
+
+    Ok(EventContextResult {
+        start: events_before.last().map(|(sn, _)| sn.to_string()),
+        end: events_after.last().map(|(sn, _)| sn.to_string()),
+        events_before: events_before.into_iter().map(|(_, pdu)| pdu.to_room_event()).collect(),
+        events_after: events_after.into_iter().map(|(_, pdu)| pdu.to_room_event()).collect(),
+        profile_info,
+    })
+}
+
 /// #GET /_matrix/client/r0/capabilities
 /// Get information on the supported feature set and other relevent capabilities of this server.
 #[endpoint]
@@ -225,15 +290,19 @@ async fn get_capabilities(_aa: AuthArgs) -> JsonResult<CapabilitiesResBody> {
     for room_version in &*crate::STABLE_ROOM_VERSIONS {
         available.insert(room_version.clone(), RoomVersionStability::Stable);
     }
-    json_ok(CapabilitiesResBody {
-        capabilities: Capabilities {
-            room_versions: RoomVersionsCapability {
-                default: crate::default_room_version(),
-                available,
-            },
-            ..Default::default()
+    let mut capabilities = Capabilities {
+        room_versions: RoomVersionsCapability {
+            default: crate::default_room_version(),
+            available,
         },
-    })
+        get_login_token: GetLoginTokenCapability::new(crate::config().login_via_existing_session),
+        ..Default::default()
+    };
+    let password_policy = crate::user::password_policy();
+    if !password_policy.is_default() {
+        capabilities.set("m.password_policy", serde_json::to_value(password_policy)?)?;
+    }
+    json_ok(CapabilitiesResBody { capabilities })
 }
 
 /// #GET /_matrix/client/versions
@@ -257,7 +326,10 @@ async fn supported_versions() -> JsonResult<VersionsResBody> {
             "v1.4".to_owned(),
             "v1.5".to_owned(),
         ],
-        unstable_features: BTreeMap::from_iter([("org.matrix.e2e_cross_signing".to_owned(), true)]),
+        unstable_features: BTreeMap::from_iter([
+            ("org.matrix.e2e_cross_signing".to_owned(), true),
+            ("org.matrix.msc3916.stable".to_owned(), true),
+        ]),
     })
 }
 
@@ -379,7 +451,7 @@ pub async fn sync_events_v4(
 ) -> JsonResult<SyncEventsResBodyV4> {
     let authed = depot.authed_info()?;
     // Setup watchers, so if there's no response, we can wait for them
-    let watcher = crate::watch(&authed.user_id(), authed.device_id());
+    let watcher = crate::watch(authed.user_id());
 
     let next_batch = crate::curr_sn()? + 1;
 
@@ -5,24 +5,21 @@
 //!
 //! [spec]: https://spec.matrix.org/latest/client-server-api/#post_matrixclientv3register
 use diesel::prelude::*;
-use palpo_core::presence::PresenceState;
 use salvo::oapi::extract::{JsonBody, QueryParam};
 use salvo::prelude::*;
 
 use crate::core::client::account::{LoginType, RegistrationKind};
 use crate::core::client::register::*;
 use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo};
-use crate::core::events::push_rules::PushRulesEventContent;
 use crate::core::events::room::message::RoomMessageEventContent;
-use crate::core::events::GlobalAccountDataEventType;
 use crate::core::identifiers::*;
-use crate::core::push::Ruleset;
+use crate::core::third_party::Medium;
 use crate::core::UnixMillis;
+use crate::mailer::MailTemplate;
 use crate::schema::*;
-use crate::user::{NewDbPresence, NewDbProfile};
 use crate::{
-    db, diesel_exists, empty_ok, exts::*, hoops, utils, AppError, AuthArgs, EmptyResult, JsonResult, MatrixError,
-    DEVICE_ID_LENGTH, RANDOM_USER_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH,
+    db, diesel_exists, exts::*, hoops, json_ok, utils, AppError, AuthArgs, EmptyResult, JsonResult, MatrixError,
+    DEVICE_ID_LENGTH, RANDOM_USER_ID_LENGTH, TOKEN_LENGTH,
 };
 
 pub fn public_router() -> Router {
@@ -33,20 +30,17 @@ pub fn public_router() -> Router {
                     .post(register)
                     .push(Router::with_path("available").get(available)),
             )
-            .push(Router::with_path("m.login.registration_token/validity").get(validate_token)),
+            .push(Router::with_path("m.login.registration_token/validity").get(validate_token))
+            .push(Router::with_path("email/requestToken").post(token_via_email))
+            .push(Router::with_path("msisdn/requestToken").post(token_via_msisdn)),
     )
 }
 
-pub fn authed_router() -> Router {
-    Router::with_path("register")
-        .push(Router::with_path("email/requestToken").post(token_via_email))
-        .push(Router::with_path("msisdn/requestToken").post(token_via_msisdn))
-}
-
 #[endpoint]
-fn register(aa: AuthArgs, body: JsonBody<RegisterReqBody>, depot: &mut Depot) -> JsonResult<RegisterResBody> {
+async fn register(aa: AuthArgs, body: JsonBody<RegisterReqBody>, depot: &mut Depot) -> JsonResult<RegisterResBody> {
     let conf = crate::config();
-    if !conf.allow_registration && !aa.from_appservice && conf.registration_token.is_none() {
+    let requires_token = conf.registration_requires_token || conf.registration_token.is_some();
+    if !conf.allow_registration && !aa.from_appservice && !requires_token {
         return Err(MatrixError::forbidden("Registration has been disabled.").into());
     }
 
@@ -87,10 +81,15 @@ fn register(aa: AuthArgs, body: JsonBody<RegisterReqBody>, depot: &mut Depot) ->
         return Err(MatrixError::exclusive("User id reserved by appservice.").into());
     }
 
+    let password = if is_guest { None } else { body.password.as_deref() };
+    if let Some(password) = password {
+        crate::user::validate_password(&user_id, password)?;
+    }
+
     // UIAA
     let mut uiaa_info = UiaaInfo {
         flows: vec![AuthFlow {
-            stages: if conf.registration_token.is_some() {
+            stages: if requires_token {
                 vec![AuthType::RegistrationToken]
             } else {
                 vec![AuthType::Dummy]
@@ -101,36 +100,110 @@ fn register(aa: AuthArgs, body: JsonBody<RegisterReqBody>, depot: &mut Depot) ->
         session: None,
         auth_error: None,
     };
+    // Users can also register with an email address, to be able to reset their password later
+    if crate::mailer::is_enabled() {
+        let mut stages = if requires_token {
+            vec![AuthType::RegistrationToken]
+        } else {
+            Vec::new()
+        };
+        stages.push(AuthType::EmailIdentity);
+        uiaa_info.flows.push(AuthFlow { stages });
+    }
+    let mut params = serde_json::Map::new();
+    if let Some(recaptcha) = &conf.uiaa.recaptcha {
+        for flow in &mut uiaa_info.flows {
+            flow.stages.insert(0, AuthType::ReCaptcha);
+        }
+        params.insert(
+            "m.login.recaptcha".to_owned(),
+            serde_json::json!({ "public_key": recaptcha.public_key }),
+        );
+    }
+    if let Some(terms) = &conf.terms {
+        // The policies are accepted right before the stage completing the flow
+        for flow in &mut uiaa_info.flows {
+            flow.stages.insert(flow.stages.len() - 1, AuthType::Terms);
+        }
+        params.insert(
+            "m.login.terms".to_owned(),
+            serde_json::json!({ "policies": crate::terms::policies(terms) }),
+        );
+    }
+    if !params.is_empty() {
+        uiaa_info.params = serde_json::value::to_raw_value(&params)?;
+    }
 
+    let mut registration_token = None;
+    let mut threepid = Ok(None);
     if body.login_type != Some(LoginType::Appservice) && !is_guest {
         if let Some(auth) = &body.auth {
-            let (worked, uiaa) = crate::uiaa::try_auth(
+            let (worked, uiaa, extras) = crate::uiaa::try_auth_with_extras(
                 &UserId::parse_with_server_name("", &conf.server_name).expect("we know this is valid"),
                 "".into(),
                 &auth,
                 &uiaa_info,
-            )?;
+            )
+            .await?;
             if !worked {
                 return Err(AppError::Uiaa(uiaa));
             }
+            registration_token = extras.registration_token;
+            threepid = match extras.threepid_session_id {
+                Some(threepid_session_id) => crate::threepid::get_session(&threepid_session_id),
+                None => Ok(None),
+            };
         } else if body.is_default() {
             return Err(MatrixError::not_json("Not json").into());
         } else {
-            uiaa_info.session = Some(utils::random_string(SESSION_ID_LENGTH));
-            crate::uiaa::update_session(
+            crate::uiaa::create_session(
                 &UserId::parse_with_server_name("", crate::server_name()).expect("we know this is valid"),
                 "".into(),
-                uiaa_info.session.as_ref().expect("session is always set"),
-                Some(&uiaa_info),
+                &mut uiaa_info,
+                None,
             )?;
             return Err(uiaa_info.into());
         }
     }
 
-    let password = if is_guest { None } else { body.password.as_deref() };
-
-    // Create user
-    crate::user::create_user(user_id.clone(), password)?;
+    // Create user, the registration token reserved in the session is given back when that fails.
+    let created = threepid.and_then(|threepid| {
+        if let Some(threepid) = &threepid {
+            if crate::user::get_user_by_threepid(&Medium::from(threepid.medium.as_str()), &threepid.address)?.is_some()
+            {
+                return Err(MatrixError::threepid_in_use("Third party identifier is already in use.").into());
+            }
+        }
+        crate::user::create_user(user_id.clone(), password)?;
+        Ok(threepid)
+    });
+    let threepid = match created {
+        Ok(threepid) => threepid,
+        Err(e) => {
+            if let Some(registration_token) = &registration_token {
+                crate::user::release_registration_token(registration_token)?;
+            }
+            return Err(e);
+        }
+    };
+    if let Some(registration_token) = &registration_token {
+        crate::user::complete_registration_token(registration_token)?;
+    }
+    // Every flow has the terms stage, so completing one accepted the policies
+    if let Some(terms) = &conf.terms {
+        if body.login_type != Some(LoginType::Appservice) && !is_guest {
+            crate::terms::record_consent(&user_id, &terms.version)?;
+        }
+    }
+    if let Some(threepid) = &threepid {
+        crate::user::add_threepid(
+            &user_id,
+            &Medium::from(threepid.medium.as_str()),
+            &threepid.address,
+            UnixMillis(threepid.validated_at.unwrap_or_default() as u64),
+        )?;
+        crate::threepid::delete_session(&threepid.session_id)?;
+    }
 
     // Default to pretty display_name
     let mut display_name = user_id.localpart().to_owned();
@@ -140,42 +213,7 @@ fn register(aa: AuthArgs, body: JsonBody<RegisterReqBody>, depot: &mut Depot) ->
         display_name.push_str(" ⚡️");
     }
 
-    diesel::insert_into(user_profiles::table)
-        .values(NewDbProfile {
-            user_id: user_id.clone(),
-            room_id: None,
-            display_name: Some(display_name.clone()),
-            avatar_url: None,
-            blurhash: None,
-        })
-        .execute(&mut db::connect()?)?;
-
-    // Presence update
-    crate::user::set_presence(
-        NewDbPresence {
-            user_id: user_id.clone(),
-            stream_id: None,
-            state: Some(PresenceState::Online.to_string()),
-            status_msg: None,
-            last_active_at: Some(UnixMillis::now()),
-            last_federation_update_at: None,
-            last_user_sync_at: None,
-            currently_active: None,
-            occur_sn: None,
-        },
-        true,
-    )?;
-
-    // Initial account data
-    crate::user::set_data(
-        &user_id,
-        None,
-        &GlobalAccountDataEventType::PushRules.to_string(),
-        serde_json::to_value(PushRulesEventContent {
-            global: Ruleset::server_default(&user_id),
-        })
-        .expect("to json always works"),
-    )?;
+    crate::user::init_new_user(&user_id, display_name.clone())?;
 
     // Inhibit login does not work for guests
     if !is_guest && body.inhibit_login {
@@ -197,6 +235,12 @@ fn register(aa: AuthArgs, body: JsonBody<RegisterReqBody>, depot: &mut Depot) ->
 
     //Create device for this account
     crate::user::create_device(&user_id, &device_id, &token, body.initial_device_display_name.clone())?;
+    let (refresh_token, expires_in) = if body.refresh_token {
+        let (refresh_token, expires_in) = crate::user::issue_refresh_token(&user_id, &device_id)?;
+        (Some(refresh_token), Some(expires_in))
+    } else {
+        (None, None)
+    };
 
     // If this is the first real user, grant them admin privileges
     // Note: the server user, @palpo:servername, is generated first
@@ -218,8 +262,8 @@ fn register(aa: AuthArgs, body: JsonBody<RegisterReqBody>, depot: &mut Depot) ->
         access_token: Some(token),
         user_id,
         device_id: Some(device_id),
-        refresh_token: None,
-        expires_in: None,
+        refresh_token,
+        expires_in,
     }))
 }
 
@@ -271,22 +315,37 @@ async fn available(username: QueryParam<String, true>) -> JsonResult<AvailableRe
 //     }
 // };
 #[endpoint]
-async fn validate_token(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
-    let _authed = depot.authed_info()?;
-    empty_ok()
+async fn validate_token(_aa: AuthArgs, args: ValidateTokenReqArgs) -> JsonResult<ValidateTokenResBody> {
+    let conf = crate::config();
+    let valid = Some(&*args.token) == conf.registration_token.as_deref()
+        || crate::user::is_registration_token_valid(&args.token)?;
+    json_ok(ValidateTokenResBody { valid })
 }
 
-// `POST /_matrix/client/*/register/email/requestToken`
+/// `POST /_matrix/client/*/register/email/requestToken`
 /// Request a registration token with a 3rd party email.
 ///
 /// `/v3/` ([spec])
 ///
 /// [spec]: https://spec.matrix.org/latest/client-server-api/#post_matrixclientv3registeremailrequesttoken
-
 #[endpoint]
-async fn token_via_email(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
-    let _authed = depot.authed_info()?;
-    empty_ok()
+async fn token_via_email(_aa: AuthArgs, body: JsonBody<TokenVisEmailReqBody>) -> JsonResult<TokenVisEmailResBody> {
+    if !crate::mailer::is_enabled() {
+        return Err(MatrixError::threepid_denied("Third party identifier is not allowed").into());
+    }
+    let email = crate::threepid::canonicalize_email(&body.email);
+    if crate::user::get_user_by_threepid(&Medium::Email, &email)?.is_some() {
+        return Err(MatrixError::threepid_in_use("Email address is already in use.").into());
+    }
+    let sid = crate::threepid::request_email_validation(
+        MailTemplate::Registration,
+        &body.client_secret,
+        &email,
+        body.send_attempt,
+        body.next_link.as_deref(),
+    )
+    .await?;
+    json_ok(TokenVisEmailResBody { sid, submit_url: None })
 }
 
 /// `POST /_matrix/client/*/register/msisdn/requestToken`
@@ -305,7 +364,59 @@ async fn token_via_email(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
 //     }
 // };
 #[endpoint]
-async fn token_via_msisdn(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
-    let _authed = depot.authed_info()?;
-    empty_ok()
+async fn token_via_msisdn(_aa: AuthArgs) -> EmptyResult {
+    Err(MatrixError::threepid_denied("Third party identifier is not allowed").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::json;
+
+    use crate::test_utils::*;
+    use crate::JsonValue;
+
+    async fn register(service: &salvo::Service, body: &JsonValue) -> (u16, JsonValue) {
+        let mut res = TestClient::post("http://127.0.0.1/_matrix/client/v3/register")
+            .json(body)
+            .send(service)
+            .await;
+        let status = res.status_code.map(|status| status.as_u16()).unwrap_or(200);
+        (status, res.take_json::<JsonValue>().await.unwrap())
+    }
+
+    /// Registers with the token, returns the status of the request completing the token stage.
+    async fn register_with_token(service: &salvo::Service, token: &str) -> u16 {
+        let mut body = json!({
+            "username": unique_user_id().localpart(),
+            "password": "correct horse battery staple",
+        });
+        let (status, uiaa) = register(service, &body).await;
+        assert_eq!(status, 401);
+        body["auth"] = json!({
+            "type": "m.login.registration_token",
+            "token": token,
+            "session": uiaa["session"],
+        });
+        register(service, &body).await.0
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PALPO_TEST_DATABASE_URL"]
+    async fn registration_token_is_completed_once() {
+        init_db();
+        let service = salvo::Service::new(crate::routing::router());
+        let token = crate::user::create_registration_token(None, Some(1), None)
+            .unwrap()
+            .token;
+
+        assert_eq!(register_with_token(&service, &token).await, 200);
+        let db_token = crate::user::get_registration_token(&token).unwrap().unwrap();
+        assert_eq!((db_token.pending, db_token.completed), (0, 1));
+
+        // The only use is taken
+        assert_eq!(register_with_token(&service, &token).await, 403);
+        let db_token = crate::user::get_registration_token(&token).unwrap().unwrap();
+        assert_eq!((db_token.pending, db_token.completed), (0, 1));
+    }
 }
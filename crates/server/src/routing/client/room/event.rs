@@ -249,6 +249,9 @@ pub(super) async fn send_redact(
     depot: &mut Depot,
 ) -> JsonResult<RedactEventResBody> {
     let authed = depot.authed_info()?;
+    if authed.appservice.is_none() {
+        crate::terms::check_consent(authed.user_id())?;
+    }
 
     let event_id = crate::room::timeline::build_and_append_pdu(
         PduBuilder {
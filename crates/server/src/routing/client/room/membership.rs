@@ -11,7 +11,7 @@ use crate::core::client::membership::{
     JoinedRoomsResBody, KickUserReqBody, LeaveRoomReqBody, MembersReqArgs, MembersResBody, RoomMember,
     UnbanUserReqBody,
 };
-use crate::core::client::room::{KnockReqArgs, KnockReqBody};
+use crate::core::client::room::{KnockReqArgs, KnockReqBody, KnockResBody};
 use crate::core::events::room::member::{MembershipState, RoomMemberEventContent};
 use crate::core::events::{StateEventType, TimelineEventType};
 use crate::core::federation::query::{profile_request, ProfileReqArgs};
@@ -446,43 +446,29 @@ pub(super) async fn kick_user(
     empty_ok()
 }
 
+/// #POST /_matrix/client/v3/knock/{room_id_or_alias}
+/// Knocks on a room, asking to be invited.
 #[endpoint]
 pub(crate) async fn knock_room(
     _aa: AuthArgs,
     args: KnockReqArgs,
     body: JsonBody<KnockReqBody>,
     depot: &mut Depot,
-) -> EmptyResult {
+) -> JsonResult<KnockResBody> {
     let authed = depot.authed_info()?;
+    let mut servers = args.server_name;
     let room_id = match OwnedRoomId::try_from(args.room_id_or_alias) {
-        Ok(room_id) => room_id,
+        Ok(room_id) => {
+            servers.push(room_id.server_name().map_err(AppError::public)?.to_owned());
+            room_id
+        }
         Err(room_alias) => {
             let response = crate::room::get_alias_response(room_alias).await?;
+            servers.extend(response.servers);
             response.room_id
         }
     };
 
-    let mut event: RoomMemberEventContent = RoomMemberEventContent::new(MembershipState::Knock);
-    event.reason = body.into_inner().reason;
-
-    let pdu = crate::room::timeline::build_and_append_pdu(
-        PduBuilder {
-            event_type: TimelineEventType::RoomMember,
-            content: to_raw_value(&event).expect("event is valid, we just created it"),
-            state_key: Some(authed.user_id().to_string()),
-            ..Default::default()
-        },
-        authed.user_id(),
-        &room_id,
-    )?;
-    crate::room::update_membership(
-        &pdu.event_id,
-        pdu.event_sn,
-        &room_id,
-        authed.user_id(),
-        MembershipState::Knock,
-        authed.user_id(),
-        None,
-    )?;
-    empty_ok()
+    crate::membership::knock_room(authed.user_id(), &room_id, body.into_inner().reason, &servers).await?;
+    json_ok(KnockResBody::new(room_id))
 }
@@ -183,6 +183,9 @@ pub(super) async fn send_message(
     depot: &mut Depot,
 ) -> JsonResult<SendMessageResBody> {
     let authed = depot.authed_info()?;
+    if authed.appservice.is_none() {
+        crate::terms::check_consent(authed.user_id())?;
+    }
 
     // Forbid m.room.encrypted if encryption is disabled
     if TimelineEventType::RoomEncrypted == args.event_type.to_string().into() && !crate::allow_encryption() {
@@ -187,6 +187,9 @@ pub(super) async fn send_state_for_key(
     depot: &mut Depot,
 ) -> JsonResult<SendStateEventResBody> {
     let authed = depot.authed_info()?;
+    if authed.appservice.is_none() {
+        crate::terms::check_consent(authed.user_id())?;
+    }
     let body = body.into_inner();
 
     let event_id = crate::state::send_state_event_for_key(
@@ -216,6 +219,9 @@ pub(super) async fn send_state_for_empty_key(
     depot: &mut Depot,
 ) -> JsonResult<SendStateEventResBody> {
     let authed = depot.authed_info()?;
+    if authed.appservice.is_none() {
+        crate::terms::check_consent(authed.user_id())?;
+    }
     let body = body.into_inner();
 
     // Forbid m.room.encryption if encryption is disabled
@@ -1,13 +1,18 @@
+use std::time::Duration;
+
 use salvo::oapi::extract::*;
 use salvo::prelude::*;
 use serde::Deserialize;
 
 use crate::core::client::session::*;
-use crate::core::client::uiaa::UserIdentifier;
+use crate::core::client::uiaa::{AuthFlow, AuthType, UiaaInfo, UserIdentifier};
+use crate::core::error::ErrorKind;
 use crate::core::identifiers::*;
+use crate::core::serde::CanonicalJsonValue;
+use crate::utils::HtmlEscape;
 use crate::{
-    empty_ok, hoops, json_ok, utils, AuthArgs, DepotExt, EmptyResult, JsonResult, MatrixError, DEVICE_ID_LENGTH,
-    TOKEN_LENGTH,
+    empty_ok, hoops, json_ok, utils, AppError, AppResult, AuthArgs, DepotExt, EmptyResult, JsonResult, MatrixError,
+    DEVICE_ID_LENGTH, TOKEN_LENGTH,
 };
 
 #[derive(Debug, Deserialize)]
@@ -17,25 +22,27 @@ struct Claims {
 }
 
 pub fn public_router() -> Router {
-    Router::new().push(
-        Router::with_path("login")
-            .hoop(hoops::limit_rate)
-            .get(login_types)
-            .post(login)
-            .push(
-                Router::with_path("sso/redirect")
-                    .get(redirect)
-                    .push(Router::with_path("idpId").get(provider_url)),
-            ),
-    )
+    Router::new()
+        .push(
+            Router::with_path("login")
+                .hoop(hoops::limit_rate)
+                .get(login_types)
+                .post(login)
+                .push(
+                    Router::with_path("sso/redirect")
+                        .get(redirect)
+                        .push(Router::with_path("<idp_id>").get(provider_url)),
+                )
+                .push(Router::with_path("sso/callback/<idp_id>").get(sso_callback)),
+        )
+        .push(Router::with_path("refresh").hoop(hoops::limit_rate).post(refresh))
 }
 pub fn authed_router() -> Router {
     Router::new()
         .push(
             Router::with_path("login")
                 .hoop(hoops::limit_rate)
-                .push(Router::with_path("get_token").post(get_token))
-                .push(Router::with_path("refresh").get(refresh_token)),
+                .push(Router::with_path("get_token").post(get_token)),
         )
         .push(
             Router::with_path("logout")
@@ -49,7 +56,35 @@ pub fn authed_router() -> Router {
 /// when logging in.
 #[endpoint]
 async fn login_types(_aa: AuthArgs) -> JsonResult<LoginTypesResBody> {
-    let flows = vec![LoginType::password(), LoginType::appservice()];
+    let conf = crate::config();
+    let mut flows = vec![];
+    if !conf.oidc.disable_password_login {
+        let password_policy = crate::user::password_policy();
+        flows.push(LoginType::Password(PasswordLoginType {
+            password_policy: (!password_policy.is_default()).then_some(password_policy),
+        }));
+    }
+    if !conf.oidc.providers.is_empty() {
+        flows.push(LoginType::Sso(SsoLoginType {
+            identity_providers: conf
+                .oidc
+                .providers
+                .iter()
+                .map(|provider| IdentityProvider {
+                    id: provider.id.clone(),
+                    name: provider.name.clone(),
+                    icon: provider.icon.clone(),
+                    brand: None,
+                })
+                .collect(),
+        }));
+    }
+    if !conf.oidc.providers.is_empty() || conf.login_via_existing_session || crate::jwt_decoding_key().is_some() {
+        flows.push(LoginType::Token(TokenLoginType {
+            get_login_token: conf.login_via_existing_session,
+        }));
+    }
+    flows.push(LoginType::appservice());
     Ok(Json(LoginTypesResBody::new(flows)))
 }
 
@@ -69,6 +104,9 @@ async fn login(body: JsonBody<LoginReqBody>, res: &mut Response) -> JsonResult<L
     // TODO: Other login methods
     let user_id = match &body.login_info {
         LoginInfo::Password(Password { identifier, password }) => {
+            if crate::config().oidc.disable_password_login {
+                return Err(MatrixError::forbidden("Password login is disabled on this server.").into());
+            }
             let username = if let UserIdentifier::UserIdOrLocalpart(user_id) = identifier {
                 user_id.to_lowercase()
             } else {
@@ -87,18 +125,25 @@ async fn login(body: JsonBody<LoginReqBody>, res: &mut Response) -> JsonResult<L
             user_id
         }
         LoginInfo::Token(Token { token }) => {
-            if let Some(jwt_decoding_key) = crate::jwt_decoding_key() {
+            let user_id = if let Some(user_id) = crate::user::take_login_token(token)? {
+                user_id
+            } else if let Some(jwt_decoding_key) = crate::jwt_decoding_key() {
                 let token =
                     jsonwebtoken::decode::<Claims>(token, jwt_decoding_key, &jsonwebtoken::Validation::default())
-                        .map_err(|_| MatrixError::invalid_username("Token is invalid."))?;
+                        .map_err(|_| MatrixError::forbidden("Invalid login token."))?;
                 let username = token.claims.sub.to_lowercase();
                 UserId::parse_with_server_name(username, &crate::config().server_name)
                     .map_err(|_| MatrixError::invalid_username("Username is invalid."))?
             } else {
-                return Err(
-                    MatrixError::unknown("Token login is not supported (server has no jwt decoding key).").into(),
-                );
+                return Err(MatrixError::forbidden("Invalid login token.").into());
+            };
+            let Some(user) = crate::user::get_user(&user_id)? else {
+                return Err(MatrixError::forbidden("User not found.").into());
+            };
+            if user.is_deactivated() {
+                return Err(MatrixError::user_deactivated("The user has been deactivated").into());
             }
+            user_id
         }
         LoginInfo::Appservice(Appservice { identifier }) => {
             let username = if let UserIdentifier::UserIdOrLocalpart(user_id) = identifier {
@@ -132,6 +177,13 @@ async fn login(body: JsonBody<LoginReqBody>, res: &mut Response) -> JsonResult<L
         crate::user::create_device(&user_id, &device_id, &token, body.initial_device_display_name.clone())?;
     }
 
+    let (refresh_token, expires_in) = if body.refresh_token {
+        let (refresh_token, expires_in) = crate::user::issue_refresh_token(&user_id, &device_id)?;
+        (Some(refresh_token), Some(expires_in))
+    } else {
+        (None, None)
+    };
+
     tracing::info!("{} logged in", user_id);
 
     json_ok(LoginResBody {
@@ -139,17 +191,57 @@ async fn login(body: JsonBody<LoginReqBody>, res: &mut Response) -> JsonResult<L
         access_token: token,
         device_id,
         well_known: None,
-        refresh_token: None,
-        expires_in: None,
+        refresh_token,
+        expires_in,
     })
 }
 
+/// #POST /_matrix/client/v1/login/get_token
+/// Creates a single-use login token for the `m.login.token` flow, so a new device can be signed in
+/// from this session.
+///
+/// - Requires UIAA to verify user password
 #[endpoint]
-async fn get_token(_aa: AuthArgs) -> EmptyResult {
-    // TODO: fixme
-    panic!("get_tokenNot implemented")
-    // let authed = depot.authed_info()?;
-    // Ok(())
+async fn get_token(
+    _aa: AuthArgs,
+    body: JsonBody<TokenReqBody>,
+    req: &mut Request,
+    depot: &mut Depot,
+) -> JsonResult<TokenResBody> {
+    let authed = depot.authed_info()?;
+    let request = req.parse_json::<CanonicalJsonValue>().await.ok();
+    let conf = crate::config();
+    if !conf.login_via_existing_session {
+        return Err(MatrixError::forbidden("Login via an existing session is not enabled on this server.").into());
+    }
+
+    // UIAA
+    let mut uiaa_info = UiaaInfo {
+        flows: vec![AuthFlow {
+            stages: vec![AuthType::Password],
+        }],
+        completed: Vec::new(),
+        params: Default::default(),
+        session: None,
+        auth_error: None,
+    };
+    let Some(auth) = &body.auth else {
+        crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
+        return Err(uiaa_info.into());
+    };
+    match crate::uiaa::try_auth(authed.user_id(), authed.device_id(), auth, &uiaa_info).await {
+        Ok((true, _)) => {}
+        Ok((false, uiaa_info)) => return Err(uiaa_info.into()),
+        Err(AppError::Matrix(e)) if e.kind == ErrorKind::Forbidden => return Err(e.into()),
+        Err(_) => {
+            crate::uiaa::create_session(authed.user_id(), authed.device_id(), &mut uiaa_info, request.as_ref())?;
+            return Err(uiaa_info.into());
+        }
+    }
+
+    let expires_in = Duration::from_secs(conf.login_token_lifetime_s);
+    let login_token = crate::user::create_login_token(authed.user_id(), expires_in)?;
+    json_ok(TokenResBody::new(expires_in, login_token))
 }
 
 /// #POST /_matrix/client/r0/logout
@@ -191,22 +283,282 @@ async fn logout_all(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
     empty_ok()
 }
 
+/// #POST /_matrix/client/v3/refresh
+/// Exchanges a refresh token for a new access token and a new refresh token.
 #[endpoint]
-async fn refresh_token(_aa: AuthArgs) -> EmptyResult {
-    // TODO: fixme
-    panic!("refresh_tokenNot implemented")
-    // let authed = depot.authed_info()?;
-    // Ok(())
+async fn refresh(_aa: AuthArgs, body: JsonBody<RefreshTokenReqBody>) -> JsonResult<RefreshTokenResBody> {
+    let (access_token, refresh_token, expires_in) = crate::user::refresh_access_token(&body.refresh_token)?;
+    json_ok(RefreshTokenResBody {
+        access_token,
+        refresh_token: Some(refresh_token),
+        expires_in_ms: Some(expires_in),
+    })
 }
 
+/// #GET /_matrix/client/v3/login/sso/redirect
+/// Redirects the user to the first configured identity provider.
 #[endpoint]
-async fn redirect(_aa: AuthArgs) -> EmptyResult {
-    // TODDO: todo
-    empty_ok()
+async fn redirect(_aa: AuthArgs, args: SsoRedirectReqArgs, res: &mut Response) -> AppResult<()> {
+    let Some(provider) = crate::config().oidc.providers.first() else {
+        return Err(MatrixError::not_found("Single sign-on is not enabled on this server.").into());
+    };
+    let url = crate::oidc::authorization_url(&provider.id, &args.redirect_url).await?;
+    res.render(Redirect::found(url.as_str()));
+    Ok(())
 }
 
+/// #GET /_matrix/client/v3/login/sso/redirect/{idpId}
+/// Redirects the user to the given identity provider.
 #[endpoint]
-async fn provider_url(_aa: AuthArgs) -> EmptyResult {
-    // TODDO: todo
-    empty_ok()
+async fn provider_url(_aa: AuthArgs, args: SsoRedirectWithProviderReqArgs, res: &mut Response) -> AppResult<()> {
+    let url = crate::oidc::authorization_url(&args.idp_id, &args.redirect_url).await?;
+    res.render(Redirect::found(url.as_str()));
+    Ok(())
+}
+
+const SSO_CONFIRM_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Continue to your client</title>
+</head>
+<body>
+<p>You are about to sign in to <strong>{destination}</strong>. Continuing gives it access to your account.</p>
+<p>If you didn't start this login from that client, close this page.</p>
+<p><a href="{url}">Continue to {destination}</a></p>
+</body>
+</html>
+"#;
+
+/// #GET /_matrix/client/v3/login/sso/callback/{idpId}
+/// Where identity providers send users back to, redirects the user to the client with a login token.
+/// Clients not in `oidc.client_redirect_allowlist` are named on a page the user has to confirm.
+#[endpoint]
+async fn sso_callback(
+    _aa: AuthArgs,
+    idp_id: PathParam<String>,
+    code: QueryParam<String, true>,
+    state: QueryParam<String, true>,
+    res: &mut Response,
+) -> AppResult<()> {
+    let url = crate::oidc::complete_login(&idp_id, &code, &state).await?;
+    if crate::oidc::is_redirect_allowed(&url) {
+        res.render(Redirect::found(url.as_str()));
+    } else {
+        let mut destination = url.clone();
+        destination.set_query(None);
+        destination.set_fragment(None);
+        res.render(Text::Html(
+            SSO_CONFIRM_TEMPLATE
+                .replace("{destination}", &HtmlEscape(destination.as_str()).to_string())
+                .replace("{url}", &HtmlEscape(url.as_str()).to_string()),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{LazyLock, Mutex};
+
+    use base64::engine::{general_purpose, Engine};
+    use diesel::prelude::*;
+    use reqwest::Url;
+    use salvo::http::header::LOCATION;
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::json;
+
+    use super::*;
+    use crate::schema::*;
+    use crate::test_utils::*;
+    use crate::{db, diesel_exists, JsonValue};
+
+    async fn request_login_token(service: &Service, access_token: &str, auth: Option<JsonValue>) -> (u16, JsonValue) {
+        let body = match auth {
+            Some(auth) => json!({ "auth": auth }),
+            None => json!({}),
+        };
+        let mut res = TestClient::post("http://127.0.0.1/_matrix/client/v1/login/get_token")
+            .bearer_auth(access_token)
+            .json(&body)
+            .send(service)
+            .await;
+        let status = res.status_code.unwrap_or(StatusCode::OK).as_u16();
+        (status, res.take_json::<JsonValue>().await.unwrap())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PALPO_TEST_DATABASE_URL"]
+    async fn get_token_requires_the_password() {
+        init_db();
+        let service = Service::new(crate::routing::router());
+        let (user_id, access_token) = create_user_with_device("correct horse");
+
+        let (status, body) = request_login_token(&service, &access_token, None).await;
+        assert_eq!(status, 401);
+        let session = body["session"].as_str().unwrap().to_owned();
+
+        let dummy = json!({ "type": "m.login.dummy", "session": session });
+        let (status, body) = request_login_token(&service, &access_token, Some(dummy)).await;
+        assert_eq!(status, 401);
+        assert!(body.get("login_token").is_none());
+
+        let mut password = json!({
+            "type": "m.login.password",
+            "identifier": { "type": "m.id.user", "user": user_id },
+            "password": "wrong",
+            "session": session,
+        });
+        let (status, body) = request_login_token(&service, &access_token, Some(password.clone())).await;
+        assert_eq!(status, 401);
+        assert!(body.get("login_token").is_none());
+
+        password["password"] = json!("correct horse");
+        let (status, body) = request_login_token(&service, &access_token, Some(password)).await;
+        assert_eq!(status, 200);
+        assert!(body["login_token"].is_string());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PALPO_TEST_DATABASE_URL"]
+    async fn token_login_rejects_deactivated_users() {
+        init_db();
+        let service = Service::new(crate::routing::router());
+        let (user_id, _) = create_user_with_device("correct horse");
+        let token_login = |token: String| {
+            TestClient::post("http://127.0.0.1/_matrix/client/v3/login")
+                .json(&json!({ "type": "m.login.token", "token": token }))
+                .send(&service)
+        };
+
+        let token = crate::user::create_login_token(&user_id, Duration::from_secs(60)).unwrap();
+        let res = token_login(token).await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+
+        let token = crate::user::create_login_token(&user_id, Duration::from_secs(60)).unwrap();
+        crate::user::deactivate(&user_id, &user_id).unwrap();
+        let mut res = token_login(token).await;
+        assert_eq!(res.status_code, Some(StatusCode::FORBIDDEN));
+        let body = res.take_json::<JsonValue>().await.unwrap();
+        assert_eq!(body["errcode"], "M_USER_DEACTIVATED");
+    }
+
+    /// PKCE challenges of the logins the mock provider expects, by authorization code.
+    static IDP_CHALLENGES: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(Default::default);
+
+    #[handler]
+    async fn idp_discovery(res: &mut Response) {
+        let issuer = format!("http://{}", mock_idp_addr());
+        res.render(Json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+            "userinfo_endpoint": format!("{issuer}/userinfo"),
+        })));
+    }
+
+    /// Exchanges a code for an access token, the token is the code itself.
+    #[handler]
+    async fn idp_token(req: &mut Request, res: &mut Response) {
+        let code = req.form::<String>("code").await.unwrap_or_default();
+        let code_verifier = req.form::<String>("code_verifier").await.unwrap_or_default();
+        let challenge = general_purpose::URL_SAFE_NO_PAD.encode(
+            crate::utils::hash::hash_data_sha2_256(code_verifier.as_bytes())
+                .unwrap()
+                .as_bytes(),
+        );
+        if IDP_CHALLENGES.lock().unwrap().remove(&code) != Some(challenge) {
+            res.status_code(StatusCode::BAD_REQUEST);
+            return;
+        }
+        res.render(Json(json!({ "access_token": code, "token_type": "Bearer" })));
+    }
+
+    #[handler]
+    async fn idp_userinfo(req: &mut Request, res: &mut Response) {
+        let token = req
+            .header::<String>("authorization")
+            .and_then(|auth| auth.strip_prefix("Bearer ").map(str::to_owned))
+            .unwrap_or_default();
+        res.render(Json(
+            json!({ "sub": token, "preferred_username": "Mock User", "name": "Mock User" }),
+        ));
+    }
+
+    /// Starts a login, returns the state and the PKCE challenge sent to the provider.
+    async fn start_sso_login(service: &Service, redirect_url: &str) -> (String, String) {
+        let res = TestClient::get(format!(
+            "http://127.0.0.1/_matrix/client/v3/login/sso/redirect/mock?redirectUrl={redirect_url}"
+        ))
+        .send(service)
+        .await;
+        assert_eq!(res.status_code, Some(StatusCode::FOUND));
+        let location = Url::parse(res.headers().get(LOCATION).unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(location.path(), "/authorize");
+        let query = location.query_pairs().into_owned().collect::<HashMap<_, _>>();
+        (query["state"].clone(), query["code_challenge"].clone())
+    }
+
+    async fn finish_sso_login(service: &Service, state: &str, code: &str) -> Response {
+        TestClient::get(format!(
+            "http://127.0.0.1/_matrix/client/v3/login/sso/callback/mock?code={code}&state={state}"
+        ))
+        .send(service)
+        .await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PALPO_TEST_DATABASE_URL"]
+    async fn sso_login_through_provider() {
+        init_db();
+        let idp = Router::new()
+            .push(Router::with_path(".well-known/openid-configuration").get(idp_discovery))
+            .push(Router::with_path("token").post(idp_token))
+            .push(Router::with_path("userinfo").get(idp_userinfo));
+        let acceptor = TcpListener::new(mock_idp_addr()).bind().await;
+        tokio::spawn(Server::new(acceptor).serve(idp));
+        let service = Service::new(crate::routing::router());
+
+        let (state, challenge) = start_sso_login(&service, "https://client.example/done").await;
+        let conn = &mut *db::connect().unwrap();
+        assert!(diesel_exists!(oidc_pending_logins::table.find(&state), conn).unwrap());
+        assert!(diesel_exists!(oidc_discoveries::table.find("mock"), conn).unwrap());
+
+        let code = crate::utils::random_string(16);
+        IDP_CHALLENGES.lock().unwrap().insert(code.clone(), challenge);
+        let res = finish_sso_login(&service, &state, &code).await;
+        assert_eq!(res.status_code, Some(StatusCode::FOUND));
+        let location = Url::parse(res.headers().get(LOCATION).unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(location.origin().ascii_serialization(), "https://client.example");
+        let login_token = location
+            .query_pairs()
+            .find(|(name, _)| name == "loginToken")
+            .map(|(_, token)| token.into_owned())
+            .unwrap();
+        let mut res = TestClient::post("http://127.0.0.1/_matrix/client/v3/login")
+            .json(&json!({ "type": "m.login.token", "token": login_token }))
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        let body = res.take_json::<JsonValue>().await.unwrap();
+        let user_id = crate::user::get_user_by_external_id("mock", &code).unwrap().unwrap();
+        assert_eq!(body["user_id"], user_id.as_str());
+
+        // The state can only be used once.
+        let res = finish_sso_login(&service, &state, &code).await;
+        assert_eq!(res.status_code, Some(StatusCode::FORBIDDEN));
+
+        // Clients not in the allowlist are named on a page instead of getting the token directly.
+        let (state, challenge) = start_sso_login(&service, "https://other.example/done").await;
+        let code = crate::utils::random_string(16);
+        IDP_CHALLENGES.lock().unwrap().insert(code.clone(), challenge);
+        let mut res = finish_sso_login(&service, &state, &code).await;
+        assert_eq!(res.status_code.unwrap_or(StatusCode::OK), StatusCode::OK);
+        assert!(res.headers().get(LOCATION).is_none());
+        let page = res.take_string().await.unwrap();
+        assert!(page.contains("Continue to https://other.example/done"));
+        assert!(page.contains("loginToken="));
+    }
 }
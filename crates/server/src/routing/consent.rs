@@ -0,0 +1,115 @@
+//! `GET /_matrix/consent`
+//!
+//! Page where users accept the terms of service, `M_CONSENT_NOT_GIVEN` errors link to it. Links
+//! carry the user ID and its signature, see `terms::consent_url`.
+use salvo::oapi::extract::*;
+use salvo::prelude::*;
+
+use crate::config::TermsConfig;
+use crate::core::{OwnedUserId, UserId};
+use crate::utils::HtmlEscape;
+use crate::{hoops, AppResult, AuthArgs, MatrixError};
+
+pub fn router() -> Router {
+    Router::with_path("consent")
+        .hoop(hoops::limit_rate)
+        .get(consent_page)
+        .post(accept_consent)
+}
+
+const PAGE_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Terms of service</title>
+</head>
+<body>
+<h1>Terms of service</h1>
+{content}
+</body>
+</html>
+"#;
+
+/// Returns the terms and the user of a signed consent link.
+fn get_terms_user(user_id: Option<&str>, signature: Option<&str>) -> AppResult<(&'static TermsConfig, OwnedUserId)> {
+    let Some(terms) = &crate::config().terms else {
+        return Err(MatrixError::not_found("This server has no terms of service.").into());
+    };
+    let user_id = user_id
+        .and_then(|user_id| UserId::parse(user_id).ok())
+        .ok_or_else(|| MatrixError::invalid_param("Invalid user ID."))?;
+    if !crate::terms::verify_consent_signature(terms, &user_id, signature.unwrap_or_default()) {
+        return Err(MatrixError::forbidden("Invalid consent link.").into());
+    }
+    Ok((terms, user_id))
+}
+
+fn policy_list(terms: &TermsConfig) -> String {
+    let mut list = String::from("<ul>\n");
+    for policy in &terms.policies {
+        list.push_str(&format!(
+            "<li><a href=\"{}\" target=\"_blank\">{}</a> ({})</li>\n",
+            HtmlEscape(&policy.url),
+            HtmlEscape(&policy.name),
+            HtmlEscape(&policy.lang)
+        ));
+    }
+    list.push_str("</ul>");
+    list
+}
+
+/// #GET /_matrix/consent?u={user_id}&h={signature}
+/// Shows the policies and asks the user to accept them when they didn't accept this version yet.
+#[endpoint]
+async fn consent_page(
+    _aa: AuthArgs,
+    u: QueryParam<String, false>,
+    h: QueryParam<String, false>,
+    res: &mut Response,
+) -> AppResult<()> {
+    let (terms, user_id) = get_terms_user(u.as_deref(), h.as_deref())?;
+    let mut content = format!(
+        "<p>Version {} of the policies of this server applies to {}:</p>\n{}\n",
+        HtmlEscape(&terms.version),
+        HtmlEscape(user_id.as_str()),
+        policy_list(terms)
+    );
+    if crate::terms::has_consented(&user_id)? {
+        content.push_str("<p>You have accepted these policies, you can return to your client.</p>");
+    } else {
+        if let Some(consent) = crate::terms::get_last_consent(&user_id)? {
+            content.push_str(&format!(
+                "<p>The policies changed since you accepted version {}.</p>\n",
+                HtmlEscape(&consent.version)
+            ));
+        }
+        content.push_str(&format!(
+            r#"<form method="post" action="">
+<input type="hidden" name="u" value="{}">
+<input type="hidden" name="h" value="{}">
+<p><label><input type="checkbox" required> I have read and agree to these policies</label></p>
+<p><input type="submit" value="Accept"></p>
+</form>"#,
+            HtmlEscape(user_id.as_str()),
+            HtmlEscape(h.as_deref().unwrap_or_default())
+        ));
+    }
+    res.render(Text::Html(PAGE_TEMPLATE.replace("{content}", &content)));
+    Ok(())
+}
+
+/// #POST /_matrix/consent
+/// Records that the user accepted the current version of the policies.
+#[endpoint]
+async fn accept_consent(_aa: AuthArgs, req: &mut Request, res: &mut Response) -> AppResult<()> {
+    let user_id = req.form::<String>("u").await;
+    let signature = req.form::<String>("h").await;
+    let (terms, user_id) = get_terms_user(user_id.as_deref(), signature.as_deref())?;
+    crate::terms::record_consent(&user_id, &terms.version)?;
+    res.render(Text::Html(PAGE_TEMPLATE.replace(
+        "{content}",
+        "<p>Thank you for accepting the policies, you can now return to your client.</p>",
+    )));
+    Ok(())
+}
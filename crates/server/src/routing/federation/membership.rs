@@ -5,7 +5,6 @@ use serde_json::value::to_raw_value;
 use stats_room_currents::state_events;
 use ulid::Ulid;
 
-use crate::core::events::room::join_rules::{JoinRule, RoomJoinRulesEventContent};
 use crate::core::events::room::member::{MembershipState, RoomMemberEventContent};
 use crate::core::events::{StateEventType,AnyStrippedStateEvent, TimelineEventType};
 use crate::core::federation::membership::*;
@@ -15,7 +14,7 @@ use crate::core::{EventId, OwnedUserId, RoomVersionId, UnixMillis};
 use crate::room::NewDbRoom;
 use crate::schema::*;
 use crate::{
-    db, empty_ok, exts::*, json_ok, utils, AppError, AuthArgs, DepotExt, EmptyResult, JsonResult, MatrixError,
+    db, empty_ok, exts::*, json_ok, utils, AuthArgs, DepotExt, EmptyResult, JsonResult, MatrixError,
     PduBuilder, PduEvent,
 };
 
@@ -44,29 +43,33 @@ async fn make_join(args: MakeJoinReqArgs, depot: &mut Depot, res: &mut Response)
         return Err(MatrixError::not_found("Room is unknown to this server.").into());
     }
     crate::event::handler::acl_check(args.user_id.server_name(), &args.room_id)?;
-    // TODO: Palpo does not implement restricted join rules yet, we always reject
-    let join_rules_event = crate::room::state::get_state(&args.room_id, &StateEventType::RoomJoinRules, "", None)?;
-    let join_rules_event_content: Option<RoomJoinRulesEventContent> = join_rules_event
-        .as_ref()
-        .map(|join_rules_event| {
-            serde_json::from_str(join_rules_event.content.get()).map_err(|e| {
-                warn!("Invalid join rules event: {}", e);
-                AppError::internal("Invalid join rules event in db::")
-            })
-        })
-        .transpose()?;
-    if let Some(join_rules_event_content) = join_rules_event_content {
-        if matches!(
-            join_rules_event_content.join_rule,
-            JoinRule::Restricted { .. } | JoinRule::KnockRestricted { .. }
-        ) {
-            return Err(MatrixError::unable_to_authorize_join("Palpo does not support restricted rooms yet.").into());
-        }
-    }
     let room_version_id = crate::room::state::get_room_version(&args.room_id)?;
     if !args.ver.contains(&room_version_id) {
         return Err(MatrixError::incompatible_room_version(room_version_id, "Room version not supported.").into());
     }
+
+    let join_authorized_via_users_server = match crate::membership::restriction_rooms(&args.room_id)? {
+        Some(restriction_rooms)
+            if !crate::room::is_joined(&args.user_id, &args.room_id)?
+                && !crate::room::is_invited(&args.user_id, &args.room_id)? =>
+        {
+            if !restriction_rooms
+                .iter()
+                .any(|room_id| crate::room::is_joined(&args.user_id, room_id).unwrap_or(false))
+            {
+                return Err(MatrixError::unable_to_authorize_join(
+                    "Joining user is not known to be in any required room.",
+                )
+                .into());
+            }
+            let authorising_user = crate::membership::select_authorising_user(&args.room_id, &args.user_id)?
+                .ok_or_else(|| {
+                    MatrixError::unable_to_grant_join("No user on this server is able to assist in joining.")
+                })?;
+            Some(authorising_user)
+        }
+        _ => None,
+    };
     let content = to_raw_value(&RoomMemberEventContent {
         avatar_url: None,
         blurhash: None,
@@ -75,7 +78,7 @@ async fn make_join(args: MakeJoinReqArgs, depot: &mut Depot, res: &mut Response)
         membership: MembershipState::Join,
         third_party_invite: None,
         reason: None,
-        join_authorized_via_users_server: None,
+        join_authorized_via_users_server,
     })
     .expect("member event is valid value");
     let (_pdu, mut pdu_json) = crate::room::timeline::create_hash_and_sign_event(
@@ -145,6 +148,7 @@ async fn invite_user(
             .into(),
     )
     .map_err(|_| MatrixError::invalid_param("sender is not a user id."))?;
+    crate::federation::check_server_allowed(sender.server_name())?;
 
     let invited_user: Box<_> = serde_json::from_value(
         signed_event
@@ -259,10 +263,8 @@ async fn send_join_v2(
     body: JsonBody<SendJoinReqBodyV2>,
     depot: &mut Depot,
 ) -> JsonResult<SendJoinResBodyV2> {
-    let server_name = args.room_id.server_name().map_err(AppError::public)?;
-    crate::event::handler::acl_check(&server_name, &args.room_id)?;
-
-    let room_state = crate::membership::send_join_v2(&server_name, &args.room_id, &body.pdu).await?;
+    let origin = depot.origin()?;
+    let room_state = crate::membership::send_join_v2(origin, &args.room_id, &body.pdu).await?;
 
     json_ok(SendJoinResBodyV2 { room_state })
 }
@@ -270,9 +272,13 @@ async fn send_join_v2(
 /// #PUT /_matrix/federation/v1/send_join/{room_id}/{event_id}
 /// Submits a signed join event.
 #[endpoint]
-async fn send_join_v1(args: RoomEventReqArgs, body: JsonBody<SendJoinReqBodyV1>) -> JsonResult<SendJoinResBodyV1> {
-    let server_name = args.room_id.server_name().map_err(AppError::public)?;
-    let room_state = crate::membership::send_join_v1(&server_name, &args.room_id, &body.pdu).await?;
+async fn send_join_v1(
+    args: RoomEventReqArgs,
+    body: JsonBody<SendJoinReqBodyV1>,
+    depot: &mut Depot,
+) -> JsonResult<SendJoinResBodyV1> {
+    let origin = depot.origin()?;
+    let room_state = crate::membership::send_join_v1(origin, &args.room_id, &body.pdu).await?;
     json_ok(SendJoinResBodyV1 { room_state })
 }
 
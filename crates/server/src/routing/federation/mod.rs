@@ -26,6 +26,8 @@ pub fn router() -> Router {
     Router::with_path("federation")
         .hoop(check_federation_enabled)
         .hoop(hoops::auth_by_access_token_or_signatures)
+        .hoop(hoops::check_server_acl)
+        .hoop(hoops::limit_rate)
         .oapi_tag("federation")
         .push(
             Router::with_path("v2")
@@ -83,4 +85,4 @@ async fn version() -> JsonResult<ServerVersionResBody> {
             version: Some(env!("CARGO_PKG_VERSION").to_owned()),
         }),
     })
-}
\ No newline at end of file
+}
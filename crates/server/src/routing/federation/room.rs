@@ -1,12 +1,20 @@
 use salvo::oapi::extract::*;
 use salvo::prelude::*;
+use serde_json::value::to_raw_value;
 
 use crate::core::client::directory::{PublicRoomsFilteredReqBody, PublicRoomsReqArgs};
 use crate::core::directory::{PublicRoomFilter, PublicRoomsResBody, RoomNetwork};
+use crate::core::events::room::join_rules::{JoinRule, RoomJoinRulesEventContent};
+use crate::core::events::room::member::{MembershipState, RoomMemberEventContent};
+use crate::core::events::{StateEventType, TimelineEventType};
 use crate::core::federation::event::{
     RoomStateAtEventReqArgs, RoomStateIdsResBody, RoomStateReqArgs, RoomStateResBody,
 };
-use crate::{empty_ok, json_ok, AuthArgs, DepotExt, EmptyResult, JsonResult, MatrixError, PduEvent};
+use crate::core::federation::knock::{
+    MakeKnockReqArgs, MakeKnockResBody, SendKnockReqArgs, SendKnockReqBody, SendKnockResBody,
+};
+use crate::core::RoomVersion;
+use crate::{json_ok, AuthArgs, DepotExt, JsonResult, MatrixError, PduBuilder, PduEvent};
 
 pub fn router() -> Router {
     Router::new()
@@ -17,7 +25,7 @@ pub fn router() -> Router {
                 .post(get_filtered_public_rooms),
         )
         .push(Router::with_path("send_knock/<room_id>/<event_id>").put(send_knock))
-        .push(Router::with_path("make_knock/<room_id>/<user_id>").put(make_knock))
+        .push(Router::with_path("make_knock/<room_id>/<user_id>").get(make_knock))
         .push(Router::with_path("state_ids/<room_id>").get(get_state_at_event))
 }
 
@@ -92,15 +100,113 @@ async fn get_filtered_public_rooms(
     .await?;
     json_ok(body)
 }
+/// #GET /_matrix/federation/v1/make_knock/{room_id}/{user_id}
+/// Creates a knock template.
 #[endpoint]
-async fn send_knock(_aa: AuthArgs) -> EmptyResult {
-    // TODDO: todo
-    empty_ok()
+async fn make_knock(_aa: AuthArgs, args: MakeKnockReqArgs, depot: &mut Depot) -> JsonResult<MakeKnockResBody> {
+    let origin = depot.origin()?;
+    if args.user_id.server_name() != origin {
+        return Err(MatrixError::forbidden("Not allowed to knock on behalf of another server.").into());
+    }
+    if !crate::room::room_exists(&args.room_id)? {
+        return Err(MatrixError::not_found("Room is unknown to this server.").into());
+    }
+    crate::event::handler::acl_check(origin, &args.room_id)?;
+
+    let room_version_id = crate::room::state::get_room_version(&args.room_id)?;
+    if !RoomVersion::new(&room_version_id).map_or(false, |v| v.allow_knocking) {
+        return Err(
+            MatrixError::incompatible_room_version(room_version_id, "Room version does not support knocking.").into(),
+        );
+    }
+    if !args.ver.contains(&room_version_id) {
+        return Err(MatrixError::incompatible_room_version(room_version_id, "Room version not supported.").into());
+    }
+
+    let join_rule = crate::room::state::get_state(&args.room_id, &StateEventType::RoomJoinRules, "", None)?
+        .and_then(|event| serde_json::from_str::<RoomJoinRulesEventContent>(event.content.get()).ok())
+        .map(|content| content.join_rule);
+    if !matches!(join_rule, Some(JoinRule::Knock | JoinRule::KnockRestricted(_))) {
+        return Err(MatrixError::forbidden("This room does not allow knocking.").into());
+    }
+
+    let (_pdu, mut pdu_json) = crate::room::timeline::create_hash_and_sign_event(
+        PduBuilder::state(
+            args.user_id.to_string(),
+            &RoomMemberEventContent::new(MembershipState::Knock),
+        ),
+        &args.user_id,
+        &args.room_id,
+    )?;
+    pdu_json.remove("event_id");
+
+    json_ok(MakeKnockResBody::new(
+        room_version_id,
+        to_raw_value(&pdu_json).expect("CanonicalJson can be serialized to JSON"),
+    ))
 }
+
+/// #PUT /_matrix/federation/v1/send_knock/{room_id}/{event_id}
+/// Submits a signed knock event.
 #[endpoint]
-async fn make_knock(_aa: AuthArgs) -> EmptyResult {
-    // TODDO: todo
-    empty_ok()
+async fn send_knock(
+    _aa: AuthArgs,
+    args: SendKnockReqArgs,
+    body: JsonBody<SendKnockReqBody>,
+    depot: &mut Depot,
+) -> JsonResult<SendKnockResBody> {
+    let origin = depot.origin()?;
+    let body = body.into_inner();
+    if !crate::room::room_exists(&args.room_id)? {
+        return Err(MatrixError::not_found("Room is unknown to this server.").into());
+    }
+    crate::event::handler::acl_check(origin, &args.room_id)?;
+
+    let room_version_id = crate::room::state::get_room_version(&args.room_id)?;
+    if !RoomVersion::new(&room_version_id).map_or(false, |v| v.allow_knocking) {
+        return Err(
+            MatrixError::incompatible_room_version(room_version_id, "Room version does not support knocking.").into(),
+        );
+    }
+
+    // We do not add the event_id field to the pdu here because of signature and hashes checks
+    let Ok((event_id, value)) = crate::event::gen_event_id_canonical_json(&body.0, &room_version_id) else {
+        return Err(MatrixError::invalid_param("Could not convert event to canonical json.").into());
+    };
+    if event_id != args.event_id {
+        return Err(MatrixError::invalid_param("Event id does not match the knock event.").into());
+    }
+
+    let pdu = PduEvent::from_id_val(&event_id, value.clone())
+        .map_err(|_| MatrixError::invalid_param("Invalid knock event."))?;
+    if pdu.room_id != args.room_id {
+        return Err(MatrixError::invalid_param("Event room id does not match the request.").into());
+    }
+    if pdu.event_ty != TimelineEventType::RoomMember {
+        return Err(MatrixError::invalid_param("Not allowed to send non-membership event to knock endpoint.").into());
+    }
+    let content: RoomMemberEventContent = serde_json::from_str(pdu.content.get())
+        .map_err(|_| MatrixError::invalid_param("Event content is empty or invalid."))?;
+    if content.membership != MembershipState::Knock {
+        return Err(
+            MatrixError::invalid_param("Not allowed to send a non-knock membership event to knock endpoint.").into(),
+        );
+    }
+    if pdu.state_key.as_deref() != Some(pdu.sender.as_str()) {
+        return Err(MatrixError::invalid_param("state_key does not match sender user.").into());
+    }
+    if pdu.sender.server_name() != origin {
+        return Err(MatrixError::forbidden("Not allowed to knock on behalf of another server.").into());
+    }
+
+    crate::event::handler::handle_incoming_pdu(origin, &event_id, &args.room_id, value, true).await?;
+
+    let servers = crate::room::get_room_servers(&args.room_id, false)?;
+    crate::sending::send_pdu(servers.into_iter(), &event_id)?;
+
+    json_ok(SendKnockResBody::new(crate::room::state::calculate_knock_state(
+        &args.room_id,
+    )?))
 }
 
 /// #GET /_matrix/federation/v1/state_ids/{room_id}
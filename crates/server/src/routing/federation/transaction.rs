@@ -32,10 +32,14 @@ async fn send_message(
 ) -> JsonResult<SendMessageResBody> {
     let body = body.into_inner();
     let server_name = &crate::config().server_name;
+    let origin = depot.origin()?;
+    if crate::federation::destination::reset_destination(origin)? {
+        info!("{origin} is reachable again");
+    }
 
     let txn_start_time = Instant::now();
-    let resolved_map = handle_pdus(&body.pdus, &body.origin, &txn_start_time).await?;
-    handle_edus(body.edus, &body.origin).await;
+    let resolved_map = handle_pdus(&body.pdus, origin, &txn_start_time).await?;
+    handle_edus(body.edus, origin).await;
 
     json_ok(SendMessageResBody {
         pdus: resolved_map
@@ -205,7 +209,7 @@ async fn handle_edu_typing(origin: &ServerName, typing: TypingContent) {
         return;
     }
 
-    if crate::event::handler::acl_check(typing.user_id.server_name(), &typing.room_id).is_err() {
+    if crate::event::handler::acl_check(origin, &typing.room_id).is_err() {
         warn!(
             %typing.user_id, %typing.room_id, %origin,
             "received typing EDU for ACL'd user's server"
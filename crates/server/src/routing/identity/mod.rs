@@ -7,13 +7,27 @@ mod pubkey;
 mod threepid;
 mod validate;
 
+use salvo::oapi::extract::JsonBody;
 use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::{empty_ok, AuthArgs, DepotExt, EmptyResult};
+use crate::core::JsonValue;
+use crate::{empty_ok, hoops, json_ok, AuthArgs, DepotExt, EmptyResult, JsonResult};
+
+#[derive(ToSchema, Deserialize, Debug)]
+struct AcceptTermsReqBody {
+    /// URLs of the policies the user accepted.
+    user_accepts: Vec<String>,
+}
+#[derive(ToSchema, Serialize, Debug)]
+struct TermsResBody {
+    policies: JsonValue,
+}
 
 pub fn router() -> Router {
     Router::with_path("identity")
         .oapi_tag("identity")
+        .hoop(hoops::limit_rate)
         .push(
             Router::with_path("v2")
                 .get(status)
@@ -25,7 +39,11 @@ pub fn router() -> Router {
                 .push(Router::with_path("store-invite").post(store_invite))
                 .push(Router::with_path("hash_details").post(hash_details))
                 .push(Router::with_path("sign-ed25519").post(sign_ed25519))
-                .push(Router::with_path("terms").get(terms).post(accept_terms)),
+                .push(
+                    Router::with_path("terms")
+                        .get(get_terms)
+                        .push(Router::with_hoop(hoops::auth_by_access_token).post(accept_terms)),
+                ),
         )
         .push(Router::with_path("versions").get(versions))
 }
@@ -73,16 +91,31 @@ async fn sign_ed25519(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
     empty_ok()
 }
 
+/// #POST /_matrix/identity/v2/terms
+/// Records that the user accepted the current version of the terms of service, once every policy
+/// of this version is among the accepted ones.
 #[endpoint]
-async fn accept_terms(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
-    // TODO: LATER
-    let _authed = depot.authed_info()?;
+async fn accept_terms(_aa: AuthArgs, body: JsonBody<AcceptTermsReqBody>, depot: &mut Depot) -> EmptyResult {
+    let authed = depot.authed_info()?;
+    if let Some(terms) = &crate::config().terms {
+        if terms
+            .policies
+            .iter()
+            .all(|policy| body.user_accepts.contains(&policy.url))
+        {
+            crate::terms::record_consent(authed.user_id(), &terms.version)?;
+        }
+    }
     empty_ok()
 }
 
+/// #GET /_matrix/identity/v2/terms
+/// Returns the policies of the terms of service.
 #[endpoint]
-async fn terms(_aa: AuthArgs, depot: &mut Depot) -> EmptyResult {
-    // TODO: LATER
-    let _authed = depot.authed_info()?;
-    empty_ok()
+async fn get_terms(_aa: AuthArgs) -> JsonResult<TermsResBody> {
+    let policies = match &crate::config().terms {
+        Some(terms) => crate::terms::policies(terms),
+        None => JsonValue::Object(Default::default()),
+    };
+    json_ok(TermsResBody { policies })
 }
@@ -1,20 +1,70 @@
+use salvo::oapi::extract::*;
 use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::{empty_ok, AuthArgs, EmptyResult};
+use crate::core::client::account::{TokenViaEmailReqBody, TokenViaEmailResBody};
+use crate::core::identifiers::*;
+use crate::core::third_party::Medium;
+use crate::mailer::MailTemplate;
+use crate::{json_ok, AppResult, AuthArgs, JsonResult, MatrixError};
 
+#[derive(ToSchema, Deserialize, Debug)]
+pub(super) struct SubmitTokenReqBody {
+    sid: OwnedSessionId,
+    client_secret: OwnedClientSecret,
+    token: String,
+}
+#[derive(ToSchema, Serialize, Debug)]
+pub(super) struct SubmitTokenResBody {
+    success: bool,
+}
+
+/// #POST /_matrix/identity/v2/validate/email/requestToken
+/// Sends a validation link to an email address that is added to an account.
 #[endpoint]
-pub(super) async fn create_session(_aa: AuthArgs) -> EmptyResult {
-    // TODDO: todo
-    empty_ok()
+pub(super) async fn create_session(
+    _aa: AuthArgs,
+    body: JsonBody<TokenViaEmailReqBody>,
+) -> JsonResult<TokenViaEmailResBody> {
+    let email = crate::threepid::canonicalize_email(&body.email);
+    if crate::user::get_user_by_threepid(&Medium::Email, &email)?.is_some() {
+        return Err(MatrixError::threepid_in_use("Email address is already in use.").into());
+    }
+    let sid = crate::threepid::request_email_validation(
+        MailTemplate::VerifyEmail,
+        &body.client_secret,
+        &email,
+        body.send_attempt,
+        body.next_link.as_deref(),
+    )
+    .await?;
+    json_ok(TokenViaEmailResBody { sid, submit_url: None })
 }
 
+/// #POST /_matrix/identity/v2/validate/email/submitToken
+/// Validates a session with the token a client got from the user.
 #[endpoint]
-pub(super) async fn validate(_aa: AuthArgs) -> EmptyResult {
-    // TODDO: todo
-    empty_ok()
+pub(super) async fn validate(_aa: AuthArgs, body: JsonBody<SubmitTokenReqBody>) -> JsonResult<SubmitTokenResBody> {
+    crate::threepid::submit_token(&body.sid, &body.client_secret, &body.token)?;
+    json_ok(SubmitTokenResBody { success: true })
 }
+
+/// #GET /_matrix/identity/v2/validate/email/submitToken
+/// Validates a session from the link in the validation email, then sends the user to the
+/// `next_link` of the session if there is one.
 #[endpoint]
-pub(super) async fn validate_by_end_user(_aa: AuthArgs) -> EmptyResult {
-    // TODDO: todo
-    empty_ok()
+pub(super) async fn validate_by_end_user(
+    _aa: AuthArgs,
+    sid: QueryParam<OwnedSessionId, true>,
+    client_secret: QueryParam<OwnedClientSecret, true>,
+    token: QueryParam<String, true>,
+    res: &mut Response,
+) -> AppResult<()> {
+    match crate::threepid::submit_token(&sid, &client_secret, &token)? {
+        Some(next_link) => res.render(Redirect::found(next_link)),
+        None => res.render(Text::Plain(
+            "Your email address has been validated, you can now return to your client.",
+        )),
+    }
+    Ok(())
 }
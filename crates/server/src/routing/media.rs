@@ -1,12 +1,8 @@
-use std::fs;
-use std::io::Cursor;
-
-use mime::Mime;
 use salvo::prelude::*;
 
 use super::client::media::*;
-use crate::core::client::media::*;
-use crate::hoops;
+use crate::core::OwnedServerName;
+use crate::{hoops, AppResult, MatrixError};
 
 pub fn router() -> Router {
     let mut media = Router::with_path("media").oapi_tag("media");
@@ -15,9 +11,10 @@ pub fn router() -> Router {
             .push(
                 Router::with_path(v)
                     .hoop(hoops::auth_by_access_token)
-                    .push(Router::with_path("create").post(create_mxc_uri))
+                    .push(Router::with_path("create").hoop(hoops::limit_rate).post(create_mxc_uri))
                     .push(
                         Router::with_path("upload")
+                            .hoop(hoops::limit_rate)
                             .post(create_content)
                             .push(Router::with_path("<server_name>/<media_id>").put(upload_content)),
                     )
@@ -25,12 +22,17 @@ pub fn router() -> Router {
                         Router::with_hoop(hoops::limit_rate)
                             .push(Router::with_path("config").get(get_config))
                             .push(Router::with_path("preview_url").get(preview_url))
-                            .push(Router::with_path("thumbnail/<server_name>/<media_id>").get(get_thumbnail)),
+                            .push(
+                                Router::with_path("thumbnail/<server_name>/<media_id>")
+                                    .hoop(check_legacy_media)
+                                    .get(get_thumbnail),
+                            ),
                     ),
             )
             .push(
                 Router::with_path(v).push(
                     Router::with_path("download/<server_name>/<media_id>")
+                        .hoop(check_legacy_media)
                         .get(get_content)
                         .push(Router::with_path("<filename>").get(get_content_with_filename)),
                 ),
@@ -38,3 +40,14 @@ pub fn router() -> Router {
     }
     media
 }
+
+/// Hides local media from the legacy unauthenticated endpoints when `media.freeze_legacy_media` is set.
+#[handler]
+async fn check_legacy_media(req: &mut Request) -> AppResult<()> {
+    if crate::config().media.freeze_legacy_media
+        && req.param::<OwnedServerName>("server_name").as_deref() == Some(crate::server_name())
+    {
+        return Err(MatrixError::not_found("Media not found.").into());
+    }
+    Ok(())
+}
@@ -1,5 +1,6 @@
 mod appservice;
 mod client;
+mod consent;
 mod federation;
 mod identity;
 mod media;
@@ -11,7 +12,7 @@ use salvo::prelude::*;
 use salvo::serve_static::StaticDir;
 use url::Url;
 
-use crate::{hoops, json_ok, AppResult, JsonResult};
+use crate::{hoops, json_ok, JsonResult};
 
 pub fn router() -> Router {
     Router::new()
@@ -25,7 +26,8 @@ pub fn router() -> Router {
                 .push(federation::key::router())
                 .push(identity::router())
                 .push(appservice::router())
-                .push(push::router()),
+                .push(push::router())
+                .push(consent::router()),
         )
         .push(
             Router::with_path(".well-known/matrix")
@@ -45,11 +47,6 @@ fn get_origin_host(req: &mut Request) -> Option<String> {
         .and_then(|url| url.host_str().map(|v| v.to_owned()))
 }
 
-#[handler]
-pub async fn limit_rate() -> AppResult<()> {
-    Ok(())
-}
-
 #[endpoint]
 fn well_known_client() -> JsonResult<ClientWellKnownResBody> {
     let client_url = crate::well_known_client();
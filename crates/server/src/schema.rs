@@ -265,6 +265,16 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    federation_destinations (server_id) {
+        server_id -> Text,
+        failure_count -> Int4,
+        failure_ts -> Nullable<Int8>,
+        retry_last_ts -> Nullable<Int8>,
+        retry_interval -> Int8,
+    }
+}
+
 diesel::table! {
     lazy_load_deliveries (id) {
         id -> Int8,
@@ -291,6 +301,32 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    media_quarantines (id) {
+        id -> Int8,
+        media_id -> Text,
+        origin_server -> Text,
+        quarantined_by -> Text,
+        quarantined_at -> Int8,
+    }
+}
+
+diesel::table! {
+    media_reference_backfills (id) {
+        id -> Int8,
+        last_event_sn -> Int8,
+        end_event_sn -> Int8,
+    }
+}
+
+diesel::table! {
+    media_references (origin_server, media_id, event_id) {
+        origin_server -> Text,
+        media_id -> Text,
+        event_id -> Text,
+    }
+}
+
 diesel::table! {
     media_thumbnails (id) {
         id -> Int8,
@@ -303,6 +339,46 @@ diesel::table! {
         height -> Int4,
         resize_method -> Text,
         created_at -> Int8,
+        animated -> Bool,
+    }
+}
+
+diesel::table! {
+    media_url_previews (id) {
+        id -> Int8,
+        url -> Text,
+        og_data -> Json,
+        created_at -> Int8,
+    }
+}
+
+diesel::table! {
+    oidc_discoveries (idp_id) {
+        idp_id -> Text,
+        issuer -> Text,
+        authorization_endpoint -> Text,
+        token_endpoint -> Text,
+        userinfo_endpoint -> Text,
+        fetched_at -> Int8,
+    }
+}
+
+diesel::table! {
+    oidc_pending_logins (state) {
+        state -> Text,
+        idp_id -> Text,
+        code_verifier -> Text,
+        redirect_url -> Text,
+        expired_at -> Int8,
+        created_at -> Int8,
+    }
+}
+
+diesel::table! {
+    outgoing_edu_positions (server_id) {
+        server_id -> Text,
+        last_edu_sn -> Int8,
+        updated_at -> Int8,
     }
 }
 
@@ -568,6 +644,15 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_consents (id) {
+        id -> Int8,
+        user_id -> Text,
+        version -> Text,
+        accepted_at -> Int8,
+    }
+}
+
 diesel::table! {
     user_datas (id) {
         id -> Int8,
@@ -603,6 +688,16 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_external_ids (id) {
+        id -> Int8,
+        auth_provider -> Text,
+        external_id -> Text,
+        user_id -> Text,
+        created_at -> Int8,
+    }
+}
+
 diesel::table! {
     user_filters (id) {
         id -> Int8,
@@ -621,6 +716,16 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_login_tokens (id) {
+        id -> Int8,
+        user_id -> Text,
+        token -> Text,
+        expired_at -> Int8,
+        created_at -> Int8,
+    }
+}
+
 diesel::table! {
     user_openid_tokens (id) {
         id -> Int8,
@@ -720,6 +825,11 @@ diesel::table! {
         device_id -> Text,
         session -> Text,
         uiaa_info -> Json,
+        request -> Nullable<Json>,
+        registration_token -> Nullable<Text>,
+        threepid_session_id -> Nullable<Text>,
+        expires_at -> Int8,
+        created_at -> Int8,
     }
 }
 
@@ -767,9 +877,17 @@ diesel::allow_tables_to_appear_in_same_query!(
     event_searches,
     event_txn_ids,
     events,
+    federation_destinations,
     lazy_load_deliveries,
     media_metadatas,
+    media_quarantines,
+    media_reference_backfills,
+    media_references,
     media_thumbnails,
+    media_url_previews,
+    oidc_discoveries,
+    oidc_pending_logins,
+    outgoing_edu_positions,
     outgoing_requests,
     pushers,
     room_aliases,
@@ -793,11 +911,14 @@ diesel::allow_tables_to_appear_in_same_query!(
     threepid_validation_sessions,
     threepid_validation_tokens,
     user_access_tokens,
+    user_consents,
     user_datas,
     user_dehydrated_devices,
     user_devices,
+    user_external_ids,
     user_filters,
     user_ignores,
+    user_login_tokens,
     user_openid_tokens,
     user_passwords,
     user_presences,
@@ -0,0 +1,85 @@
+//! Setup for tests that need the database
+//!
+//! These tests run against the database in `PALPO_TEST_DATABASE_URL`, which is migrated on first
+//! use. They are ignored by default, run them with `cargo test -- --ignored`. They share one config
+//! and one database, so they only use users and devices of their own.
+
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+
+use diesel::r2d2;
+use figment::providers::{Format, Toml};
+use figment::Figment;
+
+use crate::config::ServerConfig;
+use crate::core::identifiers::*;
+use crate::db::DieselPool;
+
+pub const TEST_SERVER_NAME: &str = "test.palpo.im";
+
+const TEST_CONFIG: &str = r#"
+server_name = "test.palpo.im"
+login_via_existing_session = true
+registration_requires_token = true
+
+[oidc]
+client_redirect_allowlist = ["https://client.example"]
+"#;
+
+/// Address of the identity provider tests log in at, its provider id is `mock`. The test
+/// serving it binds the address.
+pub fn mock_idp_addr() -> SocketAddr {
+    static ADDR: LazyLock<SocketAddr> = LazyLock::new(|| {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("a local port is free")
+    });
+    *ADDR
+}
+
+/// Sets the config and connects to the test database.
+pub fn init_db() {
+    static READY: LazyLock<()> = LazyLock::new(|| {
+        let url = std::env::var("PALPO_TEST_DATABASE_URL").expect("PALPO_TEST_DATABASE_URL is set");
+        let conf = Figment::new()
+            .merge(Toml::string(TEST_CONFIG))
+            .merge(Toml::string(&format!(
+                "[[oidc.providers]]\nid = \"mock\"\nname = \"Mock\"\nissuer = \"http://{}\"\nclient_id = \"palpo\"\n",
+                mock_idp_addr()
+            )))
+            .merge(Toml::string(&format!("[db]\nurl = \"{url}\"\npool_size = 4\n")))
+            .extract::<ServerConfig>()
+            .expect("test config is valid");
+        let pool = DieselPool::new(
+            &conf.db.url,
+            &conf.db,
+            r2d2::Pool::builder().max_size(conf.db.pool_size),
+        )
+        .expect("test database is reachable");
+        crate::db::DIESEL_POOL
+            .set(pool)
+            .ok()
+            .expect("diesel pool is only set once");
+        crate::config::CONFIG.set(conf).ok().expect("config is only set once");
+        crate::db::migrate();
+    });
+    LazyLock::force(&READY);
+}
+
+/// Creates a user with a password and a device, returns the user id and the access token.
+pub fn create_user_with_device(password: &str) -> (OwnedUserId, String) {
+    let user_id = unique_user_id();
+    crate::user::create_user(user_id.clone(), Some(password)).expect("user is created");
+    let access_token = crate::utils::random_string(crate::TOKEN_LENGTH);
+    crate::user::create_device(&user_id, "TESTDEVICE".into(), &access_token, None).expect("device is created");
+    (user_id, access_token)
+}
+
+/// A user id no other test uses.
+pub fn unique_user_id() -> OwnedUserId {
+    UserId::parse(format!(
+        "@{}:{TEST_SERVER_NAME}",
+        crate::utils::random_string(12).to_lowercase()
+    ))
+    .expect("random user id is valid")
+}
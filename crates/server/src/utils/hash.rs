@@ -45,8 +45,12 @@ pub fn hash_string(bytes: &[u8]) -> String {
 
 /// Calculate a new has h for the given password
 pub fn hash_password(password: &str) -> Result<String, argon2::Error> {
+    let conf = &crate::config().argon2;
     let hashing_config = argon2::Config {
         variant: argon2::Variant::Argon2id,
+        mem_cost: conf.memory_cost_kib,
+        time_cost: conf.time_cost,
+        lanes: conf.lanes,
         ..Default::default()
     };
 
@@ -54,6 +58,18 @@ pub fn hash_password(password: &str) -> Result<String, argon2::Error> {
     argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &hashing_config)
 }
 
+/// Whether a password hash was made with other parameters than the configured ones, hashes look
+/// like `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`.
+pub fn is_password_hash_outdated(hash: &str) -> bool {
+    let conf = &crate::config().argon2;
+    let mut parts = hash.split('$').skip(1);
+    if parts.next() != Some("argon2id") {
+        return true;
+    }
+    let params = format!("m={},t={},p={}", conf.memory_cost_kib, conf.time_cost, conf.lanes);
+    parts.find(|part| part.starts_with("m=")) != Some(params.as_str())
+}
+
 #[tracing::instrument(skip(keys))]
 pub fn hash_keys(keys: &[&[u8]]) -> Vec<u8> {
     // We only hash the pdu's event ids, not the whole pdu